@@ -5,7 +5,7 @@
 )]
 
 use serde::Serialize;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use tauri::{
   // state is used in Linux
   self,
@@ -15,9 +15,57 @@ use tauri::{
 use tauri_plugin_store;
 use tauri_plugin_window_state;
 
+mod models;
+mod services;
 mod tray_icon;
 mod utils;
 
+use services::{
+  certificate_authority::{
+    ca_generate_krl, ca_list_issued, ca_mark_key, ca_revoke_certificate, ca_sign_certificate,
+    CertificateAuthority,
+  },
+  certificates::{ssh_certificate_details, ssh_key_certificate_details},
+  directory_sync::{sync_apply, sync_plan, sync_unwatch, sync_watch, DirectorySync},
+  port_forwarding::{
+    forwarding_check_port, forwarding_clear_password, forwarding_delete, forwarding_list,
+    forwarding_pac, forwarding_save, forwarding_start, forwarding_states, forwarding_stop,
+    ForwardingService,
+  },
+  remote_editor::{
+    editor_close, editor_list, editor_open, editor_open_terminal, editor_resolve, RemoteEditor,
+  },
+  remote_tools::{
+    sftp_checksum, sftp_chown, sftp_download_archive, sftp_upload_archive, RemoteTools,
+  },
+  secure_storage::{vault_lock, vault_unlock, SecureStorage},
+  serial::serial_list_ports,
+  sftp::{
+    sftp_chmod, sftp_connect, sftp_delete, sftp_details, sftp_disconnect, sftp_download,
+    sftp_fsync, sftp_list_local_files, sftp_list_remote_files, sftp_mkdir, sftp_readlink,
+    sftp_rename, sftp_stat, sftp_symlink, sftp_upload, SftpService,
+  },
+  ssh_agent::{
+    agent_add_key, agent_confirm, agent_list_keys, agent_remove_key, agent_socket_path, SshAgent,
+  },
+  ssh_manager::{
+    ssh_connect, ssh_disconnect, ssh_host_key_answer, ssh_list_sessions, SshManager,
+  },
+  terminal_manager::{
+    terminal_ack, terminal_break, terminal_close, terminal_create, terminal_open_local,
+    terminal_open_serial, terminal_open_ssh, terminal_open_telnet, terminal_record_start,
+    terminal_record_stop, terminal_resize, terminal_search, terminal_subscribe, terminal_write,
+    TerminalManager,
+  },
+  time_machine::{
+    replay_close, replay_list, replay_open, replay_pause, replay_play, replay_seek,
+    replay_set_speed, TimeMachine,
+  },
+  transfer_manager::{
+    transfer_cancel, transfer_clear_finished, transfer_enqueue, transfer_list, transfer_pause,
+    transfer_remove, transfer_resume, TransferManager,
+  },
+};
 use tray_icon::{create_tray_icon, tray_update_lang, TrayState};
 use utils::long_running_thread;
 
@@ -69,7 +117,94 @@ pub fn run() {
     .plugin(tauri_plugin_shell::init())
     .plugin(tauri_plugin_fs::init())
     // custom commands
-    .invoke_handler(tauri::generate_handler![tray_update_lang, process_file,])
+    .invoke_handler(tauri::generate_handler![
+      tray_update_lang,
+      process_file,
+      agent_socket_path,
+      agent_list_keys,
+      agent_add_key,
+      agent_remove_key,
+      agent_confirm,
+      vault_lock,
+      vault_unlock,
+      ssh_connect,
+      ssh_host_key_answer,
+      ssh_disconnect,
+      ssh_list_sessions,
+      terminal_create,
+      terminal_open_local,
+      terminal_open_serial,
+      terminal_open_ssh,
+      terminal_open_telnet,
+      serial_list_ports,
+      terminal_subscribe,
+      terminal_ack,
+      terminal_write,
+      terminal_resize,
+      terminal_break,
+      terminal_close,
+      terminal_search,
+      terminal_record_start,
+      terminal_record_stop,
+      replay_list,
+      replay_open,
+      replay_play,
+      replay_pause,
+      replay_set_speed,
+      replay_seek,
+      replay_close,
+      ssh_key_certificate_details,
+      ssh_certificate_details,
+      ca_mark_key,
+      ca_sign_certificate,
+      ca_list_issued,
+      ca_revoke_certificate,
+      ca_generate_krl,
+      forwarding_save,
+      forwarding_delete,
+      forwarding_clear_password,
+      forwarding_list,
+      forwarding_start,
+      forwarding_stop,
+      forwarding_states,
+      forwarding_pac,
+      forwarding_check_port,
+      sftp_connect,
+      sftp_disconnect,
+      sftp_list_remote_files,
+      sftp_list_local_files,
+      sftp_stat,
+      sftp_upload,
+      sftp_download,
+      sftp_mkdir,
+      sftp_delete,
+      sftp_rename,
+      sftp_download_archive,
+      sftp_upload_archive,
+      sftp_checksum,
+      sftp_chmod,
+      sftp_chown,
+      sftp_symlink,
+      sftp_readlink,
+      sftp_details,
+      sftp_fsync,
+      transfer_enqueue,
+      transfer_list,
+      transfer_pause,
+      transfer_resume,
+      transfer_cancel,
+      transfer_remove,
+      transfer_clear_finished,
+      sync_plan,
+      sync_apply,
+      sync_watch,
+      sync_unwatch,
+      editor_open,
+      editor_open_terminal,
+      editor_close,
+      editor_resolve,
+      editor_list,
+    ])
     // allow only one instance and propagate args and cwd to existing instance
     .plugin(tauri_plugin_single_instance::init(|app, args, cwd| {
       app
@@ -89,6 +224,108 @@ pub fn run() {
       let app_handle = app.handle().clone();
       tauri::async_runtime::spawn(async move { long_running_thread(&app_handle).await });
 
+      let storage = Arc::new(tauri::async_runtime::block_on(SecureStorage::new())?);
+      let agent = Arc::new(SshAgent::new(
+        Arc::clone(&storage),
+        Arc::new(app.handle().clone()),
+      )?);
+
+      // built-in SSH agent, exported to other terminals through SSH_AUTH_SOCK
+      // and locked together with the vault
+      tauri::async_runtime::spawn(Arc::clone(&agent).watch_vault(storage.subscribe_lock()));
+      let agent_server = Arc::clone(&agent);
+      tauri::async_runtime::spawn(async move {
+        if let Err(e) = agent_server.load_keychain().await {
+          log::error!("Failed to load keychain into SSH agent: {}", e);
+        }
+        if let Err(e) = agent_server.serve().await {
+          log::error!("SSH agent stopped: {}", e);
+        }
+      });
+
+      let ssh_manager = Arc::new(SshManager::new(
+        Arc::clone(&storage),
+        Arc::clone(&agent),
+        Arc::new(app.handle().clone()),
+      ));
+
+      let terminal_manager = Arc::new(TerminalManager::new(
+        Arc::clone(&storage),
+        Arc::new(app.handle().clone()),
+      ));
+
+      // recordings written by the terminal manager, replayed with seeking
+      let time_machine = Arc::new(TimeMachine::new(Arc::new(app.handle().clone())));
+
+      let certificate_authority = Arc::new(CertificateAuthority::new(Arc::clone(&storage)));
+
+      // tunnels close together with the session that carries them
+      let forwarding_service = Arc::new(ForwardingService::new(
+        Arc::clone(&storage),
+        Arc::clone(&ssh_manager),
+        Arc::new(app.handle().clone()),
+      ));
+      // subscribe before anything connects so no session is missed
+      let session_events = ssh_manager.subscribe();
+      tauri::async_runtime::spawn(Arc::clone(&forwarding_service).watch_sessions(session_events));
+      let headless_forwarding = Arc::clone(&forwarding_service);
+      tauri::async_runtime::spawn(async move {
+        if let Err(e) = headless_forwarding.connect_headless().await {
+          log::error!("Failed to connect headless port forwardings: {}", e);
+        }
+      });
+
+      let sftp_service = Arc::new(SftpService::new(Arc::clone(&ssh_manager)));
+      let sftp_events = ssh_manager.subscribe();
+      tauri::async_runtime::spawn(Arc::clone(&sftp_service).watch_sessions(sftp_events));
+      let remote_tools = Arc::new(RemoteTools::new(Arc::clone(&sftp_service)));
+
+      // the last run's queue comes back, waiting for the user to resume it
+      let transfer_manager = Arc::new(TransferManager::new(
+        Arc::clone(&storage),
+        Arc::clone(&sftp_service),
+        Arc::clone(&remote_tools),
+        Arc::new(app.handle().clone()),
+      ));
+      let restored_transfers = Arc::clone(&transfer_manager);
+      tauri::async_runtime::spawn(async move {
+        if let Err(e) = restored_transfers.restore().await {
+          log::error!("Failed to restore the transfer queue: {}", e);
+        }
+      });
+
+      let directory_sync = Arc::new(DirectorySync::new(
+        Arc::clone(&sftp_service),
+        Arc::clone(&transfer_manager),
+        Arc::new(app.handle().clone()),
+      ));
+
+      // remote files opened in a local editor, uploaded again on save
+      let remote_editor = Arc::new(RemoteEditor::new(
+        Arc::clone(&storage),
+        Arc::clone(&sftp_service),
+        Arc::new(app.handle().clone()),
+        Arc::new(app.handle().clone()),
+      ));
+      tauri::async_runtime::spawn(async {
+        if let Err(e) = RemoteEditor::remove_stale_copies().await {
+          log::warn!("Failed to remove stale edit copies: {}", e);
+        }
+      });
+
+      app.manage(storage);
+      app.manage(agent);
+      app.manage(ssh_manager);
+      app.manage(terminal_manager);
+      app.manage(time_machine);
+      app.manage(certificate_authority);
+      app.manage(forwarding_service);
+      app.manage(sftp_service);
+      app.manage(remote_tools);
+      app.manage(transfer_manager);
+      app.manage(directory_sync);
+      app.manage(remote_editor);
+
       #[cfg(target_os = "linux")]
       app.manage(DbusState(Mutex::new(
         dbus::blocking::SyncConnection::new_session().ok(),
@@ -99,8 +336,17 @@ pub fn run() {
 
       Ok(())
     })
-    .run(tauri::generate_context!())
-    .expect("error while running tauri application");
+    .build(tauri::generate_context!())
+    .expect("error while building tauri application")
+    .run(|app, event| {
+      // transfer checkpoints live in memory until something saves them
+      if let tauri::RunEvent::Exit = event {
+        let transfer_manager = app.state::<Arc<TransferManager>>();
+        if let Err(e) = tauri::async_runtime::block_on(transfer_manager.shutdown()) {
+          log::error!("Failed to save the transfer queue: {}", e);
+        }
+      }
+    });
 }
 
 // useful crates
@@ -0,0 +1,45 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum CertificateKind {
+    #[serde(rename = "user")]
+    User,
+    #[serde(rename = "host")]
+    Host,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CertificateRequest {
+    pub ca_key_id: String,
+    // Either a keychain key or a pasted OpenSSH public key
+    pub subject_key_id: Option<String>,
+    pub public_key: Option<String>,
+    pub kind: CertificateKind,
+    pub identity: String,
+    pub principals: Vec<String>,
+    pub valid_after: Option<u64>,
+    pub valid_before: Option<u64>,
+    pub force_command: Option<String>,
+    pub source_address: Option<String>,
+    pub serial: Option<u64>,
+    pub extensions: Option<Vec<String>>,
+    // Replace a keychain key's existing certificate file
+    #[serde(default)]
+    pub overwrite: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IssuedCertificate {
+    pub id: String,
+    pub ca_key_id: String,
+    pub serial: u64,
+    pub kind: CertificateKind,
+    pub identity: String,
+    pub principals: Vec<String>,
+    pub public_key_fingerprint: String,
+    pub valid_after: u64,
+    pub valid_before: u64,
+    pub certificate: String,
+    pub issued_at: i64,
+    pub revoked_at: Option<i64>,
+}
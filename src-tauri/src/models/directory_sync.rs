@@ -0,0 +1,63 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum SyncDirection {
+    // Local tree mirrored onto the server
+    #[serde(rename = "push")]
+    Push,
+    // Remote tree mirrored onto this machine
+    #[serde(rename = "pull")]
+    Pull,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum SyncCompare {
+    #[serde(rename = "size-mtime")]
+    SizeMtime,
+    #[serde(rename = "checksum")]
+    Checksum,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SyncOptions {
+    pub host_id: String,
+    pub local_path: String,
+    pub remote_path: String,
+    pub direction: SyncDirection,
+    pub compare: SyncCompare,
+    // Remove files on the target that the source does not have
+    pub delete: bool,
+    // .gitignore-style patterns, relative to the synced roots
+    pub excludes: Vec<String>,
+    // Also read patterns from the source root's .gitignore
+    pub use_gitignore: Option<bool>,
+    pub verify: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum SyncActionKind {
+    #[serde(rename = "create")]
+    Create,
+    #[serde(rename = "update")]
+    Update,
+    #[serde(rename = "delete")]
+    Delete,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SyncAction {
+    pub kind: SyncActionKind,
+    // `/`-separated, relative to both roots
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+}
+
+/// Dry-run result; applying it performs exactly these actions.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SyncPlan {
+    // Handed back to `sync_apply`
+    pub id: String,
+    pub options: SyncOptions,
+    pub actions: Vec<SyncAction>,
+}
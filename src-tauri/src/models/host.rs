@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use super::serial::SerialConfig;
 use super::snippet::Snippet;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -11,6 +12,16 @@ pub enum AuthType {
     Agent,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum AgentForwarding {
+    // Forward the agent built into Teemux
+    #[serde(rename = "builtin")]
+    Builtin,
+    // Forward the agent named by SSH_AUTH_SOCK
+    #[serde(rename = "system")]
+    System,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Host {
     pub id: String,
@@ -29,10 +40,15 @@ pub struct Host {
     pub last_connected: Option<String>,
     pub jump_host: Option<String>,
     pub use_jump_host: Option<bool>,
+    pub forward_agent: Option<AgentForwarding>,
+    pub forward_agent_confirm: Option<bool>,
     #[serde(default)]
     pub ssh_options: std::collections::HashMap<String, String>,
     pub keep_alive_interval: Option<u32>,
     pub connection_timeout: Option<u32>,
+    // Port settings of serial console hosts
+    #[serde(default)]
+    pub serial: Option<SerialConfig>,
     pub description: Option<String>,
     pub color: Option<String>,
     pub group: Option<String>,
@@ -32,17 +32,86 @@ pub const SETTINGS_UPDATE: &str = "settings:update";
 pub const SSH_CONNECT: &str = "ssh:connect";
 pub const SSH_DISCONNECT: &str = "ssh:disconnect";
 pub const SSH_SEND_DATA: &str = "ssh:sendData";
+pub const SSH_HOST_KEY_PROMPT: &str = "ssh:hostKeyPrompt";
+pub const SSH_HOST_KEY_REJECTED: &str = "ssh:hostKeyRejected";
+pub const SSH_CERTIFICATE_EXPIRING: &str = "ssh:certificateExpiring";
 
 // SFTP related channels
 pub const SFTP_LIST_FILES: &str = "sftp:listFiles";
+pub const SFTP_CONNECT: &str = "sftp:connect";
+pub const SFTP_DISCONNECT: &str = "sftp:disconnect";
+pub const SFTP_LIST_REMOTE_FILES: &str = "sftp:listRemoteFiles";
+pub const SFTP_LIST_LOCAL_FILES: &str = "sftp:listLocalFiles";
+pub const SFTP_STAT: &str = "sftp:stat";
+pub const SFTP_MKDIR: &str = "sftp:mkdir";
+pub const SFTP_DELETE: &str = "sftp:delete";
+pub const SFTP_RENAME: &str = "sftp:rename";
 pub const SFTP_DOWNLOAD: &str = "sftp:download";
 pub const SFTP_UPLOAD: &str = "sftp:upload";
+pub const SFTP_DOWNLOAD_ARCHIVE: &str = "sftp:downloadArchive";
+pub const SFTP_UPLOAD_ARCHIVE: &str = "sftp:uploadArchive";
+pub const SFTP_CHECKSUM: &str = "sftp:checksum";
+pub const SFTP_CHMOD: &str = "sftp:chmod";
+pub const SFTP_CHOWN: &str = "sftp:chown";
+pub const SFTP_SYMLINK: &str = "sftp:symlink";
+pub const SFTP_READLINK: &str = "sftp:readlink";
+pub const SFTP_DETAILS: &str = "sftp:details";
+pub const SFTP_FSYNC: &str = "sftp:fsync";
+
+// Terminal related channels
+pub const TERMINAL_CREATE: &str = "terminal:create";
+pub const TERMINAL_OPEN_LOCAL: &str = "terminal:openLocal";
+pub const TERMINAL_OPEN_SERIAL: &str = "terminal:openSerial";
+pub const TERMINAL_OPEN_TELNET: &str = "terminal:openTelnet";
+pub const TERMINAL_SUBSCRIBE: &str = "terminal:subscribe";
+pub const TERMINAL_ACK: &str = "terminal:ack";
+pub const TERMINAL_WRITE: &str = "terminal:write";
+pub const TERMINAL_RESIZE: &str = "terminal:resize";
+pub const TERMINAL_BREAK: &str = "terminal:break";
+pub const TERMINAL_CLOSE: &str = "terminal:close";
+pub const TERMINAL_STATE: &str = "terminal:state";
+pub const TERMINAL_SEARCH: &str = "terminal:search";
+pub const TERMINAL_RECORD_START: &str = "terminal:recordStart";
+pub const TERMINAL_RECORD_STOP: &str = "terminal:recordStop";
+
+// Serial port related channels
+pub const SERIAL_LIST_PORTS: &str = "serial:listPorts";
+
+// Session replay related channels
+pub const REPLAY_LIST: &str = "replay:list";
+pub const REPLAY_OPEN: &str = "replay:open";
+pub const REPLAY_PLAY: &str = "replay:play";
+pub const REPLAY_PAUSE: &str = "replay:pause";
+pub const REPLAY_SET_SPEED: &str = "replay:setSpeed";
+pub const REPLAY_SEEK: &str = "replay:seek";
+pub const REPLAY_CLOSE: &str = "replay:close";
+pub const REPLAY_STATE: &str = "replay:state";
+
+// Transfer queue related channels
+pub const TRANSFER_UPDATED: &str = "transfer:updated";
+pub const TRANSFER_PROGRESS: &str = "transfer:progress";
+pub const TRANSFER_REMOVED: &str = "transfer:removed";
+
+// Directory sync related channels
+pub const SYNC_WATCH_EVENT: &str = "sync:watchEvent";
+
+// Remote file editing related channels
+pub const EDITOR_EVENT: &str = "editor:event";
 
 // SSH Key related channels
 pub const KEY_GENERATE: &str = "key:generate";
 pub const KEY_LIST: &str = "key:list";
 pub const KEY_DELETE: &str = "key:delete";
 
+// SSH agent related channels
+pub const AGENT_CONFIRM_REQUEST: &str = "agent:confirmRequest";
+pub const AGENT_LOCKED: &str = "agent:locked";
+pub const AGENT_UNLOCKED: &str = "agent:unlocked";
+
+// Port forwarding related channels
+pub const FORWARDING_STATE: &str = "forwarding:state";
+pub const FORWARDING_CONNECTION: &str = "forwarding:connection";
+
 // App related channels
 pub const APP_QUIT: &str = "app:quit";
 pub const APP_MINIMIZE: &str = "app:minimize";
@@ -0,0 +1,15 @@
+pub mod certificate_authority;
+pub mod directory_sync;
+pub mod host;
+pub mod ipc_channels;
+pub mod port_forwarding;
+pub mod recording;
+pub mod remote_editor;
+pub mod serial;
+pub mod session;
+pub mod sftp;
+pub mod snippet;
+pub mod ssh_key;
+pub mod terminal;
+pub mod transfer;
+pub mod user_settings;
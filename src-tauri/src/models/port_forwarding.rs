@@ -8,6 +8,9 @@ pub enum PortForwardingType {
     Remote,
     #[serde(rename = "dynamic")]
     Dynamic,
+    // HTTP CONNECT proxy, the HTTP-speaking sibling of Dynamic
+    #[serde(rename = "http")]
+    HttpProxy,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -17,10 +20,27 @@ pub struct PortForwarding {
     #[serde(rename = "type")]
     pub forwarding_type: PortForwardingType,
     pub local_port: u16,
+    pub bind_address: Option<String>,
     pub remote_host: Option<String>,
     pub remote_port: Option<u16>,
+    // Port the server actually bound for remote forwards
+    pub bound_port: Option<u16>,
+    // Unix socket paths used instead of TCP on this machine or the server
+    pub local_socket_path: Option<String>,
+    pub remote_socket_path: Option<String>,
+    // File mode for a local socket we create, 0o600 when unset
+    pub socket_mode: Option<u32>,
+    // Proxy options for dynamic and HTTP proxy forwards
+    pub socks_username: Option<String>,
+    pub socks_password: Option<String>,
+    pub remote_dns: Option<bool>,
+    pub allowed_destinations: Option<Vec<String>>,
+    pub denied_destinations: Option<Vec<String>>,
+    pub pac_domains: Option<Vec<String>>,
     pub description: Option<String>,
+    // Starts with its host's session; headless keeps a session open for it
     pub enabled: bool,
+    pub headless: Option<bool>,
     pub created_at: i64,
     pub updated_at: i64,
     pub is_pro_feature: bool,
@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+
+/// An asciicast v2 recording on disk.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RecordingInfo {
+    pub path: String,
+    pub title: Option<String>,
+    // RFC 3339, from the header's timestamp
+    pub started_at: Option<String>,
+    pub size: u64,
+}
+
+/// A recording loaded for replay.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReplayInfo {
+    pub replay_id: String,
+    pub width: u16,
+    pub height: u16,
+    // Seconds
+    pub duration: f64,
+    // Times seeking can restore the screen at without replaying from the start
+    pub keyframes: Vec<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReplayState {
+    pub replay_id: String,
+    // Seconds into the recording
+    pub position: f64,
+    pub playing: bool,
+    pub speed: f64,
+    // Terminal size at `position`
+    pub width: u16,
+    pub height: u16,
+}
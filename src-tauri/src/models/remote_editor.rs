@@ -0,0 +1,14 @@
+use serde::{Deserialize, Serialize};
+
+/// A remote file checked out into a private temp dir for a local editor.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EditSession {
+    pub id: String,
+    pub host_id: String,
+    pub remote_path: String,
+    pub local_path: String,
+    pub opened_at: String,
+    // Set for editors taken from $VISUAL or $EDITOR, which need a terminal;
+    // run it with `editor_open_terminal`
+    pub terminal_editor: Option<String>,
+}
@@ -0,0 +1,69 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum Parity {
+    #[default]
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "odd")]
+    Odd,
+    #[serde(rename = "even")]
+    Even,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub enum FlowControl {
+    #[default]
+    #[serde(rename = "none")]
+    None,
+    // XON/XOFF
+    #[serde(rename = "software")]
+    Software,
+    // RTS/CTS
+    #[serde(rename = "hardware")]
+    Hardware,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum LineEnding {
+    #[serde(rename = "cr")]
+    Cr,
+    #[serde(rename = "lf")]
+    Lf,
+    #[serde(rename = "crlf")]
+    CrLf,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SerialConfig {
+    // e.g. /dev/ttyUSB0 or COM3
+    pub port: String,
+    pub baud_rate: u32,
+    // 5 to 8
+    pub data_bits: u8,
+    #[serde(default)]
+    pub parity: Parity,
+    // 1 or 2
+    pub stop_bits: u8,
+    #[serde(default)]
+    pub flow_control: FlowControl,
+    // What Enter is sent as, typed input goes out unchanged when unset
+    #[serde(default)]
+    pub line_ending: Option<LineEnding>,
+    // Adds a carriage return to bare line feeds from the device
+    #[serde(default)]
+    pub implicit_cr: bool,
+}
+
+/// A serial port found on this machine.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct SerialPortInfo {
+    pub name: String,
+    // "usb", "pci", "bluetooth" or "unknown"
+    pub port_type: String,
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial_number: Option<String>,
+}
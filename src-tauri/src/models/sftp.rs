@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FileRights {
+    pub user: String,
+    pub group: String,
+    pub other: String,
+}
+
+// Mirrors the FileEntry shape the frontend got from ssh2-sftp-client
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FileEntry {
+    // "d" for directories, "l" for symlinks, "-" for everything else
+    #[serde(rename = "type")]
+    pub file_type: String,
+    pub name: String,
+    pub path: String,
+    pub size: u64,
+    // Milliseconds since the epoch
+    #[serde(rename = "modifyTime")]
+    pub modify_time: i64,
+    #[serde(rename = "accessTime")]
+    pub access_time: i64,
+    pub rights: FileRights,
+    pub owner: u32,
+    pub group: u32,
+    #[serde(rename = "linkTarget")]
+    pub link_target: Option<String>,
+}
+
+/// How a bulk download or upload moved its files.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ArchiveMethod {
+    // Streamed as one tar archive, compressed on the way
+    #[serde(rename = "tar+zstd")]
+    TarZstd,
+    #[serde(rename = "tar+gzip")]
+    TarGzip,
+    #[serde(rename = "tar")]
+    Tar,
+    // The server has no tar, files were copied one by one
+    #[serde(rename = "per-file")]
+    PerFile,
+}
+
+/// Usage of the filesystem a remote path lives on, from statvfs@openssh.com.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FilesystemStats {
+    pub block_size: u64,
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+    // Free space unprivileged users may use
+    pub available_bytes: u64,
+    pub total_inodes: u64,
+    pub free_inodes: u64,
+    pub read_only: bool,
+    pub max_name_length: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FileDetails {
+    pub entry: FileEntry,
+    // Permission bits in octal, e.g. "0755"
+    pub mode: String,
+    // None when the server lacks the statvfs extension
+    pub filesystem: Option<FilesystemStats>,
+}
@@ -21,8 +21,12 @@ pub struct SSHKey {
     pub bits: Option<u32>,
     pub private_key_path: String,
     pub public_key_path: String,
+    pub certificate_path: Option<String>,
     pub passphrase: Option<String>,
     pub host_ids: Vec<String>,
+    pub agent_confirm: Option<bool>,
+    pub agent_lifetime: Option<u64>,
+    pub is_ca: Option<bool>,
     pub created_at: i64,
     pub updated_at: i64,
 } 
\ No newline at end of file
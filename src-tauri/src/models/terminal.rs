@@ -0,0 +1,25 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum TerminalState {
+    // Registered, no backend stream yet
+    #[serde(rename = "created")]
+    Created,
+    // A backend stream is connected and its output is being forwarded
+    #[serde(rename = "attached")]
+    Attached,
+    // The backend stream ended or was detached; another one may be attached
+    #[serde(rename = "detached")]
+    Detached,
+    #[serde(rename = "closed")]
+    Closed,
+}
+
+/// A scrollback search hit. Offsets count bytes of raw output from the
+/// terminal's first byte, escape sequences included.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ScrollbackMatch {
+    pub start: u64,
+    pub end: u64,
+    pub text: String,
+}
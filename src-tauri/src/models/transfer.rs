@@ -0,0 +1,64 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum TransferDirection {
+    #[serde(rename = "upload")]
+    Upload,
+    #[serde(rename = "download")]
+    Download,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum TransferStatus {
+    #[serde(rename = "queued")]
+    Queued,
+    #[serde(rename = "running")]
+    Running,
+    #[serde(rename = "paused")]
+    Paused,
+    #[serde(rename = "verifying")]
+    Verifying,
+    #[serde(rename = "completed")]
+    Completed,
+    #[serde(rename = "failed")]
+    Failed,
+    #[serde(rename = "cancelled")]
+    Cancelled,
+}
+
+impl TransferStatus {
+    pub fn is_finished(self) -> bool {
+        matches!(self, Self::Completed | Self::Failed | Self::Cancelled)
+    }
+}
+
+/// A single file transfer in the persistent queue. Directories are expanded
+/// into one transfer per file when they are queued.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Transfer {
+    pub id: String,
+    pub host_id: String,
+    pub direction: TransferDirection,
+    pub local_path: String,
+    pub remote_path: String,
+    pub size: u64,
+    // Bytes known to be on the target, where an interrupted transfer resumes
+    pub transferred: u64,
+    pub status: TransferStatus,
+    // Compare SHA-256 digests of both sides once the copy finishes
+    pub verify: bool,
+    pub checksum: Option<String>,
+    pub error: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TransferProgress {
+    pub id: String,
+    pub transferred: u64,
+    pub size: u64,
+    // Bytes per second since the transfer (re)started
+    pub rate: f64,
+    pub eta_seconds: Option<u64>,
+}
@@ -13,4 +13,19 @@ pub struct UserSettings {
     pub scroll_back_buffer: u32,
     pub auto_update: bool,
     pub telemetry_enabled: bool,
+    #[serde(default)]
+    pub pro_user: bool,
+    // Transfers allowed to run at once against a single host
+    #[serde(default)]
+    pub max_transfers_per_host: Option<u32>,
+    // GUI editor remote files are opened with, e.g. "code --wait". Without
+    // one, $VISUAL or $EDITOR runs in a terminal tab instead.
+    #[serde(default)]
+    pub external_editor: Option<String>,
+    // Record every terminal to an asciicast file
+    #[serde(default)]
+    pub record_sessions: bool,
+    // Keystrokes are left out of recordings unless this is set
+    #[serde(default)]
+    pub record_input: bool,
 } 
\ No newline at end of file
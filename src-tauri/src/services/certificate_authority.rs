@@ -0,0 +1,560 @@
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use ssh_encoding::Encode;
+use ssh_key::{
+    certificate::{Builder, CertType},
+    rand_core::OsRng,
+    HashAlg, PublicKey,
+};
+use std::{fs, path::Path, sync::Arc};
+use tauri::{command, State};
+use uuid::Uuid;
+
+use crate::models::certificate_authority::{CertificateKind, CertificateRequest, IssuedCertificate};
+use crate::services::{certificates::unix_now, secure_storage::SecureStorage};
+
+// Back-date certificates slightly so clock skew does not reject fresh ones
+const CLOCK_SKEW: u64 = 5 * 60;
+const DEFAULT_VALIDITY: u64 = 24 * 60 * 60;
+
+// Extensions ssh-keygen grants user certificates by default
+const DEFAULT_USER_EXTENSIONS: [&str; 5] = [
+    "permit-X11-forwarding",
+    "permit-agent-forwarding",
+    "permit-port-forwarding",
+    "permit-pty",
+    "permit-user-rc",
+];
+
+// KRL format constants (OpenSSH PROTOCOL.krl)
+const KRL_MAGIC: u64 = 0x5353484b524c0a00;
+const KRL_FORMAT_VERSION: u32 = 1;
+const KRL_SECTION_CERTIFICATES: u8 = 1;
+const KRL_SECTION_CERT_SERIAL_LIST: u8 = 0x20;
+
+pub struct CertificateAuthority {
+    storage: Arc<SecureStorage>,
+}
+
+impl CertificateAuthority {
+    pub fn new(storage: Arc<SecureStorage>) -> Self {
+        Self { storage }
+    }
+
+    pub async fn mark_as_ca(&self, key_id: &str, is_ca: bool) -> Result<()> {
+        let mut key = self
+            .storage
+            .get_ssh_key(key_id)
+            .await?
+            .context("SSH key not found")?;
+        key.is_ca = Some(is_ca);
+        self.storage.save_ssh_key(key).await
+    }
+
+    pub async fn sign(&self, request: CertificateRequest) -> Result<IssuedCertificate> {
+        let ca_key = self
+            .storage
+            .get_ssh_key(&request.ca_key_id)
+            .await?
+            .context("CA key not found")?;
+        if ca_key.is_ca != Some(true) {
+            bail!("{} is not marked as a certificate authority", ca_key.name);
+        }
+
+        let mut subject_key = None;
+        let public_key = match (&request.subject_key_id, &request.public_key) {
+            (Some(key_id), _) => {
+                let key = self
+                    .storage
+                    .get_ssh_key(key_id)
+                    .await?
+                    .context("Subject key not found")?;
+                let public_key = PublicKey::read_openssh_file(Path::new(&key.public_key_path))?;
+                let path = certificate_path(&key.public_key_path);
+                if !request.overwrite && Path::new(&path).exists() {
+                    bail!("{} already exists", path);
+                }
+                subject_key = Some(key);
+                public_key
+            }
+            (None, Some(openssh)) => PublicKey::from_openssh(openssh.trim())?,
+            (None, None) => bail!("No public key to sign"),
+        };
+
+        let now = unix_now();
+        let valid_after = request.valid_after.unwrap_or(now.saturating_sub(CLOCK_SKEW));
+        let valid_before = request.valid_before.unwrap_or(now + DEFAULT_VALIDITY);
+
+        let mut builder = Builder::new_with_random_nonce(
+            &mut OsRng,
+            public_key.key_data().clone(),
+            valid_after,
+            valid_before,
+        )?;
+        builder.key_id(request.identity.clone())?;
+        builder.cert_type(match request.kind {
+            CertificateKind::User => CertType::User,
+            CertificateKind::Host => CertType::Host,
+        })?;
+
+        if request.principals.is_empty() {
+            builder.all_principals_valid()?;
+        }
+        for principal in &request.principals {
+            builder.valid_principal(principal.clone())?;
+        }
+
+        if let Some(command) = &request.force_command {
+            builder.critical_option("force-command", command.clone())?;
+        }
+        if let Some(addresses) = &request.source_address {
+            builder.critical_option("source-address", addresses.clone())?;
+        }
+
+        // Extensions only exist on user certificates
+        if request.kind == CertificateKind::User {
+            let extensions = match &request.extensions {
+                Some(extensions) => extensions.clone(),
+                None => DEFAULT_USER_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+            };
+            for extension in extensions {
+                builder.extension(extension, "")?;
+            }
+        }
+
+        let ca_private_key = self.storage.load_private_key(&ca_key.id).await?;
+        let fingerprint = public_key.fingerprint(HashAlg::Sha256).to_string();
+        let issued = self
+            .storage
+            .record_issued_certificate(&ca_key.id, request.serial, |serial| {
+                builder.serial(serial)?;
+                let certificate = builder.sign(&ca_private_key)?;
+                Ok(IssuedCertificate {
+                    id: Uuid::new_v4().to_string(),
+                    ca_key_id: ca_key.id.clone(),
+                    serial,
+                    kind: request.kind,
+                    identity: request.identity,
+                    principals: request.principals,
+                    public_key_fingerprint: fingerprint,
+                    valid_after,
+                    valid_before,
+                    certificate: certificate.to_openssh()?,
+                    issued_at: Utc::now().timestamp(),
+                    revoked_at: None,
+                })
+            })
+            .await?;
+
+        // Keychain subjects get their certificate saved next to the public key
+        if let Some(mut key) = subject_key {
+            let path = certificate_path(&key.public_key_path);
+            fs::write(&path, format!("{}\n", issued.certificate))?;
+            key.certificate_path = Some(path);
+            self.storage.save_ssh_key(key).await?;
+        }
+
+        Ok(issued)
+    }
+
+    /// Builds an OpenSSH key revocation list covering every revoked
+    /// certificate issued by the CA, usable as `RevokedKeys` in sshd_config.
+    pub async fn generate_krl(&self, ca_key_id: &str) -> Result<Vec<u8>> {
+        let ca_key = self
+            .storage
+            .get_ssh_key(ca_key_id)
+            .await?
+            .context("CA key not found")?;
+        let ca_public_key = PublicKey::read_openssh_file(Path::new(&ca_key.public_key_path))?;
+
+        let revoked: Vec<IssuedCertificate> = self
+            .storage
+            .get_issued_certificates(ca_key_id)
+            .await?
+            .into_iter()
+            .filter(|c| c.revoked_at.is_some())
+            .collect();
+
+        let mut krl = Vec::new();
+        KRL_MAGIC.encode(&mut krl)?;
+        KRL_FORMAT_VERSION.encode(&mut krl)?;
+        // The KRL version only has to increase between generations
+        unix_now().encode(&mut krl)?;
+        unix_now().encode(&mut krl)?;
+        0u64.encode(&mut krl)?;
+        "".encode(&mut krl)?;
+        format!("Teemux KRL for {}", ca_key.name).encode(&mut krl)?;
+
+        let mut serials: Vec<u64> = revoked.iter().map(|c| c.serial).collect();
+        serials.sort_unstable();
+        serials.dedup();
+
+        if !serials.is_empty() {
+            let mut serial_list = Vec::new();
+            for serial in &serials {
+                serial.encode(&mut serial_list)?;
+            }
+
+            let mut section = Vec::new();
+            let mut ca_blob = Vec::new();
+            ca_public_key.key_data().encode(&mut ca_blob)?;
+            ca_blob.encode(&mut section)?;
+            "".encode(&mut section)?;
+            KRL_SECTION_CERT_SERIAL_LIST.encode(&mut section)?;
+            serial_list.encode(&mut section)?;
+
+            KRL_SECTION_CERTIFICATES.encode(&mut krl)?;
+            section.encode(&mut krl)?;
+        }
+
+        Ok(krl)
+    }
+}
+
+/// Path OpenSSH looks for a key's certificate at: `id_ed25519.pub` becomes
+/// `id_ed25519-cert.pub`.
+fn certificate_path(public_key_path: &str) -> String {
+    match public_key_path.strip_suffix(".pub") {
+        Some(stem) => format!("{}-cert.pub", stem),
+        None => format!("{}-cert.pub", public_key_path),
+    }
+}
+
+#[command]
+pub async fn ca_mark_key(
+    authority: State<'_, Arc<CertificateAuthority>>,
+    key_id: String,
+    is_ca: bool,
+) -> Result<(), String> {
+    authority
+        .mark_as_ca(&key_id, is_ca)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn ca_sign_certificate(
+    authority: State<'_, Arc<CertificateAuthority>>,
+    request: CertificateRequest,
+) -> Result<IssuedCertificate, String> {
+    authority.sign(request).await.map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn ca_list_issued(
+    storage: State<'_, Arc<SecureStorage>>,
+    ca_key_id: String,
+) -> Result<Vec<IssuedCertificate>, String> {
+    storage
+        .get_issued_certificates(&ca_key_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn ca_revoke_certificate(
+    storage: State<'_, Arc<SecureStorage>>,
+    ca_key_id: String,
+    serial: u64,
+) -> Result<(), String> {
+    storage
+        .revoke_issued_certificate(&ca_key_id, serial)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn ca_generate_krl(
+    authority: State<'_, Arc<CertificateAuthority>>,
+    ca_key_id: String,
+    path: String,
+) -> Result<(), String> {
+    let krl = authority
+        .generate_krl(&ca_key_id)
+        .await
+        .map_err(|e| e.to_string())?;
+    fs::write(path, krl).map_err(|e| e.to_string())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::models::ssh_key::{SSHKey, SSHKeyType};
+    use crate::services::secure_storage::temporary_storage;
+    use ssh_encoding::Decode;
+    use ssh_key::{public::KeyData, Algorithm, Certificate, LineEnding, PrivateKey};
+    use std::path::PathBuf;
+
+    /// A CA and a subject key in the keychain, their files in a temp directory.
+    struct Keys {
+        authority: CertificateAuthority,
+        storage: Arc<SecureStorage>,
+        ca_id: String,
+        ca: PrivateKey,
+        subject_id: String,
+        subject: PrivateKey,
+        dir: PathBuf,
+    }
+
+    impl Keys {
+        async fn new() -> Self {
+            let dir = std::env::temp_dir().join(format!("teemux-ca-{}", Uuid::new_v4()));
+            fs::create_dir_all(&dir).unwrap();
+            let storage = Arc::new(temporary_storage().await);
+            let (ca_id, ca) = Self::add(&storage, &dir, "ca").await;
+            let (subject_id, subject) = Self::add(&storage, &dir, "id_ed25519").await;
+            let authority = CertificateAuthority::new(Arc::clone(&storage));
+            authority.mark_as_ca(&ca_id, true).await.unwrap();
+            Self {
+                authority,
+                storage,
+                ca_id,
+                ca,
+                subject_id,
+                subject,
+                dir,
+            }
+        }
+
+        async fn add(storage: &SecureStorage, dir: &Path, name: &str) -> (String, PrivateKey) {
+            let key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+            let private_key_path = dir.join(name);
+            let public_key_path = dir.join(format!("{}.pub", name));
+            key.write_openssh_file(&private_key_path, LineEnding::LF)
+                .unwrap();
+            key.public_key()
+                .write_openssh_file(&public_key_path)
+                .unwrap();
+
+            let id = Uuid::new_v4().to_string();
+            storage
+                .save_ssh_key(SSHKey {
+                    id: id.clone(),
+                    name: name.to_string(),
+                    key_type: SSHKeyType::Ed25519,
+                    bits: None,
+                    private_key_path: private_key_path.to_string_lossy().to_string(),
+                    public_key_path: public_key_path.to_string_lossy().to_string(),
+                    certificate_path: None,
+                    passphrase: None,
+                    host_ids: Vec::new(),
+                    agent_confirm: None,
+                    agent_lifetime: None,
+                    is_ca: None,
+                    created_at: 0,
+                    updated_at: 0,
+                })
+                .await
+                .unwrap();
+            (id, key)
+        }
+
+        fn request(&self, kind: CertificateKind) -> CertificateRequest {
+            CertificateRequest {
+                ca_key_id: self.ca_id.clone(),
+                subject_key_id: None,
+                public_key: None,
+                kind,
+                identity: "alice@example.com".to_string(),
+                principals: Vec::new(),
+                valid_after: None,
+                valid_before: None,
+                force_command: None,
+                source_address: None,
+                serial: None,
+                extensions: None,
+                overwrite: false,
+            }
+        }
+
+        /// Parses an issued certificate, checking it is signed by the CA.
+        fn parse(&self, issued: &IssuedCertificate) -> Certificate {
+            let certificate = Certificate::from_openssh(&issued.certificate).unwrap();
+            let ca = self.ca.public_key().fingerprint(HashAlg::Sha256);
+            certificate.validate_at(unix_now(), [&ca]).unwrap();
+            assert_eq!(certificate.serial(), issued.serial);
+            assert_eq!(certificate.valid_after(), issued.valid_after);
+            assert_eq!(certificate.valid_before(), issued.valid_before);
+            certificate
+        }
+    }
+
+    impl Drop for Keys {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[tokio::test]
+    async fn signs_user_certificates_next_to_keychain_keys() {
+        let keys = Keys::new().await;
+        let request = CertificateRequest {
+            subject_key_id: Some(keys.subject_id.clone()),
+            principals: vec!["deploy".to_string(), "backup".to_string()],
+            force_command: Some("uptime".to_string()),
+            source_address: Some("10.0.0.0/8".to_string()),
+            ..keys.request(CertificateKind::User)
+        };
+        let issued = keys.authority.sign(request.clone()).await.unwrap();
+
+        let certificate = keys.parse(&issued);
+        assert_eq!(certificate.serial(), 1);
+        assert_eq!(certificate.cert_type(), CertType::User);
+        assert_eq!(certificate.key_id(), "alice@example.com");
+        assert_eq!(certificate.valid_principals(), ["deploy", "backup"]);
+        assert_eq!(
+            certificate.public_key(),
+            keys.subject.public_key().key_data()
+        );
+        assert_eq!(certificate.signature_key(), keys.ca.public_key().key_data());
+        assert_eq!(certificate.critical_options()["force-command"], "uptime");
+        assert_eq!(
+            certificate.critical_options()["source-address"],
+            "10.0.0.0/8"
+        );
+        let extensions: Vec<&str> = certificate
+            .extensions()
+            .keys()
+            .map(String::as_str)
+            .collect();
+        assert_eq!(extensions, DEFAULT_USER_EXTENSIONS);
+
+        // Saved where ssh looks for it
+        let path = keys.dir.join("id_ed25519-cert.pub");
+        let saved = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            Certificate::from_openssh(saved.trim()).unwrap(),
+            certificate
+        );
+        let subject = keys
+            .storage
+            .get_ssh_key(&keys.subject_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            subject.certificate_path,
+            Some(path.to_string_lossy().to_string())
+        );
+
+        let error = keys.authority.sign(request.clone()).await.unwrap_err();
+        assert!(error.to_string().ends_with("already exists"), "{}", error);
+        let request = CertificateRequest {
+            overwrite: true,
+            ..request
+        };
+        assert_eq!(keys.authority.sign(request).await.unwrap().serial, 2);
+    }
+
+    #[tokio::test]
+    async fn signs_pasted_host_keys() {
+        let keys = Keys::new().await;
+        let host = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        let request = CertificateRequest {
+            public_key: Some(host.public_key().to_openssh().unwrap()),
+            valid_after: Some(1_000),
+            valid_before: Some(4_102_444_800),
+            serial: Some(42),
+            ..keys.request(CertificateKind::Host)
+        };
+        let issued = keys.authority.sign(request.clone()).await.unwrap();
+
+        let certificate = keys.parse(&issued);
+        assert_eq!(certificate.serial(), 42);
+        assert_eq!(certificate.cert_type(), CertType::Host);
+        assert_eq!(certificate.public_key(), host.public_key().key_data());
+        // No principals means any, and hosts get no extensions
+        assert!(certificate.valid_principals().is_empty());
+        assert!(certificate.extensions().is_empty());
+
+        // Serials stay unique to the CA
+        assert!(keys.authority.sign(request.clone()).await.is_err());
+        let request = CertificateRequest {
+            serial: None,
+            ..request
+        };
+        assert_eq!(keys.authority.sign(request).await.unwrap().serial, 43);
+    }
+
+    #[tokio::test]
+    async fn refuses_keys_not_marked_as_ca() {
+        let keys = Keys::new().await;
+        keys.authority.mark_as_ca(&keys.ca_id, false).await.unwrap();
+        let request = CertificateRequest {
+            subject_key_id: Some(keys.subject_id.clone()),
+            ..keys.request(CertificateKind::User)
+        };
+        assert!(keys.authority.sign(request).await.is_err());
+        assert!(!keys.dir.join("id_ed25519-cert.pub").exists());
+    }
+
+    #[tokio::test]
+    async fn krl_lists_revoked_serials_under_the_ca() {
+        let keys = Keys::new().await;
+        let host = PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap();
+        for serial in [9, 3, 5] {
+            let request = CertificateRequest {
+                public_key: Some(host.public_key().to_openssh().unwrap()),
+                serial: Some(serial),
+                ..keys.request(CertificateKind::Host)
+            };
+            keys.authority.sign(request).await.unwrap();
+        }
+        keys.storage
+            .revoke_issued_certificate(&keys.ca_id, 9)
+            .await
+            .unwrap();
+        keys.storage
+            .revoke_issued_certificate(&keys.ca_id, 3)
+            .await
+            .unwrap();
+
+        let krl = keys.authority.generate_krl(&keys.ca_id).await.unwrap();
+        let reader = &mut krl.as_slice();
+        assert_eq!(u64::decode(reader).unwrap(), KRL_MAGIC);
+        assert_eq!(u32::decode(reader).unwrap(), KRL_FORMAT_VERSION);
+        let _krl_version = u64::decode(reader).unwrap();
+        let generated = u64::decode(reader).unwrap();
+        assert!(generated.abs_diff(unix_now()) < 60);
+        assert_eq!(u64::decode(reader).unwrap(), 0);
+        assert_eq!(String::decode(reader).unwrap(), "");
+        assert_eq!(String::decode(reader).unwrap(), "Teemux KRL for ca");
+
+        assert_eq!(u8::decode(reader).unwrap(), KRL_SECTION_CERTIFICATES);
+        let section = Vec::<u8>::decode(reader).unwrap();
+        assert!(reader.is_empty());
+
+        let section = &mut section.as_slice();
+        let ca = Vec::<u8>::decode(section).unwrap();
+        assert_eq!(
+            &KeyData::decode(&mut ca.as_slice()).unwrap(),
+            keys.ca.public_key().key_data()
+        );
+        assert_eq!(String::decode(section).unwrap(), "");
+        assert_eq!(u8::decode(section).unwrap(), KRL_SECTION_CERT_SERIAL_LIST);
+        let serials = Vec::<u8>::decode(section).unwrap();
+        assert!(section.is_empty());
+
+        let serials = &mut serials.as_slice();
+        let mut revoked = Vec::new();
+        while !serials.is_empty() {
+            revoked.push(u64::decode(serials).unwrap());
+        }
+        assert_eq!(revoked, [3, 9]);
+    }
+
+    #[tokio::test]
+    async fn krl_without_revocations_has_no_sections() {
+        let keys = Keys::new().await;
+        let krl = keys.authority.generate_krl(&keys.ca_id).await.unwrap();
+        let reader = &mut krl.as_slice();
+        assert_eq!(u64::decode(reader).unwrap(), KRL_MAGIC);
+        assert_eq!(u32::decode(reader).unwrap(), KRL_FORMAT_VERSION);
+        for _ in 0..3 {
+            u64::decode(reader).unwrap();
+        }
+        String::decode(reader).unwrap();
+        String::decode(reader).unwrap();
+        assert!(reader.is_empty());
+    }
+}
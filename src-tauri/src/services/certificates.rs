@@ -0,0 +1,110 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use ssh_key::{Certificate, HashAlg};
+use std::{
+    collections::BTreeMap,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tauri::{command, State};
+
+use crate::services::secure_storage::SecureStorage;
+
+// Certificates valid until the end of time use this as valid_before
+const FOREVER: u64 = u64::MAX;
+
+/// How far ahead of expiry a user certificate starts raising warnings.
+pub const EXPIRY_WARNING: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CertificateDetails {
+    pub key_id: String,
+    pub serial: u64,
+    pub cert_type: String,
+    pub algorithm: String,
+    pub public_key_fingerprint: String,
+    pub signing_ca_fingerprint: String,
+    pub principals: Vec<String>,
+    pub valid_after: u64,
+    pub valid_before: Option<u64>,
+    pub critical_options: BTreeMap<String, String>,
+    pub extensions: BTreeMap<String, String>,
+    pub expired: bool,
+    pub expires_in: Option<u64>,
+}
+
+impl From<&Certificate> for CertificateDetails {
+    fn from(cert: &Certificate) -> Self {
+        let now = unix_now();
+
+        Self {
+            key_id: cert.key_id().to_string(),
+            serial: cert.serial(),
+            cert_type: if cert.cert_type().is_host() { "host" } else { "user" }.to_string(),
+            algorithm: cert.algorithm().to_string(),
+            public_key_fingerprint: cert.public_key().fingerprint(HashAlg::Sha256).to_string(),
+            signing_ca_fingerprint: cert.signature_key().fingerprint(HashAlg::Sha256).to_string(),
+            principals: cert.valid_principals().to_vec(),
+            valid_after: cert.valid_after(),
+            valid_before: (cert.valid_before() != FOREVER).then(|| cert.valid_before()),
+            critical_options: cert.critical_options().0.clone(),
+            extensions: cert.extensions().0.clone(),
+            expired: now >= cert.valid_before() || now < cert.valid_after(),
+            expires_in: (cert.valid_before() != FOREVER)
+                .then(|| cert.valid_before().saturating_sub(now)),
+        }
+    }
+}
+
+pub fn read_certificate(path: &Path) -> Result<Certificate> {
+    Certificate::read_file(path)
+        .with_context(|| format!("Failed to read certificate {}", path.display()))
+}
+
+/// Returns the remaining validity when the certificate expires within `window`,
+/// or zero when it has already expired.
+pub fn expiry_warning(cert: &Certificate, window: Duration) -> Option<Duration> {
+    if cert.valid_before() == FOREVER {
+        return None;
+    }
+
+    let remaining = cert.valid_before().saturating_sub(unix_now());
+    (remaining <= window.as_secs()).then(|| Duration::from_secs(remaining))
+}
+
+pub fn is_currently_valid(cert: &Certificate) -> bool {
+    let now = unix_now();
+    cert.valid_after() <= now && now < cert.valid_before()
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[command]
+pub async fn ssh_key_certificate_details(
+    storage: State<'_, std::sync::Arc<SecureStorage>>,
+    key_id: String,
+) -> Result<CertificateDetails, String> {
+    let key = storage
+        .get_ssh_key(&key_id)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or("SSH key not found")?;
+    let path = key
+        .certificate_path
+        .ok_or("SSH key has no certificate")?;
+
+    read_certificate(Path::new(&path))
+        .map(|cert| CertificateDetails::from(&cert))
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub fn ssh_certificate_details(certificate: String) -> Result<CertificateDetails, String> {
+    Certificate::from_openssh(certificate.trim())
+        .map(|cert| CertificateDetails::from(&cert))
+        .map_err(|e| e.to_string())
+}
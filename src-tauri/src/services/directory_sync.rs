@@ -0,0 +1,679 @@
+use anyhow::{bail, Result};
+use notify::{RecursiveMode, Watcher};
+use russh_sftp::{client::SftpSession, protocol::FileAttributes};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+use tauri::{command, State};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncReadExt},
+    sync::{mpsc, Mutex},
+    task::{JoinHandle, JoinSet},
+};
+use uuid::Uuid;
+
+use crate::models::{
+    directory_sync::{SyncAction, SyncActionKind, SyncCompare, SyncDirection, SyncOptions, SyncPlan},
+    ipc_channels::SYNC_WATCH_EVENT,
+    transfer::{Transfer, TransferDirection},
+};
+use crate::services::{
+    exclude_rules::ExcludeRules,
+    sftp::{
+        create_dir_all, is_dir, is_safe_name, is_symlink, join_remote, SftpClient, SftpService, TRANSFER_BUFFER_SIZE,
+    },
+    terminal_manager::WindowHandler,
+    transfer_manager::TransferManager,
+};
+
+const GITIGNORE: &str = ".gitignore";
+// Quiet period after a file change before the tree is pushed
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+// More changed paths than this in one go and the whole tree is re-planned
+const MAX_WATCH_SCOPES: usize = 256;
+// Plans kept for `apply`; older ones have to be planned again
+const MAX_STORED_PLANS: usize = 16;
+// Files hashed at once when comparing by checksum
+const CHECKSUM_CONCURRENCY: usize = 8;
+
+#[derive(Debug, Clone)]
+struct TreeEntry {
+    is_dir: bool,
+    size: u64,
+    // Seconds since the epoch, the resolution SFTP carries
+    mtime: i64,
+}
+
+// Keyed by `/`-separated path relative to the root
+type Tree = BTreeMap<String, TreeEntry>;
+
+#[derive(Debug, Clone, Serialize)]
+struct SyncWatchEvent {
+    watch_id: String,
+    actions: Option<Vec<SyncAction>>,
+    error: Option<String>,
+}
+
+/// rsync-style mirroring between a local and a remote directory, carried
+/// out through the transfer queue.
+pub struct DirectorySync {
+    sftp_service: Arc<SftpService>,
+    transfer_manager: Arc<TransferManager>,
+    window_handler: Arc<dyn WindowHandler>,
+    watches: Mutex<HashMap<String, JoinHandle<()>>>,
+    // Plans handed out by `plan`, newest last, so `apply` only carries out
+    // actions worked out here
+    plans: Mutex<VecDeque<SyncPlan>>,
+}
+
+impl DirectorySync {
+    pub fn new(
+        sftp_service: Arc<SftpService>,
+        transfer_manager: Arc<TransferManager>,
+        window_handler: Arc<dyn WindowHandler>,
+    ) -> Self {
+        Self {
+            sftp_service,
+            transfer_manager,
+            window_handler,
+            watches: Mutex::new(HashMap::new()),
+            plans: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Compares both trees and lists what applying the sync would do,
+    /// without changing anything. The plan is kept so `apply` can carry
+    /// out exactly these actions by id.
+    pub async fn plan(&self, options: SyncOptions) -> Result<SyncPlan> {
+        let actions = self.actions(&options, None).await?;
+        let plan = SyncPlan {
+            id: Uuid::new_v4().to_string(),
+            options,
+            actions,
+        };
+
+        let mut plans = self.plans.lock().await;
+        if plans.len() == MAX_STORED_PLANS {
+            plans.pop_front();
+        }
+        plans.push_back(plan.clone());
+        Ok(plan)
+    }
+
+    /// Carries out a plan made by `plan`: deletions and directories right
+    /// away, file copies through the transfer queue.
+    pub async fn apply(&self, plan_id: &str) -> Result<Vec<Transfer>> {
+        let plan = {
+            let mut plans = self.plans.lock().await;
+            let index = plans.iter().position(|plan| plan.id == plan_id);
+            index.and_then(|index| plans.remove(index))
+        };
+        let Some(plan) = plan else {
+            bail!("Sync plan not found, it was already applied or has expired");
+        };
+        self.carry_out(&plan.options, &plan.actions).await
+    }
+
+    /// Diffs the trees, or only the given subtrees of them. Scoped paths
+    /// are `/`-separated and relative to both roots.
+    async fn actions(&self, options: &SyncOptions, scopes: Option<&[String]>) -> Result<Vec<SyncAction>> {
+        let client = self.sftp_service.connect(&options.host_id).await?;
+        let sftp = &client.sftp;
+        let rules = exclude_rules(options, sftp).await?;
+
+        let source_exists = match options.direction {
+            SyncDirection::Push => fs::metadata(&options.local_path).await.is_ok(),
+            SyncDirection::Pull => sftp.try_exists(options.remote_path.as_str()).await?,
+        };
+        if !source_exists {
+            bail!("Nothing to sync, the source directory does not exist");
+        }
+
+        let whole = [String::new()];
+        let mut local = Tree::new();
+        let mut remote = Tree::new();
+        for scope in scopes.unwrap_or(&whole) {
+            local.extend(scan_local(Path::new(&options.local_path), scope, &rules).await?);
+            remote.extend(scan_remote(sftp, &options.remote_path, scope, &rules).await?);
+        }
+        let (source, target) = match options.direction {
+            SyncDirection::Push => (&local, &remote),
+            SyncDirection::Pull => (&remote, &local),
+        };
+
+        let changed = match options.compare {
+            SyncCompare::SizeMtime => HashSet::new(),
+            SyncCompare::Checksum => {
+                let candidates = source
+                    .iter()
+                    .filter(|(path, entry)| {
+                        target
+                            .get(*path)
+                            .is_some_and(|existing| !entry.is_dir && !existing.is_dir && existing.size == entry.size)
+                    })
+                    .map(|(path, _)| path.clone())
+                    .collect();
+                checksum_differences(options, &client, candidates).await?
+            }
+        };
+        Ok(diff(options, source, target, &changed))
+    }
+
+    async fn carry_out(&self, options: &SyncOptions, actions: &[SyncAction]) -> Result<Vec<Transfer>> {
+        if let Some(action) = actions.iter().find(|action| !is_safe_relative(&action.path)) {
+            bail!("Refusing to sync outside the synced roots: {:?}", action.path);
+        }
+
+        let client = self.sftp_service.connect(&options.host_id).await?;
+        let local_path = |path: &str| local_join(Path::new(&options.local_path), path);
+        let remote_path = |path: &str| join_remote(&options.remote_path, path);
+
+        for action in actions.iter().filter(|a| a.kind == SyncActionKind::Delete) {
+            match options.direction {
+                SyncDirection::Push => {
+                    self.sftp_service
+                        .delete(&options.host_id, &remote_path(&action.path), true)
+                        .await?
+                }
+                SyncDirection::Pull if action.is_dir => fs::remove_dir_all(local_path(&action.path)).await?,
+                SyncDirection::Pull => fs::remove_file(local_path(&action.path)).await?,
+            }
+        }
+
+        let mut files = Vec::new();
+        for action in actions.iter().filter(|a| a.kind != SyncActionKind::Delete) {
+            match (action.is_dir, options.direction) {
+                (true, SyncDirection::Push) => create_dir_all(&client.sftp, &remote_path(&action.path)).await?,
+                (true, SyncDirection::Pull) => fs::create_dir_all(local_path(&action.path)).await?,
+                (false, _) => files.push((
+                    local_path(&action.path).to_string_lossy().to_string(),
+                    remote_path(&action.path),
+                    action.size,
+                )),
+            }
+        }
+
+        let direction = match options.direction {
+            SyncDirection::Push => TransferDirection::Upload,
+            SyncDirection::Pull => TransferDirection::Download,
+        };
+        self.transfer_manager
+            .enqueue_files(&options.host_id, direction, files, options.verify.unwrap_or(false))
+            .await
+    }
+
+    /// Pushes the local tree now, then the changed parts of it whenever
+    /// files under it change.
+    pub async fn watch(self: &Arc<Self>, options: SyncOptions) -> Result<String> {
+        if options.direction != SyncDirection::Push {
+            bail!("Watch mode only pushes local changes");
+        }
+
+        let root = PathBuf::from(&options.local_path);
+        let mut rules = ExcludeRules::new(&options.excludes);
+        if options.use_gitignore.unwrap_or(false) {
+            if let Ok(contents) = fs::read_to_string(root.join(GITIGNORE)).await {
+                rules.extend(&contents);
+            }
+        }
+
+        let (changes, mut changed) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            if let Ok(event) = event {
+                let _ = changes.send(event);
+            }
+        })?;
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+
+        let watch_id = Uuid::new_v4().to_string();
+        let sync = Arc::clone(self);
+        let id = watch_id.clone();
+        let task = tokio::spawn(async move {
+            // Dropping the watcher stops the notifications
+            let _watcher = watcher;
+            let relevant = |path: &PathBuf| {
+                let relative = path.strip_prefix(&root).ok()?.to_string_lossy().replace('\\', "/");
+                if relative.is_empty() {
+                    return None;
+                }
+                let excluded = match path.symlink_metadata() {
+                    Ok(metadata) => is_excluded(&rules, &relative, metadata.is_dir()),
+                    // Removed, so whichever kind it was
+                    Err(_) => is_excluded(&rules, &relative, true) && is_excluded(&rules, &relative, false),
+                };
+                (!excluded).then_some(relative)
+            };
+
+            sync.push_changes(&id, &options, None).await;
+            while let Some(event) = changed.recv().await {
+                let mut rescan = event.need_rescan();
+                let mut paths: HashSet<String> = event.paths.iter().filter_map(&relevant).collect();
+                let quiet = tokio::time::sleep(WATCH_DEBOUNCE);
+                tokio::pin!(quiet);
+                loop {
+                    tokio::select! {
+                        _ = &mut quiet => break,
+                        more = changed.recv() => match more {
+                            Some(event) => {
+                                rescan |= event.need_rescan();
+                                paths.extend(event.paths.iter().filter_map(&relevant));
+                            }
+                            None => return,
+                        },
+                    }
+                }
+                if rescan || paths.len() > MAX_WATCH_SCOPES {
+                    sync.push_changes(&id, &options, None).await;
+                } else if !paths.is_empty() {
+                    sync.push_changes(&id, &options, Some(&outermost(&paths))).await;
+                }
+            }
+        });
+
+        self.watches.lock().await.insert(watch_id.clone(), task);
+        Ok(watch_id)
+    }
+
+    pub async fn unwatch(&self, watch_id: &str) -> Result<()> {
+        match self.watches.lock().await.remove(watch_id) {
+            Some(task) => {
+                task.abort();
+                Ok(())
+            }
+            None => bail!("Watch not found"),
+        }
+    }
+
+    async fn push_changes(&self, watch_id: &str, options: &SyncOptions, scopes: Option<&[String]>) {
+        let result = async {
+            let actions = self.actions(options, scopes).await?;
+            if !actions.is_empty() {
+                self.carry_out(options, &actions).await?;
+            }
+            Ok::<_, anyhow::Error>(actions)
+        }
+        .await;
+
+        let event = match result {
+            Ok(actions) if actions.is_empty() => return,
+            Ok(actions) => SyncWatchEvent {
+                watch_id: watch_id.to_string(),
+                actions: Some(actions),
+                error: None,
+            },
+            Err(e) => SyncWatchEvent {
+                watch_id: watch_id.to_string(),
+                actions: None,
+                error: Some(format!("{:#}", e)),
+            },
+        };
+        if let Ok(payload) = serde_json::to_value(event) {
+            self.window_handler
+                .send_to_all_windows(SYNC_WATCH_EVENT, payload);
+        }
+    }
+}
+
+/// Works out the actions that make the target match the source.
+/// `changed` holds the same-size files whose checksums differ.
+fn diff(options: &SyncOptions, source: &Tree, target: &Tree, changed: &HashSet<String>) -> Vec<SyncAction> {
+    let mut actions = Vec::new();
+    let mut deleted_dirs = HashSet::new();
+    // Source paths whose place on the target is taken by the other kind
+    let mut blocked = HashSet::new();
+    for (path, entry) in source {
+        if ancestors(path).any(|parent| blocked.contains(parent)) {
+            continue;
+        }
+        let kind = match target.get(path) {
+            None => Some(SyncActionKind::Create),
+            // A file where a directory should be or the other way round
+            Some(existing) if existing.is_dir != entry.is_dir => {
+                if !options.delete {
+                    blocked.insert(path.as_str());
+                    continue;
+                }
+                if existing.is_dir {
+                    deleted_dirs.insert(path.as_str());
+                }
+                actions.push(action(SyncActionKind::Delete, path, existing));
+                Some(SyncActionKind::Create)
+            }
+            Some(_) if entry.is_dir => None,
+            Some(existing) => {
+                let differs = existing.size != entry.size
+                    || match options.compare {
+                        SyncCompare::SizeMtime => existing.mtime != entry.mtime,
+                        SyncCompare::Checksum => changed.contains(path),
+                    };
+                differs.then_some(SyncActionKind::Update)
+            }
+        };
+        if let Some(kind) = kind {
+            actions.push(action(kind, path, entry));
+        }
+    }
+
+    if options.delete {
+        for (path, entry) in target {
+            if source.contains_key(path) {
+                continue;
+            }
+            // Already gone with a deleted parent
+            if ancestors(path).any(|parent| deleted_dirs.contains(parent)) {
+                continue;
+            }
+            if entry.is_dir {
+                deleted_dirs.insert(path.as_str());
+            }
+            actions.push(action(SyncActionKind::Delete, path, entry));
+        }
+    }
+    actions
+}
+
+/// Hashes both copies of each path, a few files at a time, and returns
+/// the paths whose contents differ.
+async fn checksum_differences(
+    options: &SyncOptions,
+    client: &Arc<SftpClient>,
+    paths: Vec<String>,
+) -> Result<HashSet<String>> {
+    let mut changed = HashSet::new();
+    let mut paths = paths.into_iter();
+    let mut checks = JoinSet::new();
+    loop {
+        while checks.len() < CHECKSUM_CONCURRENCY {
+            let Some(path) = paths.next() else { break };
+            let local = local_join(Path::new(&options.local_path), &path);
+            let remote = join_remote(&options.remote_path, &path);
+            let client = Arc::clone(client);
+            checks.spawn(async move {
+                let (local, remote) = tokio::try_join!(
+                    async { sha256(&mut fs::File::open(local).await?).await },
+                    async { sha256(&mut client.sftp.open(remote).await?).await },
+                )?;
+                Ok::<_, anyhow::Error>((path, local != remote))
+            });
+        }
+        match checks.join_next().await {
+            Some(check) => {
+                let (path, differs) = check??;
+                if differs {
+                    changed.insert(path);
+                }
+            }
+            None => return Ok(changed),
+        }
+    }
+}
+
+fn action(kind: SyncActionKind, path: &str, entry: &TreeEntry) -> SyncAction {
+    SyncAction {
+        kind,
+        path: path.to_string(),
+        is_dir: entry.is_dir,
+        size: entry.size,
+    }
+}
+
+/// The option's patterns plus, when asked for, the source root's `.gitignore`.
+async fn exclude_rules(options: &SyncOptions, sftp: &SftpSession) -> Result<ExcludeRules> {
+    let mut rules = ExcludeRules::new(&options.excludes);
+    if !options.use_gitignore.unwrap_or(false) {
+        return Ok(rules);
+    }
+
+    let contents = match options.direction {
+        SyncDirection::Push => fs::read_to_string(Path::new(&options.local_path).join(GITIGNORE))
+            .await
+            .ok(),
+        SyncDirection::Pull => match sftp.open(join_remote(&options.remote_path, GITIGNORE)).await {
+            Ok(mut file) => {
+                let mut contents = String::new();
+                file.read_to_string(&mut contents).await.ok().map(|_| contents)
+            }
+            Err(_) => None,
+        },
+    };
+    if let Some(contents) = contents {
+        rules.extend(&contents);
+    }
+    Ok(rules)
+}
+
+/// Checks a path and every directory above it against the rules.
+fn is_excluded(rules: &ExcludeRules, relative: &str, is_dir: bool) -> bool {
+    ancestors(relative).any(|parent| rules.is_excluded(parent, true)) || rules.is_excluded(relative, is_dir)
+}
+
+/// Proper ancestors of a relative path, outermost first.
+fn ancestors(path: &str) -> impl Iterator<Item = &str> {
+    path.match_indices('/').map(move |(i, _)| &path[..i])
+}
+
+fn local_join(root: &Path, relative: &str) -> PathBuf {
+    relative
+        .split('/')
+        .filter(|part| !part.is_empty())
+        .fold(root.to_path_buf(), |path, part| path.join(part))
+}
+
+/// Whether a relative path names something inside the roots.
+fn is_safe_relative(relative: &str) -> bool {
+    relative.split('/').all(is_safe_name)
+}
+
+/// Drops the paths that lie under another path of the set.
+fn outermost(paths: &HashSet<String>) -> Vec<String> {
+    paths
+        .iter()
+        .filter(|path| !ancestors(path).any(|parent| paths.contains(parent)))
+        .cloned()
+        .collect()
+}
+
+/// A local entry's metadata, following symlinks to files. `None` for
+/// symlinked directories and paths that are gone.
+async fn local_metadata(path: &Path) -> Option<std::fs::Metadata> {
+    let metadata = fs::symlink_metadata(path).await.ok()?;
+    if !metadata.file_type().is_symlink() {
+        return Some(metadata);
+    }
+    fs::metadata(path).await.ok().filter(|target| !target.is_dir())
+}
+
+/// Walks a local tree, or only the entry at `scope` and what lies below
+/// it. Symlinked files are followed, symlinked directories skipped.
+async fn scan_local(root: &Path, scope: &str, rules: &ExcludeRules) -> Result<Tree> {
+    let mut tree = Tree::new();
+    let mut pending = Vec::new();
+    if scope.is_empty() {
+        if fs::metadata(root).await.is_ok() {
+            pending.push((root.to_path_buf(), String::new()));
+        }
+    } else {
+        let path = local_join(root, scope);
+        if let Some(metadata) = local_metadata(&path).await {
+            add_local(&mut tree, &mut pending, path, scope.to_string(), &metadata);
+        }
+    }
+
+    while let Some((dir, prefix)) = pending.pop() {
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(metadata) = local_metadata(&entry.path()).await else {
+                continue;
+            };
+            let relative = join_remote(&prefix, &entry.file_name().to_string_lossy());
+            if rules.is_excluded(&relative, metadata.is_dir()) {
+                continue;
+            }
+            add_local(&mut tree, &mut pending, entry.path(), relative, &metadata);
+        }
+    }
+
+    Ok(tree)
+}
+
+fn add_local(
+    tree: &mut Tree,
+    pending: &mut Vec<(PathBuf, String)>,
+    path: PathBuf,
+    relative: String,
+    metadata: &std::fs::Metadata,
+) {
+    if metadata.is_dir() {
+        pending.push((path, relative.clone()));
+    }
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |elapsed| elapsed.as_secs() as i64);
+    tree.insert(
+        relative,
+        TreeEntry {
+            is_dir: metadata.is_dir(),
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            mtime,
+        },
+    );
+}
+
+/// Remote counterpart of `local_metadata`.
+async fn remote_metadata(sftp: &SftpSession, path: &str) -> Option<FileAttributes> {
+    let metadata = sftp.symlink_metadata(path).await.ok()?;
+    if !is_symlink(&metadata) {
+        return Some(metadata);
+    }
+    sftp.metadata(path).await.ok().filter(|target| !is_dir(target))
+}
+
+/// Remote counterpart of `scan_local`.
+async fn scan_remote(sftp: &SftpSession, root: &str, scope: &str, rules: &ExcludeRules) -> Result<Tree> {
+    let mut tree = Tree::new();
+    let mut pending = Vec::new();
+    if scope.is_empty() {
+        if sftp.try_exists(root).await? {
+            pending.push((root.to_string(), String::new()));
+        }
+    } else {
+        let path = join_remote(root, scope);
+        if let Some(metadata) = remote_metadata(sftp, &path).await {
+            add_remote(&mut tree, &mut pending, path, scope.to_string(), &metadata);
+        }
+    }
+
+    while let Some((dir, prefix)) = pending.pop() {
+        for entry in sftp.read_dir(dir.as_str()).await? {
+            let name = entry.file_name();
+            if name == "." || name == ".." {
+                continue;
+            }
+            if !is_safe_name(&name) {
+                bail!("Server returned an invalid file name {:?} in {}", name, dir);
+            }
+
+            let path = join_remote(&dir, &name);
+            let mut metadata = entry.metadata();
+            if is_symlink(&metadata) {
+                match sftp.metadata(path.as_str()).await {
+                    Ok(target) if !is_dir(&target) => metadata = target,
+                    _ => continue,
+                }
+            }
+
+            let relative = join_remote(&prefix, &name);
+            if rules.is_excluded(&relative, is_dir(&metadata)) {
+                continue;
+            }
+            add_remote(&mut tree, &mut pending, path, relative, &metadata);
+        }
+    }
+
+    Ok(tree)
+}
+
+fn add_remote(
+    tree: &mut Tree,
+    pending: &mut Vec<(String, String)>,
+    path: String,
+    relative: String,
+    metadata: &FileAttributes,
+) {
+    let directory = is_dir(metadata);
+    if directory {
+        pending.push((path, relative.clone()));
+    }
+    tree.insert(
+        relative,
+        TreeEntry {
+            is_dir: directory,
+            size: if directory { 0 } else { metadata.size.unwrap_or(0) },
+            mtime: metadata.mtime.map_or(0, i64::from),
+        },
+    );
+}
+
+async fn sha256<R: AsyncRead + Unpin>(reader: &mut R) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; TRANSFER_BUFFER_SIZE];
+    loop {
+        let n = reader.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(hex::encode(hasher.finalize()));
+        }
+        hasher.update(&buffer[..n]);
+    }
+}
+
+#[command]
+pub async fn sync_plan(
+    directory_sync: State<'_, Arc<DirectorySync>>,
+    options: SyncOptions,
+) -> Result<SyncPlan, String> {
+    directory_sync
+        .plan(options)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn sync_apply(
+    directory_sync: State<'_, Arc<DirectorySync>>,
+    plan_id: String,
+) -> Result<Vec<Transfer>, String> {
+    directory_sync
+        .apply(&plan_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn sync_watch(
+    directory_sync: State<'_, Arc<DirectorySync>>,
+    options: SyncOptions,
+) -> Result<String, String> {
+    directory_sync
+        .watch(options)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn sync_unwatch(
+    directory_sync: State<'_, Arc<DirectorySync>>,
+    watch_id: String,
+) -> Result<(), String> {
+    directory_sync
+        .unwatch(&watch_id)
+        .await
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,295 @@
+/// `.gitignore`-style exclude patterns, matched against paths relative to
+/// the root being scanned.
+///
+/// Supported: `*`, `?`, `[...]` classes (POSIX `[:name:]` ones included) and
+/// `**` wildcards, a leading `/` or any inner `/` to anchor a pattern to the
+/// root, a trailing `/` to match directories only, `!` to re-include, `#`
+/// comments and `\` escapes. As in git, trailing spaces are dropped unless
+/// escaped and the last matching pattern wins.
+#[derive(Debug, Clone, Default)]
+pub struct ExcludeRules {
+    patterns: Vec<Pattern>,
+}
+
+#[derive(Debug, Clone)]
+struct Pattern {
+    glob: String,
+    negated: bool,
+    directory_only: bool,
+    // Matched against the whole relative path rather than the file name
+    anchored: bool,
+}
+
+impl ExcludeRules {
+    pub fn new<S: AsRef<str>>(lines: &[S]) -> Self {
+        Self {
+            patterns: lines.iter().filter_map(|line| parse(line.as_ref())).collect(),
+        }
+    }
+
+    /// Adds the patterns of a `.gitignore` file's contents.
+    pub fn extend(&mut self, contents: &str) {
+        self.patterns.extend(contents.lines().filter_map(parse));
+    }
+
+    /// Checks a `/`-separated relative path. Callers prune excluded
+    /// directories, so parents are not re-checked here.
+    pub fn is_excluded(&self, relative: &str, is_dir: bool) -> bool {
+        let relative = relative.trim_matches('/');
+        let name = relative.rsplit('/').next().unwrap_or(relative);
+
+        let mut excluded = false;
+        for pattern in &self.patterns {
+            if pattern.directory_only && !is_dir {
+                continue;
+            }
+            let subject = match pattern.anchored {
+                true => relative,
+                false => name,
+            };
+            if glob_match(pattern.glob.as_bytes(), subject.as_bytes()) {
+                excluded = !pattern.negated;
+            }
+        }
+        excluded
+    }
+}
+
+fn parse(line: &str) -> Option<Pattern> {
+    let line = trim_trailing_spaces(line);
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+
+    let (negated, line) = match line.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let (directory_only, line) = match line.strip_suffix('/') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let anchored = line.contains('/');
+    let glob = line.trim_start_matches('/').to_string();
+    if glob.is_empty() {
+        return None;
+    }
+
+    Some(Pattern {
+        glob,
+        negated,
+        directory_only,
+        anchored,
+    })
+}
+
+/// Drops trailing spaces, keeping any escaped with a backslash.
+fn trim_trailing_spaces(line: &str) -> &str {
+    let bytes = line.as_bytes();
+    let mut end = bytes.len();
+    while end > 0 && bytes[end - 1] == b' ' {
+        let backslashes = bytes[..end - 1].iter().rev().take_while(|&&b| b == b'\\').count();
+        if backslashes % 2 == 1 {
+            break;
+        }
+        end -= 1;
+    }
+    &line[..end]
+}
+
+/// Shell-style matching where `*` and `?` stay within one path component.
+/// `**` crosses components when it makes up a whole one, as in `**/x`,
+/// `x/**/y` and `x/**`; anywhere else it is a plain `*`.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    // Like git, the literal prefix is compared on its own and the rest is
+    // matched as if it began the path, so `x/a**/b` matches `x/a/y/b` too
+    let literal = pattern
+        .iter()
+        .position(|c| b"*?[\\".contains(c))
+        .unwrap_or(pattern.len());
+    text.starts_with(&pattern[..literal]) && matches(&pattern[literal..], &text[literal..], true)
+}
+
+fn matches(pattern: &[u8], text: &[u8], component_start: bool) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', b'/', rest @ ..] if component_start => {
+            // Zero or more leading directories
+            matches(rest, text, true)
+                || text
+                    .iter()
+                    .enumerate()
+                    .any(|(i, &c)| c == b'/' && matches(rest, &text[i + 1..], true))
+        }
+        [b'*', b'*'] if component_start => true,
+        [b'*', rest @ ..] => (0..=text.len())
+            .take_while(|&i| i == 0 || text[i - 1] != b'/')
+            .any(|i| matches(rest, &text[i..], false)),
+        [b'?', rest @ ..] => match text {
+            [c, text @ ..] if *c != b'/' => matches(rest, text, false),
+            _ => false,
+        },
+        // An unterminated class matches nothing, as in git
+        [b'[', class @ ..] => match (text, class_match(class, text.first().copied())) {
+            ([_, text @ ..], Some((true, rest))) => matches(rest, text, false),
+            _ => false,
+        },
+        [b'\\', c, rest @ ..] => text.first() == Some(c) && matches(rest, &text[1..], *c == b'/'),
+        [c, rest @ ..] => text.first() == Some(c) && matches(rest, &text[1..], *c == b'/'),
+    }
+}
+
+/// Matches `c` against a `[...]` class body, returning whether it matched
+/// and the pattern after the closing bracket.
+fn class_match(class: &[u8], c: Option<u8>) -> Option<(bool, &[u8])> {
+    let (negated, mut body) = match class {
+        [b'!' | b'^', rest @ ..] => (true, rest),
+        _ => (false, class),
+    };
+
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        match body {
+            [] => return None,
+            [b']', rest @ ..] if !first => {
+                let matched = matched != negated && c.is_some_and(|c| c != b'/');
+                return Some((matched, rest));
+            }
+            [b'[', b':', rest @ ..] if rest.windows(2).any(|w| w == b":]") => {
+                let end = rest.windows(2).position(|w| w == b":]").unwrap();
+                matched |= c.is_some_and(|c| in_named_class(&rest[..end], c));
+                body = &rest[end + 2..];
+            }
+            [b'\\', single, rest @ ..] => {
+                matched |= c == Some(*single);
+                body = rest;
+            }
+            [low, b'-', high, rest @ ..] if *high != b']' => {
+                matched |= c.is_some_and(|c| (*low..=*high).contains(&c));
+                body = rest;
+            }
+            [single, rest @ ..] => {
+                matched |= c == Some(*single);
+                body = rest;
+            }
+        }
+        first = false;
+    }
+}
+
+/// POSIX character classes, such as `[:digit:]`. Unknown names match nothing.
+fn in_named_class(name: &[u8], c: u8) -> bool {
+    match name {
+        b"alnum" => c.is_ascii_alphanumeric(),
+        b"alpha" => c.is_ascii_alphabetic(),
+        b"blank" => c == b' ' || c == b'\t',
+        b"cntrl" => c.is_ascii_control(),
+        b"digit" => c.is_ascii_digit(),
+        b"graph" => c.is_ascii_graphic(),
+        b"lower" => c.is_ascii_lowercase(),
+        b"print" => c.is_ascii_graphic() || c == b' ',
+        b"punct" => c.is_ascii_punctuation(),
+        b"space" => c.is_ascii_whitespace() || c == 0x0b,
+        b"upper" => c.is_ascii_uppercase(),
+        b"xdigit" => c.is_ascii_hexdigit(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (patterns, path, is_dir, excluded), each checked with git check-ignore
+    fn check(cases: &[(&[&str], &str, bool, bool)]) {
+        for (patterns, path, is_dir, excluded) in cases {
+            assert_eq!(
+                ExcludeRules::new(patterns).is_excluded(path, *is_dir),
+                *excluded,
+                "{:?} on {:?}",
+                patterns,
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn matches_like_git() {
+        check(&[
+            (&["foo"], "foo", false, true),
+            (&["foo"], "a/foo", false, true),
+            (&["foo"], "a/foo", true, true),
+            (&["/foo"], "foo", false, true),
+            (&["/foo"], "a/foo", false, false),
+            (&["foo/"], "a/foo", true, true),
+            (&["foo/"], "a/foo", false, false),
+            (&["doc/frotz"], "doc/frotz", false, true),
+            (&["doc/frotz"], "a/doc/frotz", false, false),
+            (&["doc/frotz/"], "doc/frotz", true, true),
+            (&["*.log"], "a/b/c.log", false, true),
+            (&["doc/*.txt"], "doc/a.txt", false, true),
+            (&["doc/*.txt"], "doc/x/a.txt", false, false),
+            (&["**/foo"], "foo", false, true),
+            (&["**/foo"], "a/b/foo", false, true),
+            (&["**/foo/bar"], "x/foo/bar", false, true),
+            (&["abc/**"], "abc/x/y", false, true),
+            (&["abc/**"], "abc", true, false),
+            (&["a/**/b"], "a/b", false, true),
+            (&["a/**/b"], "a/x/y/b", false, true),
+            (&["a/**/b"], "a/xb", false, false),
+            (&["foo**bar"], "fooxbar", false, true),
+            (&["/a**b"], "axxb", false, true),
+            (&["/a**b"], "ax/b", false, false),
+            (&["x/a**/b"], "x/a/y/b", false, true),
+            (&["x/a**/b"], "x/ab/b", false, true),
+            (&["/a**/b"], "ax/y/b", false, true),
+            (&["x/**a/b"], "x/y/za/b", false, false),
+            (&["x/**a"], "x/y/za", false, false),
+            (&["?oo"], "foo", false, true),
+            (&["/a?b"], "a/b", false, false),
+            (&["[abc].txt"], "b.txt", false, true),
+            (&["[abc].txt"], "d.txt", false, false),
+            (&["[a-c].txt"], "c.txt", false, true),
+            (&["[!a].txt"], "a.txt", false, false),
+            (&["[!a].txt"], "b.txt", false, true),
+            (&["[^a].txt"], "b.txt", false, true),
+            (&["[]].txt"], "].txt", false, true),
+            (&["[[:digit:]].txt"], "1.txt", false, true),
+            (&["[[:digit:]].txt"], "a.txt", false, false),
+            (&["[[:upper:]]*"], "Makefile", false, true),
+            (&["[[:upper:]]*"], "makefile", false, false),
+            (&["[\\]]x"], "]x", false, true),
+            (&["/x[/]y"], "x/y", false, false),
+            (&["*.log", "!keep.log"], "keep.log", false, false),
+            (&["*.log", "!keep.log"], "x.log", false, true),
+            (&["!keep.log", "*.log"], "keep.log", false, true),
+            (&["\\!important"], "!important", false, true),
+            (&["\\#notes"], "#notes", false, true),
+            (&["#notes"], "#notes", false, false),
+            (&["foo\\ "], "foo ", false, true),
+            (&["foo  "], "foo", false, true),
+            (&["foo\t"], "foo", false, false),
+            (&["\\*"], "*", false, true),
+            (&["\\*"], "x", false, false),
+            (&["a\\[b"], "a[b", false, true),
+            (&["[ab"], "[ab", false, false),
+            (&["*"], "a/b", false, true),
+            (&["/*.c"], "a/b.c", false, false),
+            (&["/*.c"], "b.c", false, true),
+            (&["*.TXT"], "a.txt", false, false),
+            (&["**"], "a/b", false, true),
+            (&["build/", "!build/"], "build", true, false),
+        ]);
+    }
+
+    #[test]
+    fn extends_with_gitignore_contents() {
+        let mut rules = ExcludeRules::new(&["*.log"]);
+        rules.extend("# build output\ntarget/\n\n!keep.log\n");
+        assert!(rules.is_excluded("target", true));
+        assert!(!rules.is_excluded("target", false));
+        assert!(rules.is_excluded("debug.log", false));
+        assert!(!rules.is_excluded("keep.log", false));
+    }
+}
@@ -0,0 +1,236 @@
+use anyhow::{bail, Result};
+
+// Permission bits belonging to each class, special bits included
+const USER: u32 = 0o4700;
+const GROUP: u32 = 0o2070;
+const OTHER: u32 = 0o1007;
+const ALL: u32 = USER | GROUP | OTHER;
+
+#[derive(Debug, Clone, Copy)]
+enum Permissions {
+    // rwx as a triplet, plus X, s and t
+    Letters {
+        rwx: u32,
+        conditional_execute: bool,
+        set_id: bool,
+        sticky: bool,
+    },
+    // The current triplet of another class, e.g. `g=u`
+    CopyFrom { shift: u32 },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Action {
+    op: char,
+    permissions: Permissions,
+}
+
+#[derive(Debug, Clone)]
+struct Clause {
+    who: u32,
+    actions: Vec<Action>,
+}
+
+/// A chmod(1) mode argument: octal ("755") or symbolic ("u+x,go-w", "a=rX").
+/// Symbolic clauses without a class apply to everyone, since the server's
+/// umask is not known here.
+#[derive(Debug, Clone)]
+pub struct ModeChange(Change);
+
+#[derive(Debug, Clone)]
+enum Change {
+    // A fifth digit, as in "00755", also clears a directory's set-id bits
+    Octal { bits: u32, explicit: bool },
+    Symbolic(Vec<Clause>),
+}
+
+impl ModeChange {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let spec = spec.trim();
+        if !spec.is_empty() && spec.len() <= 5 && spec.chars().all(|c| ('0'..='7').contains(&c)) {
+            let bits = u32::from_str_radix(spec, 8)?;
+            if bits > 0o7777 {
+                bail!("Invalid mode: {}", spec);
+            }
+            return Ok(Self(Change::Octal {
+                bits,
+                explicit: spec.len() == 5,
+            }));
+        }
+
+        let mut clauses = Vec::new();
+        for clause in spec.split(',') {
+            let mut chars = clause.chars().peekable();
+
+            let mut who = 0;
+            while let Some(class) = chars.peek().and_then(|&c| class_mask(c)) {
+                who |= class;
+                chars.next();
+            }
+            if who == 0 {
+                who = ALL;
+            }
+
+            let mut actions = Vec::new();
+            while let Some(op) = chars.next() {
+                if !matches!(op, '+' | '-' | '=') {
+                    bail!("Invalid mode: {}", spec);
+                }
+
+                let copy_from = match chars.peek() {
+                    Some('u') => Some(6),
+                    Some('g') => Some(3),
+                    Some('o') => Some(0),
+                    _ => None,
+                };
+                if let Some(shift) = copy_from {
+                    chars.next();
+                    actions.push(Action {
+                        op,
+                        permissions: Permissions::CopyFrom { shift },
+                    });
+                    continue;
+                }
+
+                let (mut rwx, mut conditional_execute, mut set_id, mut sticky) = (0, false, false, false);
+                while let Some(&letter) = chars.peek() {
+                    match letter {
+                        'r' => rwx |= 4,
+                        'w' => rwx |= 2,
+                        'x' => rwx |= 1,
+                        'X' => conditional_execute = true,
+                        's' => set_id = true,
+                        't' => sticky = true,
+                        '+' | '-' | '=' => break,
+                        _ => bail!("Invalid mode: {}", spec),
+                    }
+                    chars.next();
+                }
+                actions.push(Action {
+                    op,
+                    permissions: Permissions::Letters {
+                        rwx,
+                        conditional_execute,
+                        set_id,
+                        sticky,
+                    },
+                });
+            }
+
+            if actions.is_empty() {
+                bail!("Invalid mode: {}", spec);
+            }
+            clauses.push(Clause { who, actions });
+        }
+
+        Ok(Self(Change::Symbolic(clauses)))
+    }
+
+    /// The permission bits (0o7777) a file with `mode` ends up with.
+    pub fn apply(&self, mode: u32, is_dir: bool) -> u32 {
+        let clauses = match &self.0 {
+            // Directories keep their set-id bits, as with GNU chmod
+            Change::Octal { bits, explicit: false } if is_dir => return bits | (mode & 0o6000),
+            Change::Octal { bits, .. } => return *bits,
+            Change::Symbolic(clauses) => clauses,
+        };
+
+        let mut mode = mode & 0o7777;
+        for clause in clauses {
+            for action in &clause.actions {
+                let bits = match action.permissions {
+                    Permissions::Letters {
+                        rwx,
+                        conditional_execute,
+                        set_id,
+                        sticky,
+                    } => {
+                        let mut triplet = rwx;
+                        // X only grants search on directories and on files
+                        // someone may already execute
+                        if conditional_execute && (is_dir || mode & 0o111 != 0) {
+                            triplet |= 1;
+                        }
+                        let mut bits = triplet * 0o111;
+                        if set_id {
+                            bits |= 0o6000;
+                        }
+                        if sticky {
+                            bits |= 0o1000;
+                        }
+                        bits & clause.who
+                    }
+                    Permissions::CopyFrom { shift } => (((mode >> shift) & 0o7) * 0o111) & clause.who & 0o777,
+                };
+
+                // As in GNU chmod, `=` leaves a directory's set-id bits
+                // alone unless it names them
+                let kept = match action.permissions {
+                    Permissions::Letters { set_id: false, .. } | Permissions::CopyFrom { .. } if is_dir => 0o6000,
+                    _ => 0,
+                };
+                mode = match action.op {
+                    '+' => mode | bits,
+                    '-' => mode & !bits,
+                    _ => (mode & !(clause.who & !kept)) | bits,
+                };
+            }
+        }
+
+        mode
+    }
+}
+
+fn class_mask(class: char) -> Option<u32> {
+    match class {
+        'u' => Some(USER),
+        'g' => Some(GROUP),
+        'o' => Some(OTHER),
+        'a' => Some(ALL),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ModeChange;
+
+    // Each row's result is what GNU chmod(1) leaves, run with umask 0
+    #[test]
+    fn matches_chmod() {
+        let cases = [
+            ("u+x", 0o644, false, 0o744),
+            ("go-w", 0o666, false, 0o644),
+            ("go-w", 0o2777, true, 0o2755),
+            ("a=rX", 0o640, false, 0o444),
+            ("a=rX", 0o750, false, 0o555),
+            ("a=rX", 0o600, true, 0o555),
+            ("a=rX", 0o2775, true, 0o2555),
+            ("g=u", 0o751, false, 0o771),
+            ("g=u", 0o2705, true, 0o2775),
+            ("+t", 0o755, true, 0o1755),
+            ("+t", 0o644, false, 0o1644),
+            ("o=", 0o1777, true, 0o770),
+            ("o=", 0o2757, true, 0o2750),
+            ("o=", 0o644, false, 0o640),
+            ("755", 0o2775, true, 0o2755),
+            ("00755", 0o2775, true, 0o755),
+            ("755", 0o2775, false, 0o755),
+        ];
+        for (spec, mode, is_dir, expected) in cases {
+            let result = ModeChange::parse(spec).unwrap().apply(mode, is_dir);
+            assert_eq!(
+                result, expected,
+                "{} on {:o} (directory: {}) gave {:o}",
+                spec, mode, is_dir, result
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_modes() {
+        for spec in ["", "u+q", "8", "17777", "u", "x+r"] {
+            assert!(ModeChange::parse(spec).is_err(), "{} was accepted", spec);
+        }
+    }
+}
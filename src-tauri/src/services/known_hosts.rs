@@ -0,0 +1,453 @@
+use anyhow::{Context, Result};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use ssh_encoding::Decode;
+use ssh_key::{
+    known_hosts::{Entry, HostPatterns, Marker},
+    public::KeyData,
+    Certificate, HashAlg, KnownHosts, PublicKey,
+};
+use std::{fs, io::Write, path::PathBuf};
+
+use crate::services::certificates::unix_now;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HostKeyStatus {
+    Trusted,
+    // Host certificate signed by a trusted @cert-authority
+    CertificateTrusted,
+    Unknown,
+    Mismatch,
+    Revoked,
+    InvalidCertificate(String),
+}
+
+pub struct KnownHostsVerifier {
+    files: Vec<PathBuf>,
+    // Where accepted keys are written, leaving ~/.ssh/known_hosts alone
+    own_file: Option<PathBuf>,
+}
+
+impl Default for KnownHostsVerifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KnownHostsVerifier {
+    pub fn new() -> Self {
+        let mut files = Vec::new();
+        if let Some(home) = dirs::home_dir() {
+            files.push(home.join(".ssh").join("known_hosts"));
+        }
+        let own_file = dirs::config_dir().map(|config| config.join("teemux").join("known_hosts"));
+        files.extend(own_file.clone());
+        Self { files, own_file }
+    }
+
+    fn entries(&self) -> Vec<Entry> {
+        self.files
+            .iter()
+            .filter_map(|path| fs::read_to_string(path).ok())
+            .flat_map(|input| {
+                // Skip lines we cannot parse instead of distrusting the whole file
+                KnownHosts::new(&input)
+                    .filter_map(|entry| entry.ok())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Whether an @cert-authority line covers the host, so a host
+    /// certificate from it can be checked.
+    pub fn has_authority(&self, hostname: &str, port: u16) -> bool {
+        let name = host_name(hostname, port);
+        self.entries().iter().any(|e| {
+            e.marker() == Some(&Marker::CertAuthority) && matches_host(e.host_patterns(), &name)
+        })
+    }
+
+    /// Checks a host key blob, which may be a plain key or a host certificate,
+    /// against the known hosts files.
+    pub fn verify(&self, hostname: &str, port: u16, host_key: &[u8]) -> Result<HostKeyStatus> {
+        let algorithm = String::decode(&mut &host_key[..])?;
+        let entries = self.entries();
+        let name = host_name(hostname, port);
+
+        if algorithm.ends_with("-cert-v01@openssh.com") {
+            let cert = Certificate::from_bytes(host_key)?;
+            return Ok(verify_certificate(&entries, &name, hostname, &cert));
+        }
+
+        let key = KeyData::decode(&mut &host_key[..])?;
+        if is_revoked(&entries, &key) {
+            return Ok(HostKeyStatus::Revoked);
+        }
+
+        let mut known = entries
+            .iter()
+            .filter(|e| e.marker().is_none() && matches_host(e.host_patterns(), &name))
+            .peekable();
+
+        if known.peek().is_none() {
+            return Ok(HostKeyStatus::Unknown);
+        }
+
+        if known.any(|e| e.public_key().key_data() == &key) {
+            Ok(HostKeyStatus::Trusted)
+        } else {
+            Ok(HostKeyStatus::Mismatch)
+        }
+    }
+
+    /// Remembers a host key the user accepted.
+    pub fn add(&self, hostname: &str, port: u16, host_key: &[u8]) -> Result<()> {
+        let path = self.own_file.as_ref().context("Failed to get config directory")?;
+        let key = PublicKey::from(KeyData::decode(&mut &host_key[..])?);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+        writeln!(file, "{} {}", host_name(hostname, port), key.to_openssh()?)?;
+        Ok(())
+    }
+}
+
+fn verify_certificate(
+    entries: &[Entry],
+    name: &str,
+    hostname: &str,
+    cert: &Certificate,
+) -> HostKeyStatus {
+    if is_revoked(entries, cert.public_key()) || is_revoked(entries, cert.signature_key()) {
+        return HostKeyStatus::Revoked;
+    }
+
+    if !cert.cert_type().is_host() {
+        return HostKeyStatus::InvalidCertificate("Not a host certificate".into());
+    }
+
+    let authorities: Vec<_> = entries
+        .iter()
+        .filter(|e| {
+            e.marker() == Some(&Marker::CertAuthority) && matches_host(e.host_patterns(), name)
+        })
+        .map(|e| e.public_key().fingerprint(HashAlg::Sha256))
+        .collect();
+
+    if authorities.is_empty() {
+        return HostKeyStatus::InvalidCertificate("No certificate authority for host".into());
+    }
+
+    // Checks the signature, the signing CA and the validity window
+    if cert.validate_at(unix_now(), &authorities).is_err() {
+        return HostKeyStatus::InvalidCertificate(
+            "Certificate is expired or not signed by a trusted authority".into(),
+        );
+    }
+
+    // As with OpenSSH, a certificate without principals is valid for any host
+    let principals = cert.valid_principals();
+    if !principals.is_empty() && !principals.iter().any(|p| p.eq_ignore_ascii_case(hostname)) {
+        return HostKeyStatus::InvalidCertificate(format!(
+            "Certificate is not valid for {}",
+            hostname
+        ));
+    }
+
+    // No critical options are defined for host certificates
+    if !cert.critical_options().is_empty() {
+        return HostKeyStatus::InvalidCertificate("Unsupported critical options".into());
+    }
+
+    HostKeyStatus::CertificateTrusted
+}
+
+fn is_revoked(entries: &[Entry], key: &KeyData) -> bool {
+    entries
+        .iter()
+        .any(|e| e.marker() == Some(&Marker::Revoked) && e.public_key().key_data() == key)
+}
+
+/// Name as it appears in known_hosts, with non-default ports in brackets.
+fn host_name(hostname: &str, port: u16) -> String {
+    if port == 22 {
+        hostname.to_string()
+    } else {
+        format!("[{}]:{}", hostname, port)
+    }
+}
+
+fn matches_host(patterns: &HostPatterns, name: &str) -> bool {
+    match patterns {
+        HostPatterns::Patterns(patterns) => {
+            let mut matched = false;
+            for pattern in patterns {
+                if let Some(negated) = pattern.strip_prefix('!') {
+                    if glob_match(negated, name) {
+                        return false;
+                    }
+                } else if glob_match(pattern, name) {
+                    matched = true;
+                }
+            }
+            matched
+        }
+        HostPatterns::HashedName { salt, hash } => {
+            let Ok(mut mac) = Hmac::<Sha1>::new_from_slice(salt) else {
+                return false;
+            };
+            mac.update(name.as_bytes());
+            mac.verify_slice(hash).is_ok()
+        }
+    }
+}
+
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let name: Vec<char> = name.to_lowercase().chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, n));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            n = matched + 1;
+            backtrack = Some((star, n));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    use ssh_key::{
+        certificate::{Builder, CertType},
+        Algorithm, PrivateKey,
+    };
+
+    const HOST: &str = "build.example.com";
+    const PORT: u16 = 2222;
+
+    fn key() -> PrivateKey {
+        PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap()
+    }
+
+    fn blob(key: &PrivateKey) -> Vec<u8> {
+        key.public_key().to_bytes().unwrap()
+    }
+
+    /// A verifier reading only the given known_hosts lines.
+    struct Verifier {
+        verifier: KnownHostsVerifier,
+        path: PathBuf,
+    }
+
+    impl Verifier {
+        fn new(lines: &[String]) -> Self {
+            let path = std::env::temp_dir().join(format!("known_hosts-{}", uuid::Uuid::new_v4()));
+            fs::write(&path, lines.join("\n")).unwrap();
+            let verifier = KnownHostsVerifier {
+                files: vec![path.clone()],
+                own_file: None,
+            };
+            Self { verifier, path }
+        }
+
+        fn verify(&self, host_key: &[u8]) -> HostKeyStatus {
+            self.verifier.verify(HOST, PORT, host_key).unwrap()
+        }
+    }
+
+    impl Drop for Verifier {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    fn line(marker: &str, patterns: &str, key: &PrivateKey) -> String {
+        format!("{} {} {}", marker, patterns, key.public_key().to_openssh().unwrap())
+            .trim_start()
+            .to_string()
+    }
+
+    struct Cert<'a> {
+        ca: &'a PrivateKey,
+        key: &'a PrivateKey,
+        cert_type: CertType,
+        principals: &'a [&'a str],
+        valid: std::ops::Range<u64>,
+    }
+
+    impl Cert<'_> {
+        fn blob(&self) -> Vec<u8> {
+            let mut builder = Builder::new_with_random_nonce(
+                &mut OsRng,
+                self.key.public_key().key_data().clone(),
+                self.valid.start,
+                self.valid.end,
+            )
+            .unwrap();
+            builder.serial(1).unwrap();
+            builder.key_id("test").unwrap();
+            builder.cert_type(self.cert_type).unwrap();
+            if self.principals.is_empty() {
+                builder.all_principals_valid().unwrap();
+            }
+            for principal in self.principals {
+                builder.valid_principal(*principal).unwrap();
+            }
+            builder.sign(self.ca).unwrap().to_bytes().unwrap()
+        }
+    }
+
+    fn host_cert<'a>(ca: &'a PrivateKey, key: &'a PrivateKey) -> Cert<'a> {
+        let now = unix_now();
+        Cert {
+            ca,
+            key,
+            cert_type: CertType::Host,
+            principals: &[HOST],
+            valid: now - 60..now + 3600,
+        }
+    }
+
+    fn invalid(status: HostKeyStatus) -> bool {
+        matches!(status, HostKeyStatus::InvalidCertificate(_))
+    }
+
+    #[test]
+    fn checks_plain_keys() {
+        let (known, other, revoked) = (key(), key(), key());
+        let verifier = Verifier::new(&[
+            line("", "[build.example.com]:2222", &known),
+            line("@revoked", "*", &revoked),
+        ]);
+
+        assert_eq!(verifier.verify(&blob(&known)), HostKeyStatus::Trusted);
+        assert_eq!(verifier.verify(&blob(&other)), HostKeyStatus::Mismatch);
+        assert_eq!(verifier.verify(&blob(&revoked)), HostKeyStatus::Revoked);
+        assert_eq!(
+            verifier.verifier.verify("other.example.com", PORT, &blob(&other)).unwrap(),
+            HostKeyStatus::Unknown
+        );
+    }
+
+    #[test]
+    fn trusts_certificates_from_the_host_authority() {
+        let (ca, host) = (key(), key());
+        let verifier = Verifier::new(&[line("@cert-authority", "*.example.com,[*]:2222", &ca)]);
+
+        assert!(verifier.verifier.has_authority(HOST, PORT));
+        assert_eq!(
+            verifier.verify(&host_cert(&ca, &host).blob()),
+            HostKeyStatus::CertificateTrusted
+        );
+
+        // No principals means any host, as with OpenSSH
+        let any_host = Cert {
+            principals: &[],
+            ..host_cert(&ca, &host)
+        };
+        assert_eq!(verifier.verify(&any_host.blob()), HostKeyStatus::CertificateTrusted);
+    }
+
+    #[test]
+    fn rejects_certificates_for_other_principals() {
+        let (ca, host) = (key(), key());
+        let verifier = Verifier::new(&[line("@cert-authority", "*", &ca)]);
+        let cert = Cert {
+            principals: &["other.example.com"],
+            ..host_cert(&ca, &host)
+        };
+
+        assert!(invalid(verifier.verify(&cert.blob())));
+    }
+
+    #[test]
+    fn rejects_certificates_outside_their_validity() {
+        let (ca, host) = (key(), key());
+        let verifier = Verifier::new(&[line("@cert-authority", "*", &ca)]);
+        let now = unix_now();
+        let expired = Cert {
+            valid: now - 7200..now - 3600,
+            ..host_cert(&ca, &host)
+        };
+        let not_yet = Cert {
+            valid: now + 3600..now + 7200,
+            ..host_cert(&ca, &host)
+        };
+
+        assert!(invalid(verifier.verify(&expired.blob())));
+        assert!(invalid(verifier.verify(&not_yet.blob())));
+    }
+
+    #[test]
+    fn rejects_certificates_from_other_authorities() {
+        let (ca, other_ca, host) = (key(), key(), key());
+        let verifier = Verifier::new(&[
+            line("@cert-authority", "*", &ca),
+            line("@cert-authority", "other.example.com", &other_ca),
+        ]);
+
+        assert!(invalid(verifier.verify(&host_cert(&other_ca, &host).blob())));
+        // The host's own key does not vouch for a certificate either
+        assert!(invalid(verifier.verify(&host_cert(&host, &host).blob())));
+    }
+
+    #[test]
+    fn rejects_certificates_without_an_authority_for_the_host() {
+        let (ca, host) = (key(), key());
+        let verifier = Verifier::new(&[
+            line("@cert-authority", "*.example.org", &ca),
+            line("", "[build.example.com]:2222", &host),
+        ]);
+
+        assert!(!verifier.verifier.has_authority(HOST, PORT));
+        assert!(invalid(verifier.verify(&host_cert(&ca, &host).blob())));
+    }
+
+    #[test]
+    fn rejects_user_certificates() {
+        let (ca, host) = (key(), key());
+        let verifier = Verifier::new(&[line("@cert-authority", "*", &ca)]);
+        let cert = Cert {
+            cert_type: CertType::User,
+            ..host_cert(&ca, &host)
+        };
+
+        assert!(invalid(verifier.verify(&cert.blob())));
+    }
+
+    #[test]
+    fn rejects_revoked_keys_and_authorities() {
+        let (ca, host, revoked_host) = (key(), key(), key());
+        let verifier = Verifier::new(&[
+            line("@cert-authority", "*", &ca),
+            line("@revoked", "*", &revoked_host),
+        ]);
+        assert_eq!(
+            verifier.verify(&host_cert(&ca, &revoked_host).blob()),
+            HostKeyStatus::Revoked
+        );
+
+        let verifier = Verifier::new(&[
+            line("@cert-authority", "*", &ca),
+            line("@revoked", "*", &ca),
+        ]);
+        assert_eq!(verifier.verify(&host_cert(&ca, &host).blob()), HostKeyStatus::Revoked);
+    }
+}
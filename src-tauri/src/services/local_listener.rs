@@ -0,0 +1,155 @@
+use anyhow::{bail, Context, Result};
+use std::{net::SocketAddr, path::PathBuf};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream},
+};
+
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+/// Owner-only by default, like the sockets OpenSSH creates.
+const DEFAULT_SOCKET_MODE: u32 = 0o600;
+
+pub trait LocalIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> LocalIo for T {}
+
+/// A connection on this machine, over TCP or a Unix domain socket.
+pub type LocalStream = Box<dyn LocalIo>;
+
+/// Originator reported to the server for Unix socket clients, which have no
+/// address of their own. OpenSSH does the same.
+#[cfg(unix)]
+fn unix_peer() -> SocketAddr {
+    SocketAddr::from((std::net::Ipv4Addr::LOCALHOST, 0))
+}
+
+pub enum LocalListener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixSocket),
+}
+
+impl LocalListener {
+    pub async fn bind_tcp(address: &str, port: u16) -> Result<Self> {
+        let listener = TcpListener::bind((address, port))
+            .await
+            .with_context(|| format!("Failed to bind {}:{}", address, port))?;
+        Ok(Self::Tcp(listener))
+    }
+
+    /// Binds a Unix socket at `path`, clearing out a stale socket left behind
+    /// by a previous run and applying `mode` to the new one. Missing parent
+    /// directories are created owner-only, so nobody else can reach the
+    /// socket before its mode is set.
+    #[cfg(unix)]
+    pub async fn bind_unix(path: &str, mode: Option<u32>) -> Result<Self> {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+        let path = PathBuf::from(path);
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        remove_stale_socket(&path).await?;
+
+        let listener = UnixListener::bind(&path)
+            .with_context(|| format!("Failed to bind {}", path.display()))?;
+        let socket = UnixSocket { listener, path };
+        std::fs::set_permissions(
+            &socket.path,
+            std::fs::Permissions::from_mode(mode.unwrap_or(DEFAULT_SOCKET_MODE)),
+        )?;
+
+        Ok(Self::Unix(socket))
+    }
+
+    #[cfg(not(unix))]
+    pub async fn bind_unix(_path: &str, _mode: Option<u32>) -> Result<Self> {
+        bail!("Unix socket forwarding is not supported on this platform")
+    }
+
+    pub async fn accept(&self) -> std::io::Result<(LocalStream, SocketAddr)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (socket, peer) = listener.accept().await?;
+                Ok((Box::new(socket), peer))
+            }
+            #[cfg(unix)]
+            Self::Unix(socket) => {
+                let (socket, _) = socket.listener.accept().await?;
+                Ok((Box::new(socket), unix_peer()))
+            }
+        }
+    }
+}
+
+/// Where connections coming out of a tunnel are delivered on this machine.
+#[derive(Debug, Clone)]
+pub enum LocalTarget {
+    Tcp(String, u16),
+    Unix(PathBuf),
+}
+
+impl LocalTarget {
+    pub async fn connect(&self) -> Result<LocalStream> {
+        match self {
+            Self::Tcp(host, port) => {
+                let socket = TcpStream::connect((host.as_str(), *port))
+                    .await
+                    .with_context(|| format!("Failed to connect to {}:{}", host, port))?;
+                Ok(Box::new(socket))
+            }
+            #[cfg(unix)]
+            Self::Unix(path) => {
+                let socket = UnixStream::connect(path)
+                    .await
+                    .with_context(|| format!("Failed to connect to {}", path.display()))?;
+                Ok(Box::new(socket))
+            }
+            #[cfg(not(unix))]
+            Self::Unix(_) => bail!("Unix socket forwarding is not supported on this platform"),
+        }
+    }
+}
+
+/// A listening Unix socket whose file is removed when it goes away.
+#[cfg(unix)]
+pub struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Deletes a socket nobody is listening on any more. Live sockets and other
+/// kinds of files are left alone.
+#[cfg(unix)]
+async fn remove_stale_socket(path: &std::path::Path) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    if !metadata.file_type().is_socket() {
+        bail!("{} exists and is not a socket", path.display());
+    }
+    if UnixStream::connect(path).await.is_ok() {
+        bail!("{} is already in use", path.display());
+    }
+
+    std::fs::remove_file(path)
+        .with_context(|| format!("Failed to remove stale socket {}", path.display()))
+}
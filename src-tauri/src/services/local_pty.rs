@@ -0,0 +1,402 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+use crate::models::user_settings::UserSettings;
+use crate::services::terminal_manager::{TerminalOutput, TerminalStream};
+
+#[cfg(not(windows))]
+const FALLBACK_SHELL: &str = "/bin/sh";
+#[cfg(windows)]
+const FALLBACK_SHELL: &str = "cmd.exe";
+// Where the system names the user's shell
+#[cfg(not(windows))]
+const SHELL_VARIABLE: &str = "SHELL";
+#[cfg(windows)]
+const SHELL_VARIABLE: &str = "ComSpec";
+// GUI apps started from a launcher often have no locale set, which leaves
+// shells unable to show anything but ASCII
+const FALLBACK_LANG: &str = "en_US.UTF-8";
+
+/// What to run in a local terminal and where.
+#[derive(Debug, Clone)]
+pub struct ShellCommand {
+    pub program: String,
+    pub args: Vec<String>,
+    pub working_directory: PathBuf,
+    pub env: Vec<(String, String)>,
+}
+
+impl ShellCommand {
+    /// The user's default shell in their default working directory, falling
+    /// back to $SHELL (%ComSpec% on Windows) and the home directory. `agent_socket` is exported as
+    /// SSH_AUTH_SOCK so the shell uses the built-in agent.
+    pub fn from_settings(settings: Option<&UserSettings>, agent_socket: Option<&Path>) -> Self {
+        let configured = settings
+            .map(|settings| settings.default_shell.trim().to_string())
+            .filter(|shell| !shell.is_empty())
+            .or_else(|| std::env::var(SHELL_VARIABLE).ok().filter(|shell| !shell.is_empty()))
+            .unwrap_or_else(|| FALLBACK_SHELL.to_string());
+        // Settings may carry arguments, e.g. "bash --noprofile"
+        let mut words = configured.split_whitespace().map(str::to_string);
+        let program = words.next().unwrap_or_else(|| FALLBACK_SHELL.to_string());
+        let args = words.collect();
+
+        let home = dirs::home_dir();
+        let working_directory = settings
+            .and_then(|settings| settings.default_working_directory.as_deref())
+            .map(str::trim)
+            .filter(|directory| !directory.is_empty())
+            .map(|directory| expand_home(directory, home.as_deref()))
+            .filter(|directory| directory.is_dir())
+            .or(home)
+            .unwrap_or_else(|| PathBuf::from("/"));
+
+        let mut env = vec![
+            ("TERM".to_string(), "xterm-256color".to_string()),
+            ("COLORTERM".to_string(), "truecolor".to_string()),
+            ("TERM_PROGRAM".to_string(), "Teemux".to_string()),
+            ("TERM_PROGRAM_VERSION".to_string(), env!("CARGO_PKG_VERSION").to_string()),
+        ];
+        if std::env::var_os("LANG").is_none() {
+            env.push(("LANG".to_string(), FALLBACK_LANG.to_string()));
+        }
+        if cfg!(unix) && std::env::var_os("SHELL").is_none() {
+            env.push(("SHELL".to_string(), program.clone()));
+        }
+        if let Some(socket) = agent_socket {
+            env.push(("SSH_AUTH_SOCK".to_string(), socket.to_string_lossy().to_string()));
+        }
+
+        Self {
+            program,
+            args,
+            working_directory,
+            env,
+        }
+    }
+
+    /// Has the shell run `command_line` and exit, instead of prompting.
+    pub fn with_command_line(mut self, command_line: &str) -> Self {
+        let name = Path::new(&self.program)
+            .file_stem()
+            .map(|name| name.to_string_lossy().to_lowercase());
+        let flag = match name.as_deref() {
+            Some("cmd") => "/C",
+            Some("powershell" | "pwsh") => "-Command",
+            _ => "-c",
+        };
+        self.args.push(flag.to_string());
+        self.args.push(command_line.to_string());
+        self
+    }
+}
+
+fn expand_home(path: &str, home: Option<&Path>) -> PathBuf {
+    match (path.strip_prefix('~'), home) {
+        (Some(rest), Some(home)) if rest.is_empty() || rest.starts_with('/') => {
+            home.join(rest.trim_start_matches('/'))
+        }
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Starts `command` as a login shell on a new pseudo-terminal of the given
+/// size. The shell gets SIGHUP once both returned halves are dropped, like
+/// when a terminal window is closed.
+#[cfg(unix)]
+pub fn spawn(command: &ShellCommand, rows: u16, cols: u16) -> Result<(TerminalOutput, Box<dyn TerminalStream>)> {
+    unix::spawn(command, rows, cols)
+}
+
+/// Starts `command` on a new pseudo console of the given size. The shell
+/// ends once both returned halves are dropped.
+#[cfg(windows)]
+pub fn spawn(command: &ShellCommand, rows: u16, cols: u16) -> Result<(TerminalOutput, Box<dyn TerminalStream>)> {
+    windows::spawn(command, rows, cols)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn spawn(_command: &ShellCommand, _rows: u16, _cols: u16) -> Result<(TerminalOutput, Box<dyn TerminalStream>)> {
+    anyhow::bail!("Local shells are not supported on this platform")
+}
+
+#[cfg(unix)]
+mod unix {
+    use anyhow::{Context, Result};
+    use async_trait::async_trait;
+    use std::{
+        fs::File,
+        io::{self, Read, Write},
+        os::{
+            fd::{AsRawFd, FromRawFd, OwnedFd},
+            unix::process::CommandExt,
+        },
+        path::Path,
+        pin::Pin,
+        process::Stdio,
+        sync::Arc,
+        task::{ready, Context as TaskContext, Poll},
+    };
+    use tokio::io::{unix::AsyncFd, AsyncRead, AsyncWrite, ReadBuf};
+
+    use super::ShellCommand;
+    use crate::services::terminal_manager::{TerminalOutput, TerminalStream};
+
+    pub fn spawn(command: &ShellCommand, rows: u16, cols: u16) -> Result<(TerminalOutput, Box<dyn TerminalStream>)> {
+        let (master, slave) = open_pty(rows, cols).context("Failed to open a pseudo-terminal")?;
+
+        let name = Path::new(&command.program)
+            .file_name()
+            .map_or_else(|| command.program.clone(), |name| name.to_string_lossy().to_string());
+        let mut process = std::process::Command::new(&command.program);
+        process
+            // A leading dash makes it a login shell, reading the profile
+            .arg0(format!("-{}", name))
+            .args(&command.args)
+            .current_dir(&command.working_directory)
+            .envs(command.env.iter().map(|(key, value)| (key, value)))
+            .stdin(Stdio::from(slave.try_clone()?))
+            .stdout(Stdio::from(slave.try_clone()?))
+            .stderr(Stdio::from(slave));
+        // SAFETY: only async-signal-safe calls between fork and exec
+        unsafe {
+            process.pre_exec(|| {
+                // A session of its own, with the pseudo-terminal as its
+                // controlling terminal, so job control and ^C work
+                if libc::setsid() == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                if libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+
+        let mut child = tokio::process::Command::from(process)
+            .spawn()
+            .with_context(|| format!("Failed to start {}", command.program))?;
+        // Reaped in the background, the output ends when the shell exits
+        tokio::spawn(async move {
+            let _ = child.wait().await;
+        });
+
+        let pty = Arc::new(AsyncFd::new(File::from(master))?);
+        Ok((Box::new(PtyOutput(Arc::clone(&pty))), Box::new(PtyInput(pty))))
+    }
+
+    fn open_pty(rows: u16, cols: u16) -> io::Result<(OwnedFd, OwnedFd)> {
+        let mut master = -1;
+        let mut slave = -1;
+        let size = window_size(rows, cols);
+        // SAFETY: openpty fills in two new descriptors we take ownership of
+        let (master, slave) = unsafe {
+            if libc::openpty(&mut master, &mut slave, std::ptr::null_mut(), std::ptr::null_mut(), &size) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            (OwnedFd::from_raw_fd(master), OwnedFd::from_raw_fd(slave))
+        };
+
+        // The shell gets the slave as 0, 1 and 2 only, and reads on the
+        // master must not block the runtime
+        for fd in [&master, &slave] {
+            // SAFETY: fcntl on a descriptor we own
+            if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        // SAFETY: as above
+        unsafe {
+            let flags = libc::fcntl(master.as_raw_fd(), libc::F_GETFL);
+            if flags == -1 || libc::fcntl(master.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok((master, slave))
+    }
+
+    fn window_size(rows: u16, cols: u16) -> libc::winsize {
+        libc::winsize {
+            ws_row: rows,
+            ws_col: cols,
+            ws_xpixel: 0,
+            ws_ypixel: 0,
+        }
+    }
+
+    struct PtyOutput(Arc<AsyncFd<File>>);
+
+    impl AsyncRead for PtyOutput {
+        fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            loop {
+                let mut guard = ready!(self.0.poll_read_ready(cx))?;
+                let unfilled = buf.initialize_unfilled();
+                match guard.try_io(|pty| pty.get_ref().read(unfilled)) {
+                    Ok(Ok(n)) => {
+                        buf.advance(n);
+                        return Poll::Ready(Ok(()));
+                    }
+                    // Linux reports EIO once everything holding the slave
+                    // side has exited, which is the end of the output
+                    Ok(Err(e)) if e.raw_os_error() == Some(libc::EIO) => return Poll::Ready(Ok(())),
+                    Ok(Err(e)) => return Poll::Ready(Err(e)),
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+    }
+
+    struct PtyInput(Arc<AsyncFd<File>>);
+
+    impl AsyncWrite for PtyInput {
+        fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+            loop {
+                let mut guard = ready!(self.0.poll_write_ready(cx))?;
+                match guard.try_io(|pty| pty.get_ref().write(data)) {
+                    Ok(result) => return Poll::Ready(result),
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[async_trait]
+    impl TerminalStream for PtyInput {
+        async fn set_window_size(&mut self, rows: u16, cols: u16) -> io::Result<()> {
+            let size = window_size(rows, cols);
+            // SAFETY: TIOCSWINSZ only reads the winsize passed in; the
+            // kernel sends SIGWINCH to the shell's foreground job
+            if unsafe { libc::ioctl(self.0.as_raw_fd(), libc::TIOCSWINSZ as _, &size) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use anyhow::{Context, Result};
+    use async_trait::async_trait;
+    use portable_pty::{native_pty_system, CommandBuilder, MasterPty, PtySize};
+    use std::{
+        io::{self, Read, Write},
+        pin::Pin,
+        sync::{Arc, Mutex},
+        task::{Context as TaskContext, Poll},
+    };
+    use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+
+    use super::ShellCommand;
+    use crate::services::terminal_manager::{TerminalOutput, TerminalStream};
+
+    // Output the terminal has not read yet, and keystrokes the shell has not
+    const BUFFER_SIZE: usize = 64 * 1024;
+
+    // None once the shell has exited and the console is closed
+    type Console = Arc<Mutex<Option<Box<dyn MasterPty + Send>>>>;
+
+    pub fn spawn(command: &ShellCommand, rows: u16, cols: u16) -> Result<(TerminalOutput, Box<dyn TerminalStream>)> {
+        let pair = native_pty_system()
+            .openpty(window_size(rows, cols))
+            .context("Failed to open a pseudo console")?;
+
+        let mut process = CommandBuilder::new(&command.program);
+        process.args(&command.args);
+        process.cwd(&command.working_directory);
+        for (key, value) in &command.env {
+            process.env(key, value);
+        }
+        let mut child = pair
+            .slave
+            .spawn_command(process)
+            .with_context(|| format!("Failed to start {}", command.program))?;
+        let mut reader = pair.master.try_clone_reader()?;
+        let mut writer = pair.master.take_writer()?;
+        let console: Console = Arc::new(Mutex::new(Some(pair.master)));
+
+        // ConPTY only offers blocking pipes, threads of their own carry the
+        // data to and from the runtime
+        let runtime = tokio::runtime::Handle::current();
+        let (output, mut sink) = tokio::io::duplex(BUFFER_SIZE);
+        let (input, mut source) = tokio::io::duplex(BUFFER_SIZE);
+        let reading = runtime.clone();
+        std::thread::spawn(move || {
+            let mut buffer = vec![0u8; BUFFER_SIZE];
+            // The pipe breaks once the console is closed
+            while let Ok(n @ 1..) = reader.read(&mut buffer) {
+                if reading.block_on(sink.write_all(&buffer[..n])).is_err() {
+                    break;
+                }
+            }
+        });
+        std::thread::spawn(move || {
+            let mut buffer = vec![0u8; BUFFER_SIZE];
+            while let Ok(n @ 1..) = runtime.block_on(source.read(&mut buffer)) {
+                if writer.write_all(&buffer[..n]).is_err() {
+                    break;
+                }
+            }
+        });
+
+        // The output only ends when the console is closed: here once the
+        // shell exits, or when the input half is dropped, which also ends
+        // the shell
+        let exited = Arc::downgrade(&console);
+        std::thread::spawn(move || {
+            let _ = child.wait();
+            if let Some(console) = exited.upgrade() {
+                console.lock().unwrap().take();
+            }
+        });
+
+        Ok((Box::new(output), Box::new(ConsoleInput { input, console })))
+    }
+
+    fn window_size(rows: u16, cols: u16) -> PtySize {
+        PtySize {
+            rows,
+            cols,
+            pixel_width: 0,
+            pixel_height: 0,
+        }
+    }
+
+    struct ConsoleInput {
+        input: DuplexStream,
+        console: Console,
+    }
+
+    impl AsyncWrite for ConsoleInput {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.input).poll_write(cx, data)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.input).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.input).poll_shutdown(cx)
+        }
+    }
+
+    #[async_trait]
+    impl TerminalStream for ConsoleInput {
+        async fn set_window_size(&mut self, rows: u16, cols: u16) -> io::Result<()> {
+            match &*self.console.lock().unwrap() {
+                Some(console) => console.resize(window_size(rows, cols)).map_err(io::Error::other),
+                None => Ok(()),
+            }
+        }
+    }
+}
@@ -0,0 +1,26 @@
+pub mod certificate_authority;
+pub mod certificates;
+pub mod directory_sync;
+pub mod exclude_rules;
+pub mod file_mode;
+pub mod http_proxy;
+pub mod known_hosts;
+pub mod local_listener;
+pub mod local_pty;
+pub mod port_forwarding;
+pub mod recorder;
+pub mod remote_editor;
+pub mod remote_tools;
+pub mod scp;
+pub mod scrollback;
+pub mod secure_storage;
+pub mod serial;
+pub mod sftp;
+pub mod socks;
+pub mod ssh_agent;
+pub mod ssh_manager;
+pub mod ssh_shell;
+pub mod telnet;
+pub mod terminal_manager;
+pub mod time_machine;
+pub mod transfer_manager;
@@ -0,0 +1,1210 @@
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::Utc;
+use russh::{client::Msg, Channel};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tauri::{command, State};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::lookup_host,
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc, Mutex,
+    },
+    task::{JoinHandle, JoinSet},
+};
+
+use crate::models::{
+    ipc_channels::{FORWARDING_CONNECTION, FORWARDING_STATE},
+    port_forwarding::{PortForwarding, PortForwardingType},
+};
+use crate::services::{
+    http_proxy::{self, ProxyTarget},
+    local_listener::{LocalListener, LocalStream, LocalTarget},
+    secure_storage::SecureStorage,
+    socks::{self, DestinationRules, SocksCredentials, SocksReply, TargetHost},
+    ssh_manager::{ForwardKey, SessionEvent, SshManager, SshSession},
+    terminal_manager::WindowHandler,
+};
+
+const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1";
+const RELAY_BUFFER_SIZE: usize = 32 * 1024;
+
+// Free ports offered when the configured one is taken
+const SUGGESTION_COUNT: usize = 3;
+const SUGGESTION_SCAN_LIMIT: u16 = 1000;
+
+// Clients that have not finished the proxy handshake by then are dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Pause after a failed accept, doubling up to the max, so errors such as
+// running out of file descriptors do not spin the listener
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TunnelState {
+    pub forwarding_id: String,
+    pub session_id: String,
+    pub listening: bool,
+    pub active_connections: usize,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub last_error: Option<String>,
+}
+
+/// Whether a local port can be bound, and alternatives when it cannot.
+#[derive(Debug, Clone, Serialize)]
+pub struct PortCheck {
+    pub available: bool,
+    pub conflict: Option<String>,
+    pub suggestions: Vec<u16>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ConnectionLogEntry {
+    pub forwarding_id: String,
+    pub destination: String,
+    // "connected", "denied" or "failed"
+    pub status: &'static str,
+    pub error: Option<String>,
+    pub timestamp: i64,
+}
+
+/// Live counters for one running forwarding, shared with its connection tasks.
+pub struct TunnelMonitor {
+    forwarding_id: String,
+    session_id: String,
+    listening: AtomicBool,
+    active_connections: AtomicUsize,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    last_error: std::sync::Mutex<Option<String>>,
+    window_handler: Arc<dyn WindowHandler>,
+}
+
+impl TunnelMonitor {
+    fn new(forwarding_id: &str, session_id: &str, window_handler: Arc<dyn WindowHandler>) -> Self {
+        Self {
+            forwarding_id: forwarding_id.to_string(),
+            session_id: session_id.to_string(),
+            listening: AtomicBool::new(false),
+            active_connections: AtomicUsize::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            last_error: std::sync::Mutex::new(None),
+            window_handler,
+        }
+    }
+
+    pub fn state(&self) -> TunnelState {
+        TunnelState {
+            forwarding_id: self.forwarding_id.clone(),
+            session_id: self.session_id.clone(),
+            listening: self.listening.load(Ordering::Relaxed),
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            last_error: self.last_error.lock().unwrap().clone(),
+        }
+    }
+
+    // Byte counters change too often to publish; the UI polls them instead
+    fn publish(&self) {
+        if let Ok(payload) = serde_json::to_value(self.state()) {
+            self.window_handler
+                .send_to_all_windows(FORWARDING_STATE, payload);
+        }
+    }
+
+    fn set_listening(&self, listening: bool) {
+        self.listening.store(listening, Ordering::Relaxed);
+        self.publish();
+    }
+
+    pub fn report_error(&self, error: impl Display) {
+        *self.last_error.lock().unwrap() = Some(error.to_string());
+        self.publish();
+    }
+
+    fn log_connection(&self, destination: &str, status: &'static str, error: Option<String>) {
+        let entry = ConnectionLogEntry {
+            forwarding_id: self.forwarding_id.clone(),
+            destination: destination.to_string(),
+            status,
+            error,
+            timestamp: Utc::now().timestamp(),
+        };
+        if let Ok(payload) = serde_json::to_value(entry) {
+            self.window_handler
+                .send_to_all_windows(FORWARDING_CONNECTION, payload);
+        }
+    }
+}
+
+struct Tunnel {
+    monitor: Arc<TunnelMonitor>,
+    task: JoinHandle<()>,
+    // Address and port the tunnel listens on locally, if any
+    local: Option<(String, u16)>,
+    remote: Option<RemoteBinding>,
+}
+
+/// A `tcpip-forward` or `streamlocal-forward` the server holds for us,
+/// released when the tunnel stops.
+struct RemoteBinding {
+    session: Arc<SshSession>,
+    key: ForwardKey,
+}
+
+impl Tunnel {
+    async fn close(self) {
+        // Aborting the tunnel task also drops every connection it spawned
+        self.task.abort();
+
+        if let Some(remote) = self.remote {
+            remote
+                .session
+                .forwarded_routes()
+                .lock()
+                .unwrap()
+                .remove(&remote.key);
+            // Fails harmlessly when the session is already gone
+            let handle = remote.session.handle().await;
+            let _ = match remote.key {
+                ForwardKey::Tcp(address, port) => handle.cancel_tcpip_forward(address, port).await,
+                ForwardKey::Socket(path) => handle.cancel_streamlocal_forward(path).await,
+            };
+        }
+
+        self.monitor.set_listening(false);
+    }
+}
+
+pub struct ForwardingService {
+    storage: Arc<SecureStorage>,
+    ssh_manager: Arc<SshManager>,
+    window_handler: Arc<dyn WindowHandler>,
+    tunnels: Mutex<HashMap<String, Tunnel>>,
+    // Forwardings binding or waiting on the server, not yet in `tunnels`
+    starting: std::sync::Mutex<HashSet<String>>,
+    // Sessions opened only to carry tunnels, closed with their last tunnel
+    headless_sessions: Mutex<HashSet<String>>,
+}
+
+impl ForwardingService {
+    pub fn new(
+        storage: Arc<SecureStorage>,
+        ssh_manager: Arc<SshManager>,
+        window_handler: Arc<dyn WindowHandler>,
+    ) -> Self {
+        Self {
+            storage,
+            ssh_manager,
+            window_handler,
+            tunnels: Mutex::new(HashMap::new()),
+            starting: std::sync::Mutex::new(HashSet::new()),
+            headless_sessions: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Starts enabled forwardings when their host connects and tears tunnels
+    /// down when their session goes away.
+    pub async fn watch_sessions(self: Arc<Self>, mut events: broadcast::Receiver<SessionEvent>) {
+        loop {
+            match events.recv().await {
+                Ok(SessionEvent::Connected {
+                    session_id,
+                    host_id,
+                }) => {
+                    let service = Arc::clone(&self);
+                    tokio::spawn(async move { service.autostart(&session_id, &host_id).await });
+                }
+                Ok(SessionEvent::Disconnected { session_id, .. }) => {
+                    self.stop_session(&session_id).await;
+                    self.headless_sessions.lock().await.remove(&session_id);
+                }
+                Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    async fn autostart(&self, session_id: &str, host_id: &str) {
+        let forwardings = match self.storage.get_all_port_forwardings().await {
+            Ok(forwardings) => forwardings,
+            Err(e) => {
+                log::error!("Failed to load port forwardings: {}", e);
+                return;
+            }
+        };
+
+        for forwarding in forwardings {
+            if !forwarding.enabled || forwarding.host_id != host_id {
+                continue;
+            }
+            if self.tunnels.lock().await.contains_key(&forwarding.id) {
+                continue;
+            }
+            if let Err(e) = self.start(&forwarding.id, session_id).await {
+                // Surface the failure on the forwarding even though it never ran
+                TunnelMonitor::new(&forwarding.id, session_id, Arc::clone(&self.window_handler))
+                    .report_error(e);
+            }
+        }
+    }
+
+    /// Connects hosts that have enabled headless forwardings but no session,
+    /// so their tunnels come up without a terminal.
+    pub async fn connect_headless(&self) -> Result<()> {
+        let connected: HashSet<String> = self
+            .ssh_manager
+            .get_all_sessions()
+            .await
+            .into_iter()
+            .map(|s| s.host_id)
+            .collect();
+
+        let hosts: HashSet<String> = self
+            .storage
+            .get_all_port_forwardings()
+            .await?
+            .into_iter()
+            .filter(|f| f.enabled && f.headless == Some(true))
+            .map(|f| f.host_id)
+            .filter(|host_id| !connected.contains(host_id))
+            .collect();
+
+        for host_id in hosts {
+            match self.ssh_manager.connect(&host_id).await {
+                Ok(session) => {
+                    self.headless_sessions.lock().await.insert(session.id);
+                }
+                Err(e) => log::error!("Failed to connect {} for port forwarding: {}", host_id, e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Disconnects a headless session once its last tunnel has stopped.
+    async fn release_headless(&self, session_id: &str) {
+        if !self.headless_sessions.lock().await.contains(session_id) {
+            return;
+        }
+
+        let in_use = self
+            .tunnels
+            .lock()
+            .await
+            .values()
+            .any(|t| t.monitor.session_id == session_id);
+        if !in_use {
+            self.headless_sessions.lock().await.remove(session_id);
+            let _ = self.ssh_manager.disconnect(session_id).await;
+        }
+    }
+
+    /// Checks a local port against running tunnels and other processes.
+    pub async fn check_port(
+        &self,
+        forwarding_id: Option<&str>,
+        bind_address: Option<&str>,
+        port: u16,
+    ) -> Result<PortCheck> {
+        let claimed = claimed_ports(&*self.tunnels.lock().await);
+        let forwarding_id = forwarding_id.map(str::to_string);
+        let address = bind_address.unwrap_or(DEFAULT_BIND_ADDRESS).to_string();
+
+        // Probing may try a thousand binds, so it runs off the async workers
+        // and without the tunnels lock
+        let check = tokio::task::spawn_blocking(move || {
+            check_port(&claimed, forwarding_id.as_deref(), &address, port)
+        })
+        .await?;
+        Ok(check)
+    }
+
+    pub async fn start(&self, forwarding_id: &str, session_id: &str) -> Result<TunnelState> {
+        let forwarding = self
+            .storage
+            .get_port_forwarding(forwarding_id)
+            .await?
+            .context("Port forwarding not found")?;
+        let session = self.ssh_manager.get_session(session_id).await?;
+
+        // Remote forwards listen on the server, everything else binds here.
+        // Unix sockets are checked when they are bound.
+        let local = match forwarding.forwarding_type {
+            PortForwardingType::Remote => None,
+            _ if forwarding.local_socket_path.is_some() => None,
+            _ => Some((
+                forwarding
+                    .bind_address
+                    .clone()
+                    .unwrap_or_else(|| DEFAULT_BIND_ADDRESS.to_string()),
+                forwarding.local_port,
+            )),
+        };
+
+        if let Some((address, port)) = &local {
+            let check = self
+                .check_port(Some(forwarding_id), Some(address), *port)
+                .await?;
+            if let Some(conflict) = check.conflict {
+                match check.suggestions.is_empty() {
+                    true => bail!("{}", conflict),
+                    false => bail!(
+                        "{}; free ports nearby: {}",
+                        conflict,
+                        check
+                            .suggestions
+                            .iter()
+                            .map(|p| p.to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                }
+            }
+        }
+
+        {
+            let tunnels = self.tunnels.lock().await;
+            if tunnels.contains_key(forwarding_id)
+                || self.starting.lock().unwrap().contains(forwarding_id)
+            {
+                bail!("Port forwarding is already running");
+            }
+            self.starting.lock().unwrap().insert(forwarding_id.to_string());
+        }
+
+        // Binding and talking to the server happen without the tunnels lock,
+        // so other forwardings can start and stop meanwhile
+        let launched = self.launch(forwarding, session, local).await;
+        self.starting.lock().unwrap().remove(forwarding_id);
+        let tunnel = launched?;
+
+        // The session may have dropped while the tunnel came up
+        if self.ssh_manager.get_session(session_id).await.is_err() {
+            tunnel.close().await;
+            bail!("Session closed while the port forwarding was starting");
+        }
+
+        tunnel.monitor.set_listening(true);
+        let state = tunnel.monitor.state();
+        self.tunnels
+            .lock()
+            .await
+            .insert(forwarding_id.to_string(), tunnel);
+
+        Ok(state)
+    }
+
+    async fn launch(
+        &self,
+        forwarding: PortForwarding,
+        session: Arc<SshSession>,
+        local: Option<(String, u16)>,
+    ) -> Result<Tunnel> {
+        let monitor = Arc::new(TunnelMonitor::new(
+            &forwarding.id,
+            &session.session.id,
+            Arc::clone(&self.window_handler),
+        ));
+
+        let (task, remote) = match forwarding.forwarding_type {
+            PortForwardingType::Local => {
+                let task = self
+                    .start_local(&forwarding, session, Arc::clone(&monitor))
+                    .await?;
+                (task, None)
+            }
+            PortForwardingType::Remote => {
+                let (task, remote) = self
+                    .start_remote(forwarding, session, Arc::clone(&monitor))
+                    .await?;
+                (task, Some(remote))
+            }
+            PortForwardingType::Dynamic => {
+                let task = self
+                    .start_dynamic(&forwarding, session, Arc::clone(&monitor))
+                    .await?;
+                (task, None)
+            }
+            PortForwardingType::HttpProxy => {
+                let task = self
+                    .start_http_proxy(&forwarding, session, Arc::clone(&monitor))
+                    .await?;
+                (task, None)
+            }
+        };
+
+        Ok(Tunnel {
+            monitor,
+            task,
+            local,
+            remote,
+        })
+    }
+
+    async fn start_local(
+        &self,
+        forwarding: &PortForwarding,
+        session: Arc<SshSession>,
+        monitor: Arc<TunnelMonitor>,
+    ) -> Result<JoinHandle<()>> {
+        // A remote socket path takes precedence over host and port
+        let remote_socket = forwarding.remote_socket_path.clone();
+        let (remote_host, remote_port) = match &remote_socket {
+            Some(_) => (String::new(), 0),
+            None => (
+                forwarding
+                    .remote_host
+                    .clone()
+                    .context("Local forwarding needs a remote host")?,
+                forwarding
+                    .remote_port
+                    .context("Local forwarding needs a remote port")?,
+            ),
+        };
+        let listener = bind_listener(forwarding).await?;
+
+        let accept_monitor = Arc::clone(&monitor);
+        Ok(tokio::spawn(serve(listener, accept_monitor, move |socket, peer| {
+            let session = Arc::clone(&session);
+            let remote_socket = remote_socket.clone();
+            let remote_host = remote_host.clone();
+            let monitor = Arc::clone(&monitor);
+
+            async move {
+                let opened = match &remote_socket {
+                    Some(path) => open_streamlocal_channel(&session, path).await,
+                    None => open_channel(&session, &remote_host, remote_port, peer).await,
+                };
+                let channel = match opened {
+                    Ok(channel) => channel,
+                    Err(e) => {
+                        monitor.report_error(e);
+                        return;
+                    }
+                };
+
+                if let Err(e) = relay(socket, channel.into_stream(), &monitor).await {
+                    monitor.report_error(e);
+                }
+            }
+        })))
+    }
+
+    /// Runs a SOCKS4/4a/5 server on `local_port` that opens a direct-tcpip
+    /// channel for every CONNECT, like `ssh -D`.
+    async fn start_dynamic(
+        &self,
+        forwarding: &PortForwarding,
+        session: Arc<SshSession>,
+        monitor: Arc<TunnelMonitor>,
+    ) -> Result<JoinHandle<()>> {
+        let rules = destination_rules(forwarding)?;
+        let proxy = Arc::new(SocksProxy {
+            session,
+            credentials: proxy_credentials(forwarding)?,
+            rules,
+            remote_dns: forwarding.remote_dns.unwrap_or(true),
+            monitor: Arc::clone(&monitor),
+        });
+        let listener = bind_listener(forwarding).await?;
+
+        Ok(tokio::spawn(serve(listener, monitor, move |socket, peer| {
+            Arc::clone(&proxy).handle(socket, peer)
+        })))
+    }
+
+    /// Runs an HTTP/1.1 proxy on `local_port` for clients that cannot speak
+    /// SOCKS, handling CONNECT and absolute-URI plain HTTP requests.
+    async fn start_http_proxy(
+        &self,
+        forwarding: &PortForwarding,
+        session: Arc<SshSession>,
+        monitor: Arc<TunnelMonitor>,
+    ) -> Result<JoinHandle<()>> {
+        let proxy = Arc::new(HttpProxy {
+            session,
+            credentials: proxy_credentials(forwarding)?,
+            rules: destination_rules(forwarding)?,
+            pac: pac_for(forwarding),
+            monitor: Arc::clone(&monitor),
+        });
+        let listener = bind_listener(forwarding).await?;
+
+        Ok(tokio::spawn(serve(listener, monitor, move |socket, peer| {
+            Arc::clone(&proxy).handle(socket, peer)
+        })))
+    }
+
+    /// Asks the server to listen on `local_port` (0 lets it pick) or on
+    /// `remote_socket_path`, and connects each forwarded channel to
+    /// `remote_host:remote_port` or `local_socket_path` from this machine,
+    /// the way `ssh -R` does.
+    async fn start_remote(
+        &self,
+        forwarding: PortForwarding,
+        session: Arc<SshSession>,
+        monitor: Arc<TunnelMonitor>,
+    ) -> Result<(JoinHandle<()>, RemoteBinding)> {
+        let target = match &forwarding.local_socket_path {
+            Some(path) => LocalTarget::Unix(path.into()),
+            None => LocalTarget::Tcp(
+                forwarding
+                    .remote_host
+                    .clone()
+                    .context("Remote forwarding needs a target host")?,
+                forwarding
+                    .remote_port
+                    .context("Remote forwarding needs a target port")?,
+            ),
+        };
+        let address = forwarding
+            .bind_address
+            .clone()
+            .unwrap_or_else(|| DEFAULT_BIND_ADDRESS.to_string());
+
+        let key = match forwarding.remote_socket_path.clone() {
+            Some(path) => {
+                session
+                    .handle_mut()
+                    .await
+                    .streamlocal_forward(path.clone())
+                    .await
+                    .with_context(|| format!("Server refused to listen on {}", path))?;
+                ForwardKey::Socket(path)
+            }
+            None => {
+                let requested = u32::from(forwarding.local_port);
+                let assigned = session
+                    .handle_mut()
+                    .await
+                    .tcpip_forward(address.clone(), requested)
+                    .await
+                    .with_context(|| {
+                        format!("Server refused to listen on {}:{}", address, requested)
+                    })?;
+                // The server only reports a port when it picked one
+                let port = if requested == 0 { assigned } else { requested };
+
+                // Only the bound port is written, so edits made while the
+                // server answered are kept
+                self.storage
+                    .set_bound_port(&forwarding.id, u16::try_from(port).ok())
+                    .await?;
+                ForwardKey::Tcp(address, port)
+            }
+        };
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        session
+            .forwarded_routes()
+            .lock()
+            .unwrap()
+            .insert(key.clone(), sender);
+
+        let task = tokio::spawn(run_remote(receiver, target, monitor));
+        let remote = RemoteBinding { session, key };
+
+        Ok((task, remote))
+    }
+
+    pub async fn stop(&self, forwarding_id: &str) -> Result<()> {
+        let tunnel = self
+            .tunnels
+            .lock()
+            .await
+            .remove(forwarding_id)
+            .context("Port forwarding is not running")?;
+
+        let session_id = tunnel.monitor.session_id.clone();
+        tunnel.close().await;
+        self.release_headless(&session_id).await;
+        Ok(())
+    }
+
+    pub async fn stop_session(&self, session_id: &str) {
+        let closing: Vec<Tunnel> = {
+            let mut tunnels = self.tunnels.lock().await;
+            let ids: Vec<String> = tunnels
+                .iter()
+                .filter(|(_, t)| t.monitor.session_id == session_id)
+                .map(|(id, _)| id.clone())
+                .collect();
+            ids.iter().filter_map(|id| tunnels.remove(id)).collect()
+        };
+
+        for tunnel in closing {
+            tunnel.close().await;
+        }
+    }
+
+    pub async fn states(&self) -> Vec<TunnelState> {
+        self.tunnels
+            .lock()
+            .await
+            .values()
+            .map(|t| t.monitor.state())
+            .collect()
+    }
+}
+
+async fn bind_listener(forwarding: &PortForwarding) -> Result<LocalListener> {
+    if let Some(path) = &forwarding.local_socket_path {
+        return LocalListener::bind_unix(path, forwarding.socket_mode).await;
+    }
+
+    let bind_address = forwarding
+        .bind_address
+        .as_deref()
+        .unwrap_or(DEFAULT_BIND_ADDRESS);
+    LocalListener::bind_tcp(bind_address, forwarding.local_port).await
+}
+
+/// A local port held by a running tunnel.
+struct ClaimedPort {
+    forwarding_id: String,
+    session_id: String,
+    address: String,
+    port: u16,
+}
+
+fn claimed_ports(tunnels: &HashMap<String, Tunnel>) -> Vec<ClaimedPort> {
+    tunnels
+        .iter()
+        .filter_map(|(id, tunnel)| {
+            let (address, port) = tunnel.local.clone()?;
+            Some(ClaimedPort {
+                forwarding_id: id.clone(),
+                session_id: tunnel.monitor.session_id.clone(),
+                address,
+                port,
+            })
+        })
+        .collect()
+}
+
+fn check_port(
+    claimed: &[ClaimedPort],
+    forwarding_id: Option<&str>,
+    address: &str,
+    port: u16,
+) -> PortCheck {
+    // Port 0 asks the OS for any free port
+    if port == 0 {
+        return PortCheck {
+            available: true,
+            conflict: None,
+            suggestions: Vec::new(),
+        };
+    }
+
+    let claimed_by = |port: u16| {
+        claimed.iter().find(|other| {
+            Some(other.forwarding_id.as_str()) != forwarding_id
+                && other.port == port
+                && addresses_overlap(address, &other.address)
+        })
+    };
+
+    // A running forwarding already holds its own port
+    let held = claimed.iter().any(|own| {
+        Some(own.forwarding_id.as_str()) == forwarding_id
+            && own.port == port
+            && own.address == address
+    });
+
+    let conflict = match claimed_by(port) {
+        Some(other) => Some(format!(
+            "Port {} is already used by forwarding {} on session {}",
+            port, other.forwarding_id, other.session_id
+        )),
+        None if held => None,
+        None => std::net::TcpListener::bind((address, port))
+            .err()
+            .map(|e| format!("Port {} on {} is not available: {}", port, address, e)),
+    };
+
+    let suggestions = match conflict {
+        Some(_) => (1..=SUGGESTION_SCAN_LIMIT)
+            .filter_map(|offset| port.checked_add(offset))
+            .filter(|candidate| claimed_by(*candidate).is_none())
+            .filter(|candidate| std::net::TcpListener::bind((address, *candidate)).is_ok())
+            .take(SUGGESTION_COUNT)
+            .collect(),
+        None => Vec::new(),
+    };
+
+    PortCheck {
+        available: conflict.is_none(),
+        conflict,
+        suggestions,
+    }
+}
+
+/// Wildcard addresses collide with every address on the same port.
+fn addresses_overlap(a: &str, b: &str) -> bool {
+    let wildcard = |address: &str| matches!(address, "0.0.0.0" | "::" | "");
+    a == b || wildcard(a) || wildcard(b)
+}
+
+/// Username and password a dynamic or HTTP proxy forwarding asks clients for.
+/// A username without a password refuses to start rather than leaving the
+/// proxy open.
+fn proxy_credentials(forwarding: &PortForwarding) -> Result<Option<SocksCredentials>> {
+    match (&forwarding.socks_username, &forwarding.socks_password) {
+        (Some(username), Some(password)) => Ok(Some(SocksCredentials {
+            username: username.clone(),
+            password: password.clone(),
+        })),
+        (Some(_), None) => bail!("The proxy has a username but no password"),
+        _ => Ok(None),
+    }
+}
+
+fn destination_rules(forwarding: &PortForwarding) -> Result<DestinationRules> {
+    DestinationRules::new(
+        forwarding.allowed_destinations.as_deref().unwrap_or_default(),
+        forwarding.denied_destinations.as_deref().unwrap_or_default(),
+    )
+}
+
+/// PAC file pointing at an HTTP proxy forwarding, as seen from this machine.
+pub fn pac_for(forwarding: &PortForwarding) -> String {
+    let host = match forwarding.bind_address.as_deref() {
+        None | Some("0.0.0.0") | Some("::") => DEFAULT_BIND_ADDRESS,
+        Some(address) => address,
+    };
+    let address = match host.contains(':') {
+        true => format!("[{}]:{}", host, forwarding.local_port),
+        false => format!("{}:{}", host, forwarding.local_port),
+    };
+    http_proxy::pac_file(&address, forwarding.pac_domains.as_deref().unwrap_or_default())
+}
+
+async fn open_channel(
+    session: &SshSession,
+    host: &str,
+    port: u16,
+    peer: SocketAddr,
+) -> Result<Channel<Msg>> {
+    session
+        .handle()
+        .await
+        .channel_open_direct_tcpip(host, port.into(), peer.ip().to_string(), peer.port().into())
+        .await
+        .map_err(|e| anyhow!("Failed to open channel to {}:{}: {}", host, port, e))
+}
+
+async fn open_streamlocal_channel(session: &SshSession, path: &str) -> Result<Channel<Msg>> {
+    session
+        .handle()
+        .await
+        .channel_open_direct_streamlocal(path)
+        .await
+        .map_err(|e| anyhow!("Failed to open channel to {}: {}", path, e))
+}
+
+/// Accepts connections on a tunnel's listener and runs `handle` for each.
+async fn serve<F, Fut>(listener: LocalListener, monitor: Arc<TunnelMonitor>, handle: F)
+where
+    F: Fn(LocalStream, SocketAddr) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    // Connections live in the set so they are dropped with the listener
+    let mut connections = JoinSet::new();
+    let mut backoff = ACCEPT_BACKOFF_MIN;
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((socket, peer)) => {
+                    backoff = ACCEPT_BACKOFF_MIN;
+                    connections.spawn(handle(socket, peer));
+                }
+                Err(e) => {
+                    monitor.report_error(e);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                }
+            },
+            Some(_) = connections.join_next() => {}
+        }
+    }
+}
+
+struct SocksProxy {
+    session: Arc<SshSession>,
+    credentials: Option<SocksCredentials>,
+    rules: DestinationRules,
+    remote_dns: bool,
+    monitor: Arc<TunnelMonitor>,
+}
+
+impl SocksProxy {
+    async fn handle(self: Arc<Self>, mut socket: LocalStream, peer: SocketAddr) {
+        let handshake = socks::handshake(&mut socket, self.credentials.as_ref());
+        let request = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+            Ok(Ok(request)) => request,
+            Ok(Err(e)) => {
+                self.monitor.report_error(e);
+                return;
+            }
+            Err(_) => {
+                self.monitor.report_error("SOCKS handshake timed out");
+                return;
+            }
+        };
+        let destination = format!("{}:{}", request.host, request.port);
+
+        if !self.rules.permits(&request.host, request.port).await {
+            let _ = socks::reply(&mut socket, request.version, SocksReply::NotAllowed).await;
+            self.monitor.log_connection(&destination, "denied", None);
+            return;
+        }
+
+        let host = match (&request.host, self.remote_dns) {
+            (TargetHost::Domain(domain), false) => {
+                let resolved = lookup_host((domain.as_str(), request.port))
+                    .await
+                    .ok()
+                    .and_then(|mut addresses| addresses.next());
+                match resolved {
+                    Some(address) => address.ip().to_string(),
+                    None => {
+                        let _ = socks::reply(&mut socket, request.version, SocksReply::HostUnreachable)
+                            .await;
+                        self.monitor.log_connection(
+                            &destination,
+                            "failed",
+                            Some(format!("Failed to resolve {}", domain)),
+                        );
+                        return;
+                    }
+                }
+            }
+            (host, _) => host.to_string(),
+        };
+
+        let channel = match open_channel(&self.session, &host, request.port, peer).await {
+            Ok(channel) => channel,
+            Err(e) => {
+                let _ = socks::reply(&mut socket, request.version, SocksReply::HostUnreachable).await;
+                self.monitor
+                    .log_connection(&destination, "failed", Some(e.to_string()));
+                return;
+            }
+        };
+
+        if let Err(e) = socks::reply(&mut socket, request.version, SocksReply::Succeeded).await {
+            self.monitor.report_error(e);
+            return;
+        }
+        self.monitor.log_connection(&destination, "connected", None);
+
+        if let Err(e) = relay(socket, channel.into_stream(), &self.monitor).await {
+            self.monitor.report_error(e);
+        }
+    }
+}
+
+struct HttpProxy {
+    session: Arc<SshSession>,
+    credentials: Option<SocksCredentials>,
+    rules: DestinationRules,
+    pac: String,
+    monitor: Arc<TunnelMonitor>,
+}
+
+impl HttpProxy {
+    async fn handle(self: Arc<Self>, socket: LocalStream, peer: SocketAddr) {
+        // Anything the client sends past the head stays buffered for the relay
+        let mut client = BufReader::new(socket);
+
+        let read_head = http_proxy::read_request_head(&mut client);
+        let head = match tokio::time::timeout(HANDSHAKE_TIMEOUT, read_head).await {
+            Ok(Ok(head)) => head,
+            Ok(Err(e)) => {
+                self.monitor.report_error(e);
+                return;
+            }
+            Err(_) => {
+                self.monitor.report_error("Proxy request timed out");
+                return;
+            }
+        };
+
+        let (host, port, path) = match head.proxy_target() {
+            Ok(ProxyTarget::Connect { host, port }) => (host, port, None),
+            Ok(ProxyTarget::Forward { host, port, path }) => (host, port, Some(path)),
+            Ok(ProxyTarget::Local { path }) if path == http_proxy::PAC_PATH => {
+                let _ = http_proxy::respond(
+                    &mut client,
+                    200,
+                    "OK",
+                    "application/x-ns-proxy-autoconfig",
+                    &self.pac,
+                )
+                .await;
+                return;
+            }
+            Ok(ProxyTarget::Local { .. }) => {
+                let _ = http_proxy::respond_error(&mut client, 404, "Not Found").await;
+                return;
+            }
+            Err(_) => {
+                let _ = http_proxy::respond_error(&mut client, 400, "Bad Request").await;
+                return;
+            }
+        };
+        let destination = format!("{}:{}", host, port);
+
+        // The PAC file is served to anyone, proxying needs the credentials
+        if let Some(credentials) = &self.credentials {
+            if !head.is_authorized(credentials) {
+                let _ = http_proxy::respond_auth_required(&mut client).await;
+                self.monitor.log_connection(&destination, "denied", None);
+                return;
+            }
+        }
+
+        if !self.rules.permits(&http_proxy::target_host(&host), port).await {
+            let _ = http_proxy::respond_error(&mut client, 403, "Forbidden").await;
+            self.monitor.log_connection(&destination, "denied", None);
+            return;
+        }
+
+        let mut remote = match open_channel(&self.session, &host, port, peer).await {
+            Ok(channel) => channel.into_stream(),
+            Err(e) => {
+                let _ = http_proxy::respond_error(&mut client, 502, "Bad Gateway").await;
+                self.monitor
+                    .log_connection(&destination, "failed", Some(e.to_string()));
+                return;
+            }
+        };
+
+        let prepared = match path {
+            None => http_proxy::respond_connected(&mut client).await,
+            Some(path) => {
+                let request = head.to_origin_request(&host, port, &path);
+                self.monitor
+                    .bytes_out
+                    .fetch_add(request.len() as u64, Ordering::Relaxed);
+                remote.write_all(&request).await.map_err(Into::into)
+            }
+        };
+        if let Err(e) = prepared {
+            self.monitor.report_error(e);
+            return;
+        }
+        self.monitor.log_connection(&destination, "connected", None);
+
+        if let Err(e) = relay(client, remote, &self.monitor).await {
+            self.monitor.report_error(e);
+        }
+    }
+}
+
+async fn run_remote(
+    mut channels: mpsc::UnboundedReceiver<Channel<Msg>>,
+    target: LocalTarget,
+    monitor: Arc<TunnelMonitor>,
+) {
+    let mut connections = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            channel = channels.recv() => {
+                let Some(channel) = channel else { break };
+                let target = target.clone();
+                let monitor = Arc::clone(&monitor);
+
+                connections.spawn(async move {
+                    let socket = match target.connect().await {
+                        Ok(socket) => socket,
+                        Err(e) => {
+                            monitor.report_error(format!("{:#}", e));
+                            return;
+                        }
+                    };
+
+                    if let Err(e) = relay(socket, channel.into_stream(), &monitor).await {
+                        monitor.report_error(e);
+                    }
+                });
+            }
+            Some(_) = connections.join_next() => {}
+        }
+    }
+}
+
+/// Copies data both ways between a local socket and a remote channel until
+/// both sides are done, counting bytes on the tunnel's monitor.
+pub async fn relay<L, R>(local: L, remote: R, monitor: &TunnelMonitor) -> std::io::Result<()>
+where
+    L: AsyncRead + AsyncWrite + Unpin,
+    R: AsyncRead + AsyncWrite + Unpin,
+{
+    monitor.active_connections.fetch_add(1, Ordering::Relaxed);
+    monitor.publish();
+
+    let (mut local_read, mut local_write) = tokio::io::split(local);
+    let (mut remote_read, mut remote_write) = tokio::io::split(remote);
+
+    let result = tokio::try_join!(
+        pump(&mut local_read, &mut remote_write, &monitor.bytes_out),
+        pump(&mut remote_read, &mut local_write, &monitor.bytes_in),
+    );
+
+    monitor.active_connections.fetch_sub(1, Ordering::Relaxed);
+    monitor.publish();
+    result.map(|_| ())
+}
+
+async fn pump<R, W>(reader: &mut R, writer: &mut W, counter: &AtomicU64) -> std::io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0u8; RELAY_BUFFER_SIZE];
+
+    loop {
+        let n = reader.read(&mut buffer).await?;
+        if n == 0 {
+            break;
+        }
+        writer.write_all(&buffer[..n]).await?;
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    // Pass the half-close on so the other side sees EOF
+    writer.shutdown().await
+}
+
+#[command]
+pub async fn forwarding_save(
+    storage: State<'_, Arc<SecureStorage>>,
+    forwarding_service: State<'_, Arc<ForwardingService>>,
+    forwarding: PortForwarding,
+) -> Result<(), String> {
+    storage
+        .save_port_forwarding(forwarding)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    // A newly enabled headless forwarding brings its session up right away,
+    // without holding up the save on the connection
+    let forwarding_service = Arc::clone(&forwarding_service);
+    tokio::spawn(async move {
+        if let Err(e) = forwarding_service.connect_headless().await {
+            log::error!("Failed to connect headless port forwardings: {}", e);
+        }
+    });
+
+    Ok(())
+}
+
+#[command]
+pub async fn forwarding_clear_password(
+    storage: State<'_, Arc<SecureStorage>>,
+    forwarding_id: String,
+) -> Result<(), String> {
+    storage
+        .clear_port_forwarding_password(&forwarding_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn forwarding_delete(
+    storage: State<'_, Arc<SecureStorage>>,
+    forwarding_service: State<'_, Arc<ForwardingService>>,
+    forwarding_id: String,
+) -> Result<(), String> {
+    let _ = forwarding_service.stop(&forwarding_id).await;
+    storage
+        .delete_port_forwarding(&forwarding_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn forwarding_list(
+    storage: State<'_, Arc<SecureStorage>>,
+) -> Result<Vec<PortForwarding>, String> {
+    storage
+        .get_all_port_forwardings()
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn forwarding_start(
+    forwarding_service: State<'_, Arc<ForwardingService>>,
+    forwarding_id: String,
+    session_id: String,
+) -> Result<TunnelState, String> {
+    forwarding_service
+        .start(&forwarding_id, &session_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn forwarding_stop(
+    forwarding_service: State<'_, Arc<ForwardingService>>,
+    forwarding_id: String,
+) -> Result<(), String> {
+    forwarding_service
+        .stop(&forwarding_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn forwarding_pac(
+    storage: State<'_, Arc<SecureStorage>>,
+    forwarding_id: String,
+) -> Result<String, String> {
+    let forwarding = storage
+        .get_port_forwarding(&forwarding_id)
+        .await
+        .map_err(|e| e.to_string())?
+        .ok_or("Port forwarding not found")?;
+    Ok(pac_for(&forwarding))
+}
+
+#[command]
+pub async fn forwarding_check_port(
+    forwarding_service: State<'_, Arc<ForwardingService>>,
+    forwarding_id: Option<String>,
+    bind_address: Option<String>,
+    port: u16,
+) -> Result<PortCheck, String> {
+    forwarding_service
+        .check_port(forwarding_id.as_deref(), bind_address.as_deref(), port)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn forwarding_states(
+    forwarding_service: State<'_, Arc<ForwardingService>>,
+) -> Result<Vec<TunnelState>, String> {
+    Ok(forwarding_service.states().await)
+}
@@ -0,0 +1,195 @@
+use anyhow::{Context, Result};
+use chrono::Utc;
+use std::path::{Path, PathBuf};
+use tokio::{
+    fs,
+    io::{AsyncWriteExt, BufWriter},
+    sync::mpsc,
+    time::Instant,
+};
+
+/// Writes a terminal's output, resizes and optionally its input to an
+/// asciicast v2 file, one event per line as they happen.
+pub struct Recorder {
+    path: PathBuf,
+    started: Instant,
+    record_input: bool,
+    events: mpsc::UnboundedSender<String>,
+    output: Utf8Decoder,
+    input: Utf8Decoder,
+}
+
+impl Recorder {
+    pub async fn start(
+        path: &Path,
+        width: u16,
+        height: u16,
+        title: &str,
+        record_input: bool,
+    ) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        // Recordings may hold passwords typed at prompts
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let file = options
+            .open(path)
+            .await
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+
+        let header = serde_json::json!({
+            "version": 2,
+            "width": width,
+            "height": height,
+            "timestamp": Utc::now().timestamp(),
+            "title": title,
+            "env": { "TERM": "xterm-256color" },
+        });
+        let (events, receiver) = mpsc::unbounded_channel();
+        let _ = events.send(header.to_string());
+        tokio::spawn(write_events(path.to_path_buf(), BufWriter::new(file), receiver));
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            started: Instant::now(),
+            record_input,
+            events,
+            output: Utf8Decoder::default(),
+            input: Utf8Decoder::default(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn output(&mut self, bytes: &[u8]) {
+        let text = self.output.decode(bytes);
+        self.event("o", &text);
+    }
+
+    pub fn input(&mut self, bytes: &[u8]) {
+        if self.record_input {
+            let text = self.input.decode(bytes);
+            self.event("i", &text);
+        }
+    }
+
+    pub fn resize(&mut self, rows: u16, cols: u16) {
+        self.event("r", &format!("{}x{}", cols, rows));
+    }
+
+    fn event(&self, kind: &str, data: &str) {
+        if data.is_empty() {
+            return;
+        }
+        let time = self.started.elapsed().as_secs_f64();
+        let _ = self
+            .events
+            .send(serde_json::json!([time, kind, data]).to_string());
+    }
+}
+
+/// Where recordings are kept, created on first use.
+pub fn recordings_dir() -> Result<PathBuf> {
+    let path = dirs::data_dir()
+        .context("Failed to get data directory")?
+        .join("teemux")
+        .join("recordings");
+    std::fs::create_dir_all(&path)?;
+    Ok(path)
+}
+
+/// Appends lines until the recorder is dropped.
+async fn write_events(
+    path: PathBuf,
+    mut file: BufWriter<fs::File>,
+    mut events: mpsc::UnboundedReceiver<String>,
+) {
+    while let Some(line) = events.recv().await {
+        let mut result = file.write_all(line.as_bytes()).await;
+        if result.is_ok() {
+            result = file.write_all(b"\n").await;
+        }
+        // Keep the file readable while the session is still running
+        if result.is_ok() && events.is_empty() {
+            result = file.flush().await;
+        }
+        if let Err(e) = result {
+            log::error!("Failed to write recording {}: {}", path.display(), e);
+            return;
+        }
+    }
+
+    let _ = file.flush().await;
+}
+
+/// Decodes a UTF-8 byte stream chunk by chunk, holding back a character
+/// split across chunks until its remaining bytes arrive. Invalid bytes
+/// become U+FFFD.
+#[derive(Debug, Default)]
+pub struct Utf8Decoder {
+    pending: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    pub fn decode(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        let complete = self.pending.len() - incomplete_tail(&self.pending);
+        let text = String::from_utf8_lossy(&self.pending[..complete]).into_owned();
+        self.pending.drain(..complete);
+        text
+    }
+}
+
+/// Length of a multibyte character cut off at the end of `bytes`.
+fn incomplete_tail(bytes: &[u8]) -> usize {
+    for length in 1..=bytes.len().min(3) {
+        let byte = bytes[bytes.len() - length];
+        // Continuation bytes are 10xxxxxx, look further back for the lead
+        if byte & 0xc0 == 0x80 {
+            continue;
+        }
+        let expected = match byte {
+            0xc0..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf7 => 4,
+            _ => 1,
+        };
+        return if expected > length { length } else { 0 };
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(chunks: &[&[u8]]) -> Vec<String> {
+        let mut decoder = Utf8Decoder::default();
+        chunks.iter().map(|chunk| decoder.decode(chunk)).collect()
+    }
+
+    #[test]
+    fn holds_back_characters_split_across_chunks() {
+        // é, € and 😀 cut after each of their bytes
+        assert_eq!(decode(&[b"a\xc3", b"\xa9b"]), ["a", "éb"]);
+        assert_eq!(decode(&[b"\xe2", b"\x82", b"\xac"]), ["", "", "€"]);
+        assert_eq!(decode(&[b"x\xf0\x9f", b"\x98", b"\x80y"]), ["x", "", "😀y"]);
+    }
+
+    #[test]
+    fn invalid_bytes_become_replacement_characters() {
+        assert_eq!(decode(&[b"a\xffb", b"\x80"]), ["a\u{fffd}b", "\u{fffd}"]);
+        // A lead byte whose character never completes
+        assert_eq!(decode(&[b"a\xe2\x82", b"b"]), ["a", "\u{fffd}b"]);
+        // More continuation bytes than any character has
+        assert_eq!(
+            decode(&[b"\x80\x80\x80\x80"]),
+            ["\u{fffd}\u{fffd}\u{fffd}\u{fffd}"]
+        );
+    }
+}
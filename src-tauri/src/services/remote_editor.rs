@@ -0,0 +1,555 @@
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use notify::{RecursiveMode, Watcher};
+use russh_sftp::{
+    client::SftpSession,
+    protocol::{FileAttributes, OpenFlags},
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tauri::{command, AppHandle, Runtime, State};
+use tauri_plugin_opener::OpenerExt;
+use tauri_plugin_shell::ShellExt;
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::{mpsc, Mutex},
+    task::JoinHandle,
+};
+use uuid::Uuid;
+
+use crate::models::{ipc_channels::EDITOR_EVENT, remote_editor::EditSession};
+use crate::services::{
+    secure_storage::SecureStorage,
+    sftp::{file_name, is_dir, SftpService},
+    ssh_agent::SshAgent,
+    terminal_manager::{TerminalManager, WindowHandler},
+};
+
+#[cfg(unix)]
+use crate::services::scp::shell_quote;
+
+// Editors often write a file in several steps; wait for them to settle
+const SAVE_DEBOUNCE: Duration = Duration::from_millis(300);
+// Checked out copies untouched for this long are removed at startup
+const STALE_COPY_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+pub trait EditorLauncher: Send + Sync {
+    /// Opens `path` with `command`, or with the system's default app.
+    fn launch(&self, command: Option<&str>, path: &Path) -> Result<()>;
+}
+
+impl<R: Runtime> EditorLauncher for AppHandle<R> {
+    fn launch(&self, command: Option<&str>, path: &Path) -> Result<()> {
+        let path = path.to_string_lossy().to_string();
+        // Plain whitespace splitting, quoted arguments are not supported
+        let mut parts = command.unwrap_or_default().split_whitespace();
+        match parts.next() {
+            Some(program) => {
+                self.shell().command(program).args(parts).arg(&path).spawn()?;
+            }
+            None => self.opener().open_path(path, None::<&str>)?,
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct EditorEvent {
+    session_id: String,
+    // "saved", "conflict", "reloaded" or "failed"
+    kind: &'static str,
+    error: Option<String>,
+}
+
+/// What the remote file looked like when we last read or wrote it.
+struct Baseline {
+    mtime: Option<u32>,
+    size: Option<u64>,
+    digest: String,
+}
+
+struct Editing {
+    session: EditSession,
+    // Also serializes saves of the same file
+    baseline: Mutex<Baseline>,
+    watcher: JoinHandle<()>,
+}
+
+pub struct RemoteEditor {
+    storage: Arc<SecureStorage>,
+    sftp_service: Arc<SftpService>,
+    launcher: Arc<dyn EditorLauncher>,
+    window_handler: Arc<dyn WindowHandler>,
+    sessions: Mutex<HashMap<String, Arc<Editing>>>,
+}
+
+impl RemoteEditor {
+    pub fn new(
+        storage: Arc<SecureStorage>,
+        sftp_service: Arc<SftpService>,
+        launcher: Arc<dyn EditorLauncher>,
+        window_handler: Arc<dyn WindowHandler>,
+    ) -> Self {
+        Self {
+            storage,
+            sftp_service,
+            launcher,
+            window_handler,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Where checked out files live, in the user's cache directory. Each
+    /// session gets its own owner-only directory below it.
+    fn base_dir() -> Result<PathBuf> {
+        let cache = dirs::cache_dir().context("No cache directory")?;
+        Ok(cache.join("teemux").join("edit"))
+    }
+
+    /// Removes copies left behind by runs that did not close their editor
+    /// tabs. Recently used ones may belong to another running instance.
+    pub async fn remove_stale_copies() -> Result<()> {
+        let mut entries = match fs::read_dir(Self::base_dir()?).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let idle = last_modified(&entry.path())
+                .await
+                .and_then(|modified| modified.elapsed().ok());
+            if idle.is_some_and(|idle| idle > STALE_COPY_AGE) {
+                fs::remove_dir_all(entry.path()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn list(&self) -> Vec<EditSession> {
+        self.sessions
+            .lock()
+            .await
+            .values()
+            .map(|editing| editing.session.clone())
+            .collect()
+    }
+
+    /// Downloads a remote file, opens it in the configured editor and
+    /// uploads it again whenever it is saved.
+    pub async fn open(self: &Arc<Self>, host_id: &str, remote_path: &str) -> Result<EditSession> {
+        let client = self.sftp_service.connect(host_id).await?;
+        let metadata = client.sftp.metadata(remote_path).await?;
+        if is_dir(&metadata) {
+            bail!("{} is a directory", remote_path);
+        }
+
+        let mut contents = Vec::new();
+        client
+            .sftp
+            .open(remote_path)
+            .await?
+            .read_to_end(&mut contents)
+            .await?;
+
+        let session_id = Uuid::new_v4().to_string();
+        let dir = Self::base_dir()?.join(&session_id);
+        create_private_dir(&dir).await?;
+        let local_path = dir.join(file_name(remote_path));
+        write_private_file(&local_path, &contents).await?;
+
+        let configured = self.configured_editor().await;
+        let terminal_editor = match configured {
+            Some(_) => None,
+            None => terminal_editor(),
+        };
+        let session = EditSession {
+            id: session_id.clone(),
+            host_id: host_id.to_string(),
+            remote_path: remote_path.to_string(),
+            local_path: local_path.to_string_lossy().to_string(),
+            opened_at: Utc::now().to_rfc3339(),
+            terminal_editor: terminal_editor.clone(),
+        };
+        let baseline = Baseline {
+            mtime: metadata.mtime,
+            size: metadata.size,
+            digest: digest(&contents),
+        };
+
+        let watcher = match self.watch(&session_id, &dir, &local_path) {
+            Ok(watcher) => watcher,
+            Err(e) => {
+                let _ = fs::remove_dir_all(&dir).await;
+                return Err(e);
+            }
+        };
+        self.sessions.lock().await.insert(
+            session_id.clone(),
+            Arc::new(Editing {
+                session: session.clone(),
+                baseline: Mutex::new(baseline),
+                watcher,
+            }),
+        );
+
+        // Terminal editors wait for a terminal from `editor_open_terminal`
+        if terminal_editor.is_none() {
+            if let Err(e) = self.launcher.launch(configured.as_deref(), &local_path) {
+                let _ = self.close(&session_id).await;
+                return Err(e.context("Failed to launch the editor"));
+            }
+        }
+
+        Ok(session)
+    }
+
+    /// Stops watching the file and removes the local copy.
+    pub async fn close(&self, session_id: &str) -> Result<()> {
+        let editing = self
+            .sessions
+            .lock()
+            .await
+            .remove(session_id)
+            .context("Edit session not found")?;
+        editing.watcher.abort();
+
+        if let Some(dir) = Path::new(&editing.session.local_path).parent() {
+            fs::remove_dir_all(dir).await?;
+        }
+        Ok(())
+    }
+
+    /// Settles a conflict, either by uploading the local copy over the
+    /// changed remote file or by replacing the local copy with it.
+    pub async fn resolve(&self, session_id: &str, keep_local: bool) -> Result<()> {
+        if keep_local {
+            return self.save(session_id, true).await;
+        }
+
+        let editing = self.editing(session_id).await?;
+        let mut baseline = editing.baseline.lock().await;
+        let session = &editing.session;
+        let client = self.sftp_service.connect(&session.host_id).await?;
+
+        let metadata = client.sftp.metadata(session.remote_path.as_str()).await?;
+        let mut contents = Vec::new();
+        client
+            .sftp
+            .open(session.remote_path.as_str())
+            .await?
+            .read_to_end(&mut contents)
+            .await?;
+        // The watcher sees this write too, but the digest makes it a no-op
+        write_private_file(Path::new(&session.local_path), &contents).await?;
+
+        *baseline = Baseline {
+            mtime: metadata.mtime,
+            size: metadata.size,
+            digest: digest(&contents),
+        };
+        self.publish(session_id, "reloaded", None);
+        Ok(())
+    }
+
+    /// Uploads the local copy if it changed. Without `force` the upload is
+    /// refused when the remote file changed since we last read or wrote it.
+    async fn save(&self, session_id: &str, force: bool) -> Result<()> {
+        let editing = self.editing(session_id).await?;
+        let mut baseline = editing.baseline.lock().await;
+        let session = &editing.session;
+
+        let contents = fs::read(&session.local_path).await?;
+        let local_digest = digest(&contents);
+        if local_digest == baseline.digest && !force {
+            return Ok(());
+        }
+
+        let client = self.sftp_service.connect(&session.host_id).await?;
+        let sftp = &client.sftp;
+        let current = sftp.metadata(session.remote_path.as_str()).await.ok();
+        let changed = match &current {
+            Some(current) => current.mtime != baseline.mtime || current.size != baseline.size,
+            // Deleted on the server meanwhile
+            None => true,
+        };
+        if changed && !force {
+            self.publish(session_id, "conflict", None);
+            return Ok(());
+        }
+
+        // Write a sibling file and rename it over the original, so a save
+        // cut short never leaves the remote file truncated
+        let remote_path = session.remote_path.as_str();
+        let temp_name = format!(".{}.{}", file_name(remote_path), Uuid::new_v4());
+        let temp_path = sibling(remote_path, &temp_name);
+        let permissions = current.as_ref().and_then(|current| current.permissions);
+        if let Err(e) = replace(sftp, remote_path, &temp_path, &contents, permissions).await {
+            let _ = sftp.remove_file(temp_path.as_str()).await;
+            return Err(e);
+        }
+
+        let written = sftp
+            .metadata(session.remote_path.as_str())
+            .await
+            .unwrap_or_else(|_| FileAttributes::empty());
+        *baseline = Baseline {
+            mtime: written.mtime,
+            size: written.size,
+            digest: local_digest,
+        };
+        self.publish(session_id, "saved", None);
+        Ok(())
+    }
+
+    /// Watches the session's directory rather than the file itself, since
+    /// many editors save by writing a new file and renaming it into place.
+    fn watch(self: &Arc<Self>, session_id: &str, dir: &Path, file: &Path) -> Result<JoinHandle<()>> {
+        let name: Option<OsString> = file.file_name().map(|name| name.to_os_string());
+        let (changes, mut changed) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            let Ok(event) = event else {
+                return;
+            };
+            let ours = event.paths.iter().any(|path| path.file_name() == name.as_deref());
+            if ours && (event.kind.is_modify() || event.kind.is_create()) {
+                let _ = changes.send(());
+            }
+        })?;
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+        let editor = Arc::clone(self);
+        let session_id = session_id.to_string();
+        Ok(tokio::spawn(async move {
+            let _watcher = watcher;
+            while changed.recv().await.is_some() {
+                tokio::time::sleep(SAVE_DEBOUNCE).await;
+                while changed.try_recv().is_ok() {}
+
+                if let Err(e) = editor.save(&session_id, false).await {
+                    editor.publish(&session_id, "failed", Some(format!("{:#}", e)));
+                }
+            }
+        }))
+    }
+
+    /// The GUI editor from the settings. It is started on its own, without
+    /// a terminal.
+    async fn configured_editor(&self) -> Option<String> {
+        self.storage
+            .get_user_settings()
+            .await
+            .ok()
+            .flatten()
+            .and_then(|settings| settings.external_editor)
+            .filter(|command| !command.trim().is_empty())
+    }
+
+    /// Shell command line running the session's terminal editor on the
+    /// local copy, replacing the shell.
+    pub async fn terminal_command(&self, session_id: &str) -> Result<String> {
+        let editing = self.editing(session_id).await?;
+        let session = &editing.session;
+        let editor = session
+            .terminal_editor
+            .as_deref()
+            .context("The file is not open in a terminal editor")?;
+        #[cfg(unix)]
+        let command_line = format!("exec {} {}", editor, shell_quote(&session.local_path));
+        // Windows paths cannot contain quotes
+        #[cfg(not(unix))]
+        let command_line = format!("{} \"{}\"", editor, session.local_path);
+        Ok(command_line)
+    }
+
+    async fn editing(&self, session_id: &str) -> Result<Arc<Editing>> {
+        self.sessions
+            .lock()
+            .await
+            .get(session_id)
+            .cloned()
+            .context("Edit session not found")
+    }
+
+    fn publish(&self, session_id: &str, kind: &'static str, error: Option<String>) {
+        let event = EditorEvent {
+            session_id: session_id.to_string(),
+            kind,
+            error,
+        };
+        if let Ok(payload) = serde_json::to_value(event) {
+            self.window_handler.send_to_all_windows(EDITOR_EVENT, payload);
+        }
+    }
+}
+
+/// $VISUAL or $EDITOR, which usually name editors that need a terminal.
+fn terminal_editor() -> Option<String> {
+    std::env::var("VISUAL")
+        .ok()
+        .filter(|command| !command.trim().is_empty())
+        .or_else(|| std::env::var("EDITOR").ok())
+        .filter(|command| !command.trim().is_empty())
+}
+
+fn digest(contents: &[u8]) -> String {
+    hex::encode(Sha256::digest(contents))
+}
+
+/// Creates a session directory, and the base above it when missing, both
+/// owner-only from the start. Fails if the session directory exists.
+async fn create_private_dir(dir: &Path) -> Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    builder.mode(0o700);
+    if let Some(parent) = dir.parent() {
+        builder.recursive(true).create(parent).await?;
+    }
+    builder.recursive(false).create(dir).await?;
+    Ok(())
+}
+
+/// The latest modification time of a session directory or the copy in it.
+async fn last_modified(dir: &Path) -> Option<SystemTime> {
+    let mut latest = fs::metadata(dir).await.ok()?.modified().ok()?;
+    if let Ok(mut entries) = fs::read_dir(dir).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if let Ok(modified) = entry.metadata().await.and_then(|metadata| metadata.modified()) {
+                latest = latest.max(modified);
+            }
+        }
+    }
+    Some(latest)
+}
+
+async fn write_private_file(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options.open(path).await?;
+    file.write_all(contents).await?;
+    file.flush().await?;
+    Ok(())
+}
+
+/// Puts `contents` at `path` by way of `temp_path` in the same directory.
+async fn replace(
+    sftp: &SftpSession,
+    path: &str,
+    temp_path: &str,
+    contents: &[u8],
+    permissions: Option<u32>,
+) -> Result<()> {
+    let mut attributes = FileAttributes::empty();
+    attributes.permissions = permissions.map(|mode| mode & 0o7777);
+    let mut file = sftp
+        .open_with_flags_and_attributes(
+            temp_path,
+            OpenFlags::CREATE | OpenFlags::EXCLUDE | OpenFlags::WRITE,
+            attributes.clone(),
+        )
+        .await?;
+    file.write_all(contents).await?;
+    file.shutdown().await?;
+    // The server's umask applied on creation
+    if attributes.permissions.is_some() {
+        sftp.set_metadata(temp_path, attributes).await?;
+    }
+
+    if sftp.rename(temp_path, path).await.is_ok() {
+        return Ok(());
+    }
+    // Plain SFTP rename refuses to replace an existing file on OpenSSH, and
+    // russh-sftp does not expose posix-rename@openssh.com. Move the original
+    // aside instead, and put it back if the new file cannot take its place.
+    let backup = format!("{}~", temp_path);
+    sftp.rename(path, backup.as_str())
+        .await
+        .with_context(|| format!("Failed to replace {}", path))?;
+    if let Err(e) = sftp.rename(temp_path, path).await {
+        let _ = sftp.rename(backup.as_str(), path).await;
+        return Err(e).with_context(|| format!("Failed to replace {}", path));
+    }
+    let _ = sftp.remove_file(backup).await;
+    Ok(())
+}
+
+/// `path` with its last component replaced by `name`.
+fn sibling(path: &str, name: &str) -> String {
+    match path.trim_end_matches('/').rsplit_once('/') {
+        Some((dir, _)) => format!("{}/{}", dir, name),
+        None => name.to_string(),
+    }
+}
+
+#[command]
+pub async fn editor_open(
+    remote_editor: State<'_, Arc<RemoteEditor>>,
+    host_id: String,
+    remote_path: String,
+) -> Result<EditSession, String> {
+    remote_editor
+        .open(&host_id, &remote_path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Runs a terminal editor session's editor in a local terminal.
+#[command]
+pub async fn editor_open_terminal(
+    remote_editor: State<'_, Arc<RemoteEditor>>,
+    terminal_manager: State<'_, Arc<TerminalManager>>,
+    agent: State<'_, Arc<SshAgent>>,
+    session_id: String,
+    terminal_id: String,
+    rows: u16,
+    cols: u16,
+) -> Result<(), String> {
+    let command_line = remote_editor
+        .terminal_command(&session_id)
+        .await
+        .map_err(|e| e.to_string())?;
+    terminal_manager
+        .open_local_command(&terminal_id, &command_line, rows, cols, Some(agent.socket_path()))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn editor_close(
+    remote_editor: State<'_, Arc<RemoteEditor>>,
+    session_id: String,
+) -> Result<(), String> {
+    remote_editor
+        .close(&session_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn editor_resolve(
+    remote_editor: State<'_, Arc<RemoteEditor>>,
+    session_id: String,
+    keep_local: bool,
+) -> Result<(), String> {
+    remote_editor
+        .resolve(&session_id, keep_local)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn editor_list(
+    remote_editor: State<'_, Arc<RemoteEditor>>,
+) -> Result<Vec<EditSession>, String> {
+    Ok(remote_editor.list().await)
+}
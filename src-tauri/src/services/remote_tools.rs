@@ -0,0 +1,574 @@
+use anyhow::{bail, Context, Result};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use russh::{client::Msg, Channel, ChannelMsg};
+use russh_sftp::{client::SftpSession, protocol::FileAttributes};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    io::{self, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tauri::{command, State};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::{mpsc, Mutex},
+    task,
+};
+
+use crate::models::sftp::ArchiveMethod;
+use crate::services::{
+    scp::shell_quote,
+    sftp::{is_dir, is_symlink, join_remote, SftpService, Transport, TRANSFER_BUFFER_SIZE},
+    ssh_manager::SshSession,
+};
+
+// Prints the name of every helper the server can run, one per line
+const PROBE: &str = "for tool in tar sha256sum; do command -v $tool >/dev/null 2>&1 && echo $tool; done; \
+    command -v zstd >/dev/null 2>&1 && tar --help 2>&1 | grep -q -e --zstd && echo zstd; \
+    command -v gzip >/dev/null 2>&1 && tar --help 2>&1 | grep -q gzip && echo gzip; \
+    exit 0";
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Capabilities {
+    tar: bool,
+    zstd: bool,
+    gzip: bool,
+    sha256sum: bool,
+}
+
+impl Capabilities {
+    fn archive_method(&self) -> ArchiveMethod {
+        match *self {
+            Capabilities { tar: false, .. } => ArchiveMethod::PerFile,
+            Capabilities { zstd: true, .. } => ArchiveMethod::TarZstd,
+            Capabilities { gzip: true, .. } => ArchiveMethod::TarGzip,
+            _ => ArchiveMethod::Tar,
+        }
+    }
+}
+
+struct Output {
+    // None when the command was killed by a signal
+    status: Option<u32>,
+    stdout: Vec<u8>,
+    stderr: String,
+}
+
+/// Server-side helpers run over exec channels: moving whole directories as
+/// one tar stream and hashing files where they live. Every helper checks
+/// what the server has first and falls back to plain SFTP.
+pub struct RemoteTools {
+    sftp_service: Arc<SftpService>,
+    // Per host, with the session they were probed on
+    capabilities: Mutex<HashMap<String, (String, Capabilities)>>,
+}
+
+impl RemoteTools {
+    pub fn new(sftp_service: Arc<SftpService>) -> Self {
+        Self {
+            sftp_service,
+            capabilities: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Downloads `remote_path` into `local_dir`, packed by the server's tar
+    /// when it has one.
+    pub async fn download_archive(&self, host_id: &str, remote_path: &str, local_dir: &str) -> Result<ArchiveMethod> {
+        let (session, capabilities) = self.capabilities(host_id).await?;
+        let method = capabilities.archive_method();
+        if method == ArchiveMethod::PerFile {
+            self.sftp_service
+                .download(host_id, &[remote_path.to_string()], local_dir)
+                .await?;
+            return Ok(method);
+        }
+
+        let (parent, name) = split_remote(remote_path)?;
+        let mut channel = exec(
+            &session,
+            &format!(
+                "tar -c {} -f - -C {} -- {}",
+                tar_flag(method),
+                shell_quote(parent),
+                shell_quote(name)
+            ),
+        )
+        .await?;
+
+        let (chunks, received) = mpsc::channel(16);
+        let local_dir = PathBuf::from(local_dir);
+        let unpacked = task::spawn_blocking(move || unpack(method, ChunkReader::new(received), &local_dir));
+
+        let output = finish(&mut channel, Some(&chunks)).await?;
+        drop(chunks);
+        if let Err(e) = unpacked.await? {
+            // A failed remote tar explains a broken stream better
+            if output.status.is_some() {
+                check(output, "Remote tar")?;
+            }
+            return Err(e);
+        }
+        check(output, "Remote tar")?;
+
+        Ok(method)
+    }
+
+    /// Uploads `local_path` into `remote_dir` as one tar stream that the
+    /// server unpacks, when it has tar.
+    pub async fn upload_archive(&self, host_id: &str, local_path: &str, remote_dir: &str) -> Result<ArchiveMethod> {
+        let (session, capabilities) = self.capabilities(host_id).await?;
+        let method = capabilities.archive_method();
+        if method == ArchiveMethod::PerFile {
+            self.sftp_service
+                .upload(host_id, &[local_path.to_string()], remote_dir)
+                .await?;
+            return Ok(method);
+        }
+
+        // -o leaves the files owned by the remote user, not by our uid
+        let mut channel = exec(
+            &session,
+            &format!(
+                "tar -x -o {} -f - -C {}",
+                tar_flag(method),
+                shell_quote(remote_dir)
+            ),
+        )
+        .await?;
+
+        let (chunks, mut received) = mpsc::channel::<Vec<u8>>(16);
+        let source = PathBuf::from(local_path);
+        let packed = task::spawn_blocking(move || pack(method, &source, ChunkWriter { chunks }));
+
+        let mut sent = Ok(());
+        while let Some(chunk) = received.recv().await {
+            if let Err(e) = channel.data(&chunk[..]).await {
+                sent = Err(e);
+                break;
+            }
+        }
+        // Unblocks the packer when sending stopped early
+        drop(received);
+        if sent.is_ok() {
+            channel.eof().await?;
+        }
+
+        let output = finish(&mut channel, None).await?;
+        let packed = packed.await?;
+        if let Err(e) = sent {
+            check(output, "Remote tar")?;
+            return Err(e.into());
+        }
+        packed?;
+        check(output, "Remote tar")?;
+
+        Ok(method)
+    }
+
+    /// SHA-256 of a remote file, computed on the server when it has
+    /// sha256sum and read back over SFTP, or SCP without it, otherwise.
+    pub async fn checksum(&self, host_id: &str, path: &str) -> Result<String> {
+        if let Some(digest) = self.remote_sha256(host_id, path).await? {
+            return Ok(digest);
+        }
+
+        match self.sftp_service.transport(host_id).await? {
+            Transport::Sftp(client) => {
+                let mut file = client
+                    .sftp
+                    .open(path)
+                    .await
+                    .with_context(|| format!("Failed to open {}", path))?;
+                sha256(&mut file).await
+            }
+            Transport::Scp(client) => {
+                let mut scp = client
+                    .receive(path, false)
+                    .await
+                    .with_context(|| format!("Failed to open {}", path))?;
+                let (size, _) = scp.file_record(path).await?;
+                scp.accept().await?;
+                sha256(&mut scp.reader(size)).await
+            }
+        }
+    }
+
+    /// Runs sha256sum on the server. `None` when it does not have one.
+    pub async fn remote_sha256(&self, host_id: &str, path: &str) -> Result<Option<String>> {
+        let (session, capabilities) = self.capabilities(host_id).await?;
+        if !capabilities.sha256sum {
+            return Ok(None);
+        }
+
+        let mut channel = exec(&session, &format!("sha256sum -- {}", shell_quote(path))).await?;
+        let output = check(finish(&mut channel, None).await?, "sha256sum")?;
+        // "<digest>  <path>", with a leading backslash when the name was escaped
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let digest = stdout
+            .split_whitespace()
+            .next()
+            .map(|digest| digest.trim_start_matches('\\'))
+            .filter(|digest| digest.len() == 64 && digest.chars().all(|c| c.is_ascii_hexdigit()))
+            .with_context(|| format!("Unexpected sha256sum output: {}", stdout.trim()))?;
+
+        Ok(Some(digest.to_lowercase()))
+    }
+
+    /// Changes owner and/or group by name, like chown(1). Names are resolved
+    /// to ids on the server since SFTP only carries numeric ids. Recursion
+    /// does not follow symlinks.
+    pub async fn chown(
+        &self,
+        host_id: &str,
+        path: &str,
+        owner: Option<&str>,
+        group: Option<&str>,
+        recursive: bool,
+    ) -> Result<()> {
+        if owner.is_none() && group.is_none() {
+            bail!("Neither an owner nor a group was given");
+        }
+
+        let uid = match owner {
+            Some(owner) => Some(self.lookup_id(host_id, "passwd", owner).await?),
+            None => None,
+        };
+        let gid = match group {
+            Some(group) => Some(self.lookup_id(host_id, "group", group).await?),
+            None => None,
+        };
+
+        let client = self.sftp_service.connect(host_id).await?;
+        chown_tree(&client.sftp, path, uid, gid, recursive).await
+    }
+
+    /// The numeric id of a user ("passwd") or group ("group") on the server.
+    /// Asks getent, so directory services count, and reads the /etc file
+    /// over SFTP when it cannot run.
+    async fn lookup_id(&self, host_id: &str, database: &str, name: &str) -> Result<u32> {
+        if let Ok(id) = name.parse() {
+            return Ok(id);
+        }
+
+        let (_, session) = self.sftp_service.session(host_id).await?;
+        let mut channel = exec(&session, &format!("getent {} {}", database, shell_quote(name))).await?;
+        let output = finish(&mut channel, None).await?;
+        let mut entries = String::from_utf8_lossy(&output.stdout).into_owned();
+        if output.status != Some(0) || entries.trim().is_empty() {
+            let client = self.sftp_service.connect(host_id).await?;
+            let mut file = client
+                .sftp
+                .open(format!("/etc/{}", database))
+                .await
+                .with_context(|| format!("Failed to read /etc/{}", database))?;
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents).await?;
+            entries = String::from_utf8_lossy(&contents).into_owned();
+        }
+
+        // "name:password:id:..." in both databases
+        entries
+            .lines()
+            .map(|line| line.split(':').collect::<Vec<_>>())
+            .find(|fields| fields.first() == Some(&name))
+            .and_then(|fields| fields.get(2)?.parse().ok())
+            .with_context(|| match database {
+                "passwd" => format!("No such user on the server: {}", name),
+                _ => format!("No such group on the server: {}", name),
+            })
+    }
+
+    async fn capabilities(&self, host_id: &str) -> Result<(Arc<SshSession>, Capabilities)> {
+        let (session_id, session) = self.sftp_service.session(host_id).await?;
+        let cached = self
+            .capabilities
+            .lock()
+            .await
+            .get(host_id)
+            .filter(|(probed_on, _)| *probed_on == session_id)
+            .map(|(_, capabilities)| *capabilities);
+        if let Some(capabilities) = cached {
+            return Ok((session, capabilities));
+        }
+
+        let mut channel = exec(&session, PROBE).await?;
+        let output = finish(&mut channel, None).await?;
+        let mut capabilities = Capabilities::default();
+        for tool in String::from_utf8_lossy(&output.stdout).lines() {
+            match tool.trim() {
+                "tar" => capabilities.tar = true,
+                "zstd" => capabilities.zstd = true,
+                "gzip" => capabilities.gzip = true,
+                "sha256sum" => capabilities.sha256sum = true,
+                _ => {}
+            }
+        }
+
+        self.capabilities
+            .lock()
+            .await
+            .insert(host_id.to_string(), (session_id, capabilities));
+        Ok((session, capabilities))
+    }
+}
+
+async fn exec(session: &SshSession, command: &str) -> Result<Channel<Msg>> {
+    let channel = session.handle().await.channel_open_session().await?;
+    channel.exec(true, command).await?;
+    Ok(channel)
+}
+
+/// Reads a command's output until its channel closes. Stdout goes to
+/// `forward` when given and is collected otherwise.
+async fn finish(channel: &mut Channel<Msg>, forward: Option<&mpsc::Sender<Vec<u8>>>) -> Result<Output> {
+    let mut output = Output {
+        status: None,
+        stdout: Vec::new(),
+        stderr: String::new(),
+    };
+
+    while let Some(message) = channel.wait().await {
+        match message {
+            ChannelMsg::Data { data } => match forward {
+                Some(forward) => {
+                    if forward.send(data.to_vec()).await.is_err() {
+                        // The reader gave up, its own error says why
+                        let _ = channel.close().await;
+                        break;
+                    }
+                }
+                None => output.stdout.extend_from_slice(&data),
+            },
+            ChannelMsg::ExtendedData { data, ext: 1 } => {
+                output.stderr.push_str(&String::from_utf8_lossy(&data));
+            }
+            ChannelMsg::ExitStatus { exit_status } => output.status = Some(exit_status),
+            _ => {}
+        }
+    }
+
+    Ok(output)
+}
+
+async fn chown_tree(
+    sftp: &SftpSession,
+    path: &str,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    recursive: bool,
+) -> Result<()> {
+    let metadata = sftp.metadata(path).await?;
+    // SFTP sets uid and gid together, the one not being changed is kept
+    let mut attributes = FileAttributes::empty();
+    attributes.uid = uid.or(metadata.uid);
+    attributes.gid = gid.or(metadata.gid);
+    if (attributes.uid, attributes.gid) != (metadata.uid, metadata.gid) {
+        sftp.set_metadata(path, attributes)
+            .await
+            .with_context(|| format!("Failed to change the owner of {}", path))?;
+    }
+
+    if !recursive || !is_dir(&metadata) {
+        return Ok(());
+    }
+    for entry in sftp.read_dir(path).await? {
+        let name = entry.file_name();
+        if name == "." || name == ".." || is_symlink(&entry.metadata()) {
+            continue;
+        }
+        Box::pin(chown_tree(sftp, &join_remote(path, &name), uid, gid, true)).await?;
+    }
+    Ok(())
+}
+
+async fn sha256<R: AsyncRead + Unpin>(reader: &mut R) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; TRANSFER_BUFFER_SIZE];
+    loop {
+        let n = reader.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(hex::encode(hasher.finalize()));
+        }
+        hasher.update(&buffer[..n]);
+    }
+}
+
+fn check(output: Output, what: &str) -> Result<Output> {
+    match output.status {
+        Some(0) => Ok(output),
+        Some(status) if output.stderr.trim().is_empty() => bail!("{} exited with status {}", what, status),
+        Some(_) => bail!("{} failed: {}", what, output.stderr.trim()),
+        None => bail!("{} was terminated", what),
+    }
+}
+
+fn tar_flag(method: ArchiveMethod) -> &'static str {
+    match method {
+        ArchiveMethod::TarZstd => "--zstd",
+        ArchiveMethod::TarGzip => "-z",
+        ArchiveMethod::Tar | ArchiveMethod::PerFile => "",
+    }
+}
+
+/// Splits a remote path into the directory tar runs in and the entry it packs.
+fn split_remote(path: &str) -> Result<(&str, &str)> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = match path.rsplit_once('/') {
+        Some(("", name)) => ("/", name),
+        Some((parent, name)) => (parent, name),
+        None => (".", path),
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        bail!("Cannot archive {}", path);
+    }
+    Ok((parent, name))
+}
+
+fn unpack(method: ArchiveMethod, reader: ChunkReader, dir: &Path) -> Result<()> {
+    let reader: Box<dyn Read> = match method {
+        ArchiveMethod::TarZstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+        ArchiveMethod::TarGzip => Box::new(GzDecoder::new(reader)),
+        ArchiveMethod::Tar | ArchiveMethod::PerFile => Box::new(reader),
+    };
+
+    std::fs::create_dir_all(dir)?;
+    // Refuses entries that would land outside `dir`
+    let mut archive = tar::Archive::new(reader);
+    archive.unpack(dir)?;
+    // Drain the end-of-archive padding so the remote tar exits cleanly
+    io::copy(&mut archive.into_inner(), &mut io::sink())?;
+    Ok(())
+}
+
+fn pack(method: ArchiveMethod, path: &Path, writer: ChunkWriter) -> Result<()> {
+    let writer = BufWriter::with_capacity(TRANSFER_BUFFER_SIZE, writer);
+    let writer = match method {
+        ArchiveMethod::TarZstd => append(zstd::stream::write::Encoder::new(writer, 0)?, path)?.finish()?,
+        ArchiveMethod::TarGzip => append(GzEncoder::new(writer, Compression::fast()), path)?.finish()?,
+        ArchiveMethod::Tar | ArchiveMethod::PerFile => append(writer, path)?,
+    };
+    writer.into_inner().map_err(|e| e.into_error())?;
+    Ok(())
+}
+
+/// Writes `path` as a tar archive holding it under its own name.
+fn append<W: Write>(writer: W, path: &Path) -> Result<W> {
+    let name = path
+        .file_name()
+        .context("Cannot upload a path without a file name")?;
+    let mut builder = tar::Builder::new(writer);
+    if path.is_dir() {
+        builder.append_dir_all(name, path)?;
+    } else {
+        builder.append_path_with_name(path, name)?;
+    }
+    Ok(builder.into_inner()?)
+}
+
+/// Blocking reader over chunks arriving from the async side.
+struct ChunkReader {
+    chunks: mpsc::Receiver<Vec<u8>>,
+    current: Vec<u8>,
+    offset: usize,
+}
+
+impl ChunkReader {
+    fn new(chunks: mpsc::Receiver<Vec<u8>>) -> Self {
+        Self {
+            chunks,
+            current: Vec::new(),
+            offset: 0,
+        }
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        while self.offset == self.current.len() {
+            match self.chunks.blocking_recv() {
+                Some(chunk) => {
+                    self.current = chunk;
+                    self.offset = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+
+        let n = buffer.len().min(self.current.len() - self.offset);
+        buffer[..n].copy_from_slice(&self.current[self.offset..self.offset + n]);
+        self.offset += n;
+        Ok(n)
+    }
+}
+
+/// Blocking writer handing chunks to the async side.
+struct ChunkWriter {
+    chunks: mpsc::Sender<Vec<u8>>,
+}
+
+impl Write for ChunkWriter {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.chunks
+            .blocking_send(buffer.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Remote tar stopped reading"))?;
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[command]
+pub async fn sftp_download_archive(
+    remote_tools: State<'_, Arc<RemoteTools>>,
+    host_id: String,
+    remote_path: String,
+    local_path: String,
+) -> Result<ArchiveMethod, String> {
+    remote_tools
+        .download_archive(&host_id, &remote_path, &local_path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn sftp_upload_archive(
+    remote_tools: State<'_, Arc<RemoteTools>>,
+    host_id: String,
+    local_path: String,
+    remote_path: String,
+) -> Result<ArchiveMethod, String> {
+    remote_tools
+        .upload_archive(&host_id, &local_path, &remote_path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn sftp_checksum(
+    remote_tools: State<'_, Arc<RemoteTools>>,
+    host_id: String,
+    path: String,
+) -> Result<String, String> {
+    remote_tools
+        .checksum(&host_id, &path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn sftp_chown(
+    remote_tools: State<'_, Arc<RemoteTools>>,
+    host_id: String,
+    path: String,
+    owner: Option<String>,
+    group: Option<String>,
+    recursive: bool,
+) -> Result<(), String> {
+    remote_tools
+        .chown(&host_id, &path, owner.as_deref(), group.as_deref(), recursive)
+        .await
+        .map_err(|e| e.to_string())
+}
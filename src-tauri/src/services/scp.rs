@@ -0,0 +1,300 @@
+use anyhow::{bail, Context, Result};
+use std::sync::Arc;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, Take,
+};
+
+use crate::services::{sftp::is_safe_name, ssh_manager::SshSession};
+
+/// A header announced by the sending side of an SCP exchange.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Record {
+    // `C` line, followed by `size` bytes of content
+    File {
+        mode: u32,
+        size: u64,
+        name: String,
+        mtime: Option<u32>,
+    },
+    // `D` line, entries up to the matching `End` live inside it
+    Directory {
+        mode: u32,
+        name: String,
+        mtime: Option<u32>,
+    },
+    // `E` line, leaves the current directory
+    End,
+    // `\x01` line: the sender could not read an entry, e.g. "scp: a/b:
+    // Permission denied", and goes on with the next one
+    Warning(String),
+}
+
+/// Runs the legacy SCP protocol against the server's `scp` binary, for
+/// servers that have no sftp subsystem.
+pub struct ScpClient {
+    pub session_id: String,
+    session: Arc<SshSession>,
+}
+
+impl ScpClient {
+    pub fn new(session_id: String, session: Arc<SshSession>) -> Self {
+        Self {
+            session_id,
+            session,
+        }
+    }
+
+    /// Starts a remote `scp -t` that receives into the directory `dir`.
+    pub async fn send(&self, dir: &str) -> Result<ScpChannel<impl AsyncRead + AsyncWrite + Unpin>> {
+        let mut channel = self
+            .exec(format!("scp -r -d -t -- {}", shell_quote(dir)))
+            .await?;
+        // The receiving side speaks first, once it is ready
+        channel
+            .response()
+            .await
+            .context("Remote scp did not start, is it installed on the server?")?;
+        Ok(channel)
+    }
+
+    /// Starts a remote `scp -f` that sends `path`, with its modification
+    /// times.
+    pub async fn receive(
+        &self,
+        path: &str,
+        recursive: bool,
+    ) -> Result<ScpChannel<impl AsyncRead + AsyncWrite + Unpin>> {
+        let flags = match recursive {
+            true => "-r -p",
+            false => "-p",
+        };
+        let mut channel = self
+            .exec(format!("scp {} -f -- {}", flags, shell_quote(path)))
+            .await?;
+        channel.ack().await?;
+        Ok(channel)
+    }
+
+    async fn exec(&self, command: String) -> Result<ScpChannel<impl AsyncRead + AsyncWrite + Unpin>> {
+        let channel = self.session.handle().await.channel_open_session().await?;
+        channel.exec(true, command.as_str()).await?;
+        Ok(ScpChannel {
+            stream: BufReader::new(channel.into_stream()),
+        })
+    }
+}
+
+/// One side of an SCP exchange over an exec channel. Each header and each
+/// file's content is confirmed by the other side before the next one.
+pub struct ScpChannel<S> {
+    stream: BufReader<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> ScpChannel<S> {
+    /// Reads the next header, confirming directories right away. Files wait
+    /// for `accept` or `skip`. Returns `None` once the sender is done.
+    pub async fn next_record(&mut self) -> Result<Option<Record>> {
+        let mut mtime = None;
+
+        loop {
+            let mut line = Vec::new();
+            if self.stream.read_until(b'\n', &mut line).await? == 0 {
+                if mtime.is_some() {
+                    bail!("Connection closed by the remote scp");
+                }
+                return Ok(None);
+            }
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches('\n');
+            let Some(kind) = line.chars().next() else {
+                bail!("Unexpected empty line from the remote scp");
+            };
+            let rest = &line[kind.len_utf8()..];
+
+            match kind {
+                '\x01' => return Ok(Some(Record::Warning(rest.trim().to_string()))),
+                // The sender gives up, e.g. "scp: ambiguous target"
+                '\x02' => bail!("{}", rest.trim()),
+                'T' => {
+                    mtime = rest.split(' ').next().and_then(|secs| secs.parse().ok());
+                    self.ack().await?;
+                }
+                'C' | 'D' => {
+                    let mut fields = rest.splitn(3, ' ');
+                    let (Some(mode), Some(size), Some(name)) =
+                        (fields.next(), fields.next(), fields.next())
+                    else {
+                        bail!("Malformed header from the remote scp: {}", line);
+                    };
+                    let mode = u32::from_str_radix(mode, 8)
+                        .with_context(|| format!("Malformed header from the remote scp: {}", line))?;
+                    if !is_safe_name(name) {
+                        bail!("Remote scp sent an invalid file name {:?}", name);
+                    }
+                    let name = name.to_string();
+
+                    if kind == 'D' {
+                        self.ack().await?;
+                        return Ok(Some(Record::Directory { mode, name, mtime }));
+                    }
+                    let size = size
+                        .parse()
+                        .with_context(|| format!("Malformed header from the remote scp: {}", line))?;
+                    return Ok(Some(Record::File {
+                        mode,
+                        size,
+                        name,
+                        mtime,
+                    }));
+                }
+                'E' => {
+                    self.ack().await?;
+                    return Ok(Some(Record::End));
+                }
+                _ => bail!("Unexpected line from the remote scp: {}", line),
+            }
+        }
+    }
+
+    /// Reads the header of the one file a non-recursive `receive` sends.
+    /// Returns its size and modification time.
+    pub async fn file_record(&mut self, path: &str) -> Result<(u64, Option<u32>)> {
+        match self.next_record().await? {
+            Some(Record::File { size, mtime, .. }) => Ok((size, mtime)),
+            Some(Record::Warning(message)) => bail!("{}", message),
+            _ => bail!("{} is not a regular file", path),
+        }
+    }
+
+    /// Asks for the content of the file `next_record` just announced.
+    pub async fn accept(&mut self) -> Result<()> {
+        self.ack().await
+    }
+
+    /// Declines the announced file; the sender moves on to the next entry.
+    pub async fn skip(&mut self) -> Result<()> {
+        self.write(b"\x01scp: skipped\n").await
+    }
+
+    /// The accepted file's content, `size` bytes long.
+    pub fn reader(&mut self, size: u64) -> Take<&mut BufReader<S>> {
+        (&mut self.stream).take(size)
+    }
+
+    /// Confirms a received file once its content has been read.
+    pub async fn complete_file(&mut self) -> Result<()> {
+        self.response().await?;
+        self.ack().await
+    }
+
+    /// Announces a directory and moves into it, creating it when missing.
+    pub async fn enter_dir(&mut self, name: &str, mode: u32) -> Result<()> {
+        self.write(format!("D{:04o} 0 {}\n", mode & 0o7777, name).as_bytes())
+            .await?;
+        self.response().await
+    }
+
+    /// Announces a file; its `size` bytes go to `writer` next.
+    pub async fn begin_file(&mut self, name: &str, mode: u32, size: u64, mtime: Option<u32>) -> Result<()> {
+        if let Some(mtime) = mtime {
+            self.write(format!("T{} 0 {} 0\n", mtime, mtime).as_bytes())
+                .await?;
+            self.response().await?;
+        }
+        self.write(format!("C{:04o} {} {}\n", mode & 0o7777, size, name).as_bytes())
+            .await?;
+        self.response().await
+    }
+
+    pub fn writer(&mut self) -> &mut S {
+        self.stream.get_mut()
+    }
+
+    /// Marks the end of a sent file and waits until the receiver has stored it.
+    pub async fn end_file(&mut self) -> Result<()> {
+        self.ack().await?;
+        self.response().await
+    }
+
+    /// Tells the receiving side there is nothing more to send.
+    pub async fn finish(&mut self) -> Result<()> {
+        self.stream.get_mut().shutdown().await?;
+        Ok(())
+    }
+
+    async fn ack(&mut self) -> Result<()> {
+        self.write(&[0]).await
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(bytes).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+
+    async fn response(&mut self) -> Result<()> {
+        let status = self
+            .stream
+            .read_u8()
+            .await
+            .context("Connection closed by the remote scp")?;
+        if status == 0 {
+            return Ok(());
+        }
+
+        let mut message = Vec::new();
+        self.stream.read_until(b'\n', &mut message).await?;
+        bail!("{}", String::from_utf8_lossy(&message).trim())
+    }
+}
+
+/// Quotes `value` as a single POSIX shell word.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(sent: &[u8]) -> ScpChannel<tokio::io::DuplexStream> {
+        let (ours, mut theirs) = tokio::io::duplex(1024);
+        let sent = sent.to_vec();
+        tokio::spawn(async move {
+            theirs.write_all(&sent).await.unwrap();
+            // Keep reading acks until the receiver is done
+            let mut acks = Vec::new();
+            let _ = theirs.read_to_end(&mut acks).await;
+        });
+        ScpChannel {
+            stream: BufReader::new(ours),
+        }
+    }
+
+    #[tokio::test]
+    async fn warnings_leave_the_exchange_going() {
+        let mut scp = channel(b"D0755 0 d\n\x01scp: d/x: Permission denied\nC0644 1 y\n");
+
+        let directory = scp.next_record().await.unwrap();
+        assert!(matches!(directory, Some(Record::Directory { name, .. }) if name == "d"));
+        let warning = scp.next_record().await.unwrap();
+        assert_eq!(warning, Some(Record::Warning("scp: d/x: Permission denied".to_string())));
+        let file = scp.next_record().await.unwrap();
+        assert!(matches!(file, Some(Record::File { size: 1, name, .. }) if name == "y"));
+    }
+
+    #[tokio::test]
+    async fn fatal_errors_end_the_exchange() {
+        let mut scp = channel(b"\x02scp: ambiguous target\n");
+        let error = scp.next_record().await.unwrap_err();
+        assert_eq!(error.to_string(), "scp: ambiguous target");
+    }
+
+    #[tokio::test]
+    async fn file_record_reports_the_warning() {
+        let mut scp = channel(b"\x01scp: x: No such file or directory\n");
+        let error = scp.file_record("x").await.unwrap_err();
+        assert_eq!(error.to_string(), "scp: x: No such file or directory");
+    }
+}
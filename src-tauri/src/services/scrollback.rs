@@ -0,0 +1,251 @@
+use regex::Regex;
+use std::collections::VecDeque;
+
+use crate::models::terminal::ScrollbackMatch;
+
+/// The most recent output of a terminal, bounded to `capacity` bytes.
+///
+/// Positions are offsets into everything the terminal ever printed, so they
+/// stay valid while older output is dropped from the front.
+#[derive(Debug, Default)]
+pub struct Scrollback {
+    data: VecDeque<u8>,
+    capacity: usize,
+    // Bytes dropped from the front so far
+    dropped: u64,
+}
+
+impl Scrollback {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            data: VecDeque::new(),
+            capacity,
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        let bytes = &bytes[bytes.len().saturating_sub(self.capacity)..];
+        let overflow = (self.data.len() + bytes.len()).saturating_sub(self.capacity);
+        self.data.drain(..overflow);
+        self.dropped += overflow as u64;
+        self.data.extend(bytes);
+    }
+
+    /// The kept output, starting at a line boundary once older output has
+    /// been dropped so replay does not begin inside an escape sequence or a
+    /// multibyte character.
+    pub fn replay(&self) -> Vec<u8> {
+        self.data.iter().skip(self.line_start()).copied().collect()
+    }
+
+    /// Matches of `pattern` in the kept output, escape sequences ignored,
+    /// oldest first and at most `limit` of them. Like `replay`, it starts at
+    /// a line boundary once older output has been dropped.
+    pub fn search(&self, pattern: &Regex, limit: usize) -> Vec<ScrollbackMatch> {
+        let start = self.line_start();
+        let (text, offsets) = printable_text(self.data.iter().skip(start).copied());
+        let base = self.dropped + start as u64;
+
+        pattern
+            .find_iter(&text)
+            .filter(|found| !found.is_empty())
+            .take(limit)
+            .map(|found| ScrollbackMatch {
+                start: base + offsets[found.start()],
+                // One past the last byte of the match in the raw output
+                end: base + offsets[found.end() - 1] + 1,
+                text: found.as_str().to_string(),
+            })
+            .collect()
+    }
+
+    /// Where the first whole line of the kept output starts.
+    fn line_start(&self) -> usize {
+        match self.dropped {
+            0 => 0,
+            _ => self
+                .data
+                .iter()
+                .position(|&byte| byte == b'\n')
+                .map_or(self.data.len(), |newline| newline + 1),
+        }
+    }
+}
+
+/// Decodes output as UTF-8 without its escape sequences and control
+/// characters other than newlines and tabs. Returns the text and, for each
+/// of its bytes, the offset of the raw byte it came from.
+fn printable_text(bytes: impl Iterator<Item = u8>) -> (String, Vec<u64>) {
+    enum Escape {
+        None,
+        // Right after ESC
+        Start,
+        // CSI: parameters until a final byte in 0x40..=0x7e
+        Csi,
+        // OSC, DCS and similar: until BEL or ST
+        String,
+        StringEsc,
+    }
+
+    let mut printable = Vec::new();
+    let mut offsets = Vec::new();
+    let mut escape = Escape::None;
+
+    for (offset, byte) in bytes.enumerate() {
+        escape = match (escape, byte) {
+            (Escape::None, 0x1b) => Escape::Start,
+            (Escape::None, b'\n' | b'\t') | (Escape::None, 0x20..) => {
+                printable.push(byte);
+                offsets.push(offset as u64);
+                Escape::None
+            }
+            (Escape::None, _) => Escape::None,
+            (Escape::Start, b'[') => Escape::Csi,
+            (Escape::Start, b']' | b'P' | b'X' | b'^' | b'_') => Escape::String,
+            (Escape::Start, _) => Escape::None,
+            (Escape::Csi, 0x40..=0x7e) => Escape::None,
+            (Escape::Csi, _) => Escape::Csi,
+            (Escape::String, 0x07) => Escape::None,
+            (Escape::String, 0x1b) => Escape::StringEsc,
+            (Escape::String, _) => Escape::String,
+            (Escape::StringEsc, _) => Escape::None,
+        };
+    }
+
+    // Invalid sequences become U+FFFD, three bytes of text for one offset
+    let mut text = String::with_capacity(printable.len());
+    let mut text_offsets = Vec::with_capacity(printable.len());
+    let mut position = 0;
+    for chunk in printable.utf8_chunks() {
+        text.push_str(chunk.valid());
+        text_offsets.extend_from_slice(&offsets[position..position + chunk.valid().len()]);
+        position += chunk.valid().len();
+
+        if !chunk.invalid().is_empty() {
+            text.push(char::REPLACEMENT_CHARACTER);
+            text_offsets.extend([offsets[position]; 3]);
+            position += chunk.invalid().len();
+        }
+    }
+
+    (text, text_offsets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pushes `chunks` into a scrollback of `capacity` bytes and searches
+    /// it, checking each match against the raw output it points at.
+    fn search(capacity: usize, chunks: &[&[u8]], pattern: &str) -> Vec<(u64, u64, String)> {
+        let mut scrollback = Scrollback::new(capacity);
+        for chunk in chunks {
+            scrollback.push(chunk);
+        }
+        let output = chunks.concat();
+        scrollback
+            .search(&Regex::new(pattern).unwrap(), 100)
+            .into_iter()
+            .map(|found| {
+                let raw = &output[found.start as usize..found.end as usize];
+                let (text, _) = printable_text(raw.iter().copied());
+                assert_eq!(text, found.text, "match at {}", found.start);
+                (found.start, found.end, found.text)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn skips_escape_sequences_split_across_pushes() {
+        let chunks: &[&[u8]] = &[
+            b"a \x1b[3",
+            b"1mred\x1b",
+            b"[0m b \x1b]0;ti",
+            b"tle\x07 c \x1bP",
+            b"dcs\x1b",
+            b"\\ d",
+        ];
+        let found = search(1024, chunks, r"red|title|dcs|[abcd]\b");
+        let texts: Vec<&str> = found.iter().map(|(_, _, text)| text.as_str()).collect();
+        assert_eq!(texts, ["a", "red", "b", "c", "d"]);
+        assert_eq!(found[1], (7, 10, "red".to_string()));
+    }
+
+    #[test]
+    fn matches_span_the_escape_sequences_inside_them() {
+        // "red" printed with its "e" in another colour
+        let found = search(1024, &[b"r\x1b[1me\x1b[0md"], "red");
+        assert_eq!(found, [(0, 11, "red".to_string())]);
+    }
+
+    #[test]
+    fn invalid_utf8_maps_to_replacement_characters() {
+        let chunks: &[&[u8]] = &[b"ab\xffcd \xe2\x82", b"x \xc3\xa9"];
+        assert_eq!(
+            search(1024, chunks, "b\u{fffd}c"),
+            [(1, 4, "b\u{fffd}c".to_string())]
+        );
+        assert_eq!(
+            search(1024, chunks, "\u{fffd}x"),
+            [(6, 9, "\u{fffd}x".to_string())]
+        );
+        // The split character came together in the buffer
+        assert_eq!(search(1024, chunks, "é"), [(10, 12, "é".to_string())]);
+        let found = search(1024, chunks, "\u{fffd}");
+        assert_eq!(
+            found.iter().map(|(start, _, _)| *start).collect::<Vec<_>>(),
+            [2, 6]
+        );
+    }
+
+    #[test]
+    fn offsets_stay_absolute_once_output_is_dropped() {
+        let chunks: &[&[u8]] = &[b"first line\n", b"needle one\n", b"needle two\n"];
+        let found = search(24, chunks, "needle [a-z]+");
+        assert_eq!(
+            found,
+            [
+                (11, 21, "needle one".to_string()),
+                (22, 32, "needle two".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn output_cut_inside_an_escape_sequence_is_not_searched() {
+        // Dropping 2 bytes leaves "31mred text" at the front
+        let chunks: &[&[u8]] = &[b"\x1b[31mred text\n", b"more red\n"];
+        let mut scrollback = Scrollback::new(21);
+        for chunk in chunks {
+            scrollback.push(chunk);
+        }
+        assert_eq!(scrollback.replay(), b"more red\n");
+        assert_eq!(search(21, chunks, "31m|red"), [(19, 22, "red".to_string())]);
+    }
+
+    #[test]
+    fn wrapped_buffer_reads_in_order() {
+        // Many small pushes wrap the ring buffer around several times
+        let mut scrollback = Scrollback::new(64);
+        let mut output = Vec::new();
+        for line in 0..100 {
+            let chunk = format!("line {:03}\n", line);
+            scrollback.push(chunk.as_bytes());
+            output.extend_from_slice(chunk.as_bytes());
+        }
+        let found = scrollback.search(&Regex::new(r"line \d+").unwrap(), 100);
+        let lines: Vec<&str> = found.iter().map(|found| found.text.as_str()).collect();
+        assert_eq!(
+            lines,
+            ["line 093", "line 094", "line 095", "line 096", "line 097", "line 098", "line 099"]
+        );
+        for found in &found {
+            assert_eq!(
+                &output[found.start as usize..found.end as usize],
+                found.text.as_bytes()
+            );
+        }
+        assert_eq!(scrollback.replay(), &output[output.len() - 63..]);
+    }
+}
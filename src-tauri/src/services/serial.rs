@@ -0,0 +1,395 @@
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context as TaskContext, Poll},
+    time::Duration,
+};
+use tauri::command;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_serial::{SerialPort, SerialPortBuilderExt, SerialPortType, SerialStream};
+
+use crate::models::serial::{FlowControl, LineEnding, Parity, SerialConfig, SerialPortInfo};
+use crate::services::terminal_manager::{TerminalOutput, TerminalStream};
+
+// How long the line is held low for a BREAK, well above the 2 frames
+// devices need and in line with what terminal programs send
+const BREAK_DURATION: Duration = Duration::from_millis(250);
+
+/// Serial ports on this machine, USB adapters with their vendor details.
+pub fn list_ports() -> Result<Vec<SerialPortInfo>> {
+    let ports = tokio_serial::available_ports().context("Failed to list serial ports")?;
+
+    Ok(ports
+        .into_iter()
+        .map(|port| {
+            let mut info = SerialPortInfo {
+                name: port.port_name,
+                port_type: "unknown".to_string(),
+                vid: None,
+                pid: None,
+                manufacturer: None,
+                product: None,
+                serial_number: None,
+            };
+            match port.port_type {
+                SerialPortType::UsbPort(usb) => {
+                    info.port_type = "usb".to_string();
+                    info.vid = Some(usb.vid);
+                    info.pid = Some(usb.pid);
+                    info.manufacturer = usb.manufacturer;
+                    info.product = usb.product;
+                    info.serial_number = usb.serial_number;
+                }
+                SerialPortType::PciPort => info.port_type = "pci".to_string(),
+                SerialPortType::BluetoothPort => info.port_type = "bluetooth".to_string(),
+                SerialPortType::Unknown => {}
+            }
+            info
+        })
+        .collect())
+}
+
+/// Opens the port described by `config` for exclusive use.
+pub fn open(config: &SerialConfig) -> Result<(TerminalOutput, Box<dyn TerminalStream>)> {
+    let data_bits = match config.data_bits {
+        5 => tokio_serial::DataBits::Five,
+        6 => tokio_serial::DataBits::Six,
+        7 => tokio_serial::DataBits::Seven,
+        8 => tokio_serial::DataBits::Eight,
+        bits => bail!("Invalid data bits: {}", bits),
+    };
+    let stop_bits = match config.stop_bits {
+        1 => tokio_serial::StopBits::One,
+        2 => tokio_serial::StopBits::Two,
+        bits => bail!("Invalid stop bits: {}", bits),
+    };
+    let parity = match config.parity {
+        Parity::None => tokio_serial::Parity::None,
+        Parity::Odd => tokio_serial::Parity::Odd,
+        Parity::Even => tokio_serial::Parity::Even,
+    };
+    let flow_control = match config.flow_control {
+        FlowControl::None => tokio_serial::FlowControl::None,
+        FlowControl::Software => tokio_serial::FlowControl::Software,
+        FlowControl::Hardware => tokio_serial::FlowControl::Hardware,
+    };
+
+    let port = tokio_serial::new(&config.port, config.baud_rate)
+        .data_bits(data_bits)
+        .stop_bits(stop_bits)
+        .parity(parity)
+        .flow_control(flow_control)
+        .open_native_async()
+        .with_context(|| format!("Failed to open {}", config.port))?;
+
+    Ok(connect(port, config))
+}
+
+/// What the terminal needs of a port. BREAK is part of it so tests can
+/// see one, pseudo-terminals quietly ignore it.
+trait Port: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    fn set_break(&self) -> io::Result<()>;
+    fn clear_break(&self) -> io::Result<()>;
+}
+
+impl Port for SerialStream {
+    fn set_break(&self) -> io::Result<()> {
+        Ok(SerialPort::set_break(self)?)
+    }
+
+    fn clear_break(&self) -> io::Result<()> {
+        Ok(SerialPort::clear_break(self)?)
+    }
+}
+
+fn connect<P: Port>(port: P, config: &SerialConfig) -> (TerminalOutput, Box<dyn TerminalStream>) {
+    let port = Arc::new(Mutex::new(port));
+    let output = SerialOutput {
+        port: Arc::clone(&port),
+        implicit_cr: config.implicit_cr,
+        after_cr: false,
+        carry: None,
+        buffer: Vec::new(),
+    };
+    let input = SerialInput {
+        port,
+        line_ending: config.line_ending,
+        pending: Vec::new(),
+    };
+    (Box::new(output), Box::new(input))
+}
+
+// Both halves poll the port under a short lock, the port registers a
+// separate waker for reading and for writing
+fn poll_port<P: Port, T>(port: &Mutex<P>, poll: impl FnOnce(Pin<&mut P>) -> Poll<T>) -> Poll<T> {
+    poll(Pin::new(&mut *port.lock().unwrap()))
+}
+
+struct SerialOutput<P> {
+    port: Arc<Mutex<P>>,
+    implicit_cr: bool,
+    // Whether the last byte passed on was a carriage return
+    after_cr: bool,
+    // The line feed of a translated pair that did not fit the last read
+    carry: Option<u8>,
+    buffer: Vec<u8>,
+}
+
+impl<P: Port> AsyncRead for SerialOutput<P> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if !self.implicit_cr {
+            return poll_port(&self.port, |port| port.poll_read(cx, buf));
+        }
+
+        let this = &mut *self;
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        if let Some(byte) = this.carry.take() {
+            buf.put_slice(&[byte]);
+            return Poll::Ready(Ok(()));
+        }
+
+        // Reads at most half of what fits so every byte can become two,
+        // but one byte when only one fits
+        this.buffer.resize((buf.remaining() / 2).max(1), 0);
+        let mut raw = ReadBuf::new(&mut this.buffer);
+        ready!(poll_port(&this.port, |port| port.poll_read(cx, &mut raw)))?;
+
+        for &byte in raw.filled() {
+            if byte == b'\n' && !this.after_cr {
+                buf.put_slice(b"\r");
+            }
+            if buf.remaining() > 0 {
+                buf.put_slice(&[byte]);
+            } else {
+                this.carry = Some(byte);
+            }
+            this.after_cr = byte == b'\r';
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+struct SerialInput<P> {
+    port: Arc<Mutex<P>>,
+    line_ending: Option<LineEnding>,
+    // Translated input not written to the port yet
+    pending: Vec<u8>,
+}
+
+impl<P: Port> SerialInput<P> {
+    fn poll_drain(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = ready!(poll_port(&self.port, |port| port.poll_write(cx, &self.pending)))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<P: Port> AsyncWrite for SerialInput<P> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let Some(line_ending) = self.line_ending else {
+            return poll_port(&self.port, |port| port.poll_write(cx, data));
+        };
+
+        ready!(self.poll_drain(cx))?;
+        // Terminals send Enter as a carriage return
+        let replacement: &[u8] = match line_ending {
+            LineEnding::Cr => b"\r",
+            LineEnding::Lf => b"\n",
+            LineEnding::CrLf => b"\r\n",
+        };
+        for &byte in data {
+            if byte == b'\r' {
+                self.pending.extend_from_slice(replacement);
+            } else {
+                self.pending.push(byte);
+            }
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        poll_port(&self.port, |port| port.poll_flush(cx))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        poll_port(&self.port, |port| port.poll_shutdown(cx))
+    }
+}
+
+#[async_trait]
+impl<P: Port> TerminalStream for SerialInput<P> {
+    // A serial line has no notion of a window size
+    async fn set_window_size(&mut self, _rows: u16, _cols: u16) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn send_break(&mut self) -> io::Result<()> {
+        self.port.lock().unwrap().set_break()?;
+        tokio::time::sleep(BREAK_DURATION).await;
+        self.port.lock().unwrap().clear_break()?;
+        Ok(())
+    }
+}
+
+#[command]
+pub async fn serial_list_ports() -> Result<Vec<SerialPortInfo>, String> {
+    // Enumerating ports can block for a while on some platforms
+    tokio::task::spawn_blocking(list_ports)
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    // A pseudo-terminal pair: the device end, and the end opened as the
+    // port, held so the line stays up
+    fn pty() -> (SerialStream, SerialStream, String) {
+        let (device, port) = SerialStream::pair().unwrap();
+        let name = port.name().unwrap();
+        (device, port, name)
+    }
+
+    fn config(port: &str, line_ending: Option<LineEnding>, implicit_cr: bool) -> SerialConfig {
+        SerialConfig {
+            port: port.to_string(),
+            baud_rate: 115_200,
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+            flow_control: FlowControl::None,
+            line_ending,
+            implicit_cr,
+        }
+    }
+
+    // Reads `len` bytes, `chunk` at a time
+    async fn read(reader: &mut (impl AsyncRead + Unpin), len: usize, chunk: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut buf = vec![0; chunk];
+        while data.len() < len {
+            let n = tokio::time::timeout(Duration::from_secs(5), reader.read(&mut buf))
+                .await
+                .expect("timed out")
+                .unwrap();
+            assert!(n > 0, "port closed");
+            data.extend_from_slice(&buf[..n]);
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn passes_data_both_ways() {
+        let (mut device, _port, name) = pty();
+        let (mut output, mut input) = open(&config(&name, None, false)).unwrap();
+
+        input.write_all(b"AT\r\n").await.unwrap();
+        input.flush().await.unwrap();
+        assert_eq!(read(&mut device, 4, 64).await, b"AT\r\n");
+
+        device.write_all(b"OK\nready\r\n").await.unwrap();
+        assert_eq!(read(&mut output, 10, 64).await, b"OK\nready\r\n");
+    }
+
+    #[tokio::test]
+    async fn sends_enter_as_the_line_ending() {
+        let cases: [(LineEnding, &[u8]); 3] = [
+            (LineEnding::Cr, b"ls\r-l\r"),
+            (LineEnding::Lf, b"ls\n-l\n"),
+            (LineEnding::CrLf, b"ls\r\n-l\r\n"),
+        ];
+        for (line_ending, expected) in cases {
+            let (mut device, _port, name) = pty();
+            let (_output, mut input) = open(&config(&name, Some(line_ending), false)).unwrap();
+
+            input.write_all(b"ls\r").await.unwrap();
+            input.write_all(b"-l\r").await.unwrap();
+            input.flush().await.unwrap();
+            assert_eq!(read(&mut device, expected.len(), 64).await, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn adds_carriage_returns_to_bare_line_feeds() {
+        let sent: &[u8] = b"one\ntwo\r\nthree\n\n";
+        let expected: &[u8] = b"one\r\ntwo\r\nthree\r\n\r\n";
+        // Reading a byte at a time leaves a line feed over for the next read
+        for chunk in [64, 1] {
+            let (mut device, _port, name) = pty();
+            let (mut output, _input) = open(&config(&name, None, true)).unwrap();
+
+            device.write_all(sent).await.unwrap();
+            assert_eq!(read(&mut output, expected.len(), chunk).await, expected);
+        }
+    }
+
+    // Records BREAKs on the way to a real port
+    struct Line {
+        stream: SerialStream,
+        breaks: Arc<Mutex<Vec<(bool, Instant)>>>,
+    }
+
+    impl AsyncRead for Line {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stream).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Line {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.stream).poll_write(cx, data)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stream).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.stream).poll_shutdown(cx)
+        }
+    }
+
+    impl Port for Line {
+        fn set_break(&self) -> io::Result<()> {
+            self.breaks.lock().unwrap().push((true, Instant::now()));
+            Port::set_break(&self.stream)
+        }
+
+        fn clear_break(&self) -> io::Result<()> {
+            self.breaks.lock().unwrap().push((false, Instant::now()));
+            Port::clear_break(&self.stream)
+        }
+    }
+
+    #[tokio::test]
+    async fn holds_break_on_the_port() {
+        let (_device, stream, name) = pty();
+        let breaks = Arc::new(Mutex::new(Vec::new()));
+        let line = Line {
+            stream,
+            breaks: Arc::clone(&breaks),
+        };
+        let (_output, mut input) = connect(line, &config(&name, None, false));
+
+        input.send_break().await.unwrap();
+        let breaks = breaks.lock().unwrap();
+        assert_eq!(breaks.iter().map(|&(on, _)| on).collect::<Vec<_>>(), [true, false]);
+        assert!(breaks[1].1 - breaks[0].1 >= BREAK_DURATION);
+    }
+}
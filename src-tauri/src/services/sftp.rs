@@ -0,0 +1,919 @@
+use anyhow::{bail, Context, Result};
+use russh::ChannelMsg;
+use russh_sftp::{
+    client::SftpSession,
+    protocol::{FileAttributes, OpenFlags},
+};
+use std::{
+    collections::HashMap,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+    time::UNIX_EPOCH,
+};
+use tauri::{command, State};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::{
+        broadcast::{self, error::RecvError},
+        oneshot, Mutex,
+    },
+};
+
+use crate::models::sftp::{FileDetails, FileEntry, FileRights, FilesystemStats};
+use crate::services::{
+    file_mode::ModeChange,
+    scp::ScpClient,
+    ssh_manager::{SessionEvent, SshManager, SshSession},
+};
+
+pub const TRANSFER_BUFFER_SIZE: usize = 64 * 1024;
+
+// File type bits of st_mode
+const S_IFMT: u32 = 0o170000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFLNK: u32 = 0o120000;
+
+// f_flag bit of statvfs for read-only mounts
+const ST_RDONLY: u64 = 1;
+
+/// An SFTP subsystem channel opened on one of the SSH manager's sessions.
+pub struct SftpClient {
+    pub session_id: String,
+    pub sftp: SftpSession,
+    // OpenSSH's sftp-server reads the two paths of SSH_FXP_SYMLINK in
+    // reverse order
+    pub reversed_symlink: bool,
+}
+
+/// Returned by `SftpService::connect` when the server has no usable sftp
+/// subsystem, as on appliances shipping without `sftp-server`.
+#[derive(Debug)]
+pub struct SubsystemUnavailable;
+
+impl std::fmt::Display for SubsystemUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Server does not provide the sftp subsystem")
+    }
+}
+
+impl std::error::Error for SubsystemUnavailable {}
+
+/// Passes a channel stream through, reporting when it ends before the
+/// server has sent anything.
+struct Handshake<S> {
+    inner: S,
+    received: bool,
+    ended: Option<oneshot::Sender<()>>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Handshake<S> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            if buf.filled().len() > filled {
+                self.received = true;
+            } else if !self.received {
+                if let Some(ended) = self.ended.take() {
+                    let _ = ended.send(());
+                }
+            }
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Handshake<S> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// How transfers reach a host's files.
+pub enum Transport {
+    Sftp(Arc<SftpClient>),
+    // The host has no sftp subsystem, files go through its scp binary
+    Scp(Arc<ScpClient>),
+}
+
+pub struct SftpService {
+    ssh_manager: Arc<SshManager>,
+    // Keyed by host, which is how the file browser addresses connections
+    clients: Mutex<HashMap<String, Arc<SftpClient>>>,
+    // Hosts whose current session turned out to lack the sftp subsystem
+    scp_clients: Mutex<HashMap<String, Arc<ScpClient>>>,
+}
+
+impl SftpService {
+    pub fn new(ssh_manager: Arc<SshManager>) -> Self {
+        Self {
+            ssh_manager,
+            clients: Mutex::new(HashMap::new()),
+            scp_clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Drops SFTP and SCP clients whose session has gone away.
+    pub async fn watch_sessions(self: Arc<Self>, mut events: broadcast::Receiver<SessionEvent>) {
+        loop {
+            match events.recv().await {
+                Ok(SessionEvent::Disconnected { session_id, .. }) => {
+                    self.clients
+                        .lock()
+                        .await
+                        .retain(|_, client| client.session_id != session_id);
+                    self.scp_clients
+                        .lock()
+                        .await
+                        .retain(|_, client| client.session_id != session_id);
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    /// Opens the sftp subsystem on the host's session, connecting the host
+    /// first when it has no session yet. Fails with `SubsystemUnavailable`
+    /// when the server cannot run it.
+    pub async fn connect(&self, host_id: &str) -> Result<Arc<SftpClient>> {
+        if let Some(client) = self.clients.lock().await.get(host_id) {
+            return Ok(Arc::clone(client));
+        }
+
+        let (session_id, session) = self.session(host_id).await?;
+        let mut channel = session.handle().await.channel_open_session().await?;
+        channel
+            .request_subsystem(true, "sftp")
+            .await
+            .context("Failed to start the sftp subsystem")?;
+        loop {
+            match channel.wait().await {
+                Some(ChannelMsg::Success) => break,
+                Some(ChannelMsg::Failure) => return Err(SubsystemUnavailable.into()),
+                Some(_) => {}
+                None => bail!("Channel closed before the sftp subsystem started"),
+            }
+        }
+
+        // Some servers accept the request and only then find sftp-server
+        // missing, closing the channel before the version handshake
+        let (ended, handshake_ended) = oneshot::channel();
+        let stream = Handshake {
+            inner: channel.into_stream(),
+            received: false,
+            ended: Some(ended),
+        };
+        let sftp = tokio::select! {
+            sftp = SftpSession::new(stream) => sftp.context("Failed to start the sftp subsystem")?,
+            Ok(()) = handshake_ended => return Err(SubsystemUnavailable.into()),
+        };
+
+        // The banner is known by now, the subsystem channel was opened
+        let reversed_symlink = session
+            .server_id()
+            .is_some_and(|id| id.starts_with("SSH-2.0-OpenSSH"));
+        let client = Arc::new(SftpClient {
+            session_id,
+            sftp,
+            reversed_symlink,
+        });
+        self.clients
+            .lock()
+            .await
+            .insert(host_id.to_string(), Arc::clone(&client));
+
+        Ok(client)
+    }
+
+    /// SFTP when the host supports it, SCP otherwise.
+    pub async fn transport(&self, host_id: &str) -> Result<Transport> {
+        if let Some(client) = self.scp_clients.lock().await.get(host_id) {
+            return Ok(Transport::Scp(Arc::clone(client)));
+        }
+
+        match self.connect(host_id).await {
+            Ok(client) => Ok(Transport::Sftp(client)),
+            Err(e) if e.is::<SubsystemUnavailable>() => {
+                let (session_id, session) = self.session(host_id).await?;
+                let client = Arc::new(ScpClient::new(session_id, session));
+                self.scp_clients
+                    .lock()
+                    .await
+                    .insert(host_id.to_string(), Arc::clone(&client));
+                Ok(Transport::Scp(client))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether transfers to the host have fallen back to SCP.
+    pub async fn uses_scp(&self, host_id: &str) -> bool {
+        self.scp_clients.lock().await.contains_key(host_id)
+    }
+
+    /// The host's session, connecting it when there is none yet.
+    pub async fn session(&self, host_id: &str) -> Result<(String, Arc<SshSession>)> {
+        let existing = self
+            .ssh_manager
+            .get_all_sessions()
+            .await
+            .into_iter()
+            .find(|s| s.host_id == host_id);
+        let session_id = match existing {
+            Some(session) => session.id,
+            None => self.ssh_manager.connect(host_id).await?.id,
+        };
+
+        let session = self.ssh_manager.get_session(&session_id).await?;
+        Ok((session_id, session))
+    }
+
+    pub async fn client(&self, host_id: &str) -> Result<Arc<SftpClient>> {
+        self.clients
+            .lock()
+            .await
+            .get(host_id)
+            .cloned()
+            .context("SFTP is not connected for this host")
+    }
+
+    pub async fn disconnect(&self, host_id: &str) -> Result<()> {
+        let client = self
+            .clients
+            .lock()
+            .await
+            .remove(host_id)
+            .context("SFTP is not connected for this host")?;
+        let _ = client.sftp.close().await;
+        Ok(())
+    }
+
+    pub async fn list_remote(&self, host_id: &str, path: &str) -> Result<Vec<FileEntry>> {
+        let client = self.client(host_id).await?;
+        let path = match path {
+            "" => client.sftp.canonicalize(".").await?,
+            path => path.to_string(),
+        };
+
+        let mut entries = Vec::new();
+        for entry in client.sftp.read_dir(path.as_str()).await? {
+            let name = entry.file_name();
+            if name == "." || name == ".." {
+                continue;
+            }
+
+            let entry_path = join_remote(&path, &name);
+            let metadata = entry.metadata();
+            let link_target = match is_symlink(&metadata) {
+                true => client.sftp.read_link(entry_path.as_str()).await.ok(),
+                false => None,
+            };
+            entries.push(remote_entry(name, entry_path, &metadata, link_target));
+        }
+
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    pub async fn stat(&self, host_id: &str, path: &str) -> Result<FileEntry> {
+        let client = self.client(host_id).await?;
+        let metadata = client.sftp.symlink_metadata(path).await?;
+        let link_target = match is_symlink(&metadata) {
+            true => client.sftp.read_link(path).await.ok(),
+            false => None,
+        };
+        Ok(remote_entry(
+            file_name(path),
+            path.to_string(),
+            &metadata,
+            link_target,
+        ))
+    }
+
+    /// Uploads files and directory trees into `remote_dir`.
+    pub async fn upload(&self, host_id: &str, local_paths: &[String], remote_dir: &str) -> Result<()> {
+        let client = self.client(host_id).await?;
+
+        for local_path in local_paths {
+            let local_path = Path::new(local_path);
+            let name = local_path
+                .file_name()
+                .context("Cannot upload a path without a file name")?
+                .to_string_lossy()
+                .to_string();
+            upload_tree(&client.sftp, local_path, &join_remote(remote_dir, &name)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Downloads files and directory trees into `local_dir`.
+    pub async fn download(&self, host_id: &str, remote_paths: &[String], local_dir: &str) -> Result<()> {
+        let client = self.client(host_id).await?;
+
+        for remote_path in remote_paths {
+            let target = Path::new(local_dir).join(file_name(remote_path));
+            download_tree(&client.sftp, remote_path, &target).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn mkdir(&self, host_id: &str, path: &str, recursive: bool) -> Result<()> {
+        let client = self.client(host_id).await?;
+
+        if !recursive {
+            client.sftp.create_dir(path).await?;
+            return Ok(());
+        }
+
+        create_dir_all(&client.sftp, path).await
+    }
+
+    pub async fn delete(&self, host_id: &str, path: &str, recursive: bool) -> Result<()> {
+        let client = self.client(host_id).await?;
+        let metadata = client.sftp.symlink_metadata(path).await?;
+
+        match (is_dir(&metadata), recursive) {
+            (true, true) => remove_tree(&client.sftp, path).await,
+            (true, false) => Ok(client.sftp.remove_dir(path).await?),
+            (false, _) => Ok(client.sftp.remove_file(path).await?),
+        }
+    }
+
+    pub async fn rename(&self, host_id: &str, from: &str, to: &str) -> Result<()> {
+        let client = self.client(host_id).await?;
+        client.sftp.rename(from, to).await?;
+        Ok(())
+    }
+
+    /// Changes permissions like chmod(1), `mode` being octal or symbolic.
+    /// Recursion does not follow symlinks.
+    pub async fn chmod(&self, host_id: &str, path: &str, mode: &str, recursive: bool) -> Result<()> {
+        let change = ModeChange::parse(mode)?;
+        let client = self.client(host_id).await?;
+        chmod_tree(&client.sftp, path, &change, recursive).await
+    }
+
+    /// Creates `link_path` pointing at `target`.
+    pub async fn symlink(&self, host_id: &str, target: &str, link_path: &str) -> Result<()> {
+        let client = self.client(host_id).await?;
+        let result = match client.reversed_symlink {
+            true => client.sftp.symlink(target, link_path).await,
+            false => client.sftp.symlink(link_path, target).await,
+        };
+        result.with_context(|| format!("Failed to create the link {}", link_path))?;
+        Ok(())
+    }
+
+    pub async fn readlink(&self, host_id: &str, path: &str) -> Result<String> {
+        let client = self.client(host_id).await?;
+        Ok(client.sftp.read_link(path).await?)
+    }
+
+    /// `stat` plus the octal mode and, where the server supports
+    /// statvfs@openssh.com, usage of the filesystem holding `path`.
+    pub async fn details(&self, host_id: &str, path: &str) -> Result<FileDetails> {
+        let client = self.client(host_id).await?;
+        let metadata = client.sftp.symlink_metadata(path).await?;
+        let link_target = match is_symlink(&metadata) {
+            true => client.sftp.read_link(path).await.ok(),
+            false => None,
+        };
+        let filesystem = client
+            .sftp
+            .fs_info(path)
+            .await
+            .ok()
+            .flatten()
+            .map(|stats| FilesystemStats {
+                block_size: stats.fragment_size,
+                total_bytes: stats.blocks * stats.fragment_size,
+                free_bytes: stats.blocks_free * stats.fragment_size,
+                available_bytes: stats.blocks_avail * stats.fragment_size,
+                total_inodes: stats.inodes,
+                free_inodes: stats.inodes_free,
+                read_only: stats.flags & ST_RDONLY != 0,
+                max_name_length: stats.name_max,
+            });
+
+        Ok(FileDetails {
+            entry: remote_entry(file_name(path), path.to_string(), &metadata, link_target),
+            mode: format!("{:04o}", metadata.permissions.unwrap_or(0) & 0o7777),
+            filesystem,
+        })
+    }
+
+    /// Flushes a remote file to disk. Does nothing on servers without
+    /// fsync@openssh.com.
+    pub async fn fsync(&self, host_id: &str, path: &str) -> Result<()> {
+        let client = self.client(host_id).await?;
+        let file = client.sftp.open(path).await?;
+        file.sync_all().await?;
+        Ok(())
+    }
+}
+
+/// Creates `path` and any missing parents, like `mkdir -p`.
+pub async fn create_dir_all(sftp: &SftpSession, path: &str) -> Result<()> {
+    let mut current = String::new();
+    for component in path.split('/') {
+        if component.is_empty() {
+            if current.is_empty() && path.starts_with('/') {
+                current.push('/');
+            }
+            continue;
+        }
+        current = join_remote(&current, component);
+        if !sftp.try_exists(current.as_str()).await? {
+            sftp.create_dir(current.as_str()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn upload_tree(sftp: &SftpSession, local_path: &Path, remote_path: &str) -> Result<()> {
+    let metadata = fs::metadata(local_path).await?;
+
+    if !metadata.is_dir() {
+        return upload_file(sftp, local_path, remote_path).await;
+    }
+
+    if !sftp.try_exists(remote_path).await? {
+        sftp.create_dir(remote_path).await?;
+    }
+
+    let mut entries = fs::read_dir(local_path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        Box::pin(upload_tree(sftp, &entry.path(), &join_remote(remote_path, &name))).await?;
+    }
+
+    Ok(())
+}
+
+async fn upload_file(sftp: &SftpSession, local_path: &Path, remote_path: &str) -> Result<()> {
+    let mut source = fs::File::open(local_path)
+        .await
+        .with_context(|| format!("Failed to open {}", local_path.display()))?;
+    let mut target = sftp
+        .open_with_flags(
+            remote_path,
+            OpenFlags::CREATE | OpenFlags::TRUNCATE | OpenFlags::WRITE,
+        )
+        .await
+        .with_context(|| format!("Failed to create {}", remote_path))?;
+
+    copy(&mut source, &mut target).await?;
+    target.shutdown().await?;
+    Ok(())
+}
+
+async fn download_tree(sftp: &SftpSession, remote_path: &str, local_path: &Path) -> Result<()> {
+    let metadata = sftp.metadata(remote_path).await?;
+
+    if !is_dir(&metadata) {
+        return download_file(sftp, remote_path, local_path).await;
+    }
+
+    fs::create_dir_all(local_path).await?;
+    for entry in sftp.read_dir(remote_path).await? {
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+        // The name becomes part of a local path, so it must stay one component
+        if !is_safe_name(&name) {
+            bail!("Server returned an invalid file name {:?} in {}", name, remote_path);
+        }
+        let entry_path = join_remote(remote_path, &name);
+        // Linked directories may loop or lead out of the tree; linked files
+        // are downloaded with their target's contents
+        if is_symlink(&sftp.symlink_metadata(entry_path.as_str()).await?)
+            && sftp
+                .metadata(entry_path.as_str())
+                .await
+                .is_ok_and(|target| is_dir(&target))
+        {
+            continue;
+        }
+        Box::pin(download_tree(sftp, &entry_path, &local_path.join(&name))).await?;
+    }
+
+    Ok(())
+}
+
+async fn download_file(sftp: &SftpSession, remote_path: &str, local_path: &Path) -> Result<()> {
+    let mut source = sftp
+        .open(remote_path)
+        .await
+        .with_context(|| format!("Failed to open {}", remote_path))?;
+    let mut target = fs::File::create(local_path)
+        .await
+        .with_context(|| format!("Failed to create {}", local_path.display()))?;
+
+    copy(&mut source, &mut target).await?;
+    target.flush().await?;
+    Ok(())
+}
+
+async fn remove_tree(sftp: &SftpSession, path: &str) -> Result<()> {
+    for entry in sftp.read_dir(path).await? {
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+
+        let entry_path = join_remote(path, &name);
+        // Symlinks to directories are removed, never followed
+        if is_dir(&entry.metadata()) {
+            Box::pin(remove_tree(sftp, &entry_path)).await?;
+        } else {
+            sftp.remove_file(entry_path).await?;
+        }
+    }
+
+    sftp.remove_dir(path).await?;
+    Ok(())
+}
+
+async fn chmod_tree(sftp: &SftpSession, path: &str, change: &ModeChange, recursive: bool) -> Result<()> {
+    let metadata = sftp.metadata(path).await?;
+    let current = metadata.permissions.unwrap_or(0);
+    let mode = change.apply(current, is_dir(&metadata));
+    if mode != current & 0o7777 {
+        let mut attributes = FileAttributes::empty();
+        attributes.permissions = Some(mode);
+        sftp.set_metadata(path, attributes)
+            .await
+            .with_context(|| format!("Failed to change the mode of {}", path))?;
+    }
+
+    if !recursive || !is_dir(&metadata) {
+        return Ok(());
+    }
+    for entry in sftp.read_dir(path).await? {
+        let name = entry.file_name();
+        if name == "." || name == ".." || is_symlink(&entry.metadata()) {
+            continue;
+        }
+        Box::pin(chmod_tree(sftp, &join_remote(path, &name), change, true)).await?;
+    }
+    Ok(())
+}
+
+async fn copy<R, W>(reader: &mut R, writer: &mut W) -> Result<u64>
+where
+    R: tokio::io::AsyncRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0u8; TRANSFER_BUFFER_SIZE];
+    let mut total = 0;
+
+    loop {
+        let n = reader.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(total);
+        }
+        writer.write_all(&buffer[..n]).await?;
+        total += n as u64;
+    }
+}
+
+/// Lists a directory on this machine, or the home directory for an empty path.
+pub fn list_local(path: &str) -> Result<Vec<FileEntry>> {
+    let dir = match path {
+        "" => dirs::home_dir().context("No home directory")?,
+        path => PathBuf::from(path),
+    };
+
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(&dir)? {
+        let entry = entry?;
+        // Entries that vanish or cannot be read are skipped, not fatal
+        let Ok(metadata) = std::fs::symlink_metadata(entry.path()) else {
+            continue;
+        };
+        entries.push(local_entry(&entry.path(), &metadata));
+    }
+
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+fn local_entry(path: &Path, metadata: &std::fs::Metadata) -> FileEntry {
+    let millis = |time: std::io::Result<std::time::SystemTime>| {
+        time.ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_millis() as i64)
+    };
+
+    #[cfg(unix)]
+    let (mode, owner, group) = {
+        use std::os::unix::fs::MetadataExt;
+        (metadata.mode(), metadata.uid(), metadata.gid())
+    };
+    #[cfg(not(unix))]
+    let (mode, owner, group) = (if metadata.permissions().readonly() { 0o444 } else { 0o644 }, 0, 0);
+
+    let file_type = metadata.file_type();
+    FileEntry {
+        file_type: if file_type.is_symlink() {
+            "l"
+        } else if file_type.is_dir() {
+            "d"
+        } else {
+            "-"
+        }
+        .to_string(),
+        name: path
+            .file_name()
+            .map_or_else(String::new, |n| n.to_string_lossy().to_string()),
+        path: path.to_string_lossy().to_string(),
+        size: metadata.len(),
+        modify_time: millis(metadata.modified()),
+        access_time: millis(metadata.accessed()),
+        rights: rights(mode),
+        owner,
+        group,
+        link_target: file_type
+            .is_symlink()
+            .then(|| std::fs::read_link(path).ok())
+            .flatten()
+            .map(|target| target.to_string_lossy().to_string()),
+    }
+}
+
+fn remote_entry(
+    name: String,
+    path: String,
+    metadata: &FileAttributes,
+    link_target: Option<String>,
+) -> FileEntry {
+    FileEntry {
+        file_type: if is_symlink(metadata) {
+            "l"
+        } else if is_dir(metadata) {
+            "d"
+        } else {
+            "-"
+        }
+        .to_string(),
+        name,
+        path,
+        size: metadata.size.unwrap_or(0),
+        modify_time: i64::from(metadata.mtime.unwrap_or(0)) * 1000,
+        access_time: i64::from(metadata.atime.unwrap_or(0)) * 1000,
+        rights: rights(metadata.permissions.unwrap_or(0)),
+        owner: metadata.uid.unwrap_or(0),
+        group: metadata.gid.unwrap_or(0),
+        link_target,
+    }
+}
+
+pub fn is_dir(metadata: &FileAttributes) -> bool {
+    metadata.permissions.unwrap_or(0) & S_IFMT == S_IFDIR
+}
+
+pub fn is_symlink(metadata: &FileAttributes) -> bool {
+    metadata.permissions.unwrap_or(0) & S_IFMT == S_IFLNK
+}
+
+/// Permission bits as the `rwx` triplets ssh2-sftp-client reports.
+fn rights(mode: u32) -> FileRights {
+    let triplet = |bits: u32| {
+        let mut rights = String::new();
+        if bits & 0o4 != 0 {
+            rights.push('r');
+        }
+        if bits & 0o2 != 0 {
+            rights.push('w');
+        }
+        if bits & 0o1 != 0 {
+            rights.push('x');
+        }
+        rights
+    };
+
+    FileRights {
+        user: triplet(mode >> 6),
+        group: triplet(mode >> 3),
+        other: triplet(mode),
+    }
+}
+
+pub fn join_remote(dir: &str, name: &str) -> String {
+    match dir {
+        "" => name.to_string(),
+        dir if dir.ends_with('/') => format!("{}{}", dir, name),
+        dir => format!("{}/{}", dir, name),
+    }
+}
+
+/// Whether a name from the server is a single path component that can be
+/// joined onto a local path without leaving the directory.
+pub fn is_safe_name(name: &str) -> bool {
+    !matches!(name, "" | "." | "..") && !name.contains(['/', '\\', '\0'])
+}
+
+pub fn file_name(path: &str) -> String {
+    path.trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .unwrap_or(path)
+        .to_string()
+}
+
+#[command]
+pub async fn sftp_connect(
+    sftp_service: State<'_, Arc<SftpService>>,
+    host_id: String,
+) -> Result<(), String> {
+    sftp_service
+        .connect(&host_id)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn sftp_disconnect(
+    sftp_service: State<'_, Arc<SftpService>>,
+    host_id: String,
+) -> Result<(), String> {
+    sftp_service
+        .disconnect(&host_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn sftp_list_remote_files(
+    sftp_service: State<'_, Arc<SftpService>>,
+    host_id: String,
+    path: String,
+) -> Result<Vec<FileEntry>, String> {
+    sftp_service
+        .list_remote(&host_id, &path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub fn sftp_list_local_files(path: String) -> Result<Vec<FileEntry>, String> {
+    list_local(&path).map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn sftp_upload(
+    sftp_service: State<'_, Arc<SftpService>>,
+    host_id: String,
+    local_paths: Vec<String>,
+    remote_path: String,
+) -> Result<(), String> {
+    sftp_service
+        .upload(&host_id, &local_paths, &remote_path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn sftp_download(
+    sftp_service: State<'_, Arc<SftpService>>,
+    host_id: String,
+    remote_paths: Vec<String>,
+    local_path: String,
+) -> Result<(), String> {
+    sftp_service
+        .download(&host_id, &remote_paths, &local_path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn sftp_mkdir(
+    sftp_service: State<'_, Arc<SftpService>>,
+    host_id: String,
+    path: String,
+    recursive: bool,
+) -> Result<(), String> {
+    sftp_service
+        .mkdir(&host_id, &path, recursive)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn sftp_delete(
+    sftp_service: State<'_, Arc<SftpService>>,
+    host_id: String,
+    path: String,
+    recursive: bool,
+) -> Result<(), String> {
+    sftp_service
+        .delete(&host_id, &path, recursive)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn sftp_rename(
+    sftp_service: State<'_, Arc<SftpService>>,
+    host_id: String,
+    from: String,
+    to: String,
+) -> Result<(), String> {
+    sftp_service
+        .rename(&host_id, &from, &to)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn sftp_stat(
+    sftp_service: State<'_, Arc<SftpService>>,
+    host_id: String,
+    path: String,
+) -> Result<FileEntry, String> {
+    sftp_service
+        .stat(&host_id, &path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn sftp_chmod(
+    sftp_service: State<'_, Arc<SftpService>>,
+    host_id: String,
+    path: String,
+    mode: String,
+    recursive: bool,
+) -> Result<(), String> {
+    sftp_service
+        .chmod(&host_id, &path, &mode, recursive)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn sftp_symlink(
+    sftp_service: State<'_, Arc<SftpService>>,
+    host_id: String,
+    target: String,
+    link_path: String,
+) -> Result<(), String> {
+    sftp_service
+        .symlink(&host_id, &target, &link_path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn sftp_readlink(
+    sftp_service: State<'_, Arc<SftpService>>,
+    host_id: String,
+    path: String,
+) -> Result<String, String> {
+    sftp_service
+        .readlink(&host_id, &path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn sftp_details(
+    sftp_service: State<'_, Arc<SftpService>>,
+    host_id: String,
+    path: String,
+) -> Result<FileDetails, String> {
+    sftp_service
+        .details(&host_id, &path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn sftp_fsync(
+    sftp_service: State<'_, Arc<SftpService>>,
+    host_id: String,
+    path: String,
+) -> Result<(), String> {
+    sftp_service
+        .fsync(&host_id, &path)
+        .await
+        .map_err(|e| e.to_string())
+}
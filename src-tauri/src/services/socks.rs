@@ -0,0 +1,529 @@
+use anyhow::{bail, Context, Result};
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use subtle::ConstantTimeEq;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::lookup_host,
+};
+
+const SOCKS4_VERSION: u8 = 0x04;
+const SOCKS5_VERSION: u8 = 0x05;
+
+const COMMAND_CONNECT: u8 = 0x01;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_USERNAME_PASSWORD: u8 = 0x02;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+
+// RFC 1929 username/password sub-negotiation
+const AUTH_VERSION: u8 = 0x01;
+const AUTH_SUCCESS: u8 = 0x00;
+const AUTH_FAILURE: u8 = 0x01;
+
+const ADDRESS_IPV4: u8 = 0x01;
+const ADDRESS_DOMAIN: u8 = 0x03;
+const ADDRESS_IPV6: u8 = 0x04;
+
+const SOCKS4_GRANTED: u8 = 0x5a;
+const SOCKS4_REJECTED: u8 = 0x5b;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SocksVersion {
+    V4,
+    V5,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TargetHost {
+    Ip(IpAddr),
+    Domain(String),
+}
+
+impl std::fmt::Display for TargetHost {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ip(ip) => write!(f, "{}", ip),
+            Self::Domain(domain) => write!(f, "{}", domain),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SocksRequest {
+    pub version: SocksVersion,
+    pub host: TargetHost,
+    pub port: u16,
+}
+
+/// SOCKS5 reply codes; SOCKS4 collapses everything but success into a
+/// single rejection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SocksReply {
+    Succeeded = 0x00,
+    NotAllowed = 0x02,
+    HostUnreachable = 0x04,
+    CommandNotSupported = 0x07,
+    AddressNotSupported = 0x08,
+}
+
+pub struct SocksCredentials {
+    pub username: String,
+    pub password: String,
+}
+
+/// Reads a SOCKS4, SOCKS4a or SOCKS5 greeting and CONNECT request from a
+/// client. Failures that the protocol can express are answered before
+/// returning the error.
+pub async fn handshake<S>(stream: &mut S, credentials: Option<&SocksCredentials>) -> Result<SocksRequest>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match stream.read_u8().await? {
+        SOCKS5_VERSION => handshake_v5(stream, credentials).await,
+        // SOCKS4 only carries a user id, so it cannot satisfy password auth
+        SOCKS4_VERSION if credentials.is_some() => {
+            reply(stream, SocksVersion::V4, SocksReply::NotAllowed).await?;
+            bail!("SOCKS4 clients cannot authenticate")
+        }
+        SOCKS4_VERSION => handshake_v4(stream).await,
+        version => bail!("Unsupported SOCKS version {}", version),
+    }
+}
+
+async fn handshake_v5<S>(stream: &mut S, credentials: Option<&SocksCredentials>) -> Result<SocksRequest>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let method_count = stream.read_u8().await?;
+    let mut methods = vec![0u8; method_count.into()];
+    stream.read_exact(&mut methods).await?;
+
+    let method = match credentials {
+        Some(_) => METHOD_USERNAME_PASSWORD,
+        None => METHOD_NO_AUTH,
+    };
+    if !methods.contains(&method) {
+        stream.write_all(&[SOCKS5_VERSION, METHOD_NONE_ACCEPTABLE]).await?;
+        bail!("Client offered no acceptable authentication method");
+    }
+    stream.write_all(&[SOCKS5_VERSION, method]).await?;
+
+    if let Some(credentials) = credentials {
+        if stream.read_u8().await? != AUTH_VERSION {
+            bail!("Unsupported authentication version");
+        }
+        let username = read_prefixed(stream).await?;
+        let password = read_prefixed(stream).await?;
+
+        // Compared in constant time so timing does not reveal how much matched
+        let matches = username.ct_eq(credentials.username.as_bytes())
+            & password.ct_eq(credentials.password.as_bytes());
+        if !bool::from(matches) {
+            stream.write_all(&[AUTH_VERSION, AUTH_FAILURE]).await?;
+            bail!("SOCKS authentication failed");
+        }
+        stream.write_all(&[AUTH_VERSION, AUTH_SUCCESS]).await?;
+    }
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let [version, command, _reserved, address_type] = header;
+    if version != SOCKS5_VERSION {
+        bail!("Unexpected SOCKS version {} in request", version);
+    }
+
+    let host = match address_type {
+        ADDRESS_IPV4 => {
+            let mut octets = [0u8; 4];
+            stream.read_exact(&mut octets).await?;
+            TargetHost::Ip(IpAddr::V4(Ipv4Addr::from(octets)))
+        }
+        ADDRESS_IPV6 => {
+            let mut octets = [0u8; 16];
+            stream.read_exact(&mut octets).await?;
+            TargetHost::Ip(IpAddr::V6(Ipv6Addr::from(octets)))
+        }
+        ADDRESS_DOMAIN => {
+            let domain = read_prefixed(stream).await?;
+            TargetHost::Domain(String::from_utf8(domain).context("Domain name is not UTF-8")?)
+        }
+        _ => {
+            reply(stream, SocksVersion::V5, SocksReply::AddressNotSupported).await?;
+            bail!("Unsupported address type {}", address_type);
+        }
+    };
+    let port = stream.read_u16().await?;
+
+    if command != COMMAND_CONNECT {
+        reply(stream, SocksVersion::V5, SocksReply::CommandNotSupported).await?;
+        bail!("Unsupported SOCKS command {}", command);
+    }
+
+    Ok(SocksRequest {
+        version: SocksVersion::V5,
+        host,
+        port,
+    })
+}
+
+async fn handshake_v4<S>(stream: &mut S) -> Result<SocksRequest>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let command = stream.read_u8().await?;
+    let port = stream.read_u16().await?;
+    let mut octets = [0u8; 4];
+    stream.read_exact(&mut octets).await?;
+    // The user id is informational only
+    read_null_terminated(stream).await?;
+
+    // SOCKS4a marks a trailing domain name with an address of 0.0.0.x, x != 0
+    let host = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+        let domain = read_null_terminated(stream).await?;
+        TargetHost::Domain(String::from_utf8(domain).context("Domain name is not UTF-8")?)
+    } else {
+        TargetHost::Ip(IpAddr::V4(Ipv4Addr::from(octets)))
+    };
+
+    if command != COMMAND_CONNECT {
+        reply(stream, SocksVersion::V4, SocksReply::CommandNotSupported).await?;
+        bail!("Unsupported SOCKS command {}", command);
+    }
+
+    Ok(SocksRequest {
+        version: SocksVersion::V4,
+        host,
+        port,
+    })
+}
+
+/// Answers a CONNECT request. The bound address is always reported as
+/// unspecified since the real socket lives on the server.
+pub async fn reply<S>(stream: &mut S, version: SocksVersion, reply: SocksReply) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    match version {
+        SocksVersion::V5 => {
+            stream
+                .write_all(&[SOCKS5_VERSION, reply as u8, 0, ADDRESS_IPV4, 0, 0, 0, 0, 0, 0])
+                .await?
+        }
+        SocksVersion::V4 => {
+            let status = if reply == SocksReply::Succeeded {
+                SOCKS4_GRANTED
+            } else {
+                SOCKS4_REJECTED
+            };
+            stream.write_all(&[0, status, 0, 0, 0, 0, 0, 0]).await?
+        }
+    }
+    Ok(())
+}
+
+async fn read_prefixed<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Vec<u8>> {
+    let length = stream.read_u8().await?;
+    let mut data = vec![0u8; length.into()];
+    stream.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+async fn read_null_terminated<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            0 => return Ok(data),
+            byte if data.len() < 255 => data.push(byte),
+            _ => bail!("SOCKS4 field is too long"),
+        }
+    }
+}
+
+/// Destination filter built from allow and deny CIDR lists. Deny entries win;
+/// an empty allow list permits everything not denied.
+#[derive(Debug, Default)]
+pub struct DestinationRules {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl DestinationRules {
+    pub fn new(allow: &[String], deny: &[String]) -> Result<Self> {
+        Ok(Self {
+            allow: parse_networks(allow)?,
+            deny: parse_networks(deny)?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    fn permits_ip(&self, ip: IpAddr) -> bool {
+        // An IPv4-mapped IPv6 address reaches the IPv4 host, so it has to
+        // match the IPv4 rules
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+
+    /// Checks a destination against the rules. Domain names are resolved
+    /// locally for the check only, and every address they resolve to has to
+    /// pass.
+    pub async fn permits(&self, host: &TargetHost, port: u16) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+
+        match host {
+            TargetHost::Ip(ip) => self.permits_ip(*ip),
+            TargetHost::Domain(domain) => match lookup_host((domain.as_str(), port)).await {
+                Ok(addresses) => {
+                    let addresses: Vec<_> = addresses.collect();
+                    !addresses.is_empty() && addresses.iter().all(|a| self.permits_ip(a.ip()))
+                }
+                // A name that cannot be checked could hide any address
+                Err(_) => false,
+            },
+        }
+    }
+}
+
+fn parse_networks(networks: &[String]) -> Result<Vec<IpNet>> {
+    networks
+        .iter()
+        .map(|network| {
+            let network = network.trim();
+            // Bare addresses are treated as single-host networks
+            network
+                .parse::<IpNet>()
+                .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+                .with_context(|| format!("Invalid CIDR {}", network))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials() -> SocksCredentials {
+        SocksCredentials {
+            username: "alice".to_string(),
+            password: "secret".to_string(),
+        }
+    }
+
+    /// Runs a handshake against what a client sent, returning the outcome
+    /// and everything the client was sent back.
+    async fn handshake_with(
+        sent: &[u8],
+        credentials: Option<&SocksCredentials>,
+    ) -> (Result<SocksRequest>, Vec<u8>) {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(sent).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let result = handshake(&mut server, credentials).await;
+        drop(server);
+        let mut replies = Vec::new();
+        client.read_to_end(&mut replies).await.unwrap();
+        (result, replies)
+    }
+
+    fn target(result: Result<SocksRequest>) -> (SocksVersion, TargetHost, u16) {
+        let request = result.unwrap();
+        (request.version, request.host, request.port)
+    }
+
+    fn domain(name: &str) -> TargetHost {
+        TargetHost::Domain(name.to_string())
+    }
+
+    fn ip(address: &str) -> TargetHost {
+        TargetHost::Ip(address.parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn socks5_connects_to_every_address_type() {
+        let (result, replies) = handshake_with(
+            b"\x05\x02\x00\x02\x05\x01\x00\x03\x0bexample.com\x01\xbb",
+            None,
+        )
+        .await;
+        assert_eq!(
+            target(result),
+            (SocksVersion::V5, domain("example.com"), 443)
+        );
+        assert_eq!(replies, [5, 0]);
+
+        let (result, _) = handshake_with(
+            b"\x05\x01\x00\x05\x01\x00\x01\x0a\x00\x00\x01\x00\x16",
+            None,
+        )
+        .await;
+        assert_eq!(target(result), (SocksVersion::V5, ip("10.0.0.1"), 22));
+
+        let mut sent = b"\x05\x01\x00\x05\x01\x00\x04".to_vec();
+        sent.extend_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        sent.extend_from_slice(&[0, 80]);
+        let (result, _) = handshake_with(&sent, None).await;
+        assert_eq!(target(result), (SocksVersion::V5, ip("2001:db8::1"), 80));
+    }
+
+    #[tokio::test]
+    async fn socks5_checks_username_and_password() {
+        let credentials = credentials();
+        let request = b"\x05\x01\x00\x01\x7f\x00\x00\x01\x00\x50";
+
+        let sent = [&b"\x05\x02\x00\x02\x01\x05alice\x06secret"[..], request].concat();
+        let (result, replies) = handshake_with(&sent, Some(&credentials)).await;
+        assert_eq!(target(result), (SocksVersion::V5, ip("127.0.0.1"), 80));
+        assert_eq!(replies, [5, 2, 1, 0]);
+
+        for login in [
+            &b"\x01\x05alice\x06secreT"[..],
+            b"\x01\x05alice\x07secret!",
+            b"\x01\x00\x00",
+        ] {
+            let sent = [&b"\x05\x01\x02"[..], login, request].concat();
+            let (result, replies) = handshake_with(&sent, Some(&credentials)).await;
+            assert!(result.is_err());
+            assert_eq!(replies, [5, 2, 1, 1]);
+        }
+    }
+
+    #[tokio::test]
+    async fn socks5_needs_the_configured_method() {
+        let credentials = credentials();
+        let (result, replies) = handshake_with(b"\x05\x01\x00", Some(&credentials)).await;
+        assert!(result.is_err());
+        assert_eq!(replies, [5, 0xff]);
+
+        let (result, replies) = handshake_with(b"\x05\x01\x02", None).await;
+        assert!(result.is_err());
+        assert_eq!(replies, [5, 0xff]);
+    }
+
+    #[tokio::test]
+    async fn socks5_answers_unsupported_requests() {
+        // BIND
+        let (result, replies) = handshake_with(
+            b"\x05\x01\x00\x05\x02\x00\x01\x7f\x00\x00\x01\x00\x50",
+            None,
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(replies, [5, 0, 5, 7, 0, 1, 0, 0, 0, 0, 0, 0]);
+
+        let (result, replies) = handshake_with(b"\x05\x01\x00\x05\x01\x00\x09", None).await;
+        assert!(result.is_err());
+        assert_eq!(replies, [5, 0, 5, 8, 0, 1, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn socks4_and_4a_connect() {
+        let (result, replies) =
+            handshake_with(b"\x04\x01\x00\x50\xc0\xa8\x01\x02user\x00", None).await;
+        assert_eq!(target(result), (SocksVersion::V4, ip("192.168.1.2"), 80));
+        assert!(replies.is_empty());
+
+        let (result, _) =
+            handshake_with(b"\x04\x01\x01\xbb\x00\x00\x00\x07\x00example.com\x00", None).await;
+        assert_eq!(
+            target(result),
+            (SocksVersion::V4, domain("example.com"), 443)
+        );
+
+        // 0.0.0.0 is an address, not the SOCKS4a marker
+        let (result, _) = handshake_with(b"\x04\x01\x00\x50\x00\x00\x00\x00\x00", None).await;
+        assert_eq!(target(result), (SocksVersion::V4, ip("0.0.0.0"), 80));
+    }
+
+    #[tokio::test]
+    async fn socks4_rejections() {
+        let rejected = [0, 0x5b, 0, 0, 0, 0, 0, 0];
+        let credentials = credentials();
+        let (result, replies) =
+            handshake_with(b"\x04\x01\x00\x50\x7f\x00\x00\x01\x00", Some(&credentials)).await;
+        assert!(result.is_err());
+        assert_eq!(replies, rejected);
+
+        let (result, replies) = handshake_with(b"\x04\x02\x00\x50\x7f\x00\x00\x01\x00", None).await;
+        assert!(result.is_err());
+        assert_eq!(replies, rejected);
+
+        let mut sent = b"\x04\x01\x00\x50\x7f\x00\x00\x01".to_vec();
+        sent.extend_from_slice(&[b'u'; 300]);
+        sent.push(0);
+        let (result, _) = handshake_with(&sent, None).await;
+        assert!(result.is_err());
+
+        // Cut off before the user id ends
+        let (result, _) = handshake_with(b"\x04\x01\x00\x50\x7f\x00\x00\x01user", None).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn other_versions_are_refused_silently() {
+        let (result, replies) = handshake_with(b"\x06\x01\x00", None).await;
+        assert!(result.is_err());
+        assert!(replies.is_empty());
+    }
+
+    fn filter(allow: &[&str], deny: &[&str]) -> DestinationRules {
+        let strings = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        DestinationRules::new(&strings(allow), &strings(deny)).unwrap()
+    }
+
+    async fn permits(rules: &DestinationRules, host: &str) -> bool {
+        rules.permits(&ip(host), 22).await
+    }
+
+    #[tokio::test]
+    async fn deny_rules_win_over_allow_rules() {
+        let rules = filter(
+            &["10.0.0.0/8", "2001:db8::/32"],
+            &["10.1.0.0/16", " 10.2.3.4 "],
+        );
+        assert!(permits(&rules, "10.0.0.1").await);
+        assert!(permits(&rules, "2001:db8::5").await);
+        assert!(!permits(&rules, "10.1.2.3").await);
+        assert!(!permits(&rules, "10.2.3.4").await);
+        assert!(permits(&rules, "10.2.3.5").await);
+        assert!(!permits(&rules, "192.168.0.1").await);
+
+        let rules = filter(&[], &["192.168.0.0/16"]);
+        assert!(permits(&rules, "8.8.8.8").await);
+        assert!(!permits(&rules, "192.168.1.1").await);
+
+        assert!(permits(&DestinationRules::default(), "192.168.1.1").await);
+        assert!(DestinationRules::new(&["10.0.0.0/33".to_string()], &[]).is_err());
+        assert!(DestinationRules::new(&[], &["intranet".to_string()]).is_err());
+    }
+
+    #[tokio::test]
+    async fn mapped_ipv6_addresses_follow_the_ipv4_rules() {
+        let rules = filter(&["10.0.0.0/8"], &["10.1.0.0/16"]);
+        assert!(permits(&rules, "::ffff:10.0.0.1").await);
+        assert!(!permits(&rules, "::ffff:10.1.0.1").await);
+        assert!(!permits(&rules, "::ffff:192.168.0.1").await);
+
+        let rules = filter(&[], &["10.0.0.0/8"]);
+        assert!(!permits(&rules, "::ffff:10.0.0.1").await);
+        assert!(permits(&rules, "::ffff:192.168.0.1").await);
+    }
+
+    #[tokio::test]
+    async fn domains_are_checked_by_every_address() {
+        let rules = filter(&["127.0.0.0/8", "::1"], &[]);
+        assert!(rules.permits(&domain("localhost"), 22).await);
+        let rules = filter(&[], &["127.0.0.0/8", "::1"]);
+        assert!(!rules.permits(&domain("localhost"), 22).await);
+        // Unchecked names are refused rather than let through
+        assert!(!rules.permits(&domain("name.invalid"), 22).await);
+    }
+}
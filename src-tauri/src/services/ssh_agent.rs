@@ -0,0 +1,875 @@
+use anyhow::{anyhow, Context, Result};
+use rsa::{
+    pkcs1v15::SigningKey as RsaSigningKey,
+    signature::{SignatureEncoding, Signer},
+};
+use serde::Serialize;
+use sha2::Sha256;
+use ssh_encoding::{Decode, Encode};
+use ssh_key::{
+    private::KeypairData, public::KeyData, Algorithm, Certificate, HashAlg, PrivateKey,
+    PublicKey, Signature,
+};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tauri::{command, State};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{oneshot, watch, Mutex},
+};
+use uuid::Uuid;
+
+use crate::models::{
+    ipc_channels::{AGENT_CONFIRM_REQUEST, AGENT_LOCKED, AGENT_UNLOCKED},
+    ssh_key::SSHKey,
+};
+use crate::services::{
+    certificates::{is_currently_valid, read_certificate},
+    secure_storage::SecureStorage,
+    terminal_manager::WindowHandler,
+};
+
+// Agent protocol message numbers (draft-miller-ssh-agent)
+const SSH_AGENT_FAILURE: u8 = 5;
+const SSH_AGENT_SUCCESS: u8 = 6;
+const SSH_AGENTC_REQUEST_IDENTITIES: u8 = 11;
+const SSH_AGENT_IDENTITIES_ANSWER: u8 = 12;
+const SSH_AGENTC_SIGN_REQUEST: u8 = 13;
+const SSH_AGENT_SIGN_RESPONSE: u8 = 14;
+const SSH_AGENTC_REMOVE_IDENTITY: u8 = 18;
+const SSH_AGENTC_REMOVE_ALL_IDENTITIES: u8 = 19;
+const SSH_AGENTC_LOCK: u8 = 22;
+const SSH_AGENTC_UNLOCK: u8 = 23;
+
+const SSH_AGENT_RSA_SHA2_256: u32 = 0x02;
+const SSH_AGENT_RSA_SHA2_512: u32 = 0x04;
+
+const MAX_MESSAGE_LEN: usize = 256 * 1024;
+const CONFIRM_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct AgentIdentity {
+    key_id: String,
+    name: String,
+    public_key: PublicKey,
+    key_blob: Vec<u8>,
+    // Certificate offered alongside the plain key, signed with the same private key
+    cert_blob: Option<Vec<u8>>,
+    confirm: bool,
+    expires_at: Option<Instant>,
+}
+
+impl AgentIdentity {
+    fn matches(&self, blob: &[u8]) -> bool {
+        self.key_blob == blob || self.cert_blob.as_deref() == Some(blob)
+    }
+
+    fn is_expired(&self) -> bool {
+        self.expires_at.is_some_and(|t| Instant::now() >= t)
+    }
+}
+
+/// The two independent ways the agent gets locked. Either one is enough to
+/// refuse requests, and each is only lifted the way it was set.
+#[derive(Default)]
+struct LockState {
+    // Set by a client through SSH_AGENTC_LOCK
+    passphrase: Option<Vec<u8>>,
+    // Set while the vault is locked; only unlocking the vault lifts it
+    vault: bool,
+}
+
+impl LockState {
+    fn is_locked(&self) -> bool {
+        self.passphrase.is_some() || self.vault
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AgentKeyInfo {
+    pub key_id: String,
+    pub name: String,
+    pub fingerprint: String,
+    pub confirm: bool,
+    pub expires_in: Option<u64>,
+}
+
+pub struct SshAgent {
+    storage: Arc<SecureStorage>,
+    window_handler: Arc<dyn WindowHandler>,
+    identities: Mutex<Vec<AgentIdentity>>,
+    lock_state: Mutex<LockState>,
+    pending_confirmations: Mutex<HashMap<String, oneshot::Sender<bool>>>,
+    socket_path: PathBuf,
+}
+
+impl SshAgent {
+    pub fn new(storage: Arc<SecureStorage>, window_handler: Arc<dyn WindowHandler>) -> Result<Self> {
+        Ok(Self::with_socket_path(storage, window_handler, Self::get_socket_path()?))
+    }
+
+    fn with_socket_path(
+        storage: Arc<SecureStorage>,
+        window_handler: Arc<dyn WindowHandler>,
+        socket_path: PathBuf,
+    ) -> Self {
+        Self {
+            storage,
+            window_handler,
+            identities: Mutex::new(Vec::new()),
+            lock_state: Mutex::new(LockState::default()),
+            pending_confirmations: Mutex::new(HashMap::new()),
+            socket_path,
+        }
+    }
+
+    fn get_socket_path() -> Result<PathBuf> {
+        let mut path = dirs::runtime_dir()
+            .or_else(dirs::config_dir)
+            .context("Failed to get runtime directory")?
+            .join("teemux");
+        create_private_dir(&path)?;
+        path.push("agent.sock");
+        Ok(path)
+    }
+
+    /// Path to export as `SSH_AUTH_SOCK`.
+    pub fn socket_path(&self) -> &Path {
+        &self.socket_path
+    }
+
+    #[cfg(unix)]
+    pub async fn serve(self: Arc<Self>) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        use tokio::net::UnixListener;
+
+        // A socket left behind by a previous run would make bind fail
+        if self.socket_path.exists() {
+            fs::remove_file(&self.socket_path)?;
+        }
+
+        // The directory is private, so nobody else can reach the socket
+        // even before its own mode is narrowed
+        let listener = UnixListener::bind(&self.socket_path)?;
+        fs::set_permissions(&self.socket_path, fs::Permissions::from_mode(0o600))?;
+
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let agent = Arc::clone(&self);
+
+            tokio::spawn(async move {
+                if let Err(e) = agent.handle_connection(stream).await {
+                    log::error!("SSH agent connection failed: {}", e);
+                }
+            });
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub async fn serve(self: Arc<Self>) -> Result<()> {
+        Err(anyhow!("The built-in SSH agent is not supported on this platform"))
+    }
+
+    async fn handle_connection<S>(&self, mut stream: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        while let Some(request) = read_frame(&mut stream).await? {
+            let response = match self.handle_message(&request).await {
+                Ok(response) => response,
+                Err(e) => {
+                    log::error!("SSH agent request failed: {}", e);
+                    vec![SSH_AGENT_FAILURE]
+                }
+            };
+
+            write_frame(&mut stream, &response).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Relays a forwarded agent channel to the agent listening on `upstream`.
+    /// With `confirm` set, every signature request is shown to the user first,
+    /// naming the remote host that asked for it.
+    #[cfg(unix)]
+    pub async fn proxy_forwarded<S>(
+        &self,
+        mut channel: S,
+        upstream: &Path,
+        remote_host: &str,
+        confirm: bool,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut upstream = tokio::net::UnixStream::connect(upstream)
+            .await
+            .context("Failed to connect to the local SSH agent")?;
+
+        // The agent protocol is strictly request/response, so frames can be
+        // relayed one at a time
+        while let Some(request) = read_frame(&mut channel).await? {
+            if confirm && request.first() == Some(&SSH_AGENTC_SIGN_REQUEST) {
+                let mut body = &request[1..];
+                let key_blob = Vec::<u8>::decode(&mut body)?;
+
+                if !self.confirm_forwarded(&key_blob, remote_host).await {
+                    write_frame(&mut channel, &status(false)).await?;
+                    continue;
+                }
+            }
+
+            write_frame(&mut upstream, &request).await?;
+            let response = read_frame(&mut upstream)
+                .await?
+                .context("Local SSH agent closed the connection")?;
+            write_frame(&mut channel, &response).await?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub async fn proxy_forwarded<S>(
+        &self,
+        _channel: S,
+        _upstream: &Path,
+        _remote_host: &str,
+        _confirm: bool,
+    ) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        Err(anyhow!("Agent forwarding is not supported on this platform"))
+    }
+
+    async fn handle_message(&self, request: &[u8]) -> Result<Vec<u8>> {
+        let mut body = &request[1..];
+
+        match request[0] {
+            SSH_AGENTC_REQUEST_IDENTITIES => self.identities_answer().await,
+            SSH_AGENTC_SIGN_REQUEST => {
+                let key_blob = Vec::<u8>::decode(&mut body)?;
+                let data = Vec::<u8>::decode(&mut body)?;
+                let flags = u32::decode(&mut body)?;
+                self.sign_request(&key_blob, &data, flags).await
+            }
+            SSH_AGENTC_REMOVE_IDENTITY => {
+                let key_blob = Vec::<u8>::decode(&mut body)?;
+                let mut identities = self.identities.lock().await;
+                let count = identities.len();
+                identities.retain(|i| !i.matches(&key_blob));
+                Ok(status(identities.len() != count))
+            }
+            SSH_AGENTC_REMOVE_ALL_IDENTITIES => {
+                self.identities.lock().await.clear();
+                Ok(status(true))
+            }
+            SSH_AGENTC_LOCK => {
+                let passphrase = Vec::<u8>::decode(&mut body)?;
+                let mut lock_state = self.lock_state.lock().await;
+                if lock_state.passphrase.is_some() {
+                    return Ok(status(false));
+                }
+                lock_state.passphrase = Some(passphrase);
+                Ok(status(true))
+            }
+            SSH_AGENTC_UNLOCK => {
+                let passphrase = Vec::<u8>::decode(&mut body)?;
+                let mut lock_state = self.lock_state.lock().await;
+                if lock_state.passphrase.as_ref() != Some(&passphrase) {
+                    return Ok(status(false));
+                }
+                // A locked vault keeps the agent locked regardless
+                lock_state.passphrase = None;
+                Ok(status(true))
+            }
+            // Keys are managed from the keychain, so ssh-add cannot load new ones
+            _ => Ok(status(false)),
+        }
+    }
+
+    async fn identities_answer(&self) -> Result<Vec<u8>> {
+        let mut response = vec![SSH_AGENT_IDENTITIES_ANSWER];
+
+        if self.is_locked().await {
+            0u32.encode(&mut response)?;
+            return Ok(response);
+        }
+
+        let mut identities = self.identities.lock().await;
+        identities.retain(|i| !i.is_expired());
+
+        let count = identities.len() + identities.iter().filter(|i| i.cert_blob.is_some()).count();
+        (count as u32).encode(&mut response)?;
+        for identity in identities.iter() {
+            // Certificates go first so clients try them before the plain key
+            if let Some(cert_blob) = &identity.cert_blob {
+                cert_blob.encode(&mut response)?;
+                identity.name.encode(&mut response)?;
+            }
+            identity.key_blob.encode(&mut response)?;
+            identity.name.encode(&mut response)?;
+        }
+
+        Ok(response)
+    }
+
+    async fn sign_request(&self, key_blob: &[u8], data: &[u8], flags: u32) -> Result<Vec<u8>> {
+        if self.is_locked().await {
+            return Ok(status(false));
+        }
+
+        let (key_id, confirm) = {
+            let mut identities = self.identities.lock().await;
+            identities.retain(|i| !i.is_expired());
+            match identities.iter().find(|i| i.matches(key_blob)) {
+                Some(identity) => (identity.key_id.clone(), identity.confirm),
+                None => return Ok(status(false)),
+            }
+        };
+
+        if confirm && !self.request_confirmation(&key_id).await {
+            return Ok(status(false));
+        }
+
+        let private_key = self.storage.load_private_key(&key_id).await?;
+        let signature = sign(&private_key, data, flags)?;
+
+        let mut response = vec![SSH_AGENT_SIGN_RESPONSE];
+        encode_to_vec(&signature)?.encode(&mut response)?;
+        Ok(response)
+    }
+
+    async fn request_confirmation(&self, key_id: &str) -> bool {
+        let details = {
+            let identities = self.identities.lock().await;
+            match identities.iter().find(|i| i.key_id == key_id) {
+                Some(identity) => serde_json::json!({
+                    "keyId": key_id,
+                    "keyName": identity.name,
+                    "fingerprint": identity.public_key.fingerprint(HashAlg::Sha256).to_string(),
+                }),
+                None => return false,
+            }
+        };
+
+        self.prompt(details).await
+    }
+
+    async fn confirm_forwarded(&self, key_blob: &[u8], remote_host: &str) -> bool {
+        let fingerprint = match KeyData::decode(&mut &key_blob[..]) {
+            Ok(key_data) => key_data.fingerprint(HashAlg::Sha256).to_string(),
+            Err(_) => match Certificate::from_bytes(key_blob) {
+                Ok(cert) => cert.public_key().fingerprint(HashAlg::Sha256).to_string(),
+                Err(_) => return false,
+            },
+        };
+
+        let (key_id, key_name) = {
+            let identities = self.identities.lock().await;
+            match identities.iter().find(|i| i.matches(key_blob)) {
+                Some(identity) => (Some(identity.key_id.clone()), Some(identity.name.clone())),
+                None => (None, None),
+            }
+        };
+
+        self.prompt(serde_json::json!({
+            "keyId": key_id,
+            "keyName": key_name,
+            "fingerprint": fingerprint,
+            "remoteHost": remote_host,
+        }))
+        .await
+    }
+
+    async fn prompt(&self, mut details: serde_json::Value) -> bool {
+        let request_id = Uuid::new_v4().to_string();
+        let (tx, rx) = oneshot::channel();
+        self.pending_confirmations
+            .lock()
+            .await
+            .insert(request_id.clone(), tx);
+
+        details["requestId"] = request_id.clone().into();
+        self.window_handler
+            .send_to_all_windows(AGENT_CONFIRM_REQUEST, details);
+
+        // An unanswered prompt counts as a refusal
+        let approved = matches!(tokio::time::timeout(CONFIRM_TIMEOUT, rx).await, Ok(Ok(true)));
+        self.pending_confirmations.lock().await.remove(&request_id);
+        approved
+    }
+
+    pub async fn resolve_confirmation(&self, request_id: &str, approved: bool) -> Result<()> {
+        let sender = self
+            .pending_confirmations
+            .lock()
+            .await
+            .remove(request_id)
+            .context("Confirmation request not found")?;
+        let _ = sender.send(approved);
+        Ok(())
+    }
+
+    /// Loads every keychain key with its stored agent settings.
+    pub async fn load_keychain(&self) -> Result<()> {
+        for key in self.storage.get_all_ssh_keys().await? {
+            if let Err(e) = self.add_identity(&key, key.agent_lifetime, key.agent_confirm).await {
+                log::error!("Failed to add SSH key {} to agent: {}", key.name, e);
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn add_key(
+        &self,
+        key_id: &str,
+        lifetime: Option<u64>,
+        confirm: Option<bool>,
+    ) -> Result<()> {
+        let key = self
+            .storage
+            .get_ssh_key(key_id)
+            .await?
+            .context("SSH key not found")?;
+
+        self.add_identity(
+            &key,
+            lifetime.or(key.agent_lifetime),
+            confirm.or(key.agent_confirm),
+        )
+        .await
+    }
+
+    async fn add_identity(
+        &self,
+        key: &SSHKey,
+        lifetime: Option<u64>,
+        confirm: Option<bool>,
+    ) -> Result<()> {
+        let public_key = PublicKey::read_openssh_file(Path::new(&key.public_key_path))?;
+        let key_blob = encode_to_vec(public_key.key_data())?;
+
+        // Expired certificates are left out so clients fall back to the key
+        let cert_blob = match &key.certificate_path {
+            Some(path) => {
+                let cert = read_certificate(Path::new(path))?;
+                if is_currently_valid(&cert) {
+                    Some(cert.to_bytes()?)
+                } else {
+                    None
+                }
+            }
+            None => None,
+        };
+
+        let mut identities = self.identities.lock().await;
+        identities.retain(|i| i.key_id != key.id);
+        identities.push(AgentIdentity {
+            key_id: key.id.clone(),
+            name: key.name.clone(),
+            public_key,
+            key_blob,
+            cert_blob,
+            confirm: confirm.unwrap_or(false),
+            expires_at: lifetime.map(|secs| Instant::now() + Duration::from_secs(secs)),
+        });
+
+        Ok(())
+    }
+
+    pub async fn remove_key(&self, key_id: &str) {
+        self.identities
+            .lock()
+            .await
+            .retain(|i| i.key_id != key_id);
+    }
+
+    pub async fn list_keys(&self) -> Vec<AgentKeyInfo> {
+        let mut identities = self.identities.lock().await;
+        identities.retain(|i| !i.is_expired());
+
+        identities
+            .iter()
+            .map(|i| AgentKeyInfo {
+                key_id: i.key_id.clone(),
+                name: i.name.clone(),
+                fingerprint: i.public_key.fingerprint(HashAlg::Sha256).to_string(),
+                confirm: i.confirm,
+                expires_in: i
+                    .expires_at
+                    .map(|t| t.saturating_duration_since(Instant::now()).as_secs()),
+            })
+            .collect()
+    }
+
+    pub async fn is_locked(&self) -> bool {
+        self.lock_state.lock().await.is_locked()
+    }
+
+    /// Locks the agent along with the vault. Client passphrases cannot undo this.
+    pub async fn lock(&self) {
+        self.lock_state.lock().await.vault = true;
+
+        // Refuse any prompt that is still waiting for the user
+        for (_, sender) in self.pending_confirmations.lock().await.drain() {
+            let _ = sender.send(false);
+        }
+
+        self.window_handler
+            .send_to_all_windows(AGENT_LOCKED, serde_json::json!({}));
+    }
+
+    /// Lifts the vault lock. A passphrase lock set by a client stays until
+    /// that client unlocks it.
+    pub async fn unlock(&self) {
+        self.lock_state.lock().await.vault = false;
+        self.window_handler
+            .send_to_all_windows(AGENT_UNLOCKED, serde_json::json!({}));
+    }
+
+    /// Follows the vault's lock state until the storage goes away.
+    pub async fn watch_vault(self: Arc<Self>, mut vault_locked: watch::Receiver<bool>) {
+        loop {
+            let locked = *vault_locked.borrow_and_update();
+            if locked != self.lock_state.lock().await.vault {
+                if locked {
+                    self.lock().await;
+                } else {
+                    self.unlock().await;
+                }
+            }
+
+            if vault_locked.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Creates `path` readable by the current user only, before anything is
+/// put in it.
+fn create_private_dir(path: &Path) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(path)?;
+        // A directory left by an older version may still be open to others
+        fs::set_permissions(path, fs::Permissions::from_mode(0o700))?;
+    }
+    #[cfg(not(unix))]
+    fs::create_dir_all(path)?;
+    Ok(())
+}
+
+async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<Vec<u8>>> {
+    let len = match stream.read_u32().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    if len == 0 || len > MAX_MESSAGE_LEN {
+        return Err(anyhow!("Invalid agent message length: {}", len));
+    }
+
+    let mut frame = vec![0u8; len];
+    stream.read_exact(&mut frame).await?;
+    Ok(Some(frame))
+}
+
+async fn write_frame<S: AsyncWrite + Unpin>(stream: &mut S, frame: &[u8]) -> Result<()> {
+    stream.write_u32(frame.len() as u32).await?;
+    stream.write_all(frame).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+fn status(success: bool) -> Vec<u8> {
+    vec![if success { SSH_AGENT_SUCCESS } else { SSH_AGENT_FAILURE }]
+}
+
+fn encode_to_vec<T: Encode + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    value.encode(&mut buf)?;
+    Ok(buf)
+}
+
+fn sign(private_key: &PrivateKey, data: &[u8], flags: u32) -> Result<Signature> {
+    // ssh-key signs RSA with SHA-512, so SHA-256 requests need rsa directly
+    if let KeypairData::Rsa(keypair) = private_key.key_data() {
+        if flags & SSH_AGENT_RSA_SHA2_256 != 0 && flags & SSH_AGENT_RSA_SHA2_512 == 0 {
+            let signing_key = RsaSigningKey::<Sha256>::try_from(keypair)?;
+            let signature = signing_key.try_sign(data)?;
+            return Ok(Signature::new(
+                Algorithm::Rsa {
+                    hash: Some(HashAlg::Sha256),
+                },
+                signature.to_vec(),
+            )?);
+        }
+    }
+
+    Ok(private_key.try_sign(data)?)
+}
+
+#[command]
+pub fn agent_socket_path(agent: State<'_, Arc<SshAgent>>) -> String {
+    agent.socket_path().to_string_lossy().into_owned()
+}
+
+#[command]
+pub async fn agent_list_keys(agent: State<'_, Arc<SshAgent>>) -> Result<Vec<AgentKeyInfo>, String> {
+    Ok(agent.list_keys().await)
+}
+
+#[command]
+pub async fn agent_add_key(
+    agent: State<'_, Arc<SshAgent>>,
+    key_id: String,
+    lifetime: Option<u64>,
+    confirm: Option<bool>,
+) -> Result<(), String> {
+    agent
+        .add_key(&key_id, lifetime, confirm)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn agent_remove_key(agent: State<'_, Arc<SshAgent>>, key_id: String) -> Result<(), String> {
+    agent.remove_key(&key_id).await;
+    Ok(())
+}
+
+#[command]
+pub async fn agent_confirm(
+    agent: State<'_, Arc<SshAgent>>,
+    request_id: String,
+    approved: bool,
+) -> Result<(), String> {
+    agent
+        .resolve_confirmation(&request_id, approved)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::services::secure_storage::temporary_storage;
+    use russh::keys::{agent::client::AgentClient, signature::Verifier};
+    use ssh_key::LineEnding;
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::net::UnixStream;
+
+    const KEY_ID: &str = "test-key";
+    const PASSPHRASE: &[u8] = b"correct horse";
+    const VAULT_PASSPHRASE: &str = "battery staple";
+
+    struct NoWindows;
+
+    impl WindowHandler for NoWindows {
+        fn send_to_all_windows(&self, _event: &str, _payload: serde_json::Value) {}
+        fn send_to_window(&self, _label: &str, _event: &str, _payload: serde_json::Value) {}
+    }
+
+    /// An agent on its own socket, serving one Ed25519 key from a vault kept
+    /// under the temp directory rather than the user's config.
+    struct TestAgent {
+        agent: Arc<SshAgent>,
+        storage: Arc<SecureStorage>,
+        key: PrivateKey,
+        dir: PathBuf,
+    }
+
+    impl TestAgent {
+        async fn start() -> Self {
+            let dir = std::env::temp_dir().join(format!("teemux-agent-{}", Uuid::new_v4()));
+            create_private_dir(&dir).unwrap();
+            let storage = Arc::new(temporary_storage().await);
+
+            let key = PrivateKey::random(&mut rand::rngs::OsRng, Algorithm::Ed25519).unwrap();
+            let private_key_path = dir.join("id_ed25519");
+            let public_key_path = dir.join("id_ed25519.pub");
+            key.write_openssh_file(&private_key_path, LineEnding::LF).unwrap();
+            key.public_key().write_openssh_file(&public_key_path).unwrap();
+            storage
+                .save_ssh_key(SSHKey {
+                    id: KEY_ID.to_string(),
+                    name: "test".to_string(),
+                    key_type: crate::models::ssh_key::SSHKeyType::Ed25519,
+                    bits: None,
+                    private_key_path: private_key_path.to_string_lossy().to_string(),
+                    public_key_path: public_key_path.to_string_lossy().to_string(),
+                    certificate_path: None,
+                    passphrase: None,
+                    host_ids: Vec::new(),
+                    agent_confirm: None,
+                    agent_lifetime: None,
+                    is_ca: None,
+                    created_at: 0,
+                    updated_at: 0,
+                })
+                .await
+                .unwrap();
+
+            let agent = Arc::new(SshAgent::with_socket_path(
+                Arc::clone(&storage),
+                Arc::new(NoWindows),
+                dir.join("agent.sock"),
+            ));
+            agent.add_key(KEY_ID, None, None).await.unwrap();
+            tokio::spawn(Arc::clone(&agent).serve());
+            tokio::spawn(Arc::clone(&agent).watch_vault(storage.subscribe_lock()));
+
+            Self {
+                agent,
+                storage,
+                key,
+                dir,
+            }
+        }
+
+        async fn client(&self) -> AgentClient<UnixStream> {
+            for _ in 0..100 {
+                if let Ok(client) = AgentClient::connect_uds(self.agent.socket_path()).await {
+                    return client;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("Agent socket never came up");
+        }
+
+        async fn wait_until_locked(&self, locked: bool) {
+            for _ in 0..100 {
+                if self.agent.is_locked().await == locked {
+                    return;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            panic!("Agent never became {}", if locked { "locked" } else { "unlocked" });
+        }
+    }
+
+    impl Drop for TestAgent {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    impl TestAgent {
+        /// The served key the way a client sees it.
+        fn public_key(&self) -> russh::keys::PublicKey {
+            russh::keys::PublicKey::from_bytes(&self.key.public_key().to_bytes().unwrap()).unwrap()
+        }
+
+        /// Whether the agent signs `data` for the client, checking the
+        /// signature when it does.
+        async fn signs(&self, mut client: AgentClient<UnixStream>) -> (AgentClient<UnixStream>, bool) {
+            let public_key = self.public_key();
+            let signed = match client.sign_request_signature(&public_key, None, b"data").await {
+                Ok(signature) => {
+                    assert!(Verifier::verify(public_key.key_data(), b"data", &signature).is_ok());
+                    true
+                }
+                Err(_) => false,
+            };
+            (client, signed)
+        }
+    }
+
+    #[tokio::test]
+    async fn lists_keychain_keys() {
+        let test = TestAgent::start().await;
+        let mut client = test.client().await;
+
+        let identities = client.request_identities().await.unwrap();
+        assert_eq!(identities.len(), 1);
+        assert_eq!(identities[0].public_key().to_bytes().unwrap(), test.key.public_key().to_bytes().unwrap());
+    }
+
+    #[tokio::test]
+    async fn signs_with_keychain_keys() {
+        let test = TestAgent::start().await;
+        let client = test.client().await;
+
+        let (_, signed) = test.signs(client).await;
+        assert!(signed);
+    }
+
+    #[tokio::test]
+    async fn client_lock_needs_the_same_passphrase() {
+        let test = TestAgent::start().await;
+        let mut client = test.client().await;
+
+        client.lock(PASSPHRASE).await.unwrap();
+        assert!(client.request_identities().await.unwrap().is_empty());
+        let (mut client, signed) = test.signs(client).await;
+        assert!(!signed);
+
+        client.unlock(b"wrong").await.unwrap();
+        assert!(test.agent.is_locked().await);
+        client.unlock(PASSPHRASE).await.unwrap();
+        assert!(!test.agent.is_locked().await);
+        assert_eq!(client.request_identities().await.unwrap().len(), 1);
+        let (_, signed) = test.signs(client).await;
+        assert!(signed);
+    }
+
+    #[tokio::test]
+    async fn locks_with_the_vault() {
+        let test = TestAgent::start().await;
+        let mut client = test.client().await;
+
+        test.storage.lock(VAULT_PASSPHRASE).unwrap();
+        test.wait_until_locked(true).await;
+        assert!(client.request_identities().await.unwrap().is_empty());
+        let (mut client, signed) = test.signs(client).await;
+        assert!(!signed);
+
+        // A client cannot lift a vault lock
+        client.lock(PASSPHRASE).await.unwrap();
+        client.unlock(PASSPHRASE).await.unwrap();
+        assert!(test.agent.is_locked().await);
+
+        test.storage.unlock(VAULT_PASSPHRASE).unwrap();
+        test.wait_until_locked(false).await;
+        let (_, signed) = test.signs(client).await;
+        assert!(signed);
+    }
+
+    #[tokio::test]
+    async fn vault_unlock_keeps_client_lock() {
+        let test = TestAgent::start().await;
+        let mut client = test.client().await;
+
+        client.lock(PASSPHRASE).await.unwrap();
+        test.storage.lock(VAULT_PASSPHRASE).unwrap();
+        test.wait_until_locked(true).await;
+        test.storage.unlock(VAULT_PASSPHRASE).unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(test.agent.is_locked().await);
+        assert!(client.request_identities().await.unwrap().is_empty());
+
+        client.unlock(PASSPHRASE).await.unwrap();
+        assert_eq!(client.request_identities().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn socket_is_private() {
+        let test = TestAgent::start().await;
+        let _client = test.client().await;
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&test.dir), 0o700);
+        assert_eq!(mode(test.agent.socket_path()), 0o600);
+    }
+}
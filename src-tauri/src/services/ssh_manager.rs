@@ -0,0 +1,696 @@
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use russh::{
+    client::{self, ChannelOpenHandle, DisconnectReason, Handle, Msg},
+    keys::{ssh_encoding::Encode, PrivateKeyWithHashAlg, PublicKeyOrCertificate},
+    Channel, ChannelId, Disconnect, Preferred,
+};
+use ssh_encoding::Decode;
+use ssh_key::{public::KeyData, Certificate, HashAlg};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+use tauri::{command, State};
+use tokio::{
+    net::TcpStream,
+    sync::{broadcast, mpsc, oneshot, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+use uuid::Uuid;
+
+use crate::models::{
+    host::{AgentForwarding, AuthType, Host},
+    ipc_channels::{SSH_CERTIFICATE_EXPIRING, SSH_HOST_KEY_PROMPT, SSH_HOST_KEY_REJECTED},
+    session::{Session, SessionStatus, SessionType},
+    ssh_key::SSHKey,
+};
+use crate::services::{
+    certificates::{expiry_warning, is_currently_valid, read_certificate, EXPIRY_WARNING},
+    known_hosts::{HostKeyStatus, KnownHostsVerifier},
+    secure_storage::SecureStorage,
+    ssh_agent::SshAgent,
+    terminal_manager::WindowHandler,
+};
+
+const DEFAULT_CONNECTION_TIMEOUT: u32 = 30;
+// How long an unknown host key waits for the user before it is refused
+const HOST_KEY_PROMPT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// What the server listens on for a remote forward.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ForwardKey {
+    // The address and port the server bound for a tcpip-forward
+    Tcp(String, u32),
+    // The socket path of a streamlocal-forward
+    Socket(String),
+}
+
+/// Remote forwards registered on a session. Incoming `forwarded-tcpip` and
+/// `forwarded-streamlocal` channels are handed to the matching one.
+pub type ForwardedRoutes =
+    Arc<std::sync::Mutex<HashMap<ForwardKey, mpsc::UnboundedSender<Channel<Msg>>>>>;
+
+/// Unknown host keys shown to the user, waiting for an answer.
+type HostKeyPrompts = Arc<std::sync::Mutex<HashMap<String, oneshot::Sender<bool>>>>;
+
+pub struct ClientHandler {
+    hostname: String,
+    port: u16,
+    host_label: String,
+    agent_forwarding: Option<AgentForwarding>,
+    confirm_forwarded: bool,
+    agent: Arc<SshAgent>,
+    window_handler: Arc<dyn WindowHandler>,
+    forwarded_routes: ForwardedRoutes,
+    host_key_prompts: HostKeyPrompts,
+    // Fired when the connection ends, however it ends
+    closed: Option<oneshot::Sender<()>>,
+    server_id: Arc<OnceLock<String>>,
+}
+
+impl client::Handler for ClientHandler {
+    type Error = anyhow::Error;
+
+    async fn check_server_key(
+        &mut self,
+        server_public_key: &PublicKeyOrCertificate,
+    ) -> Result<bool, Self::Error> {
+        let host_key = match server_public_key {
+            PublicKeyOrCertificate::PublicKey { key, .. } => key.key_data().encode_vec()?,
+            PublicKeyOrCertificate::Certificate(cert) => cert.encode_vec()?,
+        };
+        let known_hosts = KnownHostsVerifier::new();
+        let status = known_hosts.verify(&self.hostname, self.port, &host_key)?;
+
+        match status {
+            HostKeyStatus::Trusted | HostKeyStatus::CertificateTrusted => Ok(true),
+            // Written down only once the user accepted it. Certificates are
+            // never unknown, only trusted or invalid
+            HostKeyStatus::Unknown => {
+                let key_type = server_public_key.public_key().algorithm();
+                if !self.confirm_host_key(key_type.as_str(), &host_key).await {
+                    return Ok(false);
+                }
+                known_hosts.add(&self.hostname, self.port, &host_key)?;
+                Ok(true)
+            }
+            HostKeyStatus::Mismatch
+            | HostKeyStatus::Revoked
+            | HostKeyStatus::InvalidCertificate(_) => {
+                self.window_handler.send_to_all_windows(
+                    SSH_HOST_KEY_REJECTED,
+                    serde_json::json!({
+                        "hostname": self.hostname,
+                        "port": self.port,
+                        "reason": format!("{:?}", status),
+                    }),
+                );
+                Ok(false)
+            }
+        }
+    }
+
+    async fn disconnected(
+        &mut self,
+        reason: DisconnectReason<Self::Error>,
+    ) -> Result<(), Self::Error> {
+        if let Some(closed) = self.closed.take() {
+            let _ = closed.send(());
+        }
+        match reason {
+            DisconnectReason::ReceivedDisconnect(_) => Ok(()),
+            DisconnectReason::Error(e) => Err(e),
+        }
+    }
+
+    async fn channel_open_confirmation(
+        &mut self,
+        _id: ChannelId,
+        _max_packet_size: u32,
+        _window_size: u32,
+        session: &mut client::Session,
+    ) -> Result<(), Self::Error> {
+        self.server_id
+            .get_or_init(|| String::from_utf8_lossy(session.remote_sshid()).to_string());
+        Ok(())
+    }
+
+    async fn server_channel_open_agent_forward(
+        &mut self,
+        channel: Channel<Msg>,
+        reply: ChannelOpenHandle,
+        _session: &mut client::Session,
+    ) -> Result<(), Self::Error> {
+        // Dropping the reply refuses the channel, which is what happens when
+        // the server asks for an agent we never offered
+        let upstream = match self.agent_forwarding {
+            Some(AgentForwarding::Builtin) => self.agent.socket_path().to_path_buf(),
+            Some(AgentForwarding::System) => match std::env::var_os("SSH_AUTH_SOCK") {
+                Some(path) => PathBuf::from(path),
+                None => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+
+        let agent = Arc::clone(&self.agent);
+        let remote_host = self.host_label.clone();
+        let confirm = self.confirm_forwarded;
+
+        reply.accept().await;
+        tokio::spawn(async move {
+            if let Err(e) = agent
+                .proxy_forwarded(channel.into_stream(), &upstream, &remote_host, confirm)
+                .await
+            {
+                log::error!("Agent forwarding for {} failed: {}", remote_host, e);
+            }
+        });
+
+        Ok(())
+    }
+
+    async fn server_channel_open_forwarded_tcpip(
+        &mut self,
+        channel: Channel<Msg>,
+        connected_address: &str,
+        connected_port: u32,
+        _originator_address: &str,
+        _originator_port: u32,
+        reply: ChannelOpenHandle,
+        _session: &mut client::Session,
+    ) -> Result<(), Self::Error> {
+        self.route_forwarded(
+            ForwardKey::Tcp(connected_address.to_string(), connected_port),
+            channel,
+            reply,
+        )
+        .await;
+        Ok(())
+    }
+
+    async fn server_channel_open_forwarded_streamlocal(
+        &mut self,
+        channel: Channel<Msg>,
+        socket_path: &str,
+        reply: ChannelOpenHandle,
+        _session: &mut client::Session,
+    ) -> Result<(), Self::Error> {
+        self.route_forwarded(ForwardKey::Socket(socket_path.to_string()), channel, reply)
+            .await;
+        Ok(())
+    }
+}
+
+impl ClientHandler {
+    /// Shows an unknown host key to the user and waits for their answer.
+    /// Anything but an explicit accept, including no answer, refuses it.
+    async fn confirm_host_key(&self, key_type: &str, host_key: &[u8]) -> bool {
+        let Ok(key_data) = KeyData::decode(&mut &host_key[..]) else {
+            return false;
+        };
+
+        let request_id = Uuid::new_v4().to_string();
+        let (tx, rx) = oneshot::channel();
+        self.host_key_prompts
+            .lock()
+            .unwrap()
+            .insert(request_id.clone(), tx);
+
+        self.window_handler.send_to_all_windows(
+            SSH_HOST_KEY_PROMPT,
+            serde_json::json!({
+                "requestId": request_id,
+                "hostname": self.hostname,
+                "port": self.port,
+                "keyType": key_type,
+                "fingerprint": key_data.fingerprint(HashAlg::Sha256).to_string(),
+            }),
+        );
+
+        let accepted = matches!(
+            tokio::time::timeout(HOST_KEY_PROMPT_TIMEOUT, rx).await,
+            Ok(Ok(true))
+        );
+        self.host_key_prompts.lock().unwrap().remove(&request_id);
+        accepted
+    }
+
+    async fn route_forwarded(
+        &self,
+        key: ForwardKey,
+        channel: Channel<Msg>,
+        reply: ChannelOpenHandle,
+    ) {
+        // Channels for listeners we did not ask for, or whose tunnel has
+        // stopped, are refused by dropping the reply
+        let route = self.forwarded_routes.lock().unwrap().get(&key).cloned();
+        if let Some(route) = route.filter(|route| !route.is_closed()) {
+            reply.accept().await;
+            let _ = route.send(channel);
+        }
+    }
+}
+
+pub struct SshSession {
+    pub session: Session,
+    pub host: Host,
+    // Only tcpip-forward and streamlocal-forward need the handle mutably;
+    // channel opens share it and run concurrently
+    handle: RwLock<Handle<ClientHandler>>,
+    forwarded_routes: ForwardedRoutes,
+    server_id: Arc<OnceLock<String>>,
+}
+
+impl SshSession {
+    pub async fn handle(&self) -> RwLockReadGuard<'_, Handle<ClientHandler>> {
+        self.handle.read().await
+    }
+
+    /// Exclusive access for global requests that wait on a reply.
+    pub async fn handle_mut(&self) -> RwLockWriteGuard<'_, Handle<ClientHandler>> {
+        self.handle.write().await
+    }
+
+    pub fn forwarded_routes(&self) -> &ForwardedRoutes {
+        &self.forwarded_routes
+    }
+
+    /// The server's version banner, e.g. `SSH-2.0-OpenSSH_9.6`. Known once
+    /// a channel has been opened on the session.
+    pub fn server_id(&self) -> Option<&str> {
+        self.server_id.get().map(String::as_str)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum SessionEvent {
+    Connected { session_id: String, host_id: String },
+    Disconnected { session_id: String, host_id: String },
+}
+
+pub struct SshManager {
+    storage: Arc<SecureStorage>,
+    agent: Arc<SshAgent>,
+    window_handler: Arc<dyn WindowHandler>,
+    sessions: Arc<Mutex<HashMap<String, Arc<SshSession>>>>,
+    events: broadcast::Sender<SessionEvent>,
+    host_key_prompts: HostKeyPrompts,
+}
+
+impl SshManager {
+    pub fn new(
+        storage: Arc<SecureStorage>,
+        agent: Arc<SshAgent>,
+        window_handler: Arc<dyn WindowHandler>,
+    ) -> Self {
+        Self {
+            storage,
+            agent,
+            window_handler,
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            events: broadcast::channel(64).0,
+            host_key_prompts: HostKeyPrompts::default(),
+        }
+    }
+
+    /// Lets other services follow sessions as they connect and disconnect.
+    pub fn subscribe(&self) -> broadcast::Receiver<SessionEvent> {
+        self.events.subscribe()
+    }
+
+    pub async fn connect(&self, host_id: &str) -> Result<Session> {
+        let host = self
+            .storage
+            .get_host(host_id)
+            .await?
+            .context("Host not found")?;
+
+        // Servers present a host certificate only when asked for one, so it
+        // is asked for only where an @cert-authority line can check it
+        let mut preferred = Preferred::default();
+        if KnownHostsVerifier::new().has_authority(&host.hostname, host.port.unwrap_or(22)) {
+            preferred.host_key_certificates = preferred.key.clone();
+        }
+
+        let config = Arc::new(client::Config {
+            keepalive_interval: host
+                .keep_alive_interval
+                .map(|secs| Duration::from_secs(secs.into())),
+            preferred,
+            ..Default::default()
+        });
+
+        let forwarded_routes = ForwardedRoutes::default();
+        let server_id = Arc::new(OnceLock::new());
+        let (closed, on_closed) = oneshot::channel();
+        let handler = ClientHandler {
+            hostname: host.hostname.clone(),
+            port: host.port.unwrap_or(22),
+            host_label: host.label.clone(),
+            agent_forwarding: agent_forwarding(&host),
+            confirm_forwarded: host.forward_agent_confirm.unwrap_or(false),
+            agent: Arc::clone(&self.agent),
+            window_handler: Arc::clone(&self.window_handler),
+            forwarded_routes: Arc::clone(&forwarded_routes),
+            host_key_prompts: Arc::clone(&self.host_key_prompts),
+            closed: Some(closed),
+            server_id: Arc::clone(&server_id),
+        };
+
+        let timeout = Duration::from_secs(
+            host.connection_timeout
+                .unwrap_or(DEFAULT_CONNECTION_TIMEOUT)
+                .into(),
+        );
+        let address = (host.hostname.as_str(), host.port.unwrap_or(22));
+        let stream = tokio::time::timeout(timeout, TcpStream::connect(address))
+            .await
+            .context("Connection timed out")??;
+        // The handshake may wait on the user to accept an unknown host key
+        let mut handle = tokio::time::timeout(
+            timeout + HOST_KEY_PROMPT_TIMEOUT,
+            client::connect_stream(config, stream, handler),
+        )
+        .await
+        .context("Connection timed out")??;
+
+        self.authenticate(&mut handle, &host).await?;
+
+        let now = Utc::now().to_rfc3339();
+        let session = Session {
+            id: Uuid::new_v4().to_string(),
+            host_id: host.id.clone(),
+            terminal_id: String::new(),
+            start_time: now.clone(),
+            end_time: None,
+            status: SessionStatus::Connected,
+            error: None,
+            last_activity: Some(now),
+            session_type: SessionType::SSH,
+            sftp_enabled: None,
+            port_forwardings: None,
+        };
+
+        self.sessions.lock().await.insert(
+            session.id.clone(),
+            Arc::new(SshSession {
+                session: session.clone(),
+                host,
+                handle: RwLock::new(handle),
+                forwarded_routes,
+                server_id,
+            }),
+        );
+
+        let _ = self.events.send(SessionEvent::Connected {
+            session_id: session.id.clone(),
+            host_id: session.host_id.clone(),
+        });
+
+        // Connections that drop on their own are cleaned up like a disconnect.
+        // The sender also goes away if the handler is dropped without firing.
+        let sessions = Arc::clone(&self.sessions);
+        let events = self.events.clone();
+        let session_id = session.id.clone();
+        tokio::spawn(async move {
+            let _ = on_closed.await;
+            if let Some(session) = sessions.lock().await.remove(&session_id) {
+                let _ = events.send(SessionEvent::Disconnected {
+                    session_id,
+                    host_id: session.session.host_id.clone(),
+                });
+            }
+        });
+
+        Ok(session)
+    }
+
+    async fn authenticate(&self, handle: &mut Handle<ClientHandler>, host: &Host) -> Result<()> {
+        let username = host
+            .username
+            .clone()
+            .or_else(|| std::env::var("USER").ok())
+            .context("No username configured for host")?;
+
+        let authenticated = match host.auth_type {
+            AuthType::Password => {
+                let password = host
+                    .password
+                    .as_deref()
+                    .context("No password stored for host")?;
+                handle.authenticate_password(&username, password).await?.success()
+            }
+            AuthType::Key => {
+                let linked_key = self.linked_key(host).await?;
+                let passphrase = host
+                    .private_key_passphrase
+                    .as_deref()
+                    .or(host.passphrase.as_deref())
+                    .or(linked_key.as_ref().and_then(|k| k.passphrase.as_deref()));
+                let key_pair = match (&host.private_key, &host.private_key_path, &linked_key) {
+                    (Some(pem), _, _) => russh::keys::decode_secret_key(pem, passphrase)?,
+                    (None, Some(path), _) => russh::keys::load_secret_key(path, passphrase)?,
+                    (None, None, Some(key)) => {
+                        russh::keys::load_secret_key(&key.private_key_path, passphrase)?
+                    }
+                    (None, None, None) => bail!("No private key configured for host"),
+                };
+                let key_pair = Arc::new(key_pair);
+
+                // Inline keys have no keychain entry, so no certificate either
+                let certificate = match (&host.private_key, &linked_key) {
+                    (None, Some(key)) => match &key.certificate_path {
+                        Some(path) => self.usable_certificate(host, key, Path::new(path))?,
+                        None => None,
+                    },
+                    _ => None,
+                };
+
+                // Servers that do not trust the CA may still accept the plain key
+                let mut authenticated = false;
+                if let Some(certificate) = certificate {
+                    let certificate =
+                        russh::keys::Certificate::from_openssh(&certificate.to_openssh()?)?;
+                    authenticated = handle
+                        .authenticate_openssh_cert(&username, Arc::clone(&key_pair), certificate)
+                        .await?
+                        .success();
+                }
+                if !authenticated {
+                    let hash_alg = handle.best_supported_rsa_hash().await?.flatten();
+                    authenticated = handle
+                        .authenticate_publickey(
+                            &username,
+                            PrivateKeyWithHashAlg::new(key_pair, hash_alg),
+                        )
+                        .await?
+                        .success();
+                }
+                authenticated
+            }
+            AuthType::Agent => self.authenticate_with_agent(handle, &username).await?,
+        };
+
+        if !authenticated {
+            bail!("Authentication failed for {}@{}", username, host.hostname);
+        }
+
+        Ok(())
+    }
+
+    /// Keychain key used by a host, matched by key path or by the key's host list.
+    async fn linked_key(&self, host: &Host) -> Result<Option<SSHKey>> {
+        let keys = self.storage.get_all_ssh_keys().await?;
+        let key_id = match &host.private_key_path {
+            Some(path) => keys.iter().find(|k| &k.private_key_path == path),
+            None => keys.iter().find(|k| k.host_ids.contains(&host.id)),
+        }
+        .map(|k| k.id.clone());
+
+        match key_id {
+            Some(key_id) => self.storage.get_ssh_key(&key_id).await,
+            None => Ok(None),
+        }
+    }
+
+    /// Loads a user certificate, warning the UI when it is close to expiry.
+    /// Returns `None` once the certificate is outside its validity window.
+    fn usable_certificate(
+        &self,
+        host: &Host,
+        key: &SSHKey,
+        path: &Path,
+    ) -> Result<Option<Certificate>> {
+        let certificate = read_certificate(path)?;
+
+        if let Some(remaining) = expiry_warning(&certificate, EXPIRY_WARNING) {
+            self.window_handler.send_to_all_windows(
+                SSH_CERTIFICATE_EXPIRING,
+                serde_json::json!({
+                    "hostId": host.id,
+                    "keyId": key.id,
+                    "keyName": key.name,
+                    "expiresIn": remaining.as_secs(),
+                    "expired": remaining.is_zero(),
+                }),
+            );
+        }
+
+        Ok(is_currently_valid(&certificate).then_some(certificate))
+    }
+
+    #[cfg(unix)]
+    async fn authenticate_with_agent(
+        &self,
+        handle: &mut Handle<ClientHandler>,
+        username: &str,
+    ) -> Result<bool> {
+        use russh::keys::agent::{client::AgentClient, AgentIdentity};
+
+        let mut agent = AgentClient::connect_uds(self.agent.socket_path()).await?;
+        let hash_alg = handle.best_supported_rsa_hash().await?.flatten();
+        for identity in agent.request_identities().await? {
+            let result = match identity {
+                AgentIdentity::PublicKey { key, .. } => {
+                    handle
+                        .authenticate_publickey_with(username, key, hash_alg, &mut agent)
+                        .await?
+                }
+                AgentIdentity::Certificate { certificate, .. } => {
+                    handle
+                        .authenticate_certificate_with(username, certificate, hash_alg, &mut agent)
+                        .await?
+                }
+            };
+            if result.success() {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    #[cfg(not(unix))]
+    async fn authenticate_with_agent(
+        &self,
+        _handle: &mut Handle<ClientHandler>,
+        _username: &str,
+    ) -> Result<bool> {
+        bail!("Agent authentication is not supported on this platform")
+    }
+
+    pub fn resolve_host_key(&self, request_id: &str, accepted: bool) -> Result<()> {
+        let sender = self
+            .host_key_prompts
+            .lock()
+            .unwrap()
+            .remove(request_id)
+            .context("Host key prompt not found")?;
+        let _ = sender.send(accepted);
+        Ok(())
+    }
+
+    pub async fn get_session(&self, session_id: &str) -> Result<Arc<SshSession>> {
+        self.sessions
+            .lock()
+            .await
+            .get(session_id)
+            .cloned()
+            .context("Session not found")
+    }
+
+    pub async fn get_all_sessions(&self) -> Vec<Session> {
+        self.sessions
+            .lock()
+            .await
+            .values()
+            .map(|s| s.session.clone())
+            .collect()
+    }
+
+    /// Opens a session channel, asking the server to forward our agent on it
+    /// when the host has agent forwarding enabled.
+    pub async fn open_session_channel(&self, session_id: &str) -> Result<Channel<Msg>> {
+        let session = self.get_session(session_id).await?;
+        let channel = session.handle().await.channel_open_session().await?;
+
+        if agent_forwarding(&session.host).is_some() {
+            channel.agent_forward(false).await?;
+        }
+
+        Ok(channel)
+    }
+
+    pub async fn disconnect(&self, session_id: &str) -> Result<()> {
+        let session = self
+            .sessions
+            .lock()
+            .await
+            .remove(session_id)
+            .context("Session not found")?;
+
+        let _ = self.events.send(SessionEvent::Disconnected {
+            session_id: session.session.id.clone(),
+            host_id: session.session.host_id.clone(),
+        });
+
+        session
+            .handle()
+            .await
+            .disconnect(Disconnect::ByApplication, "", "en")
+            .await?;
+        Ok(())
+    }
+}
+
+/// Resolves the agent forwarding mode, falling back to an OpenSSH style
+/// `ForwardAgent` entry in `ssh_options`.
+fn agent_forwarding(host: &Host) -> Option<AgentForwarding> {
+    if host.forward_agent.is_some() {
+        return host.forward_agent;
+    }
+
+    match host.ssh_options.get("ForwardAgent").map(|v| v.to_lowercase()) {
+        Some(value) if value == "yes" => Some(AgentForwarding::Builtin),
+        _ => None,
+    }
+}
+
+#[command]
+pub async fn ssh_connect(
+    ssh_manager: State<'_, Arc<SshManager>>,
+    host_id: String,
+) -> Result<Session, String> {
+    ssh_manager.connect(&host_id).await.map_err(|e| e.to_string())
+}
+
+#[command]
+pub fn ssh_host_key_answer(
+    ssh_manager: State<'_, Arc<SshManager>>,
+    request_id: String,
+    accepted: bool,
+) -> Result<(), String> {
+    ssh_manager
+        .resolve_host_key(&request_id, accepted)
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn ssh_disconnect(
+    ssh_manager: State<'_, Arc<SshManager>>,
+    session_id: String,
+) -> Result<(), String> {
+    ssh_manager
+        .disconnect(&session_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn ssh_list_sessions(
+    ssh_manager: State<'_, Arc<SshManager>>,
+) -> Result<Vec<Session>, String> {
+    Ok(ssh_manager.get_all_sessions().await)
+}
@@ -0,0 +1,128 @@
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use russh::{client::Msg, Channel, ChannelMsg};
+use std::{
+    io,
+    pin::Pin,
+    task::{Context as TaskContext, Poll},
+};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt, DuplexStream},
+    sync::mpsc,
+};
+
+use crate::services::terminal_manager::{TerminalOutput, TerminalStream};
+
+const TERM: &str = "xterm-256color";
+// Output buffered between the channel and the terminal's reader task
+const OUTPUT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Requests a pseudo-terminal of the given size and the user's login shell
+/// on a session channel.
+///
+/// The channel stays with a task that copies its data to the returned
+/// output and passes window size changes on, since russh needs the channel
+/// itself for `window_change` once its stream is taken apart.
+pub async fn open(
+    mut channel: Channel<Msg>,
+    rows: u16,
+    cols: u16,
+) -> Result<(TerminalOutput, Box<dyn TerminalStream>)> {
+    // Output that arrives before the shell request is confirmed
+    let mut early = Vec::new();
+    channel
+        .request_pty(true, TERM, cols.into(), rows.into(), 0, 0, &[])
+        .await?;
+    confirm(&mut channel, "a pseudo-terminal", &mut early).await?;
+    channel.request_shell(true).await?;
+    confirm(&mut channel, "a shell", &mut early).await?;
+
+    let (output, mut sink) = tokio::io::duplex(OUTPUT_BUFFER_SIZE);
+    let writer = Box::pin(channel.make_writer());
+    let (resizes, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        if sink.write_all(&early).await.is_ok() {
+            pump(channel, sink, receiver).await;
+        }
+    });
+
+    Ok((Box::new(output), Box::new(SshInput { writer, resizes })))
+}
+
+/// Waits for the reply to a channel request.
+async fn confirm(channel: &mut Channel<Msg>, what: &str, early: &mut Vec<u8>) -> Result<()> {
+    loop {
+        match channel.wait().await {
+            Some(ChannelMsg::Success) => return Ok(()),
+            Some(ChannelMsg::Failure) => bail!("Server refused {}", what),
+            Some(ChannelMsg::Data { data }) | Some(ChannelMsg::ExtendedData { data, .. }) => {
+                early.extend_from_slice(&data)
+            }
+            Some(_) => {}
+            None => bail!("Channel closed while requesting {}", what),
+        }
+    }
+}
+
+/// Copies the shell's output, stderr included, until it ends or the
+/// terminal lets go of either side.
+///
+/// russh grants the server more window as data arrives, so that is not what
+/// holds it back. While the terminal is not reading, writes to the sink
+/// block, the channel's bounded queue fills up and the session stops reading
+/// the connection until there is room again. Other channels on the same
+/// connection wait along with it.
+async fn pump(
+    mut channel: Channel<Msg>,
+    mut sink: DuplexStream,
+    mut resizes: mpsc::UnboundedReceiver<(u16, u16)>,
+) {
+    loop {
+        tokio::select! {
+            message = channel.wait() => match message {
+                Some(ChannelMsg::Data { data }) | Some(ChannelMsg::ExtendedData { data, .. }) => {
+                    if sink.write_all(&data).await.is_err() {
+                        break;
+                    }
+                }
+                Some(ChannelMsg::Eof) | Some(ChannelMsg::Close) | None => break,
+                Some(_) => {}
+            },
+            resize = resizes.recv() => match resize {
+                Some((rows, cols)) => {
+                    let _ = channel.window_change(cols.into(), rows.into(), 0, 0).await;
+                }
+                None => break,
+            },
+        }
+    }
+    let _ = channel.close().await;
+}
+
+struct SshInput {
+    writer: Pin<Box<dyn AsyncWrite + Send>>,
+    resizes: mpsc::UnboundedSender<(u16, u16)>,
+}
+
+impl AsyncWrite for SshInput {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.writer.as_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        self.writer.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        self.writer.as_mut().poll_shutdown(cx)
+    }
+}
+
+#[async_trait]
+impl TerminalStream for SshInput {
+    async fn set_window_size(&mut self, rows: u16, cols: u16) -> io::Result<()> {
+        self.resizes
+            .send((rows, cols))
+            .map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+}
@@ -0,0 +1,627 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context as TaskContext, Poll, Waker},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::mpsc,
+};
+
+use crate::services::terminal_manager::{TerminalOutput, TerminalStream};
+
+pub const DEFAULT_PORT: u16 = 23;
+
+// Commands, RFC 854
+const SE: u8 = 240;
+const BRK: u8 = 243;
+const SB: u8 = 250;
+const WILL: u8 = 251;
+const WONT: u8 = 252;
+const DO: u8 = 253;
+const DONT: u8 = 254;
+const IAC: u8 = 255;
+
+// Options: RFC 856, 857, 858, 1091 and 1073
+const BINARY: u8 = 0;
+const ECHO: u8 = 1;
+const SGA: u8 = 3;
+const TTYPE: u8 = 24;
+const NAWS: u8 = 31;
+
+const TTYPE_IS: u8 = 0;
+const TTYPE_SEND: u8 = 1;
+// Offered one per request, repeating the last one once the server has
+// seen them all
+const TERMINAL_TYPES: &[&str] = &["XTERM-256COLOR", "XTERM", "VT100"];
+// Longer subnegotiations are cut off, none of ours come close
+const MAX_SUBNEGOTIATION: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OptionState {
+    No,
+    // Asked for, waiting for the answer
+    WantYes,
+    Yes,
+}
+
+/// Option state for both directions, kept as RFC 1143 suggests so the two
+/// sides never loop on each other's answers.
+struct Negotiation {
+    // Options this side performs, agreed through DO and DONT
+    local: [OptionState; 256],
+    // Options the server performs, agreed through WILL and WONT
+    remote: [OptionState; 256],
+    // Rows and columns
+    window_size: (u16, u16),
+    terminal_types_sent: usize,
+    // Typed input to show while the server does not echo it
+    local_echo: Vec<u8>,
+    reader: Option<Waker>,
+}
+
+impl Negotiation {
+    fn new(rows: u16, cols: u16) -> Self {
+        Self {
+            local: [OptionState::No; 256],
+            remote: [OptionState::No; 256],
+            window_size: (rows, cols),
+            terminal_types_sent: 0,
+            local_echo: Vec::new(),
+            reader: None,
+        }
+    }
+
+    /// What this side asks for right after connecting.
+    fn start(&mut self, replies: &mut Vec<u8>) {
+        for option in [BINARY, TTYPE, NAWS] {
+            self.local[option as usize] = OptionState::WantYes;
+            replies.extend_from_slice(&[IAC, WILL, option]);
+        }
+        for option in [BINARY, SGA] {
+            self.remote[option as usize] = OptionState::WantYes;
+            replies.extend_from_slice(&[IAC, DO, option]);
+        }
+    }
+
+    fn receive(&mut self, verb: u8, option: u8, replies: &mut Vec<u8>) {
+        let index = option as usize;
+        match verb {
+            DO => match self.local[index] {
+                OptionState::No if matches!(option, BINARY | TTYPE | NAWS) => {
+                    self.local[index] = OptionState::Yes;
+                    replies.extend_from_slice(&[IAC, WILL, option]);
+                    self.enabled(option, replies);
+                }
+                OptionState::No => replies.extend_from_slice(&[IAC, WONT, option]),
+                OptionState::WantYes => {
+                    self.local[index] = OptionState::Yes;
+                    self.enabled(option, replies);
+                }
+                OptionState::Yes => {}
+            },
+            DONT => match self.local[index] {
+                OptionState::Yes => {
+                    self.local[index] = OptionState::No;
+                    replies.extend_from_slice(&[IAC, WONT, option]);
+                }
+                OptionState::WantYes => self.local[index] = OptionState::No,
+                OptionState::No => {}
+            },
+            WILL => match self.remote[index] {
+                OptionState::No if matches!(option, BINARY | ECHO | SGA) => {
+                    self.remote[index] = OptionState::Yes;
+                    replies.extend_from_slice(&[IAC, DO, option]);
+                }
+                OptionState::No => replies.extend_from_slice(&[IAC, DONT, option]),
+                OptionState::WantYes => self.remote[index] = OptionState::Yes,
+                OptionState::Yes => {}
+            },
+            WONT => match self.remote[index] {
+                OptionState::Yes => {
+                    self.remote[index] = OptionState::No;
+                    replies.extend_from_slice(&[IAC, DONT, option]);
+                }
+                OptionState::WantYes => self.remote[index] = OptionState::No,
+                OptionState::No => {}
+            },
+            _ => {}
+        }
+    }
+
+    // The server wants the window size as soon as NAWS is on
+    fn enabled(&self, option: u8, replies: &mut Vec<u8>) {
+        if option == NAWS {
+            self.send_window_size(replies);
+        }
+    }
+
+    fn subnegotiate(&mut self, data: &[u8], replies: &mut Vec<u8>) {
+        if data != [TTYPE, TTYPE_SEND] || !self.local_on(TTYPE) {
+            return;
+        }
+
+        let index = self.terminal_types_sent.min(TERMINAL_TYPES.len() - 1);
+        self.terminal_types_sent += 1;
+        replies.extend_from_slice(&[IAC, SB, TTYPE, TTYPE_IS]);
+        replies.extend_from_slice(TERMINAL_TYPES[index].as_bytes());
+        replies.extend_from_slice(&[IAC, SE]);
+    }
+
+    fn send_window_size(&self, replies: &mut Vec<u8>) {
+        let (rows, cols) = self.window_size;
+        replies.extend_from_slice(&[IAC, SB, NAWS]);
+        for byte in [cols.to_be_bytes(), rows.to_be_bytes()].concat() {
+            escape(byte, replies);
+        }
+        replies.extend_from_slice(&[IAC, SE]);
+    }
+
+    fn local_on(&self, option: u8) -> bool {
+        self.local[option as usize] == OptionState::Yes
+    }
+
+    fn remote_on(&self, option: u8) -> bool {
+        self.remote[option as usize] == OptionState::Yes
+    }
+}
+
+fn escape(byte: u8, out: &mut Vec<u8>) {
+    if byte == IAC {
+        out.push(IAC);
+    }
+    out.push(byte);
+}
+
+struct Shared {
+    negotiation: Mutex<Negotiation>,
+    // Everything sent to the server goes through the writer task, so
+    // answers from the reader and typed input never interleave
+    outgoing: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+/// Connects to a telnet server and negotiates binary mode, the terminal
+/// type, the window size, echo and suppress go-ahead.
+pub async fn connect(
+    hostname: &str,
+    port: u16,
+    timeout: Duration,
+    rows: u16,
+    cols: u16,
+) -> Result<(TerminalOutput, Box<dyn TerminalStream>)> {
+    let stream = tokio::time::timeout(timeout, TcpStream::connect((hostname, port)))
+        .await
+        .context("Connection timed out")?
+        .with_context(|| format!("Failed to connect to {}:{}", hostname, port))?;
+    stream.set_nodelay(true)?;
+    let (reader, writer) = stream.into_split();
+
+    let (outgoing, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(write_to_server(writer, receiver));
+
+    let mut negotiation = Negotiation::new(rows, cols);
+    let mut request = Vec::new();
+    negotiation.start(&mut request);
+    let _ = outgoing.send(request);
+
+    let shared = Arc::new(Shared {
+        negotiation: Mutex::new(negotiation),
+        outgoing,
+    });
+    let output = TelnetOutput {
+        stream: reader,
+        shared: Arc::clone(&shared),
+        decoder: Decoder::default(),
+        raw: Vec::new(),
+    };
+    Ok((Box::new(output), Box::new(TelnetInput { shared })))
+}
+
+async fn write_to_server(mut stream: OwnedWriteHalf, mut outgoing: mpsc::UnboundedReceiver<Vec<u8>>) {
+    while let Some(bytes) = outgoing.recv().await {
+        if let Err(e) = stream.write_all(&bytes).await {
+            log::error!("Failed to write to telnet server: {}", e);
+            break;
+        }
+    }
+
+    let _ = stream.shutdown().await;
+}
+
+#[derive(Debug, Default)]
+enum State {
+    #[default]
+    Data,
+    Iac,
+    // After WILL, WONT, DO or DONT
+    Verb(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+/// Splits the server's stream into terminal data and telnet commands.
+#[derive(Debug, Default)]
+struct Decoder {
+    state: State,
+    subnegotiation: Vec<u8>,
+    // Whether the last data byte was a carriage return
+    after_cr: bool,
+}
+
+impl Decoder {
+    fn decode(&mut self, input: &[u8], negotiation: &mut Negotiation, data: &mut ReadBuf<'_>, replies: &mut Vec<u8>) {
+        // Outside binary mode a carriage return is sent as CR NUL
+        let binary = negotiation.remote_on(BINARY);
+
+        for &byte in input {
+            self.state = match (&self.state, byte) {
+                (State::Data, IAC) => State::Iac,
+                (State::Data, _) => {
+                    if !(byte == 0 && self.after_cr && !binary) {
+                        data.put_slice(&[byte]);
+                    }
+                    self.after_cr = byte == b'\r';
+                    State::Data
+                }
+                (State::Iac, IAC) => {
+                    data.put_slice(&[IAC]);
+                    self.after_cr = false;
+                    State::Data
+                }
+                (State::Iac, WILL | WONT | DO | DONT) => State::Verb(byte),
+                (State::Iac, SB) => {
+                    self.subnegotiation.clear();
+                    State::Subnegotiation
+                }
+                // NOP, GA, DM and the like carry nothing for a terminal
+                (State::Iac, _) => State::Data,
+                (State::Verb(verb), option) => {
+                    negotiation.receive(*verb, option, replies);
+                    State::Data
+                }
+                (State::Subnegotiation, IAC) => State::SubnegotiationIac,
+                (State::Subnegotiation, _) => {
+                    if self.subnegotiation.len() < MAX_SUBNEGOTIATION {
+                        self.subnegotiation.push(byte);
+                    }
+                    State::Subnegotiation
+                }
+                (State::SubnegotiationIac, IAC) => {
+                    if self.subnegotiation.len() < MAX_SUBNEGOTIATION {
+                        self.subnegotiation.push(IAC);
+                    }
+                    State::Subnegotiation
+                }
+                (State::SubnegotiationIac, SE) => {
+                    negotiation.subnegotiate(&self.subnegotiation, replies);
+                    State::Data
+                }
+                // Malformed, drop the subnegotiation
+                (State::SubnegotiationIac, _) => State::Data,
+            };
+        }
+    }
+}
+
+struct TelnetOutput {
+    stream: OwnedReadHalf,
+    shared: Arc<Shared>,
+    decoder: Decoder,
+    raw: Vec<u8>,
+}
+
+impl AsyncRead for TelnetOutput {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let filled = buf.filled().len();
+
+        loop {
+            {
+                let mut negotiation = this.shared.negotiation.lock().unwrap();
+                if !negotiation.local_echo.is_empty() {
+                    let n = negotiation.local_echo.len().min(buf.remaining());
+                    buf.put_slice(&negotiation.local_echo[..n]);
+                    negotiation.local_echo.drain(..n);
+                    return Poll::Ready(Ok(()));
+                }
+                negotiation.reader = Some(cx.waker().clone());
+            }
+
+            // Decoding never yields more data than it was given
+            this.raw.resize(buf.remaining(), 0);
+            let mut raw = ReadBuf::new(&mut this.raw);
+            ready!(Pin::new(&mut this.stream).poll_read(cx, &mut raw))?;
+            if raw.filled().is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+
+            let mut replies = Vec::new();
+            let mut negotiation = this.shared.negotiation.lock().unwrap();
+            this.decoder.decode(raw.filled(), &mut negotiation, buf, &mut replies);
+            drop(negotiation);
+            if !replies.is_empty() {
+                let _ = this.shared.outgoing.send(replies);
+            }
+
+            // Reads holding only commands must not look like the end
+            if buf.filled().len() > filled {
+                return Poll::Ready(Ok(()));
+            }
+        }
+    }
+}
+
+struct TelnetInput {
+    shared: Arc<Shared>,
+}
+
+impl AsyncWrite for TelnetInput {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>, data: &[u8]) -> Poll<io::Result<usize>> {
+        let mut negotiation = self.shared.negotiation.lock().unwrap();
+        let binary = negotiation.local_on(BINARY);
+
+        let mut encoded = Vec::with_capacity(data.len());
+        for &byte in data {
+            match byte {
+                // Enter is a telnet newline outside binary mode
+                b'\r' if !binary => encoded.extend_from_slice(b"\r\n"),
+                _ => escape(byte, &mut encoded),
+            }
+        }
+
+        if !negotiation.remote_on(ECHO) {
+            for &byte in data {
+                match byte {
+                    b'\r' => negotiation.local_echo.extend_from_slice(b"\r\n"),
+                    // Erase the character before the cursor
+                    0x7f | 0x08 => negotiation.local_echo.extend_from_slice(b"\x08 \x08"),
+                    _ => negotiation.local_echo.push(byte),
+                }
+            }
+            if let Some(reader) = negotiation.reader.take() {
+                reader.wake();
+            }
+        }
+        drop(negotiation);
+
+        if self.shared.outgoing.send(encoded).is_err() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl TelnetInput {
+    fn send(&self, bytes: Vec<u8>) -> io::Result<()> {
+        self.shared
+            .outgoing
+            .send(bytes)
+            .map_err(|_| io::ErrorKind::BrokenPipe.into())
+    }
+}
+
+#[async_trait]
+impl TerminalStream for TelnetInput {
+    async fn set_window_size(&mut self, rows: u16, cols: u16) -> io::Result<()> {
+        let mut negotiation = self.shared.negotiation.lock().unwrap();
+        negotiation.window_size = (rows, cols);
+        if !negotiation.local_on(NAWS) {
+            return Ok(());
+        }
+
+        let mut update = Vec::new();
+        negotiation.send_window_size(&mut update);
+        drop(negotiation);
+        self.send(update)
+    }
+
+    async fn send_break(&mut self) -> io::Result<()> {
+        self.send(vec![IAC, BRK])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    // What the client asks for on connecting
+    const START: &[u8] = &[
+        IAC, WILL, BINARY, IAC, WILL, TTYPE, IAC, WILL, NAWS, IAC, DO, BINARY, IAC, DO, SGA,
+    ];
+
+    struct Session {
+        // The server's end of the connection
+        server: TcpStream,
+        // Terminal output, as the reader hands it on
+        output: mpsc::UnboundedReceiver<Vec<u8>>,
+        input: Box<dyn TerminalStream>,
+    }
+
+    impl Session {
+        async fn start(rows: u16, cols: u16) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let (accepted, connected) = tokio::join!(
+                listener.accept(),
+                connect("127.0.0.1", port, TIMEOUT, rows, cols)
+            );
+            let (mut output, input) = connected.unwrap();
+
+            let (sender, receiver) = mpsc::unbounded_channel();
+            tokio::spawn(async move {
+                let mut buf = [0; 1024];
+                while let Ok(n @ 1..) = output.read(&mut buf).await {
+                    if sender.send(buf[..n].to_vec()).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            let mut session = Self {
+                server: accepted.unwrap().0,
+                output: receiver,
+                input,
+            };
+            session.expect_sent(START).await;
+            session
+        }
+
+        async fn send(&mut self, bytes: &[u8]) {
+            self.server.write_all(bytes).await.unwrap();
+        }
+
+        // The next bytes the client sends must be exactly these
+        async fn expect_sent(&mut self, expected: &[u8]) {
+            let mut received = vec![0; expected.len()];
+            tokio::time::timeout(TIMEOUT, self.server.read_exact(&mut received))
+                .await
+                .expect("timed out")
+                .unwrap();
+            assert_eq!(received, expected);
+        }
+
+        // The next terminal output must be exactly this
+        async fn expect_output(&mut self, expected: &[u8]) {
+            let mut received = Vec::new();
+            while received.len() < expected.len() {
+                let chunk = tokio::time::timeout(TIMEOUT, self.output.recv())
+                    .await
+                    .expect("timed out")
+                    .expect("connection closed");
+                received.extend_from_slice(&chunk);
+            }
+            assert_eq!(received, expected);
+        }
+
+        async fn type_in(&mut self, bytes: &[u8]) {
+            self.input.write_all(bytes).await.unwrap();
+        }
+    }
+
+    fn naws(cols: &[u8], rows: &[u8]) -> Vec<u8> {
+        [&[IAC, SB, NAWS], cols, rows, &[IAC, SE]].concat()
+    }
+
+    #[tokio::test]
+    async fn answers_each_option_once() {
+        let mut session = Session::start(24, 80).await;
+        session
+            .send(&[
+                // Agreeing to what the client asked for needs no answer,
+                // besides the window size once NAWS is on
+                IAC, DO, BINARY, IAC, DO, TTYPE, IAC, DO, NAWS, IAC, WILL, BINARY, IAC, WILL, SGA,
+                // Nor does repeating it
+                IAC, DO, BINARY, IAC, WILL, SGA,
+                // Unknown options are refused
+                IAC, DO, 99, IAC, WILL, 99,
+                IAC, WILL, ECHO,
+                IAC, DONT, NAWS, IAC, DONT, NAWS,
+                // Refused too, marking the end of the answers
+                IAC, DO, 98,
+            ])
+            .await;
+
+        let expected = [
+            naws(&[0, 80], &[0, 24]),
+            vec![IAC, WONT, 99, IAC, DONT, 99],
+            vec![IAC, DO, ECHO],
+            vec![IAC, WONT, NAWS],
+            vec![IAC, WONT, 98],
+        ];
+        session.expect_sent(&expected.concat()).await;
+    }
+
+    #[tokio::test]
+    async fn sends_the_window_size() {
+        let mut session = Session::start(24, 80).await;
+        // Only remembered until the server turns NAWS on
+        session.input.set_window_size(40, 255).await.unwrap();
+        session.send(&[IAC, DO, NAWS]).await;
+        session.expect_sent(&naws(&[0, IAC, IAC], &[0, 40])).await;
+
+        session.input.set_window_size(511, 120).await.unwrap();
+        session.expect_sent(&naws(&[0, 120], &[1, IAC, IAC])).await;
+    }
+
+    #[tokio::test]
+    async fn cycles_through_terminal_types() {
+        let mut session = Session::start(24, 80).await;
+        session.send(&[IAC, DO, TTYPE]).await;
+
+        let mut expected = Vec::new();
+        for name in ["XTERM-256COLOR", "XTERM", "VT100", "VT100"] {
+            session.send(&[IAC, SB, TTYPE, TTYPE_SEND, IAC, SE]).await;
+            expected.extend_from_slice(&[IAC, SB, TTYPE, TTYPE_IS]);
+            expected.extend_from_slice(name.as_bytes());
+            expected.extend_from_slice(&[IAC, SE]);
+        }
+        session.expect_sent(&expected).await;
+    }
+
+    #[tokio::test]
+    async fn escapes_iac_both_ways() {
+        let mut session = Session::start(24, 80).await;
+        session
+            .send(&[IAC, DO, BINARY, IAC, WILL, BINARY, IAC, WILL, SGA, IAC, WILL, ECHO])
+            .await;
+        session.expect_sent(&[IAC, DO, ECHO]).await;
+
+        // Binary mode keeps carriage returns and what follows them as is
+        session.send(&[b'a', IAC, IAC, b'b', b'\r', 0, b'\r']).await;
+        session.expect_output(&[b'a', IAC, b'b', b'\r', 0, b'\r']).await;
+
+        session.type_in(&[b'x', IAC, b'y', b'\r']).await;
+        session.expect_sent(&[b'x', IAC, IAC, b'y', b'\r']).await;
+    }
+
+    #[tokio::test]
+    async fn translates_newlines_outside_binary_mode() {
+        let mut session = Session::start(24, 80).await;
+        session
+            .send(&[IAC, WONT, BINARY, IAC, DONT, BINARY, IAC, WILL, SGA, IAC, WILL, ECHO])
+            .await;
+        session.expect_sent(&[IAC, DO, ECHO]).await;
+
+        session.send(b"a\r\0b\r\nc").await;
+        session.expect_output(b"a\rb\r\nc").await;
+
+        session.type_in(b"ls\r").await;
+        session.expect_sent(b"ls\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn echoes_locally_until_the_server_does() {
+        let mut session = Session::start(24, 80).await;
+        session.type_in(b"ab\x7fc\r").await;
+        session.expect_output(b"ab\x08 \x08c\r\n").await;
+        session.expect_sent(b"ab\x7fc\r\n").await;
+
+        session.send(&[IAC, WILL, ECHO]).await;
+        session.expect_sent(&[IAC, DO, ECHO]).await;
+        session.type_in(b"d").await;
+        session.expect_sent(b"d").await;
+        session.send(b"d").await;
+        session.expect_output(b"d").await;
+    }
+}
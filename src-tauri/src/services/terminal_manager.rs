@@ -1,34 +1,274 @@
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use regex::Regex;
+use russh::{client::Msg, Channel as SshChannel};
 use std::{
     collections::HashMap,
-    io::{Read, Write},
+    io,
+    path::{Path, PathBuf},
     sync::Arc,
-    time::{Duration, Instant},
+    time::Duration,
+};
+use tauri::{
+    command,
+    ipc::{Channel, InvokeBody, InvokeResponseBody, Request},
+    AppHandle, Emitter, Runtime, State, Window,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{mpsc, oneshot, Mutex, Notify},
+    task::JoinHandle,
+    time::{self, Instant},
 };
-use tokio::sync::Mutex;
 use uuid::Uuid;
 
+use crate::models::{
+    ipc_channels::TERMINAL_STATE,
+    serial::SerialConfig,
+    terminal::{ScrollbackMatch, TerminalState},
+};
+use crate::services::{
+    local_pty::{self, ShellCommand},
+    recorder::{recordings_dir, Recorder},
+    scrollback::Scrollback,
+    secure_storage::SecureStorage,
+    serial,
+    ssh_agent::SshAgent,
+    ssh_manager::SshManager,
+    ssh_shell, telnet,
+};
+
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+// Output is coalesced into frames of at most FRAME_SIZE bytes, sent at the
+// latest FRAME_INTERVAL after their first byte was read
+const FRAME_SIZE: usize = 64 * 1024;
+const FRAME_INTERVAL: Duration = Duration::from_millis(8);
+// Reads pause once the client is HIGH_WATERMARK bytes behind and resume when
+// it is back under LOW_WATERMARK. Unread output then backs up into the
+// backend: a local PTY blocks the program writing it, while SSH and telnet
+// stop reading the socket and leave it to TCP to slow the server down
+const HIGH_WATERMARK: usize = 1024 * 1024;
+const LOW_WATERMARK: usize = 256 * 1024;
+// Scrollback is configured in lines but kept in bytes, escape sequences
+// included
+const SCROLLBACK_BYTES_PER_LINE: usize = 256;
+const DEFAULT_SCROLLBACK_LINES: u32 = 10_000;
+const MAX_SEARCH_MATCHES: usize = 1000;
+const DEFAULT_CONNECTION_TIMEOUT: u32 = 30;
+// Names the terminal `terminal_write` input is for, its body being raw bytes
+const TERMINAL_ID_HEADER: &str = "terminal-id";
+
+/// The output side of a terminal backend.
+pub type TerminalOutput = Box<dyn AsyncRead + Send + Unpin>;
+
+/// The input side of a terminal backend: keystrokes are written to it and
+/// window size changes are passed on to whatever runs behind it.
+#[async_trait]
+pub trait TerminalStream: AsyncWrite + Send + Unpin {
+    async fn set_window_size(&mut self, rows: u16, cols: u16) -> io::Result<()>;
+
+    /// Sends a BREAK, for backends where that means something.
+    async fn send_break(&mut self) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "This terminal cannot send a BREAK"))
+    }
+}
+
+/// A frontend receiving a terminal's output as raw bytes. Decoding is left
+/// to the terminal emulator, so multibyte characters split across reads and
+/// binary output arrive intact.
+pub trait TerminalClient: Send + Sync {
+    // false once the client is gone
+    fn send(&self, data: &[u8]) -> bool;
+}
+
+impl TerminalClient for Channel<InvokeResponseBody> {
+    fn send(&self, data: &[u8]) -> bool {
+        Channel::send(self, InvokeResponseBody::Raw(data.to_vec())).is_ok()
+    }
+}
+
+struct Subscriber {
+    client: Box<dyn TerminalClient>,
+    // Label of the window the client lives in
+    window: String,
+    // Bytes sent that the client has not acknowledged yet
+    unacknowledged: usize,
+}
+
+struct OutletState {
+    subscriber: Option<Subscriber>,
+    scrollback: Scrollback,
+    recorder: Option<Recorder>,
+    // Rows and columns, as last resized
+    window_size: (u16, u16),
+}
+
+/// Where a terminal's output goes, shared with its reader task. Output is
+/// kept in the scrollback whether or not a client is subscribed, and teed to
+/// the recording if there is one.
+struct Outlet {
+    state: std::sync::Mutex<OutletState>,
+    // Wakes the reader once the client caught up or was replaced
+    resume: Notify,
+}
+
+impl Outlet {
+    fn new(scrollback: usize) -> Self {
+        Self {
+            state: std::sync::Mutex::new(OutletState {
+                subscriber: None,
+                scrollback: Scrollback::new(scrollback),
+                recorder: None,
+                window_size: (24, 80),
+            }),
+            resume: Notify::new(),
+        }
+    }
+
+    fn send(&self, frame: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        state.scrollback.push(frame);
+        if let Some(recorder) = state.recorder.as_mut() {
+            recorder.output(frame);
+        }
+
+        let Some(current) = state.subscriber.as_mut() else {
+            return;
+        };
+        if current.client.send(frame) {
+            current.unacknowledged += frame.len();
+        } else {
+            // The window went away, a new one catches up through the scrollback
+            state.subscriber = None;
+        }
+    }
+
+    /// Replaces the client, replaying the scrollback to it first.
+    fn subscribe(&self, client: Box<dyn TerminalClient>, window: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let replay = state.scrollback.replay();
+        for frame in replay.chunks(FRAME_SIZE) {
+            if !client.send(frame) {
+                bail!("Failed to send the scrollback");
+            }
+        }
+
+        state.subscriber = Some(Subscriber {
+            client,
+            window: window.to_string(),
+            unacknowledged: replay.len(),
+        });
+        self.resume.notify_one();
+        Ok(())
+    }
+
+    fn acknowledge(&self, bytes: usize) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(current) = state.subscriber.as_mut() {
+            current.unacknowledged = current.unacknowledged.saturating_sub(bytes);
+            if current.unacknowledged < LOW_WATERMARK {
+                self.resume.notify_one();
+            }
+        }
+    }
+
+    fn is_behind(&self, limit: usize) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .subscriber
+            .as_ref()
+            .is_some_and(|subscriber| subscriber.unacknowledged >= limit)
+    }
+
+    async fn wait_for_client(&self) {
+        if !self.is_behind(HIGH_WATERMARK) {
+            return;
+        }
+        while self.is_behind(LOW_WATERMARK) {
+            self.resume.notified().await;
+        }
+    }
+
+    fn window(&self) -> Option<String> {
+        self.state
+            .lock()
+            .unwrap()
+            .subscriber
+            .as_ref()
+            .map(|subscriber| subscriber.window.clone())
+    }
+}
+
+enum Command {
+    Write(Vec<u8>),
+    Resize { rows: u16, cols: u16 },
+    // Answered on the channel, a backend without BREAK keeps running
+    Break(oneshot::Sender<io::Result<()>>),
+    Close,
+}
+
+// The tasks serving an attached stream
+struct Attachment {
+    commands: mpsc::UnboundedSender<Command>,
+    reader: JoinHandle<()>,
+}
+
+impl Attachment {
+    fn stop(self) {
+        let _ = self.commands.send(Command::Close);
+        self.reader.abort();
+    }
+}
+
 pub struct Terminal {
     id: String,
-    created_at: Instant,
-    stream: Option<Box<dyn TerminalStream>>,
+    state: TerminalState,
+    attachment: Option<Attachment>,
+    outlet: Arc<Outlet>,
+}
+
+impl Terminal {
+    fn send_command(&self, command: Command) -> Result<()> {
+        let Some(attachment) = &self.attachment else {
+            bail!("Terminal has no stream attached");
+        };
+        if attachment.commands.send(command).is_err() {
+            bail!("Terminal stream is closed");
+        }
+        Ok(())
+    }
+}
+
+pub trait WindowHandler: Send + Sync {
+    fn send_to_all_windows(&self, event: &str, payload: serde_json::Value);
+    fn send_to_window(&self, label: &str, event: &str, payload: serde_json::Value);
 }
 
-pub trait TerminalStream: Read + Write + Send + Sync {
-    fn set_window_size(&mut self, rows: u16, cols: u16) -> std::io::Result<()>;
+impl<R: Runtime> WindowHandler for AppHandle<R> {
+    fn send_to_all_windows(&self, event: &str, payload: serde_json::Value) {
+        let _ = self.emit(event, payload);
+    }
+
+    fn send_to_window(&self, label: &str, event: &str, payload: serde_json::Value) {
+        let _ = self.emit_to(label, event, payload);
+    }
 }
 
+/// Terminals and the backend streams behind them. Each attached stream gets
+/// a reader task forwarding its output and a writer task fed through a
+/// command channel, so the map is only locked to look a terminal up.
 pub struct TerminalManager {
+    storage: Arc<SecureStorage>,
     terminals: Arc<Mutex<HashMap<String, Terminal>>>,
     window_handler: Arc<dyn WindowHandler>,
 }
 
-pub trait WindowHandler: Send + Sync {
-    fn send_to_all_windows(&self, event: &str, payload: serde_json::Value);
-}
-
 impl TerminalManager {
-    pub fn new(window_handler: Arc<dyn WindowHandler>) -> Self {
+    pub fn new(storage: Arc<SecureStorage>, window_handler: Arc<dyn WindowHandler>) -> Self {
         Self {
+            storage,
             terminals: Arc::new(Mutex::new(HashMap::new())),
             window_handler,
         }
@@ -36,77 +276,694 @@ impl TerminalManager {
 
     pub async fn create_terminal(&self, session_id: &str) -> String {
         let terminal_id = format!("terminal-{}-{}", Uuid::new_v4(), session_id);
-        
+
+        let lines = self
+            .storage
+            .get_user_settings()
+            .await
+            .ok()
+            .flatten()
+            .map_or(DEFAULT_SCROLLBACK_LINES, |settings| settings.scroll_back_buffer);
         let terminal = Terminal {
             id: terminal_id.clone(),
-            created_at: Instant::now(),
-            stream: None,
+            state: TerminalState::Created,
+            attachment: None,
+            outlet: Arc::new(Outlet::new(lines as usize * SCROLLBACK_BYTES_PER_LINE)),
         };
-        
+
         self.terminals.lock().await.insert(terminal_id.clone(), terminal);
         terminal_id
     }
 
+    /// Connects a backend to the terminal, replacing the one attached before.
     pub async fn attach_stream(
         &self,
         terminal_id: &str,
-        stream: Box<dyn TerminalStream>,
-    ) -> std::io::Result<()> {
+        output: TerminalOutput,
+        input: Box<dyn TerminalStream>,
+    ) -> Result<()> {
+        let settings = self.storage.get_user_settings().await.ok().flatten();
+
         let mut terminals = self.terminals.lock().await;
-        
-        if let Some(terminal) = terminals.get_mut(terminal_id) {
-            terminal.stream = Some(stream);
-            
-            // Setup data forwarding
-            let terminal_id = terminal_id.to_string();
-            let window_handler = Arc::clone(&self.window_handler);
-            
-            tokio::spawn(async move {
-                let mut buffer = [0; 1024];
-                
-                while let Ok(n) = terminal.stream.as_mut().unwrap().read(&mut buffer) {
-                    if n == 0 {
-                        break;
-                    }
-                    
-                    let data = String::from_utf8_lossy(&buffer[..n]);
-                    window_handler.send_to_all_windows(
-                        "terminal:data",
-                        serde_json::json!({
-                            "terminalId": terminal_id,
-                            "data": data,
-                        }),
-                    );
-                }
-            });
-            
-            Ok(())
-        } else {
-            Err(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                "Terminal not found",
-            ))
+        let terminal = terminals
+            .get_mut(terminal_id)
+            .context("Terminal not found")?;
+        if let Some(previous) = terminal.attachment.take() {
+            previous.stop();
+        }
+
+        let (commands, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(write_input(terminal_id.to_string(), input, receiver));
+        let reader = tokio::spawn(forward_output(
+            terminal_id.to_string(),
+            output,
+            Arc::clone(&terminal.outlet),
+            commands.clone(),
+            Arc::clone(&self.terminals),
+            Arc::clone(&self.window_handler),
+        ));
+
+        terminal.attachment = Some(Attachment { commands, reader });
+        terminal.state = TerminalState::Attached;
+        let outlet = Arc::clone(&terminal.outlet);
+        drop(terminals);
+
+        send_state(self.window_handler.as_ref(), &outlet, terminal_id, TerminalState::Attached);
+
+        let recording = outlet.state.lock().unwrap().recorder.is_some();
+        if let Some(settings) = settings.filter(|settings| settings.record_sessions && !recording) {
+            if let Err(e) = self.start_recording(terminal_id, settings.record_input).await {
+                log::error!("Failed to record terminal {}: {}", terminal_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs the user's default shell on this machine in the terminal, through
+    /// a pseudo-terminal of the given size.
+    pub async fn open_local_shell(
+        &self,
+        terminal_id: &str,
+        rows: u16,
+        cols: u16,
+        agent_socket: Option<&Path>,
+    ) -> Result<()> {
+        let settings = self.storage.get_user_settings().await.ok().flatten();
+        let command = ShellCommand::from_settings(settings.as_ref(), agent_socket);
+        self.open_local(terminal_id, &command, rows, cols).await
+    }
+
+    /// Runs `command_line` with the user's default shell in the terminal,
+    /// ending when the command exits.
+    pub async fn open_local_command(
+        &self,
+        terminal_id: &str,
+        command_line: &str,
+        rows: u16,
+        cols: u16,
+        agent_socket: Option<&Path>,
+    ) -> Result<()> {
+        let settings = self.storage.get_user_settings().await.ok().flatten();
+        let command = ShellCommand::from_settings(settings.as_ref(), agent_socket)
+            .with_command_line(command_line);
+        self.open_local(terminal_id, &command, rows, cols).await
+    }
+
+    async fn open_local(
+        &self,
+        terminal_id: &str,
+        command: &ShellCommand,
+        rows: u16,
+        cols: u16,
+    ) -> Result<()> {
+        let (output, input) = local_pty::spawn(command, rows, cols)?;
+
+        self.outlet(terminal_id).await?.state.lock().unwrap().window_size = (rows, cols);
+        self.attach_stream(terminal_id, output, input).await
+    }
+
+    /// Runs the user's login shell on an SSH session channel, through a
+    /// pseudo-terminal of the given size.
+    pub async fn open_ssh_shell(
+        &self,
+        terminal_id: &str,
+        channel: SshChannel<Msg>,
+        rows: u16,
+        cols: u16,
+    ) -> Result<()> {
+        let (output, input) = ssh_shell::open(channel, rows, cols).await?;
+
+        self.outlet(terminal_id).await?.state.lock().unwrap().window_size = (rows, cols);
+        self.attach_stream(terminal_id, output, input).await
+    }
+
+    /// Connects the terminal to a serial port.
+    pub async fn open_serial(&self, terminal_id: &str, config: &SerialConfig) -> Result<()> {
+        let (output, input) = serial::open(config)?;
+        self.attach_stream(terminal_id, output, input).await
+    }
+
+    /// Connects the terminal to a host over telnet, at the given window size.
+    pub async fn open_telnet(&self, terminal_id: &str, host_id: &str, rows: u16, cols: u16) -> Result<()> {
+        let host = self
+            .storage
+            .get_host(host_id)
+            .await?
+            .context("Host not found")?;
+        let timeout = Duration::from_secs(
+            host.connection_timeout
+                .unwrap_or(DEFAULT_CONNECTION_TIMEOUT)
+                .into(),
+        );
+        let port = host.port.unwrap_or(telnet::DEFAULT_PORT);
+        let (output, input) = telnet::connect(&host.hostname, port, timeout, rows, cols).await?;
+
+        self.outlet(terminal_id).await?.state.lock().unwrap().window_size = (rows, cols);
+        self.attach_stream(terminal_id, output, input).await
+    }
+
+    /// Starts recording the terminal's output and resizes, and its input if
+    /// `record_input` is set, to a new asciicast file. Returns its path.
+    pub async fn start_recording(&self, terminal_id: &str, record_input: bool) -> Result<PathBuf> {
+        let outlet = self.outlet(terminal_id).await?;
+        let (rows, cols) = {
+            let state = outlet.state.lock().unwrap();
+            if state.recorder.is_some() {
+                bail!("Terminal is already being recorded");
+            }
+            state.window_size
+        };
+
+        let name = format!("{}-{}.cast", Utc::now().format("%Y%m%d-%H%M%S"), terminal_id);
+        let path = recordings_dir()?.join(name);
+        let recorder = Recorder::start(&path, cols, rows, terminal_id, record_input).await?;
+
+        let mut state = outlet.state.lock().unwrap();
+        if state.recorder.is_some() {
+            bail!("Terminal is already being recorded");
         }
+        state.recorder = Some(recorder);
+        Ok(path)
+    }
+
+    /// Finishes the terminal's recording, returning the file's path.
+    pub async fn stop_recording(&self, terminal_id: &str) -> Result<PathBuf> {
+        let outlet = self.outlet(terminal_id).await?;
+        let recorder = outlet
+            .state
+            .lock()
+            .unwrap()
+            .recorder
+            .take()
+            .context("Terminal is not being recorded")?;
+        Ok(recorder.path().to_path_buf())
+    }
+
+    /// Sends the terminal's output to `client`, living in the window labelled
+    /// `window`, in place of the client subscribed before. The client gets
+    /// the scrollback first, so a reloaded or moved tab picks up where it
+    /// was. State changes go to that window only from now on.
+    pub async fn subscribe(
+        &self,
+        terminal_id: &str,
+        window: &str,
+        client: Box<dyn TerminalClient>,
+    ) -> Result<()> {
+        let terminals = self.terminals.lock().await;
+        let terminal = terminals.get(terminal_id).context("Terminal not found")?;
+        terminal.outlet.subscribe(client, window)
+    }
+
+    /// Finds `pattern` in the terminal's scrollback, ignoring escape
+    /// sequences. Offsets count from the terminal's first output byte.
+    pub async fn search(&self, terminal_id: &str, pattern: &str) -> Result<Vec<ScrollbackMatch>> {
+        let pattern = Regex::new(pattern)?;
+        let outlet = self.outlet(terminal_id).await?;
+
+        let matches = outlet
+            .state
+            .lock()
+            .unwrap()
+            .scrollback
+            .search(&pattern, MAX_SEARCH_MATCHES);
+        Ok(matches)
+    }
+
+    /// Records that the client has processed `bytes` more of the output.
+    /// Reads pause while too much of it is unacknowledged.
+    pub async fn acknowledge(&self, terminal_id: &str, bytes: usize) -> Result<()> {
+        let terminals = self.terminals.lock().await;
+        let terminal = terminals.get(terminal_id).context("Terminal not found")?;
+        terminal.outlet.acknowledge(bytes);
+        Ok(())
+    }
+
+    pub async fn write(&self, terminal_id: &str, data: Vec<u8>) -> Result<()> {
+        let terminals = self.terminals.lock().await;
+        let terminal = terminals.get(terminal_id).context("Terminal not found")?;
+        if let Some(recorder) = terminal.outlet.state.lock().unwrap().recorder.as_mut() {
+            recorder.input(&data);
+        }
+        terminal.send_command(Command::Write(data))
+    }
+
+    pub async fn resize(&self, terminal_id: &str, rows: u16, cols: u16) -> Result<()> {
+        let terminals = self.terminals.lock().await;
+        let terminal = terminals.get(terminal_id).context("Terminal not found")?;
+        terminal.send_command(Command::Resize { rows, cols })?;
+
+        let mut state = terminal.outlet.state.lock().unwrap();
+        state.window_size = (rows, cols);
+        if let Some(recorder) = state.recorder.as_mut() {
+            recorder.resize(rows, cols);
+        }
+        Ok(())
+    }
+
+    pub async fn send_break(&self, terminal_id: &str) -> Result<()> {
+        let (reply, result) = oneshot::channel();
+        self.terminals
+            .lock()
+            .await
+            .get(terminal_id)
+            .context("Terminal not found")?
+            .send_command(Command::Break(reply))?;
+        result.await.context("Terminal stream is closed")??;
+        Ok(())
     }
 
     pub async fn destroy_terminal(&self, terminal_id: &str) {
-        let mut terminals = self.terminals.lock().await;
-        
-        if let Some(terminal) = terminals.remove(terminal_id) {
-            if terminal.created_at.elapsed() < Duration::from_secs(1) {
-                // Re-add terminal and try again later
-                terminals.insert(terminal_id.to_string(), terminal);
-                
-                let self_clone = Arc::new(self.clone());
-                let terminal_id = terminal_id.to_string();
-                
-                tokio::spawn(async move {
-                    tokio::time::sleep(Duration::from_secs(1)).await;
-                    self_clone.destroy_terminal(&terminal_id).await;
-                });
-                
-                return;
+        let Some(mut terminal) = self.terminals.lock().await.remove(terminal_id) else {
+            return;
+        };
+        if let Some(attachment) = terminal.attachment.take() {
+            attachment.stop();
+        }
+        terminal.state = TerminalState::Closed;
+
+        send_state(self.window_handler.as_ref(), &terminal.outlet, &terminal.id, terminal.state);
+    }
+
+    async fn outlet(&self, terminal_id: &str) -> Result<Arc<Outlet>> {
+        self.terminals
+            .lock()
+            .await
+            .get(terminal_id)
+            .map(|terminal| Arc::clone(&terminal.outlet))
+            .context("Terminal not found")
+    }
+}
+
+/// Forwards a backend's output in frames until it ends, then marks the
+/// terminal detached unless another stream has replaced this one meanwhile.
+async fn forward_output(
+    terminal_id: String,
+    mut output: TerminalOutput,
+    outlet: Arc<Outlet>,
+    commands: mpsc::UnboundedSender<Command>,
+    terminals: Arc<Mutex<HashMap<String, Terminal>>>,
+    window_handler: Arc<dyn WindowHandler>,
+) {
+    let mut buffer = vec![0; READ_BUFFER_SIZE];
+    let mut frame = Vec::with_capacity(FRAME_SIZE);
+    let mut deadline = None;
+
+    loop {
+        if frame.is_empty() {
+            outlet.wait_for_client().await;
+        }
+
+        let read = match deadline {
+            Some(deadline) => tokio::select! {
+                read = output.read(&mut buffer) => Some(read),
+                _ = time::sleep_until(deadline) => None,
+            },
+            None => Some(output.read(&mut buffer).await),
+        };
+        let n = match read {
+            // The frame's time is up
+            None => 0,
+            Some(Ok(0)) => break,
+            Some(Ok(n)) => n,
+            Some(Err(e)) => {
+                log::error!("Failed to read from terminal {}: {}", terminal_id, e);
+                break;
+            }
+        };
+
+        if frame.is_empty() {
+            deadline = Some(Instant::now() + FRAME_INTERVAL);
+        }
+        frame.extend_from_slice(&buffer[..n]);
+        if n == 0 || frame.len() >= FRAME_SIZE {
+            outlet.send(&frame);
+            frame.clear();
+            deadline = None;
+        }
+    }
+
+    if !frame.is_empty() {
+        outlet.send(&frame);
+    }
+    let _ = commands.send(Command::Close);
+
+    let mut terminals = terminals.lock().await;
+    let Some(terminal) = terminals.get_mut(&terminal_id) else {
+        return;
+    };
+    let current = terminal
+        .attachment
+        .as_ref()
+        .is_some_and(|attachment| attachment.commands.same_channel(&commands));
+    if current {
+        terminal.attachment = None;
+        terminal.state = TerminalState::Detached;
+        drop(terminals);
+        send_state(window_handler.as_ref(), &outlet, &terminal_id, TerminalState::Detached);
+    }
+}
+
+/// Applies writes, resizes and breaks in the order they were requested.
+async fn write_input(
+    terminal_id: String,
+    mut input: Box<dyn TerminalStream>,
+    mut commands: mpsc::UnboundedReceiver<Command>,
+) {
+    while let Some(command) = commands.recv().await {
+        let result = match command {
+            Command::Write(data) => match input.write_all(&data).await {
+                Ok(()) => input.flush().await,
+                Err(e) => Err(e),
+            },
+            Command::Resize { rows, cols } => input.set_window_size(rows, cols).await,
+            Command::Break(reply) => {
+                let _ = reply.send(input.send_break().await);
+                continue;
             }
+            Command::Close => break,
+        };
+
+        if let Err(e) = result {
+            log::error!("Failed to write to terminal {}: {}", terminal_id, e);
+            break;
         }
     }
+
+    let _ = input.shutdown().await;
+}
+
+/// Tells the window showing the terminal about a state change, or every
+/// window while none has subscribed.
+fn send_state(window_handler: &dyn WindowHandler, outlet: &Outlet, terminal_id: &str, state: TerminalState) {
+    let payload = serde_json::json!({
+        "terminalId": terminal_id,
+        "state": state,
+    });
+    match outlet.window() {
+        Some(window) => window_handler.send_to_window(&window, TERMINAL_STATE, payload),
+        None => window_handler.send_to_all_windows(TERMINAL_STATE, payload),
+    }
+}
+
+#[command]
+pub async fn terminal_create(
+    terminal_manager: State<'_, Arc<TerminalManager>>,
+    session_id: String,
+) -> Result<String, String> {
+    Ok(terminal_manager.create_terminal(&session_id).await)
+}
+
+#[command]
+pub async fn terminal_open_local(
+    terminal_manager: State<'_, Arc<TerminalManager>>,
+    agent: State<'_, Arc<SshAgent>>,
+    terminal_id: String,
+    rows: u16,
+    cols: u16,
+) -> Result<(), String> {
+    terminal_manager
+        .open_local_shell(&terminal_id, rows, cols, Some(agent.socket_path()))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn terminal_open_ssh(
+    terminal_manager: State<'_, Arc<TerminalManager>>,
+    ssh_manager: State<'_, Arc<SshManager>>,
+    terminal_id: String,
+    session_id: String,
+    rows: u16,
+    cols: u16,
+) -> Result<(), String> {
+    let channel = ssh_manager
+        .open_session_channel(&session_id)
+        .await
+        .map_err(|e| e.to_string())?;
+    terminal_manager
+        .open_ssh_shell(&terminal_id, channel, rows, cols)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn terminal_open_serial(
+    terminal_manager: State<'_, Arc<TerminalManager>>,
+    terminal_id: String,
+    config: SerialConfig,
+) -> Result<(), String> {
+    terminal_manager
+        .open_serial(&terminal_id, &config)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn terminal_open_telnet(
+    terminal_manager: State<'_, Arc<TerminalManager>>,
+    terminal_id: String,
+    host_id: String,
+    rows: u16,
+    cols: u16,
+) -> Result<(), String> {
+    terminal_manager
+        .open_telnet(&terminal_id, &host_id, rows, cols)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn terminal_subscribe(
+    terminal_manager: State<'_, Arc<TerminalManager>>,
+    window: Window,
+    terminal_id: String,
+    output: Channel<InvokeResponseBody>,
+) -> Result<(), String> {
+    terminal_manager
+        .subscribe(&terminal_id, window.label(), Box::new(output))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn terminal_ack(
+    terminal_manager: State<'_, Arc<TerminalManager>>,
+    terminal_id: String,
+    bytes: usize,
+) -> Result<(), String> {
+    terminal_manager
+        .acknowledge(&terminal_id, bytes)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+/// Takes the input as a raw request body, so bytes that are not UTF-8, like
+/// Meta sent as a high bit, reach the backend unchanged. The terminal is
+/// named in the `Terminal-Id` header.
+#[command]
+pub async fn terminal_write(
+    terminal_manager: State<'_, Arc<TerminalManager>>,
+    request: Request<'_>,
+) -> Result<(), String> {
+    let InvokeBody::Raw(data) = request.body() else {
+        return Err("Terminal input must be sent as raw bytes".to_string());
+    };
+    let terminal_id = request
+        .headers()
+        .get(TERMINAL_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or("Missing Terminal-Id header")?;
+    terminal_manager
+        .write(terminal_id, data.clone())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn terminal_resize(
+    terminal_manager: State<'_, Arc<TerminalManager>>,
+    terminal_id: String,
+    rows: u16,
+    cols: u16,
+) -> Result<(), String> {
+    terminal_manager
+        .resize(&terminal_id, rows, cols)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn terminal_break(
+    terminal_manager: State<'_, Arc<TerminalManager>>,
+    terminal_id: String,
+) -> Result<(), String> {
+    terminal_manager
+        .send_break(&terminal_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn terminal_close(
+    terminal_manager: State<'_, Arc<TerminalManager>>,
+    terminal_id: String,
+) -> Result<(), String> {
+    terminal_manager.destroy_terminal(&terminal_id).await;
+    Ok(())
+}
+
+#[command]
+pub async fn terminal_search(
+    terminal_manager: State<'_, Arc<TerminalManager>>,
+    terminal_id: String,
+    pattern: String,
+) -> Result<Vec<ScrollbackMatch>, String> {
+    terminal_manager
+        .search(&terminal_id, &pattern)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn terminal_record_start(
+    terminal_manager: State<'_, Arc<TerminalManager>>,
+    terminal_id: String,
+    record_input: bool,
+) -> Result<String, String> {
+    terminal_manager
+        .start_recording(&terminal_id, record_input)
+        .await
+        .map(|path| path.to_string_lossy().to_string())
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn terminal_record_stop(
+    terminal_manager: State<'_, Arc<TerminalManager>>,
+    terminal_id: String,
+) -> Result<String, String> {
+    terminal_manager
+        .stop_recording(&terminal_id)
+        .await
+        .map(|path| path.to_string_lossy().to_string())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        pin::Pin,
+        task::{Context as TaskContext, Poll},
+    };
+    use tokio::io::ReadBuf;
+
+    // One line of the generated output
+    const LINE: &[u8] = "The quick brown fox jumps over the lazy dog 0123456789 ─│┌┐└┘ äöü €\r\n".as_bytes();
+
+    /// Printable output of a given length.
+    struct GeneratedOutput {
+        remaining: u64,
+        offset: usize,
+    }
+
+    impl AsyncRead for GeneratedOutput {
+        fn poll_read(mut self: Pin<&mut Self>, _cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+            while buf.remaining() > 0 && self.remaining > 0 {
+                let offset = self.offset;
+                let line = &LINE[offset..];
+                let n = line
+                    .len()
+                    .min(buf.remaining())
+                    .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+                buf.put_slice(&line[..n]);
+                self.offset = (offset + n) % LINE.len();
+                self.remaining -= n as u64;
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    /// Hands frame sizes to a task that acknowledges them, like a frontend
+    /// rendering as fast as it can. Acknowledging from `send` itself would
+    /// deadlock on the outlet.
+    struct Client {
+        frames: mpsc::UnboundedSender<usize>,
+    }
+
+    impl TerminalClient for Client {
+        fn send(&self, data: &[u8]) -> bool {
+            self.frames.send(data.len()).is_ok()
+        }
+    }
+
+    struct NoWindows;
+
+    impl WindowHandler for NoWindows {
+        fn send_to_all_windows(&self, _event: &str, _payload: serde_json::Value) {}
+        fn send_to_window(&self, _label: &str, _event: &str, _payload: serde_json::Value) {}
+    }
+
+    /// Pushes `bytes` of output through a subscribed outlet, returning what
+    /// the client received, in how many frames, and how long it took.
+    async fn pump(bytes: u64) -> (u64, usize, Duration) {
+        let outlet = Arc::new(Outlet::new(1024 * 1024));
+        let (frames, mut received) = mpsc::unbounded_channel();
+        outlet.subscribe(Box::new(Client { frames }), "main").unwrap();
+
+        let acknowledger = {
+            let outlet = Arc::clone(&outlet);
+            tokio::spawn(async move {
+                let (mut total, mut count) = (0, 0);
+                while let Some(n) = received.recv().await {
+                    outlet.acknowledge(n);
+                    total += n as u64;
+                    count += 1;
+                }
+                (total, count)
+            })
+        };
+
+        let started = Instant::now();
+        let (commands, _receiver) = mpsc::unbounded_channel();
+        forward_output(
+            "benchmark".to_string(),
+            Box::new(GeneratedOutput { remaining: bytes, offset: 0 }),
+            Arc::clone(&outlet),
+            commands,
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(NoWindows),
+        )
+        .await;
+        let elapsed = started.elapsed();
+
+        // Dropping the subscriber ends the acknowledging task
+        outlet.state.lock().unwrap().subscriber = None;
+        let (total, count) = acknowledger.await.unwrap();
+        (total, count, elapsed)
+    }
+
+    #[tokio::test]
+    async fn forwards_output_in_frames() {
+        let bytes = 4 * FRAME_SIZE as u64 + 10;
+        let (total, frames, _) = pump(bytes).await;
+        assert_eq!(total, bytes);
+        assert_eq!(frames, 5);
+    }
+
+    // cargo test --release throughput -- --ignored --nocapture
+    #[tokio::test]
+    #[ignore]
+    async fn throughput() {
+        let bytes = 512 * 1024 * 1024;
+        let (total, frames, elapsed) = pump(bytes).await;
+        assert_eq!(total, bytes);
+        println!(
+            "{} MiB in {} frames, {:.2?}: {:.0} MiB/s",
+            bytes / (1024 * 1024),
+            frames,
+            elapsed,
+            bytes as f64 / (1024.0 * 1024.0) / elapsed.as_secs_f64()
+        );
+    }
 }
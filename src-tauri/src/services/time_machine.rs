@@ -0,0 +1,817 @@
+use anyhow::{bail, Context, Result};
+use chrono::{TimeZone, Utc};
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+use tauri::{
+    command,
+    ipc::{Channel, InvokeResponseBody},
+    State, Window,
+};
+use tokio::{
+    sync::{Mutex, Notify},
+    task::JoinHandle,
+    time::{self, Instant},
+};
+use uuid::Uuid;
+
+use crate::models::{
+    ipc_channels::REPLAY_STATE,
+    recording::{RecordingInfo, ReplayInfo, ReplayState},
+};
+use crate::services::{
+    recorder::recordings_dir,
+    terminal_manager::{TerminalClient, WindowHandler},
+};
+
+// Recording time between screen snapshots, bounding how much output a seek
+// has to replay
+const KEYFRAME_INTERVAL: f64 = 10.0;
+const MIN_SPEED: f64 = 0.1;
+const MAX_SPEED: f64 = 64.0;
+// Resets the terminal before a snapshot is drawn
+const RESET: &[u8] = b"\x1bc";
+const ALTERNATE_SCREEN: &[u8] = b"\x1b[?1049h";
+// Leaves the alternate screen without restoring the cursor
+const PRIMARY_SCREEN: &[u8] = b"\x1b[?47l";
+
+enum Event {
+    Output(String),
+    Resize { rows: u16, cols: u16 },
+}
+
+/// The screen at some point of the recording.
+struct Keyframe {
+    time: f64,
+    // First event not reflected in `screen`
+    index: usize,
+    rows: u16,
+    cols: u16,
+    screen: Vec<u8>,
+}
+
+struct Recording {
+    // Events in order, input left out
+    events: Vec<(f64, Event)>,
+    keyframes: Vec<Keyframe>,
+    duration: f64,
+}
+
+impl Recording {
+    fn load(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let mut lines = BufReader::new(file).lines();
+
+        let header: serde_json::Value = serde_json::from_str(&lines.next().context("Recording is empty")??)
+            .context("Invalid asciicast header")?;
+        if header["version"] != 2 {
+            bail!("Only asciicast v2 recordings are supported");
+        }
+        let cols = header["width"].as_u64().context("Header has no width")? as u16;
+        let rows = header["height"].as_u64().context("Header has no height")? as u16;
+
+        let mut events = Vec::new();
+        let mut lines = lines.enumerate().peekable();
+        while let Some((number, line)) = lines.next() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (time, code, data) = match serde_json::from_str::<(f64, String, String)>(&line) {
+                Ok(event) => event,
+                // A recording still being written may end in a partial line
+                Err(_) if lines.peek().is_none() => break,
+                Err(e) => bail!("Invalid event on line {}: {}", number + 2, e),
+            };
+
+            let event = match code.as_str() {
+                "o" => Event::Output(data),
+                "r" => {
+                    let Some((cols, rows)) = data.split_once('x') else {
+                        bail!("Invalid resize on line {}: {}", number + 2, data);
+                    };
+                    Event::Resize {
+                        rows: rows.parse()?,
+                        cols: cols.parse()?,
+                    }
+                }
+                // Input and markers do not change the screen
+                _ => continue,
+            };
+            events.push((time, event));
+        }
+
+        let keyframes = index_keyframes(&events, rows, cols);
+        let duration = events.last().map_or(0.0, |(time, _)| *time);
+        Ok(Self {
+            events,
+            keyframes,
+            duration,
+        })
+    }
+
+    /// What to send a reset terminal for it to show the recording at `time`,
+    /// its size at that point, and the first event after it.
+    fn screen_at(&self, time: f64) -> (Vec<u8>, (u16, u16), usize) {
+        let keyframe = self
+            .keyframes
+            .iter()
+            .rev()
+            .find(|keyframe| keyframe.time <= time)
+            .unwrap_or(&self.keyframes[0]);
+
+        let mut screen = keyframe.screen.clone();
+        let mut size = (keyframe.rows, keyframe.cols);
+        let mut index = keyframe.index;
+        while let Some((event_time, event)) = self.events.get(index) {
+            if *event_time > time {
+                break;
+            }
+            match event {
+                Event::Output(data) => screen.extend_from_slice(data.as_bytes()),
+                Event::Resize { rows, cols } => {
+                    // Redraw what came before at the new size, as output
+                    // after it wraps at that size
+                    let mut emulator = Emulator::new(size.0, size.1);
+                    emulator.process(&screen);
+                    emulator.set_size(*rows, *cols);
+                    screen = emulator.snapshot();
+                    size = (*rows, *cols);
+                }
+            }
+            index += 1;
+        }
+        (screen, size, index)
+    }
+}
+
+/// Runs the recording through a terminal emulator, snapshotting the screen
+/// every KEYFRAME_INTERVAL seconds. The first keyframe is the empty screen.
+fn index_keyframes(events: &[(f64, Event)], rows: u16, cols: u16) -> Vec<Keyframe> {
+    let mut emulator = Emulator::new(rows, cols);
+    let mut keyframes = vec![Keyframe {
+        time: 0.0,
+        index: 0,
+        rows,
+        cols,
+        screen: emulator.snapshot(),
+    }];
+
+    for (index, (time, event)) in events.iter().enumerate() {
+        let last = keyframes.last().map_or(0.0, |keyframe| keyframe.time);
+        if index > 0 && *time - last >= KEYFRAME_INTERVAL {
+            let (rows, cols) = emulator.terminal.screen().size();
+            keyframes.push(Keyframe {
+                // The screen stays like this until the next event
+                time: events[index - 1].0,
+                index,
+                rows,
+                cols,
+                screen: emulator.snapshot(),
+            });
+        }
+
+        match event {
+            Event::Output(data) => emulator.process(data.as_bytes()),
+            Event::Resize { rows, cols } => emulator.set_size(*rows, *cols),
+        }
+    }
+
+    keyframes
+}
+
+/// A terminal emulator that can be snapshotted, including the primary
+/// screen hidden under the alternate one.
+struct Emulator {
+    terminal: vt100::Parser,
+    // Sees only output to the primary screen, holding it while the
+    // alternate screen is up
+    primary: vt100::Parser,
+}
+
+impl Emulator {
+    fn new(rows: u16, cols: u16) -> Self {
+        Self {
+            terminal: vt100::Parser::new(rows, cols, 0),
+            primary: vt100::Parser::new(rows, cols, 0),
+        }
+    }
+
+    fn process(&mut self, bytes: &[u8]) {
+        for byte in bytes.chunks(1) {
+            let alternate = self.terminal.screen().alternate_screen();
+            self.terminal.process(byte);
+            if !alternate {
+                self.primary.process(byte);
+                if self.terminal.screen().alternate_screen() {
+                    self.primary.process(PRIMARY_SCREEN);
+                }
+            }
+        }
+    }
+
+    fn set_size(&mut self, rows: u16, cols: u16) {
+        self.terminal.set_size(rows, cols);
+        self.primary.set_size(rows, cols);
+    }
+
+    /// What to send a reset terminal to show the same screen.
+    fn snapshot(&self) -> Vec<u8> {
+        let screen = self.terminal.screen();
+        let mut bytes = RESET.to_vec();
+        if screen.alternate_screen() {
+            bytes.extend(self.primary.screen().contents_formatted());
+            bytes.extend_from_slice(ALTERNATE_SCREEN);
+        }
+        bytes.extend(screen.state_formatted());
+        bytes
+    }
+}
+
+struct Playback {
+    position: f64,
+    playing: bool,
+    speed: f64,
+    // Set until the playback task has jumped there
+    seek: Option<f64>,
+    size: (u16, u16),
+}
+
+/// A recording being replayed, shared between the commands and its task.
+struct Replay {
+    playback: std::sync::Mutex<Playback>,
+    changed: Notify,
+}
+
+impl Replay {
+    fn state(&self, replay_id: &str) -> ReplayState {
+        let playback = self.playback.lock().unwrap();
+        ReplayState {
+            replay_id: replay_id.to_string(),
+            position: playback.position,
+            playing: playback.playing,
+            speed: playback.speed,
+            width: playback.size.1,
+            height: playback.size.0,
+        }
+    }
+
+    fn update(&self, change: impl FnOnce(&mut Playback)) {
+        change(&mut self.playback.lock().unwrap());
+        self.changed.notify_one();
+    }
+}
+
+struct OpenReplay {
+    replay: Arc<Replay>,
+    task: JoinHandle<()>,
+}
+
+/// Replays asciicast recordings into a terminal emulator, with seeking
+/// backed by keyframes so long recordings do not replay from the start.
+pub struct TimeMachine {
+    replays: Mutex<HashMap<String, OpenReplay>>,
+    window_handler: Arc<dyn WindowHandler>,
+}
+
+impl TimeMachine {
+    pub fn new(window_handler: Arc<dyn WindowHandler>) -> Self {
+        Self {
+            replays: Mutex::new(HashMap::new()),
+            window_handler,
+        }
+    }
+
+    /// Recordings in the recordings directory, newest first.
+    pub async fn list_recordings(&self) -> Result<Vec<RecordingInfo>> {
+        tokio::task::spawn_blocking(|| {
+            let mut recordings = Vec::new();
+            for entry in std::fs::read_dir(recordings_dir()?)? {
+                let path = entry?.path();
+                if path.extension().is_none_or(|extension| extension != "cast") {
+                    continue;
+                }
+                let size = std::fs::metadata(&path)?.len();
+
+                let header = std::fs::File::open(&path)
+                    .ok()
+                    .and_then(|file| BufReader::new(file).lines().next()?.ok())
+                    .and_then(|line| serde_json::from_str::<serde_json::Value>(&line).ok())
+                    .unwrap_or_default();
+                recordings.push(RecordingInfo {
+                    path: path.to_string_lossy().to_string(),
+                    title: header["title"].as_str().map(str::to_string),
+                    started_at: header["timestamp"]
+                        .as_i64()
+                        .and_then(|timestamp| Utc.timestamp_opt(timestamp, 0).single())
+                        .map(|time| time.to_rfc3339()),
+                    size,
+                });
+            }
+
+            recordings.sort_by(|a, b| b.started_at.cmp(&a.started_at));
+            Ok(recordings)
+        })
+        .await?
+    }
+
+    /// Loads a recording and starts a paused replay at its beginning, sending
+    /// output to `client` and state changes to the window labelled `window`.
+    pub async fn open(&self, path: &str, window: &str, client: Box<dyn TerminalClient>) -> Result<ReplayInfo> {
+        let path = Path::new(path).to_path_buf();
+        let recording = Arc::new(
+            tokio::task::spawn_blocking(move || {
+                // Only recordings made here can be replayed
+                let path = path
+                    .canonicalize()
+                    .with_context(|| format!("Failed to open {}", path.display()))?;
+                if !path.starts_with(recordings_dir()?.canonicalize()?) {
+                    bail!("{} is not a recording", path.display());
+                }
+                Recording::load(&path)
+            })
+            .await??,
+        );
+
+        let replay_id = Uuid::new_v4().to_string();
+        let first = &recording.keyframes[0];
+        let info = ReplayInfo {
+            replay_id: replay_id.clone(),
+            width: first.cols,
+            height: first.rows,
+            duration: recording.duration,
+            keyframes: recording.keyframes.iter().map(|keyframe| keyframe.time).collect(),
+        };
+        let replay = Arc::new(Replay {
+            playback: std::sync::Mutex::new(Playback {
+                position: 0.0,
+                playing: false,
+                speed: 1.0,
+                seek: Some(0.0),
+                size: (first.rows, first.cols),
+            }),
+            changed: Notify::new(),
+        });
+
+        let task = tokio::spawn(play(
+            replay_id.clone(),
+            recording,
+            Arc::clone(&replay),
+            client,
+            window.to_string(),
+            Arc::clone(&self.window_handler),
+        ));
+        self.replays
+            .lock()
+            .await
+            .insert(replay_id, OpenReplay { replay, task });
+        Ok(info)
+    }
+
+    pub async fn play(&self, replay_id: &str) -> Result<()> {
+        self.update(replay_id, |playback| playback.playing = true).await
+    }
+
+    pub async fn pause(&self, replay_id: &str) -> Result<()> {
+        self.update(replay_id, |playback| playback.playing = false).await
+    }
+
+    pub async fn set_speed(&self, replay_id: &str, speed: f64) -> Result<()> {
+        if !speed.is_finite() {
+            bail!("Invalid speed: {}", speed);
+        }
+        self.update(replay_id, |playback| playback.speed = speed.clamp(MIN_SPEED, MAX_SPEED))
+            .await
+    }
+
+    /// Jumps to `position` seconds into the recording, keeping the replay
+    /// playing or paused as it was.
+    pub async fn seek(&self, replay_id: &str, position: f64) -> Result<()> {
+        if !position.is_finite() {
+            bail!("Invalid position: {}", position);
+        }
+        self.update(replay_id, |playback| playback.seek = Some(position.max(0.0)))
+            .await
+    }
+
+    pub async fn close(&self, replay_id: &str) {
+        if let Some(open) = self.replays.lock().await.remove(replay_id) {
+            open.task.abort();
+        }
+    }
+
+    async fn update(&self, replay_id: &str, change: impl FnOnce(&mut Playback)) -> Result<()> {
+        let replays = self.replays.lock().await;
+        let open = replays.get(replay_id).context("Replay not found")?;
+        open.replay.update(change);
+        Ok(())
+    }
+}
+
+/// Sends the recording's output as it becomes due, coalescing everything
+/// due at once into one frame, until the client goes away.
+async fn play(
+    replay_id: String,
+    recording: Arc<Recording>,
+    replay: Arc<Replay>,
+    client: Box<dyn TerminalClient>,
+    window: String,
+    window_handler: Arc<dyn WindowHandler>,
+) {
+    let send_state = || {
+        let state = replay.state(&replay_id);
+        if let Ok(payload) = serde_json::to_value(state) {
+            window_handler.send_to_window(&window, REPLAY_STATE, payload);
+        }
+    };
+
+    let mut next = 0;
+    // Recording time at `anchor`, advancing from there at the current speed
+    let mut anchor = Instant::now();
+    let mut anchor_position = 0.0;
+    let (mut playing, mut speed) = (false, 1.0);
+
+    loop {
+        let (seek, changed) = {
+            let mut playback = replay.playback.lock().unwrap();
+            let changed = playback.playing != playing || playback.speed != speed;
+            if changed {
+                // Keep the position reached so far at the old speed
+                if playing {
+                    let position = anchor_position + anchor.elapsed().as_secs_f64() * speed;
+                    playback.position = position.min(recording.duration);
+                }
+                anchor = Instant::now();
+                anchor_position = playback.position;
+                (playing, speed) = (playback.playing, playback.speed);
+            }
+            (playback.seek.take(), changed)
+        };
+
+        if let Some(target) = seek {
+            let target = target.min(recording.duration);
+            let (screen, size, index) = recording.screen_at(target);
+            next = index;
+            anchor = Instant::now();
+            anchor_position = target;
+            replay.update(|playback| {
+                playback.position = target;
+                playback.size = size;
+            });
+            // The frontend resizes before drawing the screen
+            send_state();
+            if !client.send(&screen) {
+                return;
+            }
+            continue;
+        }
+        if changed {
+            send_state();
+        }
+
+        if !playing {
+            replay.changed.notified().await;
+            continue;
+        }
+        let Some((due, _)) = recording.events.get(next) else {
+            // Reached the end
+            replay.update(|playback| {
+                playback.position = recording.duration;
+                playback.playing = false;
+            });
+            continue;
+        };
+
+        let wait = (due - anchor_position) / speed;
+        let deadline = anchor + Duration::from_secs_f64(wait.max(0.0));
+        if deadline > Instant::now() {
+            tokio::select! {
+                _ = time::sleep_until(deadline) => {}
+                _ = replay.changed.notified() => continue,
+            }
+        }
+
+        let position = anchor_position + anchor.elapsed().as_secs_f64() * speed;
+        let mut frame = Vec::new();
+        let mut resized = None;
+        while let Some((time, event)) = recording.events.get(next) {
+            if *time > position {
+                break;
+            }
+            match event {
+                Event::Output(data) => frame.extend_from_slice(data.as_bytes()),
+                Event::Resize { rows, cols } => {
+                    // The frontend resizes before drawing what follows
+                    resized = Some((*rows, *cols));
+                    next += 1;
+                    break;
+                }
+            }
+            next += 1;
+        }
+
+        if !frame.is_empty() && !client.send(&frame) {
+            return;
+        }
+        let mut playback = replay.playback.lock().unwrap();
+        playback.position = position.min(recording.duration);
+        if let Some(size) = resized {
+            playback.size = size;
+            drop(playback);
+            send_state();
+        }
+    }
+}
+
+#[command]
+pub async fn replay_list(
+    time_machine: State<'_, Arc<TimeMachine>>,
+) -> Result<Vec<RecordingInfo>, String> {
+    time_machine.list_recordings().await.map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn replay_open(
+    time_machine: State<'_, Arc<TimeMachine>>,
+    window: Window,
+    path: String,
+    output: Channel<InvokeResponseBody>,
+) -> Result<ReplayInfo, String> {
+    time_machine
+        .open(&path, window.label(), Box::new(output))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn replay_play(
+    time_machine: State<'_, Arc<TimeMachine>>,
+    replay_id: String,
+) -> Result<(), String> {
+    time_machine.play(&replay_id).await.map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn replay_pause(
+    time_machine: State<'_, Arc<TimeMachine>>,
+    replay_id: String,
+) -> Result<(), String> {
+    time_machine.pause(&replay_id).await.map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn replay_set_speed(
+    time_machine: State<'_, Arc<TimeMachine>>,
+    replay_id: String,
+    speed: f64,
+) -> Result<(), String> {
+    time_machine
+        .set_speed(&replay_id, speed)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn replay_seek(
+    time_machine: State<'_, Arc<TimeMachine>>,
+    replay_id: String,
+    position: f64,
+) -> Result<(), String> {
+    time_machine
+        .seek(&replay_id, position)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn replay_close(
+    time_machine: State<'_, Arc<TimeMachine>>,
+    replay_id: String,
+) -> Result<(), String> {
+    time_machine.close(&replay_id).await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A .cast file with the given lines after an 80x24 header.
+    struct Cast(std::path::PathBuf);
+
+    impl Cast {
+        fn new(lines: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("replay-{}.cast", Uuid::new_v4()));
+            let header = r#"{"version": 2, "width": 80, "height": 24, "timestamp": 1700000000}"#;
+            std::fs::write(&path, format!("{}\n{}", header, lines)).unwrap();
+            Self(path)
+        }
+
+        fn load(&self) -> Result<Recording> {
+            Recording::load(&self.0)
+        }
+    }
+
+    impl Drop for Cast {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn output(time: f64, data: &str) -> (f64, Event) {
+        (time, Event::Output(data.to_string()))
+    }
+
+    fn resize(time: f64, rows: u16, cols: u16) -> (f64, Event) {
+        (time, Event::Resize { rows, cols })
+    }
+
+    /// What a terminal shows after drawing `screen`, sized as `screen_at` says.
+    fn replayed(recording: &Recording, time: f64) -> (String, (u16, u16)) {
+        let (screen, (rows, cols), _) = recording.screen_at(time);
+        let mut parser = vt100::Parser::new(rows, cols, 0);
+        parser.process(&screen);
+        (
+            parser.screen().contents(),
+            parser.screen().cursor_position(),
+        )
+    }
+
+    /// What a terminal shows after every event up to `time`, played in order.
+    fn played(recording: &Recording, time: f64) -> (String, (u16, u16)) {
+        let mut parser = vt100::Parser::new(24, 80, 0);
+        for (_, event) in recording.events.iter().take_while(|(at, _)| *at <= time) {
+            match event {
+                Event::Output(data) => parser.process(data.as_bytes()),
+                Event::Resize { rows, cols } => parser.set_size(*rows, *cols),
+            }
+        }
+        (
+            parser.screen().contents(),
+            parser.screen().cursor_position(),
+        )
+    }
+
+    fn recording(events: Vec<(f64, Event)>) -> Recording {
+        let keyframes = index_keyframes(&events, 24, 80);
+        let duration = events.last().map_or(0.0, |(time, _)| *time);
+        Recording {
+            events,
+            keyframes,
+            duration,
+        }
+    }
+
+    #[test]
+    fn loads_screen_events_and_skips_the_rest() {
+        let cast = Cast::new(concat!(
+            "[0.5, \"o\", \"hello\\r\\n\"]\n",
+            "[1.0, \"i\", \"ls\\r\"]\n",
+            "\n",
+            "[1.5, \"m\", \"marker\"]\n",
+            "[2.0, \"r\", \"100x30\"]\n",
+            "[2.5, \"o\", \"wörld\"]\n",
+        ));
+        let recording = cast.load().unwrap();
+        assert!(matches!(&recording.events[..], [
+            (0.5, Event::Output(hello)),
+            (2.0, Event::Resize { rows: 30, cols: 100 }),
+            (2.5, Event::Output(world)),
+        ] if hello == "hello\r\n" && world == "wörld"));
+        assert_eq!(recording.duration, 2.5);
+        assert_eq!(
+            (recording.keyframes[0].rows, recording.keyframes[0].cols),
+            (24, 80)
+        );
+    }
+
+    #[test]
+    fn truncated_last_line_is_dropped() {
+        let cast = Cast::new("[0.5, \"o\", \"done\"]\n[1.0, \"o\", \"hal");
+        let recording = cast.load().unwrap();
+        assert!(matches!(&recording.events[..], [(0.5, Event::Output(data))] if data == "done"));
+        assert_eq!(recording.duration, 0.5);
+    }
+
+    #[test]
+    fn truncated_line_before_the_end_is_an_error() {
+        let cast = Cast::new("[0.5, \"o\", \"hal\n[1.0, \"o\", \"done\"]\n");
+        let error = cast.load().err().unwrap().to_string();
+        assert!(error.starts_with("Invalid event on line 2"), "{}", error);
+
+        let cast = Cast::new("[0.5, \"r\", \"wide\"]\n[1.0, \"o\", \"done\"]\n");
+        let error = cast.load().err().unwrap().to_string();
+        assert_eq!(error, "Invalid resize on line 2: wide");
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let path = std::env::temp_dir().join(format!("replay-{}.cast", Uuid::new_v4()));
+        std::fs::write(&path, "{\"version\": 1, \"width\": 80, \"height\": 24}\n").unwrap();
+        let result = Recording::load(&path);
+        let _ = std::fs::remove_file(&path);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn keyframes_follow_the_interval() {
+        let events = (0..50)
+            .map(|second| output(second as f64, &format!("{}\r\n", second)))
+            .collect();
+        let recording = recording(events);
+        let keyframes: Vec<(f64, usize)> = recording
+            .keyframes
+            .iter()
+            .map(|keyframe| (keyframe.time, keyframe.index))
+            .collect();
+        // Each snapshot holds the screen after the last event before it
+        assert_eq!(
+            keyframes,
+            [
+                (0.0, 0),
+                (9.0, 10),
+                (18.0, 19),
+                (27.0, 28),
+                (36.0, 37),
+                (45.0, 46)
+            ]
+        );
+        assert_eq!(recording.screen_at(25.0).2, 26);
+        assert_eq!(recording.screen_at(100.0).2, 50);
+    }
+
+    #[test]
+    fn seeking_matches_playing_from_the_start() {
+        let mut events = Vec::new();
+        for second in 0..60 {
+            let time = second as f64;
+            events.push(output(
+                time,
+                &format!("\x1b[3{}mline {}\x1b[0m\r\n", second % 8, second),
+            ));
+            if second == 30 {
+                events.push(output(time, "\x1b[?1049h\x1b[Hfull screen"));
+            }
+            if second == 40 {
+                events.push(output(time, "\x1b[?1049l"));
+            }
+        }
+        let recording = recording(events);
+        assert!(recording.keyframes.len() > 3);
+
+        for tenth in 0..=620 {
+            let time = tenth as f64 / 10.0;
+            assert_eq!(
+                replayed(&recording, time),
+                played(&recording, time),
+                "at {}",
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn seeking_across_resizes_uses_the_size_at_that_point() {
+        let long = "x".repeat(70);
+        let mut events = vec![output(0.0, &format!("{}\r\n", long))];
+        // Keyframes land at 9, 18, 27 and 36s, the resizes between them
+        for second in 1..40 {
+            let time = second as f64;
+            events.push(output(time, &format!("{} {}\r\n", second, long)));
+            match second {
+                5 => events.push(resize(time + 0.5, 20, 40)),
+                25 => events.push(resize(time + 0.5, 30, 100)),
+                28 => events.push(resize(time + 0.5, 10, 60)),
+                _ => {}
+            }
+        }
+        let recording = recording(events);
+        assert!(recording
+            .keyframes
+            .iter()
+            .any(|keyframe| (keyframe.rows, keyframe.cols) == (20, 40)));
+
+        for (time, size) in [
+            (3.0, (24, 80)),
+            (6.0, (20, 40)),
+            (26.0, (30, 100)),
+            (35.0, (10, 60)),
+        ] {
+            assert_eq!(recording.screen_at(time).1, size, "at {}", time);
+        }
+        for tenth in 0..=400 {
+            let time = tenth as f64 / 10.0;
+            assert_eq!(
+                replayed(&recording, time),
+                played(&recording, time),
+                "at {}",
+                time
+            );
+        }
+    }
+}
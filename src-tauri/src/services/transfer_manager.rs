@@ -0,0 +1,1251 @@
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use russh_sftp::{
+    client::SftpSession,
+    protocol::{FileAttributes, OpenFlags},
+};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+use tauri::{command, State};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+    sync::{watch, Mutex, Semaphore},
+};
+use uuid::Uuid;
+
+use crate::models::{
+    ipc_channels::{TRANSFER_PROGRESS, TRANSFER_REMOVED, TRANSFER_UPDATED},
+    transfer::{Transfer, TransferDirection, TransferProgress, TransferStatus},
+};
+use crate::services::{
+    remote_tools::RemoteTools,
+    scp::{Record, ScpClient},
+    secure_storage::SecureStorage,
+    sftp::{
+        create_dir_all, file_name, is_dir, is_safe_name, is_symlink, join_remote, SftpService,
+        Transport, TRANSFER_BUFFER_SIZE,
+    },
+    terminal_manager::WindowHandler,
+};
+
+const DEFAULT_TRANSFERS_PER_HOST: u32 = 2;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+// How often the resume offset is recorded while copying. The store is
+// written at most this often for all running transfers together.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Control {
+    Run,
+    Pause,
+    Cancel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    Completed,
+    Paused,
+    Cancelled,
+}
+
+/// Persistent SFTP transfer queue. Transfers run in the background, at most
+/// `max_transfers_per_host` at a time per host, and resume from the last
+/// checkpointed offset after a pause, a failure or an app restart. Hosts
+/// without the sftp subsystem are served over SCP, which always restarts
+/// a file from the beginning.
+pub struct TransferManager {
+    storage: Arc<SecureStorage>,
+    sftp_service: Arc<SftpService>,
+    remote_tools: Arc<RemoteTools>,
+    window_handler: Arc<dyn WindowHandler>,
+    transfers: Mutex<HashMap<String, Transfer>>,
+    controls: Mutex<HashMap<String, watch::Sender<Control>>>,
+    // Per-host limit and the semaphore enforcing it
+    host_slots: Mutex<HashMap<String, (u32, Arc<Semaphore>)>>,
+    // Files queued again while a started transfer of them was unfinished,
+    // keyed by that transfer, with the new size and verify flag
+    follow_ups: Mutex<HashMap<String, (u64, bool)>>,
+    // When checkpoints were last written to the store
+    checkpoints_saved: Mutex<Instant>,
+}
+
+impl TransferManager {
+    pub fn new(
+        storage: Arc<SecureStorage>,
+        sftp_service: Arc<SftpService>,
+        remote_tools: Arc<RemoteTools>,
+        window_handler: Arc<dyn WindowHandler>,
+    ) -> Self {
+        Self {
+            storage,
+            sftp_service,
+            remote_tools,
+            window_handler,
+            transfers: Mutex::new(HashMap::new()),
+            controls: Mutex::new(HashMap::new()),
+            host_slots: Mutex::new(HashMap::new()),
+            follow_ups: Mutex::new(HashMap::new()),
+            checkpoints_saved: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Loads the queue saved by a previous run. Whatever was queued or in
+    /// flight when the app went away stays queued until the user resumes
+    /// it, so starting the app never connects to a host by itself.
+    pub async fn restore(&self) -> Result<()> {
+        let mut saved = self.storage.get_all_transfers().await?;
+        saved.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+
+        let mut interrupted = Vec::new();
+        {
+            let mut transfers = self.transfers.lock().await;
+            for mut transfer in saved {
+                if matches!(transfer.status, TransferStatus::Running | TransferStatus::Verifying) {
+                    transfer.status = TransferStatus::Queued;
+                    interrupted.push(transfer.clone());
+                }
+                transfers.insert(transfer.id.clone(), transfer);
+            }
+        }
+
+        self.storage.save_transfers(&interrupted).await
+    }
+
+    /// Saves the checkpoints of transfers still in flight, for when the app
+    /// exits.
+    pub async fn shutdown(&self) -> Result<()> {
+        let transfers = self.transfers.lock().await;
+        self.storage.save_transfers(&unfinished(&transfers)).await
+    }
+
+    pub async fn list(&self) -> Vec<Transfer> {
+        let mut transfers: Vec<Transfer> = self.transfers.lock().await.values().cloned().collect();
+        transfers.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+        transfers
+    }
+
+    /// Queues `sources` for copying into `target_dir`. Directories are
+    /// expanded into one transfer per file, keeping their layout.
+    pub async fn enqueue(
+        self: &Arc<Self>,
+        host_id: &str,
+        direction: TransferDirection,
+        sources: &[String],
+        target_dir: &str,
+        verify: bool,
+    ) -> Result<Vec<Transfer>> {
+        let mut files = Vec::new();
+        // Entries the server could not read, as (local, remote path, error)
+        let mut unreadable = Vec::new();
+        match direction {
+            TransferDirection::Upload => {
+                for source in sources {
+                    let root = Path::new(source);
+                    let name = root
+                        .file_name()
+                        .context("Cannot upload a path without a file name")?
+                        .to_string_lossy()
+                        .to_string();
+                    let mut found = Vec::new();
+                    collect_local(root, "", &mut found).await?;
+                    for (local_path, relative, size) in found {
+                        let remote_path = join_remote(&join_remote(target_dir, &name), &relative);
+                        files.push((local_path.to_string_lossy().to_string(), remote_path, size));
+                    }
+                }
+            }
+            TransferDirection::Download => {
+                let transport = self.sftp_service.transport(host_id).await?;
+                for source in sources {
+                    let root = Path::new(target_dir).join(file_name(source));
+                    let local_path = |relative: &str| -> Result<String> {
+                        let mut local_path = root.clone();
+                        for part in relative.split('/').filter(|part| !part.is_empty()) {
+                            // Server names must not lead out of the target
+                            if !is_safe_name(part) {
+                                bail!("Server returned an invalid path {:?}", relative);
+                            }
+                            local_path.push(part);
+                        }
+                        Ok(local_path.to_string_lossy().to_string())
+                    };
+                    let mut found = Vec::new();
+                    let mut failed = Vec::new();
+                    match &transport {
+                        Transport::Sftp(client) => {
+                            collect_remote(&client.sftp, source, "", &mut found).await?
+                        }
+                        Transport::Scp(client) => {
+                            collect_scp(client, source, &mut found, &mut failed).await?
+                        }
+                    }
+                    for (remote_path, relative, size) in found {
+                        files.push((local_path(&relative)?, remote_path, size));
+                    }
+                    for (remote_path, relative, error) in failed {
+                        unreadable.push((local_path(&relative)?, remote_path, error));
+                    }
+                }
+            }
+        }
+
+        let mut queued = self.enqueue_files(host_id, direction, files, verify).await?;
+        if !unreadable.is_empty() {
+            queued.extend(self.add_failed(host_id, direction, unreadable, verify).await?);
+        }
+        Ok(queued)
+    }
+
+    /// Queues individual files as (local path, remote path, size). A file
+    /// whose transfer between the same paths is still queued is left to it,
+    /// since it reads the file when it starts. One that has already started
+    /// may have copied older contents, so the file is queued again once
+    /// that transfer finishes.
+    pub async fn enqueue_files(
+        self: &Arc<Self>,
+        host_id: &str,
+        direction: TransferDirection,
+        files: Vec<(String, String, u64)>,
+        verify: bool,
+    ) -> Result<Vec<Transfer>> {
+        let now = Utc::now().to_rfc3339();
+        let queued: Vec<Transfer> = {
+            let mut transfers = self.transfers.lock().await;
+            let mut follow_ups = self.follow_ups.lock().await;
+            let mut files_to_queue = Vec::new();
+            for (local_path, remote_path, size) in files {
+                let pending = transfers.values().find(|t| {
+                    !t.status.is_finished()
+                        && t.host_id == host_id
+                        && t.direction == direction
+                        && t.local_path == local_path
+                        && t.remote_path == remote_path
+                });
+                match pending {
+                    None => files_to_queue.push((local_path, remote_path, size)),
+                    Some(t) if t.status == TransferStatus::Queued && t.transferred == 0 => {}
+                    Some(t) => {
+                        follow_ups.insert(t.id.clone(), (size, verify));
+                    }
+                }
+            }
+            let queued = files_to_queue
+                .into_iter()
+                .map(|(local_path, remote_path, size)| {
+                    new_transfer(host_id, direction, local_path, remote_path, size, verify, &now)
+                })
+                .collect::<Vec<_>>();
+            for transfer in &queued {
+                transfers.insert(transfer.id.clone(), transfer.clone());
+            }
+            queued
+        };
+
+        self.storage.save_transfers(&queued).await?;
+        for transfer in &queued {
+            self.publish(transfer);
+            self.schedule(transfer.id.clone());
+        }
+
+        Ok(queued)
+    }
+
+    /// Lists files that could not be read while expanding a directory as
+    /// failed transfers, so they show up with the rest and can be retried.
+    async fn add_failed(
+        &self,
+        host_id: &str,
+        direction: TransferDirection,
+        files: Vec<(String, String, String)>,
+        verify: bool,
+    ) -> Result<Vec<Transfer>> {
+        let now = Utc::now().to_rfc3339();
+        let failed: Vec<Transfer> = files
+            .into_iter()
+            .map(|(local_path, remote_path, error)| Transfer {
+                status: TransferStatus::Failed,
+                error: Some(error),
+                ..new_transfer(host_id, direction, local_path, remote_path, 0, verify, &now)
+            })
+            .collect();
+
+        {
+            let mut transfers = self.transfers.lock().await;
+            for transfer in &failed {
+                transfers.insert(transfer.id.clone(), transfer.clone());
+            }
+        }
+        self.storage.save_transfers(&failed).await?;
+        for transfer in &failed {
+            self.publish(transfer);
+        }
+        Ok(failed)
+    }
+
+    /// Pauses a running transfer at the next chunk boundary, or holds a
+    /// queued one back.
+    pub async fn pause(&self, transfer_id: &str) -> Result<()> {
+        let status = self.status(transfer_id).await?;
+        match status {
+            TransferStatus::Running | TransferStatus::Verifying => {
+                self.signal(transfer_id, Control::Pause).await;
+                Ok(())
+            }
+            TransferStatus::Queued => {
+                self.update(transfer_id, |t| t.status = TransferStatus::Paused)
+                    .await;
+                Ok(())
+            }
+            _ => bail!("Only queued or running transfers can be paused"),
+        }
+    }
+
+    /// Starts a queued transfer restored from a previous run, or requeues a
+    /// paused, failed or cancelled one. All but cancelled ones pick up from
+    /// their last checkpoint.
+    pub async fn resume(self: &Arc<Self>, transfer_id: &str) -> Result<()> {
+        let status = self.status(transfer_id).await?;
+        if !matches!(
+            status,
+            TransferStatus::Queued
+                | TransferStatus::Paused
+                | TransferStatus::Failed
+                | TransferStatus::Cancelled
+        ) {
+            bail!("Only queued, paused, failed or cancelled transfers can be resumed");
+        }
+
+        self.update(transfer_id, |t| {
+            t.status = TransferStatus::Queued;
+            t.error = None;
+        })
+        .await;
+        self.schedule(transfer_id.to_string());
+        Ok(())
+    }
+
+    /// Stops a transfer for good and removes the partial copy.
+    pub async fn cancel(&self, transfer_id: &str) -> Result<()> {
+        let status = self.status(transfer_id).await?;
+        match status {
+            TransferStatus::Running | TransferStatus::Verifying => {
+                self.signal(transfer_id, Control::Cancel).await;
+            }
+            TransferStatus::Queued | TransferStatus::Paused | TransferStatus::Failed => {
+                self.follow_ups.lock().await.remove(transfer_id);
+                if let Some(transfer) = self
+                    .update(transfer_id, |t| {
+                        t.status = TransferStatus::Cancelled;
+                        t.transferred = 0;
+                    })
+                    .await
+                {
+                    self.discard_partial(&transfer).await;
+                }
+            }
+            _ => bail!("Transfer has already finished"),
+        }
+        Ok(())
+    }
+
+    /// Drops finished transfers from the queue.
+    pub async fn remove(&self, transfer_ids: &[String]) -> Result<()> {
+        let removed: Vec<String> = {
+            let mut transfers = self.transfers.lock().await;
+            let active = transfer_ids
+                .iter()
+                .filter_map(|id| transfers.get(id))
+                .any(|t| !t.status.is_finished() && t.status != TransferStatus::Paused);
+            if active {
+                bail!("Cancel the transfer before removing it");
+            }
+            transfer_ids
+                .iter()
+                .filter(|id| transfers.remove(*id).is_some())
+                .cloned()
+                .collect()
+        };
+
+        self.storage.delete_transfers(&removed).await?;
+        for transfer_id in removed {
+            self.window_handler
+                .send_to_all_windows(TRANSFER_REMOVED, serde_json::json!({ "id": transfer_id }));
+        }
+        Ok(())
+    }
+
+    pub async fn clear_finished(&self) -> Result<()> {
+        let finished: Vec<String> = self
+            .transfers
+            .lock()
+            .await
+            .values()
+            .filter(|t| t.status.is_finished())
+            .map(|t| t.id.clone())
+            .collect();
+        self.remove(&finished).await
+    }
+
+    fn schedule(self: &Arc<Self>, transfer_id: String) {
+        let manager = Arc::clone(self);
+        tokio::spawn(async move { manager.run(transfer_id).await });
+    }
+
+    async fn run(self: &Arc<Self>, transfer_id: String) {
+        let Some(host_id) = self
+            .transfers
+            .lock()
+            .await
+            .get(&transfer_id)
+            .map(|t| t.host_id.clone())
+        else {
+            return;
+        };
+
+        let slots = self.host_slots(&host_id).await;
+        let Ok(_permit) = slots.acquire_owned().await else {
+            return;
+        };
+
+        // The transfer may have been paused or cancelled while it waited
+        let (control_tx, control_rx) = watch::channel(Control::Run);
+        let transfer = {
+            let mut transfers = self.transfers.lock().await;
+            let Some(transfer) = transfers.get_mut(&transfer_id) else {
+                return;
+            };
+            if transfer.status != TransferStatus::Queued {
+                return;
+            }
+            transfer.status = TransferStatus::Running;
+            transfer.updated_at = Utc::now().to_rfc3339();
+            self.controls
+                .lock()
+                .await
+                .insert(transfer_id.clone(), control_tx);
+            transfer.clone()
+        };
+        self.persist(&transfer).await;
+
+        let result = self.execute(&transfer, control_rx).await;
+        self.controls.lock().await.remove(&transfer_id);
+
+        match result {
+            Ok((Outcome::Completed, checksum)) => {
+                self.update(&transfer_id, |t| {
+                    t.status = TransferStatus::Completed;
+                    t.transferred = t.size;
+                    t.checksum = checksum;
+                })
+                .await;
+            }
+            Ok((Outcome::Paused, _)) => {
+                self.update(&transfer_id, |t| t.status = TransferStatus::Paused)
+                    .await;
+            }
+            Ok((Outcome::Cancelled, _)) => {
+                self.update(&transfer_id, |t| {
+                    t.status = TransferStatus::Cancelled;
+                    t.transferred = 0;
+                })
+                .await;
+            }
+            Err(e) => {
+                self.update(&transfer_id, |t| {
+                    t.status = TransferStatus::Failed;
+                    t.error = Some(format!("{:#}", e));
+                })
+                .await;
+            }
+        }
+
+        self.follow_up(&transfer_id).await;
+    }
+
+    /// Queues the file again if it changed while the transfer that just
+    /// stopped was under way. A paused transfer keeps its follow-up until
+    /// it finishes, a cancelled one drops it.
+    async fn follow_up(self: &Arc<Self>, transfer_id: &str) {
+        let Some(transfer) = self.transfers.lock().await.get(transfer_id).cloned() else {
+            return;
+        };
+        if !transfer.status.is_finished() {
+            return;
+        }
+        let Some((size, verify)) = self.follow_ups.lock().await.remove(transfer_id) else {
+            return;
+        };
+        if transfer.status == TransferStatus::Cancelled {
+            return;
+        }
+
+        let file = (transfer.local_path.clone(), transfer.remote_path.clone(), size);
+        if let Err(e) = self
+            .enqueue_files(&transfer.host_id, transfer.direction, vec![file], verify)
+            .await
+        {
+            log::error!("Failed to queue {} again: {}", transfer.local_path, e);
+        }
+    }
+
+    async fn execute(
+        &self,
+        transfer: &Transfer,
+        control: watch::Receiver<Control>,
+    ) -> Result<(Outcome, Option<String>)> {
+        let transport = self.sftp_service.transport(&transfer.host_id).await?;
+
+        let outcome = match (&transport, transfer.direction) {
+            (Transport::Sftp(client), TransferDirection::Upload) => {
+                self.upload(&client.sftp, transfer, &control).await?
+            }
+            (Transport::Sftp(client), TransferDirection::Download) => {
+                self.download(&client.sftp, transfer, &control).await?
+            }
+            (Transport::Scp(client), TransferDirection::Upload) => {
+                self.scp_upload(client, transfer, &control).await?
+            }
+            (Transport::Scp(client), TransferDirection::Download) => {
+                self.scp_download(client, transfer, &control).await?
+            }
+        };
+        // SCP cannot write at an offset, the next attempt starts over
+        if outcome == Outcome::Paused && matches!(transport, Transport::Scp(_)) {
+            self.update(&transfer.id, |t| t.transferred = 0).await;
+        }
+        if outcome == Outcome::Cancelled {
+            self.discard_partial(transfer).await;
+        }
+        if outcome != Outcome::Completed || !transfer.verify {
+            return Ok((outcome, None));
+        }
+
+        self.update(&transfer.id, |t| {
+            t.status = TransferStatus::Verifying;
+            t.transferred = t.size;
+        })
+        .await;
+        let local = sha256(
+            &mut fs::File::open(&transfer.local_path).await?,
+            &control,
+        )
+        .await?;
+        let remote_sha256 = match local {
+            Some(_) => {
+                self.remote_tools
+                    .remote_sha256(&transfer.host_id, &transfer.remote_path)
+                    .await
+            }
+            None => Ok(None),
+        };
+        let remote = match (remote_sha256, &transport) {
+            // Hashed on the server, nothing has to be read back
+            (Ok(Some(digest)), _) => Some(digest),
+            (_, Transport::Sftp(client)) => {
+                sha256(&mut client.sftp.open(transfer.remote_path.as_str()).await?, &control).await?
+            }
+            (_, Transport::Scp(client)) => {
+                let mut scp = client.receive(&transfer.remote_path, false).await?;
+                let (size, _) = scp.file_record(&transfer.remote_path).await?;
+                scp.accept().await?;
+                sha256(&mut scp.reader(size), &control).await?
+            }
+        };
+
+        match (local, remote) {
+            (Some(local), Some(remote)) if local == remote => Ok((Outcome::Completed, Some(local))),
+            (Some(local), Some(remote)) => {
+                // Nothing on the target can be trusted any more, start over next time
+                self.update(&transfer.id, |t| t.transferred = 0).await;
+                bail!("Checksum mismatch: local {} but remote {}", local, remote)
+            }
+            // Interrupted while hashing; the copy itself is complete
+            _ => Ok((
+                match *control.borrow() {
+                    Control::Cancel => Outcome::Cancelled,
+                    _ => Outcome::Paused,
+                },
+                None,
+            )),
+        }
+    }
+
+    async fn upload(
+        &self,
+        sftp: &SftpSession,
+        transfer: &Transfer,
+        control: &watch::Receiver<Control>,
+    ) -> Result<Outcome> {
+        let mut source = fs::File::open(&transfer.local_path)
+            .await
+            .with_context(|| format!("Failed to open {}", transfer.local_path))?;
+        let metadata = source.metadata().await?;
+        let size = metadata.len();
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|elapsed| elapsed.as_secs() as u32);
+
+        if let Some((parent, _)) = transfer.remote_path.rsplit_once('/') {
+            if !parent.is_empty() {
+                create_dir_all(sftp, parent).await?;
+            }
+        }
+
+        let existing = sftp
+            .metadata(transfer.remote_path.as_str())
+            .await
+            .ok()
+            .and_then(|m| m.size)
+            .unwrap_or(0);
+        // Only bytes we checkpointed count, an unrelated file may be in the way
+        let offset = transfer.transferred.min(existing).min(size);
+        let flags = match offset {
+            0 => OpenFlags::CREATE | OpenFlags::TRUNCATE | OpenFlags::WRITE,
+            _ => OpenFlags::CREATE | OpenFlags::WRITE,
+        };
+        let mut target = sftp
+            .open_with_flags(transfer.remote_path.as_str(), flags)
+            .await
+            .with_context(|| format!("Failed to create {}", transfer.remote_path))?;
+
+        source.seek(SeekFrom::Start(offset)).await?;
+        target.seek(SeekFrom::Start(offset)).await?;
+        self.update(&transfer.id, |t| t.size = size).await;
+
+        let outcome = self
+            .copy(&transfer.id, &mut source, &mut target, offset, size, control)
+            .await?;
+        target.shutdown().await?;
+
+        if outcome == Outcome::Completed {
+            let mut attributes = FileAttributes::empty();
+            // Drop whatever an older, longer copy left past the end
+            if existing > size {
+                attributes.size = Some(size);
+            }
+            // Keep the source's mtime so size+mtime comparisons line up later
+            attributes.atime = mtime;
+            attributes.mtime = mtime;
+            sftp.set_metadata(transfer.remote_path.as_str(), attributes)
+                .await?;
+        }
+
+        Ok(outcome)
+    }
+
+    async fn download(
+        &self,
+        sftp: &SftpSession,
+        transfer: &Transfer,
+        control: &watch::Receiver<Control>,
+    ) -> Result<Outcome> {
+        let mut source = sftp
+            .open(transfer.remote_path.as_str())
+            .await
+            .with_context(|| format!("Failed to open {}", transfer.remote_path))?;
+        let metadata = source.metadata().await?;
+        let size = metadata.size.unwrap_or(0);
+
+        let local_path = PathBuf::from(&transfer.local_path);
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        let existing = fs::metadata(&local_path).await.map_or(0, |m| m.len());
+        let offset = transfer.transferred.min(existing).min(size);
+        let mut target = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&local_path)
+            .await
+            .with_context(|| format!("Failed to create {}", local_path.display()))?;
+        target.set_len(offset).await?;
+
+        source.seek(SeekFrom::Start(offset)).await?;
+        target.seek(SeekFrom::Start(offset)).await?;
+        self.update(&transfer.id, |t| t.size = size).await;
+
+        let outcome = self
+            .copy(&transfer.id, &mut source, &mut target, offset, size, control)
+            .await?;
+        target.flush().await?;
+
+        if let (Outcome::Completed, Some(mtime)) = (outcome, metadata.mtime) {
+            target
+                .into_std()
+                .await
+                .set_modified(UNIX_EPOCH + Duration::from_secs(mtime.into()))?;
+        }
+
+        Ok(outcome)
+    }
+
+    async fn scp_upload(
+        &self,
+        scp: &ScpClient,
+        transfer: &Transfer,
+        control: &watch::Receiver<Control>,
+    ) -> Result<Outcome> {
+        let source = fs::File::open(&transfer.local_path)
+            .await
+            .with_context(|| format!("Failed to open {}", transfer.local_path))?;
+        let metadata = source.metadata().await?;
+        let size = metadata.len();
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|elapsed| elapsed.as_secs() as u32);
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            metadata.permissions().mode()
+        };
+        #[cfg(not(unix))]
+        let mode = 0o644;
+
+        // Walking down from the root announces each parent as a directory,
+        // which creates the missing ones like `create_dir_all` does
+        let (root, path) = match transfer.remote_path.strip_prefix('/') {
+            Some(path) => ("/", path),
+            None => (".", transfer.remote_path.as_str()),
+        };
+        let mut parents: Vec<&str> = path
+            .split('/')
+            .filter(|part| !part.is_empty() && *part != ".")
+            .collect();
+        let name = parents
+            .pop()
+            .with_context(|| format!("Failed to create {}", transfer.remote_path))?;
+
+        let mut target = scp.send(root).await?;
+        for parent in parents {
+            target.enter_dir(parent, 0o755).await?;
+        }
+        target
+            .begin_file(name, mode, size, mtime)
+            .await
+            .with_context(|| format!("Failed to create {}", transfer.remote_path))?;
+        self.update(&transfer.id, |t| t.size = size).await;
+
+        // The header promised exactly `size` bytes
+        let mut source = source.take(size);
+        let outcome = self
+            .copy(&transfer.id, &mut source, target.writer(), 0, size, control)
+            .await?;
+        if outcome == Outcome::Completed {
+            if source.limit() > 0 {
+                bail!("{} changed while it was being uploaded", transfer.local_path);
+            }
+            target.end_file().await?;
+            target.finish().await?;
+        }
+
+        Ok(outcome)
+    }
+
+    async fn scp_download(
+        &self,
+        scp: &ScpClient,
+        transfer: &Transfer,
+        control: &watch::Receiver<Control>,
+    ) -> Result<Outcome> {
+        let mut source = scp
+            .receive(&transfer.remote_path, false)
+            .await
+            .with_context(|| format!("Failed to open {}", transfer.remote_path))?;
+        let (size, mtime) = source
+            .file_record(&transfer.remote_path)
+            .await
+            .with_context(|| format!("Failed to open {}", transfer.remote_path))?;
+
+        let local_path = PathBuf::from(&transfer.local_path);
+        if let Some(parent) = local_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let mut target = fs::File::create(&local_path)
+            .await
+            .with_context(|| format!("Failed to create {}", local_path.display()))?;
+        self.update(&transfer.id, |t| t.size = size).await;
+
+        source.accept().await?;
+        let mut content = source.reader(size);
+        let outcome = self
+            .copy(&transfer.id, &mut content, &mut target, 0, size, control)
+            .await?;
+        if outcome == Outcome::Completed && content.limit() > 0 {
+            bail!("Connection closed while downloading {}", transfer.remote_path);
+        }
+        target.flush().await?;
+
+        if outcome == Outcome::Completed {
+            source.complete_file().await?;
+            if let Some(mtime) = mtime {
+                target
+                    .into_std()
+                    .await
+                    .set_modified(UNIX_EPOCH + Duration::from_secs(mtime.into()))?;
+            }
+        }
+
+        Ok(outcome)
+    }
+
+    /// Copies from `offset` to the end, publishing progress and saving the
+    /// offset now and then so an interrupted transfer can resume.
+    async fn copy<R, W>(
+        &self,
+        transfer_id: &str,
+        reader: &mut R,
+        writer: &mut W,
+        offset: u64,
+        size: u64,
+        control: &watch::Receiver<Control>,
+    ) -> Result<Outcome>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut buffer = vec![0u8; TRANSFER_BUFFER_SIZE];
+        let mut transferred = offset;
+        let started = Instant::now();
+        let mut last_progress = started;
+        let mut last_checkpoint = started;
+
+        loop {
+            let signal = *control.borrow();
+            match signal {
+                Control::Run => {}
+                Control::Pause => {
+                    writer.flush().await?;
+                    self.update(transfer_id, |t| t.transferred = transferred)
+                        .await;
+                    return Ok(Outcome::Paused);
+                }
+                Control::Cancel => return Ok(Outcome::Cancelled),
+            }
+
+            let n = reader.read(&mut buffer).await?;
+            if n == 0 {
+                self.report_progress(transfer_id, transferred, size, offset, started);
+                return Ok(Outcome::Completed);
+            }
+            writer.write_all(&buffer[..n]).await?;
+            transferred += n as u64;
+
+            let now = Instant::now();
+            if now.duration_since(last_progress) >= PROGRESS_INTERVAL {
+                self.report_progress(transfer_id, transferred, size, offset, started);
+                last_progress = now;
+            }
+            if now.duration_since(last_checkpoint) >= CHECKPOINT_INTERVAL {
+                // Only record bytes that have actually left our buffers
+                writer.flush().await?;
+                self.checkpoint(transfer_id, transferred).await;
+                last_checkpoint = now;
+            }
+        }
+    }
+
+    fn report_progress(&self, transfer_id: &str, transferred: u64, size: u64, offset: u64, started: Instant) {
+        let elapsed = started.elapsed().as_secs_f64();
+        let rate = match elapsed > 0.0 {
+            true => (transferred - offset) as f64 / elapsed,
+            false => 0.0,
+        };
+        let eta_seconds = (rate > 0.0).then(|| (size.saturating_sub(transferred) as f64 / rate) as u64);
+
+        let progress = TransferProgress {
+            id: transfer_id.to_string(),
+            transferred,
+            size,
+            rate,
+            eta_seconds,
+        };
+        if let Ok(payload) = serde_json::to_value(progress) {
+            self.window_handler
+                .send_to_all_windows(TRANSFER_PROGRESS, payload);
+        }
+    }
+
+    /// Best-effort removal of a cancelled transfer's partial copy.
+    async fn discard_partial(&self, transfer: &Transfer) {
+        let result = match transfer.direction {
+            TransferDirection::Download => fs::remove_file(&transfer.local_path)
+                .await
+                .map_err(anyhow::Error::from),
+            // SCP has no way to delete files, the partial upload stays
+            TransferDirection::Upload if self.sftp_service.uses_scp(&transfer.host_id).await => {
+                return;
+            }
+            TransferDirection::Upload => match self.sftp_service.client(&transfer.host_id).await {
+                Ok(client) => client
+                    .sftp
+                    .remove_file(transfer.remote_path.as_str())
+                    .await
+                    .map_err(anyhow::Error::from),
+                Err(e) => Err(e),
+            },
+        };
+        if let Err(e) = result {
+            log::warn!("Failed to remove partial transfer {}: {}", transfer.id, e);
+        }
+    }
+
+    async fn host_slots(&self, host_id: &str) -> Arc<Semaphore> {
+        let limit = self
+            .storage
+            .get_user_settings()
+            .await
+            .ok()
+            .flatten()
+            .and_then(|settings| settings.max_transfers_per_host)
+            .unwrap_or(DEFAULT_TRANSFERS_PER_HOST)
+            .max(1);
+
+        let mut slots = self.host_slots.lock().await;
+        let (current, semaphore) = slots
+            .entry(host_id.to_string())
+            .or_insert_with(|| (limit, Arc::new(Semaphore::new(limit as usize))));
+        // The semaphore is resized rather than replaced, so transfers already
+        // waiting on it count against the new limit too
+        if limit > *current {
+            semaphore.add_permits((limit - *current) as usize);
+        } else if limit < *current {
+            // Shrinks as running transfers hand their permits back
+            let semaphore = Arc::clone(semaphore);
+            let excess = *current - limit;
+            tokio::spawn(async move {
+                if let Ok(permits) = semaphore.acquire_many_owned(excess).await {
+                    permits.forget();
+                }
+            });
+        }
+        *current = limit;
+        Arc::clone(semaphore)
+    }
+
+    async fn status(&self, transfer_id: &str) -> Result<TransferStatus> {
+        self.transfers
+            .lock()
+            .await
+            .get(transfer_id)
+            .map(|t| t.status)
+            .context("Transfer not found")
+    }
+
+    async fn signal(&self, transfer_id: &str, signal: Control) {
+        if let Some(control) = self.controls.lock().await.get(transfer_id) {
+            let _ = control.send(signal);
+        }
+    }
+
+    /// Records a resume offset, saving every unfinished transfer's offset
+    /// when the store was last written a CHECKPOINT_INTERVAL ago, so a crash
+    /// loses little. Progress events keep the UI up to date meanwhile.
+    async fn checkpoint(&self, transfer_id: &str, transferred: u64) {
+        let mut transfers = self.transfers.lock().await;
+        if let Some(transfer) = transfers.get_mut(transfer_id) {
+            transfer.transferred = transferred;
+        }
+
+        let mut saved = self.checkpoints_saved.lock().await;
+        if saved.elapsed() < CHECKPOINT_INTERVAL {
+            return;
+        }
+        *saved = Instant::now();
+        // Saved under the lock, so a newer status saved by `update` is never
+        // overwritten with an older copy
+        if let Err(e) = self.storage.save_transfers(&unfinished(&transfers)).await {
+            log::error!("Failed to save transfer checkpoints: {}", e);
+        }
+    }
+
+    /// Applies `change` to a transfer, then saves and publishes it.
+    async fn update(&self, transfer_id: &str, change: impl FnOnce(&mut Transfer)) -> Option<Transfer> {
+        let transfer = {
+            let mut transfers = self.transfers.lock().await;
+            let transfer = transfers.get_mut(transfer_id)?;
+            change(transfer);
+            transfer.updated_at = Utc::now().to_rfc3339();
+            transfer.clone()
+        };
+        self.persist(&transfer).await;
+        Some(transfer)
+    }
+
+    async fn persist(&self, transfer: &Transfer) {
+        if let Err(e) = self.storage.save_transfer(transfer.clone()).await {
+            log::error!("Failed to save transfer {}: {}", transfer.id, e);
+        }
+        self.publish(transfer);
+    }
+
+    fn publish(&self, transfer: &Transfer) {
+        if let Ok(payload) = serde_json::to_value(transfer) {
+            self.window_handler
+                .send_to_all_windows(TRANSFER_UPDATED, payload);
+        }
+    }
+}
+
+fn new_transfer(
+    host_id: &str,
+    direction: TransferDirection,
+    local_path: String,
+    remote_path: String,
+    size: u64,
+    verify: bool,
+    now: &str,
+) -> Transfer {
+    Transfer {
+        id: Uuid::new_v4().to_string(),
+        host_id: host_id.to_string(),
+        direction,
+        local_path,
+        remote_path,
+        size,
+        transferred: 0,
+        status: TransferStatus::Queued,
+        verify,
+        checksum: None,
+        error: None,
+        created_at: now.to_string(),
+        updated_at: now.to_string(),
+    }
+}
+
+fn unfinished(transfers: &HashMap<String, Transfer>) -> Vec<Transfer> {
+    transfers
+        .values()
+        .filter(|t| !t.status.is_finished())
+        .cloned()
+        .collect()
+}
+
+/// Hashes everything `reader` yields. Returns `None` when the transfer is
+/// paused or cancelled part way.
+async fn sha256<R>(reader: &mut R, control: &watch::Receiver<Control>) -> Result<Option<String>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; TRANSFER_BUFFER_SIZE];
+
+    loop {
+        if *control.borrow() != Control::Run {
+            return Ok(None);
+        }
+        let n = reader.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(Some(hex::encode(hasher.finalize())));
+        }
+        hasher.update(&buffer[..n]);
+    }
+}
+
+/// Lists the files under a local path as (path, path relative to the root,
+/// size). A plain file yields itself with an empty relative path.
+async fn collect_local(path: &Path, relative: &str, files: &mut Vec<(PathBuf, String, u64)>) -> Result<()> {
+    let metadata = fs::metadata(path)
+        .await
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    if !metadata.is_dir() {
+        files.push((path.to_path_buf(), relative.to_string(), metadata.len()));
+        return Ok(());
+    }
+
+    let mut entries = fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        Box::pin(collect_local(&entry.path(), &join_remote(relative, &name), files)).await?;
+    }
+    Ok(())
+}
+
+/// Remote counterpart of `collect_local`. Symlinked directories are skipped
+/// so a link cycle cannot expand forever.
+async fn collect_remote(
+    sftp: &SftpSession,
+    path: &str,
+    relative: &str,
+    files: &mut Vec<(String, String, u64)>,
+) -> Result<()> {
+    let metadata = sftp
+        .metadata(path)
+        .await
+        .with_context(|| format!("Failed to read {}", path))?;
+    if !is_dir(&metadata) {
+        files.push((path.to_string(), relative.to_string(), metadata.size.unwrap_or(0)));
+        return Ok(());
+    }
+
+    for entry in sftp.read_dir(path).await? {
+        let name = entry.file_name();
+        if name == "." || name == ".." {
+            continue;
+        }
+        if !is_safe_name(&name) {
+            bail!("Server returned an invalid file name {:?} in {}", name, path);
+        }
+        let entry_path = join_remote(path, &name);
+        if is_symlink(&entry.metadata()) {
+            let target = sftp.metadata(entry_path.as_str()).await;
+            if target.map_or(true, |m| is_dir(&m)) {
+                continue;
+            }
+        }
+        Box::pin(collect_remote(sftp, &entry_path, &join_remote(relative, &name), files)).await?;
+    }
+    Ok(())
+}
+
+/// `collect_remote` for hosts without SFTP. The remote scp walks the tree
+/// and announces every file, which is declined before any content is sent.
+/// Entries it cannot read end up in `failed` with its warning.
+async fn collect_scp(
+    scp: &ScpClient,
+    path: &str,
+    files: &mut Vec<(String, String, u64)>,
+    failed: &mut Vec<(String, String, String)>,
+) -> Result<()> {
+    let mut source = scp
+        .receive(path, true)
+        .await
+        .with_context(|| format!("Failed to read {}", path))?;
+    // Relative paths of the directories the sender is currently in
+    let mut dirs: Vec<String> = Vec::new();
+
+    while let Some(record) = source
+        .next_record()
+        .await
+        .with_context(|| format!("Failed to read {}", path))?
+    {
+        match record {
+            Record::Directory { name, .. } => {
+                let relative = dirs
+                    .last()
+                    .map_or_else(String::new, |parent| join_remote(parent, &name));
+                dirs.push(relative);
+            }
+            Record::End => {
+                dirs.pop();
+            }
+            Record::File { name, size, .. } => {
+                let relative = dirs
+                    .last()
+                    .map_or_else(String::new, |parent| join_remote(parent, &name));
+                let remote_path = match relative.as_str() {
+                    "" => path.to_string(),
+                    relative => join_remote(path, relative),
+                };
+                files.push((remote_path, relative, size));
+                source.skip().await?;
+            }
+            Record::Warning(message) => {
+                // "scp: <path>: <reason>", for an entry of the current directory
+                let name = message
+                    .strip_prefix("scp: ")
+                    .and_then(|message| message.rsplit_once(": "))
+                    .map(|(warned, _)| file_name(warned))
+                    .filter(|name| is_safe_name(name));
+                let (remote_path, relative) = match (name, dirs.last()) {
+                    (Some(name), Some(parent)) => {
+                        let relative = join_remote(parent, &name);
+                        (join_remote(path, &relative), relative)
+                    }
+                    // The source itself, or nothing to tell which entry
+                    _ => (path.to_string(), String::new()),
+                };
+                failed.push((remote_path, relative, message));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[command]
+pub async fn transfer_enqueue(
+    transfer_manager: State<'_, Arc<TransferManager>>,
+    host_id: String,
+    direction: TransferDirection,
+    sources: Vec<String>,
+    target: String,
+    verify: Option<bool>,
+) -> Result<Vec<Transfer>, String> {
+    transfer_manager
+        .enqueue(&host_id, direction, &sources, &target, verify.unwrap_or(false))
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn transfer_list(
+    transfer_manager: State<'_, Arc<TransferManager>>,
+) -> Result<Vec<Transfer>, String> {
+    Ok(transfer_manager.list().await)
+}
+
+#[command]
+pub async fn transfer_pause(
+    transfer_manager: State<'_, Arc<TransferManager>>,
+    transfer_id: String,
+) -> Result<(), String> {
+    transfer_manager
+        .pause(&transfer_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn transfer_resume(
+    transfer_manager: State<'_, Arc<TransferManager>>,
+    transfer_id: String,
+) -> Result<(), String> {
+    transfer_manager
+        .resume(&transfer_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn transfer_cancel(
+    transfer_manager: State<'_, Arc<TransferManager>>,
+    transfer_id: String,
+) -> Result<(), String> {
+    transfer_manager
+        .cancel(&transfer_id)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn transfer_remove(
+    transfer_manager: State<'_, Arc<TransferManager>>,
+    transfer_ids: Vec<String>,
+) -> Result<(), String> {
+    transfer_manager
+        .remove(&transfer_ids)
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[command]
+pub async fn transfer_clear_finished(
+    transfer_manager: State<'_, Arc<TransferManager>>,
+) -> Result<(), String> {
+    transfer_manager
+        .clear_finished()
+        .await
+        .map_err(|e| e.to_string())
+}
@@ -0,0 +1,48 @@
+import { RecordingInfo, ReplayInfo } from "@/models/recording";
+import { Channel, invoke } from "@tauri-apps/api/core";
+
+export function useTimeMachine() {
+	const listRecordings = async () => {
+		return await invoke<RecordingInfo[]>("replay_list");
+	};
+
+	// Opens a paused replay. Output arrives as raw bytes for a terminal
+	// emulator; a seek resets it and redraws the screen at the new position.
+	// Position, speed and size changes are sent to this window as
+	// "replay:state" events.
+	const openReplay = async (path: string, onData: (data: Uint8Array) => void) => {
+		const output = new Channel<ArrayBuffer>();
+		output.onmessage = (data) => onData(new Uint8Array(data));
+		return await invoke<ReplayInfo>("replay_open", { path, output });
+	};
+
+	const playReplay = async (replayId: string) => {
+		await invoke("replay_play", { replayId });
+	};
+
+	const pauseReplay = async (replayId: string) => {
+		await invoke("replay_pause", { replayId });
+	};
+
+	const setReplaySpeed = async (replayId: string, speed: number) => {
+		await invoke("replay_set_speed", { replayId, speed });
+	};
+
+	const seekReplay = async (replayId: string, position: number) => {
+		await invoke("replay_seek", { replayId, position });
+	};
+
+	const closeReplay = async (replayId: string) => {
+		await invoke("replay_close", { replayId });
+	};
+
+	return {
+		listRecordings,
+		openReplay,
+		playReplay,
+		pauseReplay,
+		setReplaySpeed,
+		seekReplay,
+		closeReplay,
+	};
+}
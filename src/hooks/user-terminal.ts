@@ -1,9 +1,85 @@
 import { Host } from "@/models/host";
-import { invoke } from "@tauri-apps/api/core";
+import { SerialConfig, SerialPortInfo } from "@/models/serial";
+import { ScrollbackMatch } from "@/models/terminal";
+import { Channel, invoke } from "@tauri-apps/api/core";
 
 export function useTerminal() {
 	const createTerminal = async (sessionId: string) => {
-		return await invoke<string>("create_terminal", { sessionId });
+		return await invoke<string>("terminal_create", { sessionId });
+	};
+
+	// Runs the default shell from the settings on this machine
+	const openLocalShell = async (terminalId: string, rows: number, cols: number) => {
+		await invoke("terminal_open_local", { terminalId, rows, cols });
+	};
+
+	// Runs the login shell on a connected SSH session, with agent forwarding
+	// when the host has it enabled
+	const openSshShell = async (terminalId: string, sessionId: string, rows: number, cols: number) => {
+		await invoke("terminal_open_ssh", { terminalId, sessionId, rows, cols });
+	};
+
+	const listSerialPorts = async () => {
+		return await invoke<SerialPortInfo[]>("serial_list_ports");
+	};
+
+	const openSerial = async (terminalId: string, config: SerialConfig) => {
+		await invoke("terminal_open_serial", { terminalId, config });
+	};
+
+	// Connects to the host's hostname and port, 23 when unset
+	const openTelnet = async (terminalId: string, hostId: string, rows: number, cols: number) => {
+		await invoke("terminal_open_telnet", { terminalId, hostId, rows, cols });
+	};
+
+	// Output arrives as raw bytes, xterm.js decodes UTF-8 across chunks itself.
+	// onData calls `done` once a frame is rendered, e.g. from xterm's write
+	// callback; the backend stops reading while too many frames are pending.
+	const subscribeTerminal = async (
+		terminalId: string,
+		onData: (data: Uint8Array, done: () => void) => void,
+	) => {
+		const output = new Channel<ArrayBuffer>();
+		output.onmessage = (data) => {
+			const frame = new Uint8Array(data);
+			onData(frame, () => {
+				invoke("terminal_ack", { terminalId, bytes: frame.byteLength });
+			});
+		};
+		await invoke("terminal_subscribe", { terminalId, output });
+	};
+
+	const searchTerminal = async (terminalId: string, pattern: string) => {
+		return await invoke<ScrollbackMatch[]>("terminal_search", { terminalId, pattern });
+	};
+
+	// Resolves to the path of the .cast file being written
+	const startRecording = async (terminalId: string, recordInput: boolean) => {
+		return await invoke<string>("terminal_record_start", { terminalId, recordInput });
+	};
+
+	const stopRecording = async (terminalId: string) => {
+		return await invoke<string>("terminal_record_stop", { terminalId });
+	};
+
+	// Input goes out as raw bytes, strings such as xterm's onData encoded as
+	// UTF-8. Pass xterm's onBinary output as bytes, one per character.
+	const writeTerminal = async (terminalId: string, data: string | Uint8Array) => {
+		const bytes = typeof data === "string" ? new TextEncoder().encode(data) : data;
+		await invoke("terminal_write", bytes, { headers: { "Terminal-Id": terminalId } });
+	};
+
+	const resizeTerminal = async (terminalId: string, rows: number, cols: number) => {
+		await invoke("terminal_resize", { terminalId, rows, cols });
+	};
+
+	// Fails for terminals whose backend has no notion of a BREAK
+	const sendBreak = async (terminalId: string) => {
+		await invoke("terminal_break", { terminalId });
+	};
+
+	const closeTerminal = async (terminalId: string) => {
+		await invoke("terminal_close", { terminalId });
 	};
 
 	const saveHost = async (host: Host) => {
@@ -12,6 +88,19 @@ export function useTerminal() {
 
 	return {
 		createTerminal,
+		openLocalShell,
+		openSshShell,
+		listSerialPorts,
+		openSerial,
+		openTelnet,
+		subscribeTerminal,
+		writeTerminal,
+		resizeTerminal,
+		sendBreak,
+		closeTerminal,
+		searchTerminal,
+		startRecording,
+		stopRecording,
 		saveHost,
 	};
 }
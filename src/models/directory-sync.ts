@@ -0,0 +1,30 @@
+export interface SyncOptions {
+  hostId: string;
+  localPath: string;
+  remotePath: string;
+  direction: 'push' | 'pull'; // push mirrors local onto the server
+  compare: 'size-mtime' | 'checksum';
+  delete: boolean; // Remove target files missing from the source
+  excludes: string[]; // .gitignore-style patterns
+  useGitignore?: boolean; // Also read the source root's .gitignore
+  verify?: boolean;
+}
+
+export interface SyncAction {
+  kind: 'create' | 'update' | 'delete';
+  path: string; // Relative to both roots
+  isDir: boolean;
+  size: number;
+}
+
+export interface SyncPlan {
+  id: string; // Pass to sync_apply
+  options: SyncOptions;
+  actions: SyncAction[];
+}
+
+export interface SyncWatchEvent {
+  watchId: string;
+  actions?: SyncAction[];
+  error?: string;
+}
@@ -1,3 +1,4 @@
+import { SerialConfig } from './serial';
 import { Snippet } from './snippet';
 
 export interface Host {
@@ -17,9 +18,12 @@ export interface Host {
   lastConnected?: string;
   jumpHost?: string;
   useJumpHost?: boolean;
+  forwardAgent?: 'builtin' | 'system';
+  forwardAgentConfirm?: boolean; // Ask before each forwarded signature
   sshOptions?: Record<string, string>;
   keepAliveInterval?: number;
   connectionTimeout?: number;
+  serial?: SerialConfig; // Port settings of serial console hosts
   description?: string;
   color?: string;
   group?: string;
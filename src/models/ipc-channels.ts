@@ -34,17 +34,86 @@ export const IpcChannels = {
   SSH_CONNECT: 'ssh:connect',
   SSH_DISCONNECT: 'ssh:disconnect',
   SSH_SEND_DATA: 'ssh:sendData',
+  SSH_HOST_KEY_PROMPT: 'ssh:hostKeyPrompt',
+  SSH_HOST_KEY_REJECTED: 'ssh:hostKeyRejected',
+  SSH_CERTIFICATE_EXPIRING: 'ssh:certificateExpiring',
 
   // SFTP related channels
   SFTP_LIST_FILES: 'sftp:listFiles',
+  SFTP_CONNECT: 'sftp:connect',
+  SFTP_DISCONNECT: 'sftp:disconnect',
+  SFTP_LIST_REMOTE_FILES: 'sftp:listRemoteFiles',
+  SFTP_LIST_LOCAL_FILES: 'sftp:listLocalFiles',
+  SFTP_STAT: 'sftp:stat',
+  SFTP_MKDIR: 'sftp:mkdir',
+  SFTP_DELETE: 'sftp:delete',
+  SFTP_RENAME: 'sftp:rename',
   SFTP_DOWNLOAD: 'sftp:download',
   SFTP_UPLOAD: 'sftp:upload',
+  SFTP_DOWNLOAD_ARCHIVE: 'sftp:downloadArchive',
+  SFTP_UPLOAD_ARCHIVE: 'sftp:uploadArchive',
+  SFTP_CHECKSUM: 'sftp:checksum',
+  SFTP_CHMOD: 'sftp:chmod',
+  SFTP_CHOWN: 'sftp:chown',
+  SFTP_SYMLINK: 'sftp:symlink',
+  SFTP_READLINK: 'sftp:readlink',
+  SFTP_DETAILS: 'sftp:details',
+  SFTP_FSYNC: 'sftp:fsync',
+
+  // Terminal related channels
+  TERMINAL_CREATE: 'terminal:create',
+  TERMINAL_OPEN_LOCAL: 'terminal:openLocal',
+  TERMINAL_OPEN_SERIAL: 'terminal:openSerial',
+  TERMINAL_OPEN_TELNET: 'terminal:openTelnet',
+  TERMINAL_SUBSCRIBE: 'terminal:subscribe',
+  TERMINAL_ACK: 'terminal:ack',
+  TERMINAL_WRITE: 'terminal:write',
+  TERMINAL_RESIZE: 'terminal:resize',
+  TERMINAL_BREAK: 'terminal:break',
+  TERMINAL_CLOSE: 'terminal:close',
+  TERMINAL_STATE: 'terminal:state',
+  TERMINAL_SEARCH: 'terminal:search',
+  TERMINAL_RECORD_START: 'terminal:recordStart',
+  TERMINAL_RECORD_STOP: 'terminal:recordStop',
+
+  // Serial port related channels
+  SERIAL_LIST_PORTS: 'serial:listPorts',
+
+  // Session replay related channels
+  REPLAY_LIST: 'replay:list',
+  REPLAY_OPEN: 'replay:open',
+  REPLAY_PLAY: 'replay:play',
+  REPLAY_PAUSE: 'replay:pause',
+  REPLAY_SET_SPEED: 'replay:setSpeed',
+  REPLAY_SEEK: 'replay:seek',
+  REPLAY_CLOSE: 'replay:close',
+  REPLAY_STATE: 'replay:state',
+
+  // Transfer queue related channels
+  TRANSFER_UPDATED: 'transfer:updated',
+  TRANSFER_PROGRESS: 'transfer:progress',
+  TRANSFER_REMOVED: 'transfer:removed',
+
+  // Directory sync related channels
+  SYNC_WATCH_EVENT: 'sync:watchEvent',
+
+  // Remote file editing related channels
+  EDITOR_EVENT: 'editor:event',
 
   // SSH Key related channels
   KEY_GENERATE: 'key:generate',
   KEY_LIST: 'key:list',
   KEY_DELETE: 'key:delete',
 
+  // SSH agent related channels
+  AGENT_CONFIRM_REQUEST: 'agent:confirmRequest',
+  AGENT_LOCKED: 'agent:locked',
+  AGENT_UNLOCKED: 'agent:unlocked',
+
+  // Port forwarding related channels
+  FORWARDING_STATE: 'forwarding:state',
+  FORWARDING_CONNECTION: 'forwarding:connection',
+
   // App related channels
   APP_QUIT: 'app:quit',
   APP_MINIMIZE: 'app:minimize',
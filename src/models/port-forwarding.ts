@@ -1,12 +1,26 @@
 export interface PortForwarding {
   id: string;
   hostId: string;
-  type: 'local' | 'remote' | 'dynamic';
+  type: 'local' | 'remote' | 'dynamic' | 'http';
   localPort: number;
+  bindAddress?: string; // Defaults to 127.0.0.1
   remoteHost?: string;
   remotePort?: number;
+  boundPort?: number; // Port the server bound for remote forwards
+  // Unix socket paths used instead of TCP on this machine or the server
+  localSocketPath?: string;
+  remoteSocketPath?: string;
+  socketMode?: number; // File mode for a local socket, 0o600 when unset
+  // SOCKS options for dynamic forwards
+  socksUsername?: string;
+  socksPassword?: string; // Left out of listings, the stored one is kept when saved without it
+  remoteDns?: boolean; // Defaults to true
+  allowedDestinations?: string[]; // CIDRs
+  deniedDestinations?: string[]; // CIDRs
+  pacDomains?: string[]; // Domains the generated PAC file sends through an HTTP proxy
   description?: string;
-  enabled: boolean;
+  enabled: boolean; // Starts automatically when the host connects
+  headless?: boolean; // Keeps a session open just for this tunnel
   createdAt: Date;
   updatedAt: Date;
   isProFeature: boolean;
@@ -0,0 +1,23 @@
+export interface RecordingInfo {
+  path: string;
+  title?: string;
+  startedAt?: string;
+  size: number;
+}
+
+export interface ReplayInfo {
+  replayId: string;
+  width: number;
+  height: number;
+  duration: number; // Seconds
+  keyframes: number[]; // Seek targets restored without replaying from the start
+}
+
+export interface ReplayState {
+  replayId: string;
+  position: number; // Seconds into the recording
+  playing: boolean;
+  speed: number;
+  width: number;
+  height: number;
+}
@@ -0,0 +1,14 @@
+export interface EditSession {
+  id: string;
+  hostId: string;
+  remotePath: string;
+  localPath: string; // Private temp copy the editor works on
+  openedAt: Date;
+  terminalEditor?: string; // From $VISUAL/$EDITOR, run with editor_open_terminal
+}
+
+export interface EditorEvent {
+  sessionId: string;
+  kind: 'saved' | 'conflict' | 'reloaded' | 'failed';
+  error?: string;
+}
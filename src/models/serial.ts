@@ -0,0 +1,20 @@
+export interface SerialConfig {
+  port: string; // e.g. /dev/ttyUSB0 or COM3
+  baudRate: number;
+  dataBits: 5 | 6 | 7 | 8;
+  parity?: 'none' | 'odd' | 'even';
+  stopBits: 1 | 2;
+  flowControl?: 'none' | 'software' | 'hardware'; // XON/XOFF or RTS/CTS
+  lineEnding?: 'cr' | 'lf' | 'crlf'; // What Enter is sent as
+  implicitCr?: boolean; // Add a carriage return to bare line feeds from the device
+}
+
+export interface SerialPortInfo {
+  name: string;
+  portType: 'usb' | 'pci' | 'bluetooth' | 'unknown';
+  vid?: number;
+  pid?: number;
+  manufacturer?: string;
+  product?: string;
+  serialNumber?: string;
+}
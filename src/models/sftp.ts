@@ -0,0 +1,21 @@
+import { FileEntry } from '../services/sftp-client';
+
+// How a bulk download or upload moved its files
+export type ArchiveMethod = 'tar+zstd' | 'tar+gzip' | 'tar' | 'per-file';
+
+export interface FilesystemStats {
+  blockSize: number;
+  totalBytes: number;
+  freeBytes: number;
+  availableBytes: number; // Free space unprivileged users may use
+  totalInodes: number;
+  freeInodes: number;
+  readOnly: boolean;
+  maxNameLength: number;
+}
+
+export interface FileDetails {
+  entry: FileEntry;
+  mode: string; // Octal, e.g. "0755"
+  filesystem?: FilesystemStats; // Missing when the server lacks statvfs@openssh.com
+}
@@ -5,8 +5,12 @@ export interface SSHKey {
   bits?: number; // For RSA keys
   privateKeyPath: string;
   publicKeyPath: string;
+  certificatePath?: string; // OpenSSH user certificate (-cert.pub)
   passphrase?: string; // Only stored encrypted
   hostIds: string[]; // Hosts using this key
+  agentConfirm?: boolean; // Prompt before the built-in agent signs with this key
+  agentLifetime?: number; // Seconds the built-in agent keeps this key loaded
+  isCa?: boolean; // Key signs certificates for the local CA
   createdAt: Date;
   updatedAt: Date;
 }
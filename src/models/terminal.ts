@@ -0,0 +1,13 @@
+export type TerminalState = 'created' | 'attached' | 'detached' | 'closed';
+
+export interface TerminalStateEvent {
+  terminalId: string;
+  state: TerminalState;
+}
+
+// Offsets count raw output bytes from the terminal's first byte
+export interface ScrollbackMatch {
+  start: number;
+  end: number;
+  text: string;
+}
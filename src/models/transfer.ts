@@ -0,0 +1,32 @@
+export type TransferStatus =
+  | 'queued'
+  | 'running'
+  | 'paused'
+  | 'verifying'
+  | 'completed'
+  | 'failed'
+  | 'cancelled';
+
+export interface Transfer {
+  id: string;
+  hostId: string;
+  direction: 'upload' | 'download';
+  localPath: string;
+  remotePath: string;
+  size: number;
+  transferred: number; // Bytes on the target, where a resume picks up
+  status: TransferStatus;
+  verify: boolean; // SHA-256 comparison after the copy
+  checksum?: string;
+  error?: string;
+  createdAt: Date;
+  updatedAt: Date;
+}
+
+export interface TransferProgress {
+  id: string;
+  transferred: number;
+  size: number;
+  rate: number; // Bytes per second
+  etaSeconds?: number;
+}
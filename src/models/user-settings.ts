@@ -14,4 +14,8 @@ export interface UserSettings {
   cloudSyncEnabled: boolean;
   proUser: boolean;
   proExpiryDate?: Date;
+  maxTransfersPerHost?: number;
+  externalEditor?: string; // A GUI editor, e.g. "code --wait"; else $VISUAL/$EDITOR in a terminal
+  recordSessions?: boolean; // Record every terminal to an asciicast file
+  recordInput?: boolean; // Include keystrokes, passwords typed at prompts too
 }
@@ -16,6 +16,7 @@ export interface FileEntry {
   owner: number;
   group: number;
   path: string;
+  linkTarget?: string;
 }
 
 export class SFTPManager extends EventEmitter {
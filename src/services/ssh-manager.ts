@@ -348,7 +348,7 @@ export class SSHManager extends EventEmitter {
 
   async setupPortForwarding(
     sessionId: string,
-    type: 'local' | 'remote' | 'dynamic',
+    type: 'local' | 'remote' | 'dynamic' | 'http',
     localPort: number,
     remoteHost?: string,
     remotePort?: number